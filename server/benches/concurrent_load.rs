@@ -0,0 +1,95 @@
+//! Healthcheck latency while the server is flooded with progress writes.
+//!
+//! Runs over real HTTP against a file database on a 4-worker runtime:
+//! `WRITERS` tasks each send `WRITES_PER_TASK` progress updates while a
+//! probe requests /healthcheck in a loop. Run with `cargo bench`.
+
+use std::time::{Duration, Instant};
+
+use kosync_server::{create_router, AppState, Config, Database};
+
+const WORKERS: usize = 4;
+const WRITERS: usize = 64;
+const WRITES_PER_TASK: usize = 20;
+
+fn main() {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(WORKERS)
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run());
+}
+
+async fn run() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("bench.redb")).unwrap();
+    let key = format!("{:x}", md5::compute("pass"));
+    db.create_user("bench", &key).unwrap();
+    let app = create_router(AppState::new(db, Config::default()));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let client = reqwest::Client::new();
+    let (done_tx, done_rx) = tokio::sync::watch::channel(false);
+    let probe = tokio::spawn({
+        let client = client.clone();
+        let url = format!("{}/healthcheck", base);
+        async move {
+            let mut latencies = Vec::new();
+            while !*done_rx.borrow() {
+                let start = Instant::now();
+                client.get(&url).send().await.unwrap();
+                latencies.push(start.elapsed());
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            latencies
+        }
+    });
+
+    let start = Instant::now();
+    let writers: Vec<_> = (0..WRITERS)
+        .map(|task| {
+            let client = client.clone();
+            let url = format!("{}/syncs/progress", base);
+            let key = key.clone();
+            tokio::spawn(async move {
+                for i in 0..WRITES_PER_TASK {
+                    let response = client
+                        .put(&url)
+                        .header("x-auth-user", "bench")
+                        .header("x-auth-key", &key)
+                        .json(&serde_json::json!({
+                            "document": format!("doc{}", task),
+                            "progress": format!("page{}", i),
+                            "percentage": i as f64 / WRITES_PER_TASK as f64,
+                            "device": "bench",
+                        }))
+                        .send()
+                        .await
+                        .unwrap();
+                    assert!(response.status().is_success(), "{}", response.status());
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    done_tx.send(true).unwrap();
+
+    let mut latencies = probe.await.unwrap();
+    latencies.sort();
+    let p99 = latencies[(latencies.len() * 99 / 100).min(latencies.len() - 1)];
+    let writes = WRITERS * WRITES_PER_TASK;
+    println!("healthcheck samples  {}", latencies.len());
+    println!("healthcheck p99      {:?}", p99);
+    println!("healthcheck max      {:?}", latencies.last().unwrap());
+    println!(
+        "write throughput     {:.0}/s",
+        writes as f64 / elapsed.as_secs_f64()
+    );
+}
@@ -0,0 +1,124 @@
+//! Backups of the whole database.
+//!
+//! A backup is a complete redb file that can be used in place of the live
+//! one: stop the server, copy the backup over `KOSYNC_DB_PATH`, start it
+//! again. See `Database::backup` for how it is taken without pausing
+//! writes.
+//!
+//! redb reuses the pages of deleted data but never gives them back, so its
+//! file only grows. Since a backup is written fresh, it holds only live
+//! data; `compact` uses one to replace the database file.
+
+use std::path::{Path, PathBuf};
+
+use crate::db::{self, Database};
+use crate::error::{AppError, Result};
+use crate::s3;
+use crate::streaks;
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+/// Write a backup into `dir`, named after the current time, and return its
+/// path and size in bytes.
+pub fn create_in(db: &Database, dir: &Path) -> Result<(PathBuf, u64)> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(file_name(db::now()));
+    let size = db.backup(&path)?;
+    Ok((path, size))
+}
+
+/// Take a backup into a temporary file and open it for reading, returning
+/// the file and its size. The file is unlinked once open, so on Unix it is
+/// gone as soon as the caller is done with it.
+pub fn snapshot(db: &Database) -> Result<(std::fs::File, u64)> {
+    let path = std::env::temp_dir().join(format!("kosync-snapshot-{}.db", uuid::Uuid::new_v4()));
+    let size = db.backup(&path)?;
+    let file = std::fs::File::open(&path);
+    if let Err(e) = std::fs::remove_file(&path) {
+        tracing::warn!("Failed to delete snapshot {}: {}", path.display(), e);
+    }
+    Ok((file?, size))
+}
+
+/// Rewrite the database file at `path` into a fresh one holding only live
+/// data, returning its sizes before and after. The database must not be
+/// open elsewhere.
+pub fn compact(path: &Path) -> Result<(u64, u64)> {
+    let before = std::fs::metadata(path)?.len();
+    let mut fresh = path.as_os_str().to_owned();
+    fresh.push(".compact");
+    let fresh = PathBuf::from(fresh);
+    // Left over from a compaction that was interrupted
+    if fresh.exists() {
+        std::fs::remove_file(&fresh)?;
+    }
+    let after = Database::open(path)?.backup(&fresh)?;
+    std::fs::rename(&fresh, path)?;
+    Ok((before, after))
+}
+
+/// Delete all but the newest `keep` backups in `dir`, returning how many
+/// were deleted. Only files named by `file_name` are considered.
+pub fn prune(dir: &Path, keep: usize) -> Result<usize> {
+    let mut backups = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if name.to_str().is_some_and(is_backup) {
+            backups.push(name);
+        }
+    }
+    backups.sort_unstable();
+    let excess = backups.len().saturating_sub(keep);
+    for name in &backups[..excess] {
+        std::fs::remove_file(dir.join(name))?;
+    }
+    Ok(excess)
+}
+
+/// Upload the backup at `path` to `bucket` under its file name, then delete
+/// all but the newest `keep` backups there (0 keeps all), returning how many
+/// were deleted.
+pub async fn upload(bucket: &s3::Bucket, path: &Path, keep: usize) -> Result<usize> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| AppError::InvalidRequest(format!("{} has no name", path.display())))?;
+    bucket.put(name, tokio::fs::read(path).await?).await?;
+    if keep == 0 {
+        return Ok(0);
+    }
+    let mut backups: Vec<String> = bucket
+        .list()
+        .await?
+        .into_iter()
+        .filter(|name| is_backup(name))
+        .collect();
+    backups.sort_unstable();
+    let excess = backups.len().saturating_sub(keep);
+    for name in &backups[..excess] {
+        bucket.delete(name).await?;
+    }
+    Ok(excess)
+}
+
+fn is_backup(name: &str) -> bool {
+    name.starts_with("kosync-") && name.ends_with("Z.db")
+}
+
+/// `kosync-20240131T235959Z.db`, which sorts in the order backups were taken.
+pub fn file_name(timestamp: i64) -> String {
+    format!("kosync-{}.db", basic_time(timestamp))
+}
+
+/// `20240131T235959Z`, the ISO 8601 basic format.
+pub(crate) fn basic_time(timestamp: i64) -> String {
+    let date = streaks::format_date(timestamp.div_euclid(DAY_SECS)).replace('-', "");
+    let seconds = timestamp.rem_euclid(DAY_SECS);
+    format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
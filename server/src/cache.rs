@@ -0,0 +1,106 @@
+//! In-memory cache of frequently polled reads, such as a document's
+//! progress checked by every device on wake.
+//!
+//! Entries aren't invalidated one by one: a write can change what another
+//! user or document reads (aliases, shared annotations), so every commit
+//! bumps a generation instead, and an entry read under an older one is
+//! neither stored nor returned.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// The current generation, shared by every cache of one database.
+#[derive(Debug, Default)]
+pub struct Generation(AtomicU64);
+
+impl Generation {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Invalidate every cached entry; call after a commit.
+    pub fn bump(&self) {
+        self.0.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+struct Entry<V> {
+    value: V,
+    generation: u64,
+    stored_at: Instant,
+    used_at: Instant,
+}
+
+/// A bounded cache with a time-to-live, evicting the least recently used
+/// entry when full. A capacity of 0 disables it.
+pub struct ReadCache<K, V> {
+    entries: Mutex<HashMap<K, Entry<V>>>,
+    generation: Arc<Generation>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> ReadCache<K, V> {
+    pub fn new(generation: Arc<Generation>, capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::default(),
+            generation,
+            capacity,
+            ttl,
+        }
+    }
+
+    /// The cached value for `key`, or `read`'s result, cached if nothing
+    /// was committed while it ran.
+    pub fn get_or_read<E>(&self, key: K, read: impl FnOnce() -> Result<V, E>) -> Result<V, E> {
+        if self.capacity == 0 {
+            return read();
+        }
+        // Before reading, so a commit that lands mid-read is noticed
+        let generation = self.generation.get();
+        let now = Instant::now();
+        if let Some(entry) = self.lock().get_mut(&key) {
+            if entry.generation == generation && now < entry.stored_at + self.ttl {
+                entry.used_at = now;
+                return Ok(entry.value.clone());
+            }
+        }
+
+        let value = read()?;
+        let mut entries = self.lock();
+        if self.generation.get() != generation {
+            return Ok(value);
+        }
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|_, entry| {
+                entry.generation == generation && now < entry.stored_at + self.ttl
+            });
+        }
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key,
+            Entry {
+                value: value.clone(),
+                generation,
+                stored_at: now,
+                used_at: now,
+            },
+        );
+        Ok(value)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<K, Entry<V>>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
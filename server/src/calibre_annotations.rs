@@ -0,0 +1,324 @@
+//! Conversion between kosync annotations and the Calibre viewer's
+//! annotation JSON.
+//!
+//! Calibre locates highlights by EPUB CFI and KOReader by xpointer, and
+//! neither can be translated exactly without the book itself. Annotations
+//! imported from Calibre keep their CFIs so they export back unchanged; for
+//! the rest, the spine item comes from the xpointer's `DocFragment` and the
+//! CFI path is a best-effort guess that assumes each element is the first of
+//! its siblings with that tag name.
+
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Annotation, CalibreLocation};
+
+/// The file Calibre's viewer writes when exporting highlights.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CalibreAnnotationCollection {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub version: u32,
+    pub annotations: Vec<CalibreAnnotation>,
+}
+
+/// Accepted import bodies: an exported collection or a bare array.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum CalibreImport {
+    Collection(CalibreAnnotationCollection),
+    Annotations(Vec<CalibreAnnotation>),
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CalibreAnnotation {
+    /// `highlight` or `bookmark`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    pub timestamp: String,
+    /// Calibre keeps deleted annotations around with this set.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub removed: bool,
+
+    // Highlights
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_cfi: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_cfi: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spine_index: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub toc_family_titles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub highlighted_text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub style: Option<CalibreStyle>,
+
+    // Bookmarks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pos: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pos_type: Option<String>,
+
+    /// The KOReader position this was derived from, for reference.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kosync_xpointer: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibreStyle {
+    /// `color` or `decoration`.
+    pub kind: String,
+    #[serde(rename = "type")]
+    pub style_type: String,
+    pub which: String,
+}
+
+const COLLECTION_TYPE: &str = "calibre_annotation_collection";
+
+/// Convert a Calibre import into annotations to merge and ids to delete.
+pub fn import(body: CalibreImport) -> (Vec<Annotation>, Vec<String>) {
+    let entries = match body {
+        CalibreImport::Collection(collection) => collection.annotations,
+        CalibreImport::Annotations(entries) => entries,
+    };
+
+    let mut annotations = Vec::new();
+    let mut deleted = Vec::new();
+    for entry in entries {
+        if entry.removed {
+            deleted.extend(entry.uuid);
+        } else if let Some(annotation) = to_annotation(entry) {
+            annotations.push(annotation);
+        }
+    }
+    (annotations, deleted)
+}
+
+/// Annotations in Calibre's export format. Those without an EPUB position
+/// (PDF page numbers, for instance) are left out.
+pub fn export(annotations: &[Annotation]) -> CalibreAnnotationCollection {
+    CalibreAnnotationCollection {
+        kind: COLLECTION_TYPE.to_string(),
+        version: 1,
+        annotations: annotations.iter().filter_map(to_calibre).collect(),
+    }
+}
+
+fn to_annotation(entry: CalibreAnnotation) -> Option<Annotation> {
+    let datetime = from_calibre_timestamp(&entry.timestamp);
+    let (spine_index, location, text, note, chapter) = match entry.kind.as_str() {
+        "highlight" => {
+            let spine_index = entry.spine_index?;
+            let location = CalibreLocation {
+                spine_index,
+                start_cfi: entry.start_cfi?,
+                end_cfi: entry.end_cfi,
+            };
+            (
+                spine_index,
+                location,
+                entry.highlighted_text,
+                entry.notes,
+                entry.toc_family_titles.last().cloned(),
+            )
+        }
+        "bookmark" => {
+            let pos = entry.pos?;
+            let spine_index = cfi_spine_index(&pos)?;
+            let location = CalibreLocation {
+                spine_index,
+                start_cfi: pos,
+                end_cfi: None,
+            };
+            (spine_index, location, None, entry.title, None)
+        }
+        _ => return None,
+    };
+
+    let (drawer, color) = match &entry.style {
+        Some(style) if style.kind == "decoration" => {
+            let drawer = match style.which.as_str() {
+                "strikeout" => "strikeout",
+                _ => "underscore",
+            };
+            (Some(drawer.to_string()), None)
+        }
+        Some(style) => (Some("lighten".to_string()), Some(style.which.clone())),
+        None if text.is_some() => (Some("lighten".to_string()), None),
+        None => (None, None),
+    };
+
+    Some(Annotation {
+        id: entry.uuid,
+        datetime,
+        datetime_updated: None,
+        drawer,
+        color,
+        text,
+        text_edited: None,
+        note,
+        chapter,
+        pageno: None,
+        page: serde_json::Value::String(format!("/body/DocFragment[{}]", spine_index + 1)),
+        pos0: None,
+        pos1: None,
+        version: None,
+        tags: Vec::new(),
+        attachments: Vec::new(),
+        clocks: Default::default(),
+        calibre: Some(location),
+        kindle: None,
+        created_by: None,
+        modified_by: None,
+    })
+}
+
+fn to_calibre(annotation: &Annotation) -> Option<CalibreAnnotation> {
+    let page = annotation.page.as_str();
+    let location = match &annotation.calibre {
+        Some(location) => location.clone(),
+        None => {
+            let page = page?;
+            let start = annotation.pos0.as_ref().and_then(|p| p.as_str());
+            let end = annotation.pos1.as_ref().and_then(|p| p.as_str());
+            CalibreLocation {
+                spine_index: xpointer_spine_index(page)?,
+                start_cfi: xpointer_to_cfi(start.unwrap_or(page))?,
+                end_cfi: end.and_then(xpointer_to_cfi),
+            }
+        }
+    };
+
+    let timestamp = to_calibre_timestamp(
+        annotation
+            .datetime_updated
+            .as_deref()
+            .unwrap_or(&annotation.datetime),
+    );
+    let text = annotation.text.clone().filter(|t| !t.trim().is_empty());
+
+    let mut entry = CalibreAnnotation {
+        uuid: annotation.id.clone(),
+        timestamp,
+        kosync_xpointer: page.map(str::to_string),
+        ..Default::default()
+    };
+    match (text, location.end_cfi) {
+        (Some(text), Some(end_cfi)) => {
+            entry.kind = "highlight".to_string();
+            entry.spine_index = Some(location.spine_index);
+            entry.start_cfi = Some(location.start_cfi);
+            entry.end_cfi = Some(end_cfi);
+            entry.highlighted_text = Some(text);
+            entry.notes = annotation.note.clone();
+            entry.toc_family_titles = annotation.chapter.clone().into_iter().collect();
+            entry.style = Some(calibre_style(annotation));
+        }
+        _ => {
+            entry.kind = "bookmark".to_string();
+            entry.pos = Some(if location.start_cfi.starts_with("epubcfi(") {
+                location.start_cfi
+            } else {
+                format!(
+                    "epubcfi(/{}{})",
+                    (location.spine_index + 1) * 2,
+                    location.start_cfi
+                )
+            });
+            entry.pos_type = Some("epubcfi".to_string());
+            entry.title = annotation
+                .note
+                .clone()
+                .or_else(|| annotation.chapter.clone())
+                .or_else(|| Some("Bookmark".to_string()));
+        }
+    }
+    Some(entry)
+}
+
+fn calibre_style(annotation: &Annotation) -> CalibreStyle {
+    match annotation.drawer.as_deref() {
+        Some(drawer @ ("underscore" | "strikeout")) => CalibreStyle {
+            kind: "decoration".to_string(),
+            style_type: "builtin".to_string(),
+            which: if drawer == "strikeout" {
+                "strikeout"
+            } else {
+                "wavy"
+            }
+            .to_string(),
+        },
+        _ => CalibreStyle {
+            kind: "color".to_string(),
+            style_type: "builtin".to_string(),
+            which: annotation
+                .color
+                .clone()
+                .unwrap_or_else(|| "yellow".to_string()),
+        },
+    }
+}
+
+/// Zero-based spine index of `/body/DocFragment[N]/...`.
+fn xpointer_spine_index(xpointer: &str) -> Option<u32> {
+    let rest = xpointer.strip_prefix("/body/DocFragment[")?;
+    let index: u32 = rest[..rest.find(']')?].parse().ok()?;
+    index.checked_sub(1)
+}
+
+/// Zero-based spine index of `epubcfi(/N/...)`.
+fn cfi_spine_index(cfi: &str) -> Option<u32> {
+    let path = cfi.strip_prefix("epubcfi(/")?;
+    let step = path.split(['/', '!', ')', '[']).next()?;
+    (step.parse::<u32>().ok()? / 2).checked_sub(1)
+}
+
+/// Best-effort CFI for the part of an xpointer inside its spine item, e.g.
+/// `/body/DocFragment[3]/body/div/p[5]/text().17` becomes `/4/2/10:17`.
+fn xpointer_to_cfi(xpointer: &str) -> Option<String> {
+    let rest = xpointer.strip_prefix("/body/DocFragment[")?;
+    let rest = &rest[rest.find(']')? + 1..];
+
+    let mut cfi = String::new();
+    for step in rest.split('/').filter(|s| !s.is_empty()) {
+        if let Some(text) = step.strip_prefix("text()") {
+            let offset = text.rsplit('.').next().filter(|_| text.contains('.'));
+            cfi.push(':');
+            cfi.push_str(offset.unwrap_or("0"));
+            break;
+        }
+        let (name, index) = match step.find('[') {
+            Some(open) => (
+                &step[..open],
+                step[open + 1..].trim_end_matches(']').parse().ok()?,
+            ),
+            None => (step, 1u32),
+        };
+        // The body is the second child of <html>, after <head>
+        let index = if name == "body" && cfi.is_empty() {
+            2
+        } else {
+            index
+        };
+        cfi.push_str(&format!("/{}", index * 2));
+    }
+    Some(cfi)
+}
+
+/// `2024-01-15T10:00:00.000Z` to KOReader's `2024-01-15 10:00:00`.
+fn from_calibre_timestamp(timestamp: &str) -> String {
+    timestamp
+        .get(..19)
+        .unwrap_or(timestamp)
+        .replacen('T', " ", 1)
+}
+
+fn to_calibre_timestamp(datetime: &str) -> String {
+    format!("{}.000Z", datetime.replacen(' ', "T", 1))
+}
@@ -0,0 +1,184 @@
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::db::ProgressWrite;
+use crate::error::{AppError, Result};
+use crate::models::{CalibreBook, CalibreWebIntegration, SyncEvent, UpdateProgressRequest};
+use crate::{outbound, AppState};
+
+/// Key the login is stored under in the integrations table.
+pub const NAME: &str = "calibre-web";
+
+/// Device name recorded for positions pulled from calibre-web. Updates from
+/// this device are not pushed back.
+pub const DEVICE: &str = "calibre-web";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Push progress for documents mapped to a Calibre book to the user's
+/// calibre-web instance, as the browser reader's bookmark.
+///
+/// Positions are exchanged verbatim: calibre-web stores whatever bookmark
+/// string it is given, so the web reader and KOReader need to agree on the
+/// position format for the result to be usable on both sides.
+pub fn spawn_sync(state: AppState) {
+    let mut events = state.events.subscribe();
+    let client = client(&state);
+
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("calibre-web sync skipped {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let SyncEvent::Progress {
+                document,
+                progress,
+                device,
+                ..
+            } = &event.event
+            else {
+                continue;
+            };
+            if device == DEVICE {
+                continue;
+            }
+
+            let (integration, book) = match lookup(&state, &event.username, document) {
+                Ok(Some(found)) => found,
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::error!("Failed to load calibre-web mapping: {}", e);
+                    continue;
+                }
+            };
+
+            let client = client.clone();
+            let progress = progress.clone();
+            tokio::spawn(async move {
+                if let Err(e) = push(&client, &integration, &book, &progress).await {
+                    tracing::warn!("calibre-web push for {} failed: {}", event.username, e);
+                }
+            });
+        }
+    });
+}
+
+/// Fetch the calibre-web reader's bookmark for a document and store it as
+/// the user's progress. Returns `None` if calibre-web has no bookmark.
+pub async fn pull(
+    state: &AppState,
+    username: &str,
+    document: &str,
+) -> Result<Option<(UpdateProgressRequest, ProgressWrite)>> {
+    let (integration, book) = lookup(state, username, document)?.ok_or_else(|| {
+        AppError::InvalidRequest("calibre-web is not configured for this document".into())
+    })?;
+
+    let Some(bookmark) = fetch_bookmark(&client(state), &integration, &book)
+        .await
+        .map_err(AppError::Upstream)?
+    else {
+        return Ok(None);
+    };
+
+    // calibre-web has no notion of percentage; keep the one we have
+    let percentage = state
+        .with_db(|db| db.get_progress(username, document))?
+        .percentage
+        .unwrap_or(0.0);
+    let update = UpdateProgressRequest {
+        document: document.to_string(),
+        progress: bookmark,
+        percentage,
+        device: DEVICE.to_string(),
+        force: true,
+        ..Default::default()
+    };
+    let written = state.with_db(|db| db.set_progress(username, &update))?;
+    Ok(Some((update, written)))
+}
+
+fn lookup(
+    state: &AppState,
+    username: &str,
+    document: &str,
+) -> Result<Option<(CalibreWebIntegration, CalibreBook)>> {
+    let Some(integration): Option<CalibreWebIntegration> =
+        state.with_db(|db| db.get_integration(username, NAME))?
+    else {
+        return Ok(None);
+    };
+    // Saved before private addresses were refused
+    outbound::check_url(&integration.url, state.config.private_urls)
+        .map_err(|e| AppError::InvalidRequest(format!("calibre-web {}", e)))?;
+    Ok(state
+        .with_db(|db| db.get_calibre_book(username, document))?
+        .map(|book| (integration, book)))
+}
+
+fn client(state: &AppState) -> reqwest::Client {
+    outbound::client(REQUEST_TIMEOUT, state.config.private_urls)
+}
+
+fn endpoint(integration: &CalibreWebIntegration, path: &str) -> String {
+    format!("{}{}", integration.url.trim_end_matches('/'), path)
+}
+
+async fn push(
+    client: &reqwest::Client,
+    integration: &CalibreWebIntegration,
+    book: &CalibreBook,
+    progress: &str,
+) -> std::result::Result<(), String> {
+    let url = endpoint(
+        integration,
+        &format!("/ajax/bookmark/{}/{}", book.book_id, book.format),
+    );
+    let response = client
+        .post(url)
+        .basic_auth(&integration.username, Some(&integration.password))
+        .form(&[("bookmark", progress)])
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}", response.status()));
+    }
+    Ok(())
+}
+
+/// calibre-web only exposes the bookmark inside the reader page, as
+/// `bookmark: "<key>"` in its inline script.
+async fn fetch_bookmark(
+    client: &reqwest::Client,
+    integration: &CalibreWebIntegration,
+    book: &CalibreBook,
+) -> std::result::Result<Option<String>, String> {
+    let url = endpoint(
+        integration,
+        &format!("/read/{}/{}", book.book_id, book.format),
+    );
+    let response = client
+        .get(url)
+        .basic_auth(&integration.username, Some(&integration.password))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}", response.status()));
+    }
+    let page = response.text().await.map_err(|e| e.to_string())?;
+
+    const MARKER: &str = "bookmark: \"";
+    let Some(start) = page.find(MARKER).map(|i| i + MARKER.len()) else {
+        return Err("no bookmark in reader page".into());
+    };
+    let bookmark = page[start..].split('"').next().unwrap_or_default();
+    Ok((!bookmark.is_empty()).then(|| bookmark.to_string()))
+}
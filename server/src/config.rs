@@ -0,0 +1,451 @@
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How out-of-range progress percentages are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PercentageMode {
+    /// Reject anything outside `[0, 1]`.
+    #[default]
+    Strict,
+    /// Clamp values into `[0, 1]`; only non-finite values are rejected.
+    Lenient,
+}
+
+impl FromStr for PercentageMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Ok(Self::Strict),
+            "lenient" => Ok(Self::Lenient),
+            _ => Err(format!("unknown percentage mode: {}", s)),
+        }
+    }
+}
+
+/// When a commit reaches the disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Every commit is flushed to disk before it returns.
+    #[default]
+    Immediate,
+    /// Commits return once applied in memory and are flushed to disk
+    /// together every `flush_interval`; a crash loses at most that much.
+    Eventual,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "immediate" => Ok(Self::Immediate),
+            "eventual" => Ok(Self::Eventual),
+            _ => Err(format!("unknown durability: {}", s)),
+        }
+    }
+}
+
+/// How an uploaded annotation that conflicts with a concurrent change on the
+/// server is resolved; see `merge::MergeStrategy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeStrategyKind {
+    /// Keep the stored annotation.
+    ServerWins,
+    /// Replace the stored annotation with the upload.
+    ClientWins,
+    /// Keep the most recent change to each field.
+    #[default]
+    NewestWins,
+    /// Keep both, storing the upload as a new annotation.
+    Union,
+    /// Reject the upload so the client can resolve the conflict.
+    Manual,
+}
+
+impl FromStr for MergeStrategyKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "server-wins" => Ok(Self::ServerWins),
+            "client-wins" => Ok(Self::ClientWins),
+            "newest-wins" => Ok(Self::NewestWins),
+            "union" => Ok(Self::Union),
+            "manual" => Ok(Self::Manual),
+            _ => Err(format!("unknown merge strategy: {}", s)),
+        }
+    }
+}
+
+/// Runtime settings, read from `KOSYNC_*` environment variables.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Users allowed to call the `/admin/*` endpoints.
+    pub admin_users: Vec<String>,
+    /// Length of the rolling window used for per-user usage metrics.
+    pub usage_window: Duration,
+    /// Enables the built-in `demo` account, which accepts any key.
+    pub demo_mode: bool,
+    /// How often the demo account's data is wiped.
+    pub demo_reset_interval: Duration,
+    /// Record every progress update in the history table.
+    pub progress_history: bool,
+    /// Refuse progress updates that move a document backwards, unless the
+    /// client sets `force`. Users can override this in their settings.
+    pub furthest_read_only: bool,
+    pub percentage_mode: PercentageMode,
+    /// Percentage at which a document counts as finished.
+    pub finished_threshold: f64,
+    /// Delivery attempts per webhook event before giving up.
+    pub webhook_max_attempts: u32,
+    /// Delay before the first webhook retry; doubles on each attempt.
+    pub webhook_retry_delay: Duration,
+    /// Let user-supplied URLs reach loopback, private and link-local
+    /// addresses; see `outbound`.
+    pub private_urls: bool,
+    /// Keep each device's latest position per document as well.
+    pub device_progress: bool,
+    /// GraphQL endpoint used by the Hardcover integration.
+    pub hardcover_url: String,
+    /// Purge progress untouched for this long. `None` keeps it forever.
+    pub progress_retention: Option<Duration>,
+    /// How often the retention task runs.
+    pub retention_interval: Duration,
+    /// Refuse progress updates whose client timestamp is older than the stored one.
+    pub reject_stale_progress: bool,
+    /// Answer 404 instead of `{}` for documents without progress.
+    pub missing_progress_404: bool,
+    /// How long `Idempotency-Key`s on progress updates are remembered.
+    pub idempotency_ttl: Duration,
+    /// Highlight endpoint used by the Readwise integration.
+    pub readwise_url: String,
+    /// How often failed Readwise pushes are retried; the wait doubles per attempt.
+    pub readwise_retry_interval: Duration,
+    /// Age after which annotation deletions fetched by every syncing device are forgotten; kept forever when unset.
+    pub tombstone_retention: Option<Duration>,
+    /// Versions of each document's annotations kept for revert; 0 keeps none.
+    pub annotation_history: usize,
+    /// Default resolution of annotation conflicts; uploads may override it.
+    pub merge_strategy: MergeStrategyKind,
+    /// Largest annotation attachment accepted, in bytes.
+    pub max_attachment_size: usize,
+    /// Longest highlighted text or note accepted on an annotation, in bytes.
+    pub max_annotation_text_bytes: usize,
+    /// Longest value accepted for an annotation's other fields, such as its position or chapter.
+    pub max_annotation_field_bytes: usize,
+    /// Most annotations and deletions accepted in one upload.
+    pub max_annotations_per_request: usize,
+    /// Most annotations kept for one document; 0 for no limit.
+    pub max_annotations_per_document: usize,
+    /// Directory backups are written to. `POST /admin/backup` is refused and
+    /// no backups are scheduled when unset.
+    pub backup_dir: Option<PathBuf>,
+    /// How often a backup is written to `backup_dir`, at whole multiples of
+    /// the interval since midnight UTC; `None` schedules none.
+    pub backup_interval: Option<Duration>,
+    /// Backups kept in `backup_dir` after a scheduled one; older ones are
+    /// deleted. 0 keeps all.
+    pub backup_keep: usize,
+    /// Rewrite the database file before serving, reclaiming space left by deletions.
+    pub compact_on_startup: bool,
+    /// Progress and annotation reads kept in memory, per kind; 0 disables the cache.
+    pub read_cache_size: usize,
+    /// How long a cached read is served before it is read again.
+    pub read_cache_ttl: Duration,
+    /// Directory, typically on another disk or machine, that snapshots are
+    /// continuously replicated to.
+    pub replica_dir: Option<PathBuf>,
+    /// How often a snapshot is replicated, if anything changed since the last.
+    pub replica_interval: Duration,
+    /// Snapshots kept in `replica_dir`; 0 keeps all.
+    pub replica_keep: usize,
+    /// S3-compatible endpoint scheduled backups are also uploaded to, e.g.
+    /// `https://s3.us-east-1.amazonaws.com`. Needs `s3_bucket` and credentials.
+    pub s3_endpoint: Option<String>,
+    /// Bucket backups are uploaded to.
+    pub s3_bucket: Option<String>,
+    /// Region requests to `s3_endpoint` are signed for.
+    pub s3_region: String,
+    /// Credentials requests to `s3_endpoint` are signed with.
+    pub s3_access_key_id: Option<String>,
+    pub s3_secret_access_key: Option<String>,
+    /// Prefix of the keys backups are uploaded under, e.g. `kosync/`.
+    pub s3_prefix: String,
+    /// When commits reach the disk.
+    pub durability: Durability,
+    /// How often commits are flushed to disk with `Durability::Eventual`.
+    pub flush_interval: Duration,
+    /// How long deleted progress and annotations stay in the trash. Zero
+    /// disables the trash, deleting them outright.
+    pub trash_retention: Duration,
+    /// How long change journal entries are kept. Zero keeps them forever.
+    pub journal_retention: Duration,
+    /// Base URL of the leader this server follows, e.g.
+    /// `https://sync.example.com`. A follower applies the leader's change
+    /// journal and refuses writes of its own.
+    pub leader_url: Option<String>,
+    /// Credentials of an admin account on the leader; the key is the MD5 of
+    /// its password, as clients send it.
+    pub leader_username: Option<String>,
+    pub leader_key: Option<String>,
+    /// How often a follower asks the leader for new changes.
+    pub follow_interval: Duration,
+    /// Size in bytes the database file should stay under.
+    pub disk_budget: Option<u64>,
+    /// Share of `disk_budget` at which a warning is logged.
+    pub disk_warn_percent: u64,
+    /// Refuse writes once the database file reaches `disk_budget`.
+    pub disk_read_only: bool,
+    /// How often the database file is measured against `disk_budget`.
+    pub disk_check_interval: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            admin_users: Vec::new(),
+            usage_window: Duration::from_secs(24 * 60 * 60),
+            demo_mode: false,
+            demo_reset_interval: Duration::from_secs(60 * 60),
+            progress_history: false,
+            furthest_read_only: false,
+            percentage_mode: PercentageMode::default(),
+            finished_threshold: 0.98,
+            webhook_max_attempts: 5,
+            webhook_retry_delay: Duration::from_secs(2),
+            private_urls: false,
+            device_progress: false,
+            hardcover_url: "https://api.hardcover.app/v1/graphql".into(),
+            progress_retention: None,
+            retention_interval: Duration::from_secs(60 * 60),
+            reject_stale_progress: false,
+            missing_progress_404: false,
+            idempotency_ttl: Duration::from_secs(24 * 60 * 60),
+            readwise_url: "https://readwise.io/api/v2/highlights/".into(),
+            readwise_retry_interval: Duration::from_secs(5 * 60),
+            tombstone_retention: None,
+            annotation_history: 20,
+            merge_strategy: MergeStrategyKind::default(),
+            max_attachment_size: 5 * 1024 * 1024,
+            max_annotation_text_bytes: 64 * 1024,
+            max_annotation_field_bytes: 1024,
+            max_annotations_per_request: 5000,
+            max_annotations_per_document: 10_000,
+            backup_dir: None,
+            backup_interval: None,
+            backup_keep: 7,
+            compact_on_startup: false,
+            read_cache_size: 1000,
+            read_cache_ttl: Duration::from_secs(60),
+            replica_dir: None,
+            replica_interval: Duration::from_secs(60),
+            replica_keep: 24,
+            s3_endpoint: None,
+            s3_bucket: None,
+            s3_region: "us-east-1".to_string(),
+            s3_access_key_id: None,
+            s3_secret_access_key: None,
+            s3_prefix: String::new(),
+            durability: Durability::default(),
+            flush_interval: Duration::from_secs(1),
+            trash_retention: Duration::from_secs(30 * 24 * 60 * 60),
+            journal_retention: Duration::from_secs(30 * 24 * 60 * 60),
+            leader_url: None,
+            leader_username: None,
+            leader_key: None,
+            follow_interval: Duration::from_secs(5),
+            disk_budget: None,
+            disk_warn_percent: 80,
+            disk_read_only: false,
+            disk_check_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            admin_users: env_list("KOSYNC_ADMIN_USERS").unwrap_or(default.admin_users),
+            usage_window: env_parse("KOSYNC_USAGE_WINDOW_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.usage_window),
+            demo_mode: env_bool("KOSYNC_DEMO_MODE").unwrap_or(default.demo_mode),
+            demo_reset_interval: env_interval("KOSYNC_DEMO_RESET_SECS", Duration::from_secs)
+                .unwrap_or(default.demo_reset_interval),
+            progress_history: env_bool("KOSYNC_PROGRESS_HISTORY")
+                .unwrap_or(default.progress_history),
+            furthest_read_only: env_bool("KOSYNC_FURTHEST_READ_ONLY")
+                .unwrap_or(default.furthest_read_only),
+            percentage_mode: env_parse("KOSYNC_PERCENTAGE_MODE").unwrap_or(default.percentage_mode),
+            finished_threshold: env_parse("KOSYNC_FINISHED_THRESHOLD")
+                .unwrap_or(default.finished_threshold),
+            webhook_max_attempts: env_parse("KOSYNC_WEBHOOK_MAX_ATTEMPTS")
+                .unwrap_or(default.webhook_max_attempts),
+            webhook_retry_delay: env_interval("KOSYNC_WEBHOOK_RETRY_SECS", Duration::from_secs)
+                .unwrap_or(default.webhook_retry_delay),
+            private_urls: env_bool("KOSYNC_ALLOW_PRIVATE_URLS").unwrap_or(default.private_urls),
+            device_progress: env_bool("KOSYNC_DEVICE_PROGRESS").unwrap_or(default.device_progress),
+            hardcover_url: std::env::var("KOSYNC_HARDCOVER_URL").unwrap_or(default.hardcover_url),
+            progress_retention: env_parse("KOSYNC_PROGRESS_RETENTION_DAYS")
+                .map(|days: u64| Duration::from_secs(days * 24 * 60 * 60))
+                .or(default.progress_retention),
+            retention_interval: env_interval("KOSYNC_RETENTION_INTERVAL_SECS", Duration::from_secs)
+                .unwrap_or(default.retention_interval),
+            reject_stale_progress: env_bool("KOSYNC_REJECT_STALE_PROGRESS")
+                .unwrap_or(default.reject_stale_progress),
+            missing_progress_404: env_bool("KOSYNC_MISSING_PROGRESS_404")
+                .unwrap_or(default.missing_progress_404),
+            idempotency_ttl: env_parse("KOSYNC_IDEMPOTENCY_TTL_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.idempotency_ttl),
+            readwise_url: std::env::var("KOSYNC_READWISE_URL").unwrap_or(default.readwise_url),
+            readwise_retry_interval: env_interval(
+                "KOSYNC_READWISE_RETRY_SECS",
+                Duration::from_secs,
+            )
+            .unwrap_or(default.readwise_retry_interval),
+            tombstone_retention: env_parse("KOSYNC_TOMBSTONE_RETENTION_DAYS")
+                .map(|days: u64| Duration::from_secs(days * 24 * 60 * 60))
+                .or(default.tombstone_retention),
+            annotation_history: env_parse("KOSYNC_ANNOTATION_HISTORY")
+                .unwrap_or(default.annotation_history),
+            merge_strategy: env_parse("KOSYNC_MERGE_STRATEGY").unwrap_or(default.merge_strategy),
+            max_attachment_size: env_parse("KOSYNC_MAX_ATTACHMENT_BYTES")
+                .unwrap_or(default.max_attachment_size),
+            max_annotation_text_bytes: env_parse("KOSYNC_MAX_ANNOTATION_TEXT_BYTES")
+                .unwrap_or(default.max_annotation_text_bytes),
+            max_annotation_field_bytes: env_parse("KOSYNC_MAX_ANNOTATION_FIELD_BYTES")
+                .unwrap_or(default.max_annotation_field_bytes),
+            max_annotations_per_request: env_parse("KOSYNC_MAX_ANNOTATIONS_PER_REQUEST")
+                .unwrap_or(default.max_annotations_per_request),
+            max_annotations_per_document: env_parse("KOSYNC_MAX_ANNOTATIONS_PER_DOCUMENT")
+                .unwrap_or(default.max_annotations_per_document),
+            backup_dir: std::env::var_os("KOSYNC_BACKUP_DIR")
+                .map(PathBuf::from)
+                .or(default.backup_dir),
+            backup_interval: env_interval("KOSYNC_BACKUP_INTERVAL_SECS", Duration::from_secs)
+                .or(default.backup_interval),
+            backup_keep: env_parse("KOSYNC_BACKUP_KEEP").unwrap_or(default.backup_keep),
+            compact_on_startup: env_bool("KOSYNC_COMPACT_ON_STARTUP")
+                .unwrap_or(default.compact_on_startup),
+            read_cache_size: env_parse("KOSYNC_READ_CACHE_SIZE").unwrap_or(default.read_cache_size),
+            read_cache_ttl: env_parse("KOSYNC_READ_CACHE_TTL_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.read_cache_ttl),
+            replica_dir: std::env::var_os("KOSYNC_REPLICA_DIR")
+                .map(PathBuf::from)
+                .or(default.replica_dir),
+            replica_interval: env_interval("KOSYNC_REPLICA_INTERVAL_SECS", Duration::from_secs)
+                .unwrap_or(default.replica_interval),
+            replica_keep: env_parse("KOSYNC_REPLICA_KEEP").unwrap_or(default.replica_keep),
+            s3_endpoint: std::env::var("KOSYNC_S3_ENDPOINT")
+                .ok()
+                .or(default.s3_endpoint),
+            s3_bucket: std::env::var("KOSYNC_S3_BUCKET").ok().or(default.s3_bucket),
+            s3_region: std::env::var("KOSYNC_S3_REGION").unwrap_or(default.s3_region),
+            s3_access_key_id: std::env::var("KOSYNC_S3_ACCESS_KEY_ID")
+                .ok()
+                .or(default.s3_access_key_id),
+            s3_secret_access_key: std::env::var("KOSYNC_S3_SECRET_ACCESS_KEY")
+                .ok()
+                .or(default.s3_secret_access_key),
+            s3_prefix: std::env::var("KOSYNC_S3_PREFIX").unwrap_or(default.s3_prefix),
+            durability: env_parse("KOSYNC_DURABILITY").unwrap_or(default.durability),
+            flush_interval: env_interval("KOSYNC_FLUSH_INTERVAL_MS", Duration::from_millis)
+                .unwrap_or(default.flush_interval),
+            trash_retention: env_parse("KOSYNC_TRASH_RETENTION_DAYS")
+                .map(|days: u64| Duration::from_secs(days * 24 * 60 * 60))
+                .unwrap_or(default.trash_retention),
+            journal_retention: env_parse("KOSYNC_JOURNAL_RETENTION_DAYS")
+                .map(|days: u64| Duration::from_secs(days * 24 * 60 * 60))
+                .unwrap_or(default.journal_retention),
+            leader_url: std::env::var("KOSYNC_LEADER_URL")
+                .ok()
+                .or(default.leader_url),
+            leader_username: std::env::var("KOSYNC_LEADER_USER")
+                .ok()
+                .or(default.leader_username),
+            leader_key: std::env::var("KOSYNC_LEADER_KEY")
+                .ok()
+                .or(default.leader_key),
+            follow_interval: env_interval("KOSYNC_FOLLOW_INTERVAL_SECS", Duration::from_secs)
+                .unwrap_or(default.follow_interval),
+            disk_budget: env_parse("KOSYNC_DISK_BUDGET_MB")
+                .map(|mb: u64| mb * 1024 * 1024)
+                .or(default.disk_budget),
+            disk_warn_percent: env_parse("KOSYNC_DISK_WARN_PERCENT")
+                .unwrap_or(default.disk_warn_percent),
+            disk_read_only: env_bool("KOSYNC_DISK_READ_ONLY").unwrap_or(default.disk_read_only),
+            disk_check_interval: env_interval("KOSYNC_DISK_CHECK_SECS", Duration::from_secs)
+                .unwrap_or(default.disk_check_interval),
+        }
+    }
+
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.admin_users.iter().any(|u| u == username)
+    }
+
+    /// Whether this server follows a leader rather than taking writes.
+    pub fn is_follower(&self) -> bool {
+        self.leader_url.is_some()
+    }
+
+    pub fn is_demo_user(&self, username: &str) -> bool {
+        self.demo_mode && username == DEMO_USER
+    }
+}
+
+/// Username of the shared account available when demo mode is on.
+pub const DEMO_USER: &str = "demo";
+
+fn env_parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    match value.parse() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            tracing::warn!("Ignoring invalid value for {}: {:?}", name, value);
+            None
+        }
+    }
+}
+
+/// A positive interval in the unit `unit` converts from. Zero is refused:
+/// a ticker can't fire every zero seconds.
+fn env_interval(name: &str, unit: fn(u64) -> Duration) -> Option<Duration> {
+    match env_parse(name)? {
+        0 => {
+            tracing::warn!("Ignoring {}=0, it must be at least 1", name);
+            None
+        }
+        value => Some(unit(value)),
+    }
+}
+
+fn env_bool(name: &str) -> Option<bool> {
+    let value = std::env::var(name).ok()?;
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => {
+            tracing::warn!("Ignoring invalid value for {}: {:?}", name, value);
+            None
+        }
+    }
+}
+
+fn env_list(name: &str) -> Option<Vec<String>> {
+    let value = std::env::var(name).ok()?;
+    Some(
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect(),
+    )
+}
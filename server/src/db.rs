@@ -1,268 +1,5379 @@
-use redb::{Database as RedbDatabase, ReadableTable, TableDefinition};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use redb::{
+    backends::InMemoryBackend, Database as RedbDatabase, Key, ReadTransaction, ReadableTable,
+    ReadableTableMetadata, Table, TableDefinition, TableHandle, Value, WriteTransaction,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::cache::{Generation, ReadCache};
+use crate::config::{Config, Durability};
+use crate::dump;
 use crate::error::{AppError, Result};
-use crate::models::{DocumentAnnotations, Progress};
+use crate::merge::{
+    assign_annotation_ids, merge_annotations, Incoming, Merge, MergeOptions, NewestWins,
+};
+use crate::migrations::{self, MIGRATIONS};
+use crate::models::{
+    AnnotationConflict, AnnotationVersion, AnnotationsStamp, Attachment, BookStatus, CalibreBook,
+    CalibreBookMapping, Device, DocumentAlias, DocumentAnnotations, DocumentMetadata, DocumentNote,
+    DocumentStatus, DocumentTags, FinishedBook, HighlightReview, JournalChange, JournalEntry,
+    JournalResponse, PageStat, Progress, PublicShare, ReadingSession, ReadwiseRetry,
+    RestoreResponse, Review, ShareGroup, ShareMember, StatBook, Statistics, StatisticsMergeResult,
+    StatisticsUpload, TrashItem, TrashKind, UpdateProgressRequest, UserSettings, Webhook,
+};
+use crate::search;
+use crate::style;
+use crate::write_queue::WriteQueue;
 
 // Table definitions
 const USERS: TableDefinition<&str, &str> = TableDefinition::new("users");
-const PROGRESS: TableDefinition<&str, &[u8]> = TableDefinition::new("progress");
-const ANNOTATIONS: TableDefinition<&str, &[u8]> = TableDefinition::new("annotations");
+/// Keyed by `(user, document)`.
+const PROGRESS: TableDefinition<(&str, &str), &[u8]> = TableDefinition::new("progress");
+/// A user's own annotations, keyed by `(user, document)`.
+const ANNOTATIONS: TableDefinition<(&str, &str), &[u8]> = TableDefinition::new("annotations");
+/// `user:document` -> `IndexedDocument`, which of the user's own records
+/// exist for the document, so their documents can be listed without
+/// reading the records themselves.
+const USER_DOCUMENTS: TableDefinition<&str, &[u8]> = TableDefinition::new("user_documents");
+const PROGRESS_HISTORY: TableDefinition<&str, &[u8]> = TableDefinition::new("progress_history");
+const USER_SETTINGS: TableDefinition<&str, &[u8]> = TableDefinition::new("user_settings");
+const ALIASES: TableDefinition<&str, &str> = TableDefinition::new("aliases");
+const DOCUMENT_METADATA: TableDefinition<&str, &[u8]> = TableDefinition::new("document_metadata");
+const CALIBRE_BOOKS: TableDefinition<&str, &[u8]> = TableDefinition::new("calibre_books");
+const INTEGRATIONS: TableDefinition<&str, &[u8]> = TableDefinition::new("integrations");
+const DEVICE_PROGRESS: TableDefinition<&str, &[u8]> = TableDefinition::new("device_progress");
+const DEVICES: TableDefinition<&str, &[u8]> = TableDefinition::new("devices");
+const FINISHED: TableDefinition<&str, &[u8]> = TableDefinition::new("finished");
+const WEBHOOKS: TableDefinition<&str, &[u8]> = TableDefinition::new("webhooks");
+const STAT_BOOKS: TableDefinition<&str, &[u8]> = TableDefinition::new("stat_books");
+const STAT_PAGES: TableDefinition<&str, &[u8]> = TableDefinition::new("stat_pages");
+const SESSIONS: TableDefinition<&str, &[u8]> = TableDefinition::new("sessions");
+const DOCUMENT_NOTES: TableDefinition<&str, &[u8]> = TableDefinition::new("document_notes");
+const DOCUMENT_STATUS: TableDefinition<&str, &[u8]> = TableDefinition::new("document_status");
+const REVIEWS: TableDefinition<&str, &[u8]> = TableDefinition::new("reviews");
+const DOCUMENT_TAGS: TableDefinition<&str, &[u8]> = TableDefinition::new("document_tags");
+/// `user:tag:document` -> empty, so filtering by tag is a range scan.
+const TAG_INDEX: TableDefinition<&str, &[u8]> = TableDefinition::new("tag_index");
+/// Ids of annotations already sent to Readwise, by `user:document`.
+const READWISE_PUSHED: TableDefinition<&str, &[u8]> = TableDefinition::new("readwise_pushed");
+const READWISE_QUEUE: TableDefinition<&str, &[u8]> = TableDefinition::new("readwise_queue");
+/// `user:document` -> review counts of its highlights, by annotation id.
+const HIGHLIGHT_REVIEWS: TableDefinition<&str, &[u8]> = TableDefinition::new("highlight_reviews");
+/// `user:term:document:annotation id` -> empty; see `search`.
+const ANNOTATION_INDEX: TableDefinition<&str, &[u8]> = TableDefinition::new("annotation_index");
+const IDEMPOTENCY_KEYS: TableDefinition<&str, &[u8]> = TableDefinition::new("idempotency_keys");
+/// `<annotations key>:<version>` -> the annotation set as of that version.
+const ANNOTATION_HISTORY: TableDefinition<&str, &[u8]> = TableDefinition::new("annotation_history");
+/// Public page token -> `PublicShare`.
+const PUBLIC_SHARES: TableDefinition<&str, &[u8]> = TableDefinition::new("public_shares");
+/// `user:document` -> public page token.
+const PUBLIC_SHARE_TOKENS: TableDefinition<&str, &[u8]> =
+    TableDefinition::new("public_share_tokens");
+/// Feed token -> username.
+const FEEDS: TableDefinition<&str, &str> = TableDefinition::new("feeds");
+/// Username -> feed token.
+const FEED_TOKENS: TableDefinition<&str, &str> = TableDefinition::new("feed_tokens");
+/// `user:sha256` -> attachment content.
+const ATTACHMENT_BLOBS: TableDefinition<&str, &[u8]> = TableDefinition::new("attachment_blobs");
+/// `user:document:attachment id` -> `Attachment`.
+const ATTACHMENTS: TableDefinition<&str, &[u8]> = TableDefinition::new("attachments");
+/// `user:document:device id` -> last annotation version the device fetched.
+const ANNOTATION_SYNCS: TableDefinition<&str, &[u8]> = TableDefinition::new("annotation_syncs");
+const SHARE_GROUPS: TableDefinition<&str, &[u8]> = TableDefinition::new("share_groups");
+/// `user:document` -> share group id, for invited and joined members.
+const SHARE_MEMBERS: TableDefinition<&str, &str> = TableDefinition::new("share_members");
+/// Merged annotations of a share group's joined members, by group id.
+const SHARED_ANNOTATIONS: TableDefinition<&str, &[u8]> = TableDefinition::new("shared_annotations");
+/// `user:trash item id` -> `TrashItem`, a deleted record kept for restoring.
+const TRASH: TableDefinition<&str, &[u8]> = TableDefinition::new("trash");
+
+/// `<table>/<key>` -> an entry `fsck` moved out of its table.
+const QUARANTINE: TableDefinition<&str, &[u8]> = TableDefinition::new("quarantine");
+
+/// Server-wide values, such as the schema version.
+const META: TableDefinition<&str, u64> = TableDefinition::new("meta");
+
+/// Sequence number -> `JournalEntry`, every change to users' data in the
+/// order it was committed; see `Database::journal`.
+const JOURNAL: TableDefinition<u64, &[u8]> = TableDefinition::new("journal");
+
+const SCHEMA_VERSION: &str = "schema_version";
+/// The last sequence number given to a journal entry.
+const JOURNAL_SEQUENCE: &str = "journal_sequence";
+
+/// Tables whose keys all start with `user:`; wiped by `delete_user_data`.
+const USER_TABLES: &[TableDefinition<&str, &[u8]>] = &[
+    USER_DOCUMENTS,
+    PROGRESS_HISTORY,
+    STAT_BOOKS,
+    STAT_PAGES,
+    SESSIONS,
+    DOCUMENT_METADATA,
+    WEBHOOKS,
+    FINISHED,
+    DEVICES,
+    DEVICE_PROGRESS,
+    INTEGRATIONS,
+    CALIBRE_BOOKS,
+    IDEMPOTENCY_KEYS,
+    DOCUMENT_NOTES,
+    DOCUMENT_STATUS,
+    REVIEWS,
+    DOCUMENT_TAGS,
+    TAG_INDEX,
+    READWISE_PUSHED,
+    READWISE_QUEUE,
+    ANNOTATION_INDEX,
+    ANNOTATION_SYNCS,
+    ANNOTATION_HISTORY,
+    ATTACHMENT_BLOBS,
+    ATTACHMENTS,
+    PUBLIC_SHARE_TOKENS,
+    HIGHLIGHT_REVIEWS,
+    TRASH,
+];
+
+/// User tables whose changes aren't journaled: indexes rebuilt from the
+/// records they index, and bookkeeping only the server that wrote it needs.
+const UNJOURNALED_TABLES: &[TableDefinition<&str, &[u8]>] = &[
+    USER_DOCUMENTS,
+    TAG_INDEX,
+    ANNOTATION_INDEX,
+    IDEMPOTENCY_KEYS,
+    ANNOTATION_SYNCS,
+    READWISE_QUEUE,
+];
+
+/// Tables keyed by `(user, document)`; also wiped by `delete_user_data`,
+/// through `USER_DOCUMENTS`.
+const DOCUMENT_TABLES: &[TableDefinition<(&str, &str), &[u8]>] = &[PROGRESS, ANNOTATIONS];
+
+/// Tables holding data not keyed by `user:`.
+const SHARED_TABLES: &[TableDefinition<&str, &[u8]>] = &[
+    USER_SETTINGS,
+    SHARE_GROUPS,
+    SHARED_ANNOTATIONS,
+    PUBLIC_SHARES,
+    QUARANTINE,
+];
+
+/// Tables whose values are plain strings.
+const STRING_TABLES: &[TableDefinition<&str, &str>] =
+    &[USERS, ALIASES, SHARE_MEMBERS, FEEDS, FEED_TOKENS];
+
+/// An entry of `USER_DOCUMENTS`. Entries with neither are removed.
+#[derive(Default, Serialize, Deserialize)]
+struct IndexedDocument {
+    #[serde(default)]
+    progress: bool,
+    #[serde(default)]
+    annotations: bool,
+}
+
+/// The kinds of record `USER_DOCUMENTS` tracks.
+#[derive(Clone, Copy)]
+enum Indexed {
+    Progress,
+    Annotations,
+}
+
+/// What happened to a journaled entry.
+#[derive(Clone, Copy)]
+enum Change {
+    /// Written, at the record's version if it has one.
+    Put(Option<u64>),
+    Removed,
+    /// Every entry whose key starts with the journaled key was removed.
+    RemovedPrefix,
+}
+
+/// A journaled table, as found from its name by `JournaledTable::named`.
+#[derive(Clone, Copy)]
+enum JournaledTable {
+    Bytes(TableDefinition<'static, &'static str, &'static [u8]>),
+    Document(TableDefinition<'static, (&'static str, &'static str), &'static [u8]>),
+    Text(TableDefinition<'static, &'static str, &'static str>),
+}
+
+impl JournaledTable {
+    fn named(name: &str) -> Result<Self> {
+        let journaled = |table: &&TableDefinition<&str, &[u8]>| {
+            table.name() == name
+                && table.name() != QUARANTINE.name()
+                && !UNJOURNALED_TABLES.iter().any(|t| t.name() == name)
+        };
+        if let Some(table) = USER_TABLES.iter().chain(SHARED_TABLES).find(journaled) {
+            return Ok(Self::Bytes(*table));
+        }
+        if let Some(table) = DOCUMENT_TABLES.iter().find(|t| t.name() == name) {
+            return Ok(Self::Document(*table));
+        }
+        if let Some(table) = STRING_TABLES.iter().find(|t| t.name() == name) {
+            return Ok(Self::Text(*table));
+        }
+        Err(AppError::InvalidRequest(format!(
+            "{} isn't a journaled table",
+            name
+        )))
+    }
+}
+
+/// A progress update remembered under its `Idempotency-Key`.
+#[derive(Serialize, Deserialize)]
+struct IdempotentWrite {
+    /// The hash as sent, to spot a key reused for another document.
+    requested: String,
+    document: String,
+    timestamp: i64,
+    #[serde(default)]
+    version: u64,
+}
+
+/// Outcome of a stored progress update.
+#[derive(Debug, Clone)]
+pub struct ProgressWrite {
+    /// The hash the record was stored under, after alias resolution.
+    pub document: String,
+    pub timestamp: i64,
+    pub version: u64,
+    /// The update moved the document across the finished threshold.
+    pub finished: bool,
+}
+
+/// A stored entry `fsck` found fault with.
+#[derive(Debug)]
+pub struct FsckProblem {
+    pub table: String,
+    /// The key, with a `(user, document)` key written `user:document`.
+    pub key: String,
+    pub reason: String,
+}
+
+/// Outcome of `Database::fsck`.
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// redb found the file itself damaged, and repaired it.
+    pub repaired: bool,
+    /// Entries checked.
+    pub checked: usize,
+    pub problems: Vec<FsckProblem>,
+    /// The problems were moved to the quarantine table.
+    pub quarantined: bool,
+}
+
+/// A username with records but no account, found by
+/// `Database::collect_orphans`.
+#[derive(Debug)]
+pub struct OrphanedUser {
+    pub username: String,
+    /// Documents with progress stored under the name.
+    pub progress: usize,
+    /// Documents with annotations stored under the name.
+    pub annotations: usize,
+}
+
+/// Outcome of a stored annotation upload.
+#[derive(Debug)]
+pub struct AnnotationsWrite {
+    pub version: u64,
+    pub timestamp: i64,
+    /// Ids of the uploaded annotations, in upload order.
+    pub ids: Vec<String>,
+    pub conflicts: Vec<AnnotationConflict>,
+    /// Ids collapsed into another annotation, and the one kept.
+    pub duplicates: BTreeMap<String, String>,
+}
 
 pub struct Database {
     db: RedbDatabase,
+    config: Arc<Config>,
+    writes: WriteQueue,
+    generation: Arc<Generation>,
+    progress_cache: ReadCache<(String, String), Progress>,
+    annotations_cache: ReadCache<(String, String), DocumentAnnotations>,
+    /// The database file, unless it is held in memory.
+    path: Option<PathBuf>,
+    /// The file has outgrown its disk budget; see `set_full`.
+    full: AtomicBool,
+}
+
+/// A write transaction that invalidates the read caches when committed.
+pub(crate) struct WriteTxn<'a> {
+    txn: WriteTransaction,
+    generation: &'a Generation,
+}
+
+impl Deref for WriteTxn<'_> {
+    type Target = WriteTransaction;
+
+    fn deref(&self) -> &WriteTransaction {
+        &self.txn
+    }
+}
+
+impl WriteTxn<'_> {
+    pub(crate) fn commit(self) -> Result<()> {
+        self.txn.commit()?;
+        self.generation.bump();
+        Ok(())
+    }
+
+    /// Commit without invalidating the read caches, for bookkeeping that
+    /// no cached read depends on.
+    fn commit_uncached(self) -> Result<()> {
+        self.txn.commit()?;
+        Ok(())
+    }
+}
+
+impl Database {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        Self::init(RedbDatabase::create(path)?, Some(path.to_path_buf()))
+    }
+
+    /// A database held in memory and lost when it is dropped, for tests
+    /// and throwaway demo servers.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(
+            RedbDatabase::builder().create_with_backend(InMemoryBackend::new())?,
+            None,
+        )
+    }
+
+    fn init(db: RedbDatabase, path: Option<PathBuf>) -> Result<Self> {
+        let write_txn = db.begin_write()?;
+        // Before any table is opened, since a migration may change its types
+        Self::migrate(&write_txn)?;
+        // Initialize tables
+        {
+            for table in USER_TABLES.iter().chain(SHARED_TABLES) {
+                let _ = write_txn.open_table(*table)?;
+            }
+            for table in DOCUMENT_TABLES {
+                let _ = write_txn.open_table(*table)?;
+            }
+            for table in STRING_TABLES {
+                let _ = write_txn.open_table(*table)?;
+            }
+            let _ = write_txn.open_table(JOURNAL)?;
+        }
+        Self::build_annotation_index(&write_txn)?;
+        write_txn.commit()?;
+
+        let config = Arc::<Config>::default();
+        let generation = Arc::<Generation>::default();
+        Ok(Self {
+            db,
+            writes: WriteQueue::default(),
+            progress_cache: ReadCache::new(
+                generation.clone(),
+                config.read_cache_size,
+                config.read_cache_ttl,
+            ),
+            annotations_cache: ReadCache::new(
+                generation.clone(),
+                config.read_cache_size,
+                config.read_cache_ttl,
+            ),
+            generation,
+            config,
+            path,
+            full: AtomicBool::new(false),
+        })
+    }
+
+    /// A number that changes with every commit.
+    pub fn generation(&self) -> u64 {
+        self.generation.get()
+    }
+
+    /// Start a write transaction changing users' data. Followers refuse
+    /// these: their data only changes with their leader's.
+    pub(crate) fn begin_write(&self) -> Result<WriteTxn<'_>> {
+        self.check_writable()?;
+        self.begin_local_write()
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.config.is_follower() {
+            return Err(AppError::ReadOnly("this server is a follower".into()));
+        }
+        if self.is_full() {
+            return Err(AppError::ReadOnly(
+                "the database has reached its disk budget".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Size of the database file in bytes; 0 when it is held in memory.
+    pub fn file_size(&self) -> Result<u64> {
+        match &self.path {
+            Some(path) => Ok(std::fs::metadata(path)?.len()),
+            None => Ok(0),
+        }
+    }
+
+    /// Refuse writes of users' data while `full`, so that a database
+    /// nearing a full disk stops growing before its commits start failing.
+    /// Followers keep applying their leader's changes.
+    pub fn set_full(&self, full: bool) {
+        self.full.store(full, Ordering::Relaxed);
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.full.load(Ordering::Relaxed)
+    }
+
+    /// Start a write transaction, even on a follower.
+    fn begin_local_write(&self) -> Result<WriteTxn<'_>> {
+        let mut txn = self.db.begin_write()?;
+        if self.config.durability == Durability::Eventual {
+            // redb's own `Eventual` still syncs on most platforms; these
+            // commits are made durable by `flush` instead
+            txn.set_durability(redb::Durability::None);
+        }
+        Ok(WriteTxn {
+            txn,
+            generation: &self.generation,
+        })
+    }
+
+    /// Make every commit so far durable. Only needed with
+    /// `Durability::Eventual`, where commits aren't synced as they are made.
+    pub fn flush(&self) -> Result<()> {
+        // An empty durable commit persists the ones before it
+        self.db.begin_write()?.commit()?;
+        Ok(())
+    }
+
+    /// Run `op` in a write transaction shared with whatever other batched
+    /// writes are waiting, so a burst of them costs one commit. See
+    /// [`WriteQueue::submit`] for what `op` must guarantee.
+    fn batched_write<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&Database, &WriteTransaction) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        self.check_writable()?;
+        self.writes.submit(self, op)
+    }
+
+    /// Write a copy of the database to a new file at `path`, returning its
+    /// size in bytes.
+    ///
+    /// Everything is read in one read transaction, a snapshot of the last
+    /// commit: writes made while the copy runs are neither blocked nor
+    /// included. The copy is built under a `.partial` name and renamed
+    /// once complete, so a failed backup leaves no truncated file behind.
+    pub fn backup(&self, path: &Path) -> Result<u64> {
+        if path.exists() {
+            return Err(AppError::InvalidRequest(format!(
+                "{} already exists",
+                path.display()
+            )));
+        }
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+
+        let read_txn = self.db.begin_read()?;
+        let copied = Self::write_backup(&read_txn, Path::new(&partial))
+            .and_then(|()| Ok(std::fs::rename(&partial, path)?));
+        if let Err(e) = copied {
+            // Otherwise each failed snapshot would leave a full copy behind
+            match std::fs::remove_file(&partial) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => tracing::warn!("Failed to delete {:?}: {}", partial, e),
+            }
+            return Err(e);
+        }
+        Ok(std::fs::metadata(path)?.len())
+    }
+
+    /// Copy every table from `read_txn` into a new database file at `path`.
+    fn write_backup(read_txn: &ReadTransaction, path: &Path) -> Result<()> {
+        let copy = RedbDatabase::create(path)?;
+        let write_txn = copy.begin_write()?;
+        for table in USER_TABLES.iter().chain(SHARED_TABLES) {
+            copy_table(read_txn, &write_txn, *table)?;
+        }
+        for table in DOCUMENT_TABLES {
+            copy_table(read_txn, &write_txn, *table)?;
+        }
+        for table in STRING_TABLES {
+            copy_table(read_txn, &write_txn, *table)?;
+        }
+        copy_table(read_txn, &write_txn, JOURNAL)?;
+        copy_table(read_txn, &write_txn, META)?;
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Bring the database up to the current schema version; see
+    /// `migrations`.
+    fn migrate(write_txn: &WriteTransaction) -> Result<()> {
+        let mut meta = write_txn.open_table(META)?;
+        let stored = meta.get(SCHEMA_VERSION)?.map_or(0, |v| v.value());
+        let current = migrations::current_version();
+        if stored > current {
+            return Err(AppError::UnsupportedSchema(stored));
+        }
+        for migration in MIGRATIONS.iter().filter(|m| m.version > stored) {
+            tracing::info!(
+                "Migrating database to schema version {}: {}",
+                migration.version,
+                migration.description
+            );
+            (migration.apply)(write_txn)?;
+        }
+        meta.insert(SCHEMA_VERSION, current)?;
+        Ok(())
+    }
+
+    /// The schema version the database is at.
+    pub fn schema_version(&self) -> Result<u64> {
+        let read_txn = self.db.begin_read()?;
+        let meta = read_txn.open_table(META)?;
+        Ok(meta.get(SCHEMA_VERSION)?.map_or(0, |v| v.value()))
+    }
+
+    /// Migration 1: apply `style::normalize` to stored annotations.
+    pub(crate) fn normalize_stored_styles(write_txn: &WriteTransaction) -> Result<()> {
+        // `annotations` as it was keyed before migration 2
+        let annotations: TableDefinition<&str, &[u8]> = TableDefinition::new("annotations");
+        for definition in [annotations, SHARED_ANNOTATIONS] {
+            let mut table = write_txn.open_table(definition)?;
+            let mut updates = Vec::new();
+            for entry in table.iter()? {
+                let (key, data) = entry?;
+                let mut doc: DocumentAnnotations = decode_annotations(data.value())?;
+                let mut changed = false;
+                for annotation in &mut doc.annotations {
+                    let (drawer, color) = (annotation.drawer.clone(), annotation.color.clone());
+                    style::normalize(annotation);
+                    changed |= annotation.drawer != drawer || annotation.color != color;
+                }
+                if changed {
+                    updates.push((key.value().to_string(), encode_annotations(&doc)?));
+                }
+            }
+            for (key, data) in updates {
+                table.insert(key.as_str(), data.as_slice())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Migration 2: key `progress` and `annotations` by `(user, document)`
+    /// instead of `"user:document"`. Each table is copied into a new one,
+    /// which then takes its name.
+    pub(crate) fn split_document_keys(write_txn: &WriteTransaction) -> Result<()> {
+        for name in ["progress", "annotations"] {
+            let legacy: TableDefinition<&str, &[u8]> = TableDefinition::new(name);
+            let split: TableDefinition<(&str, &str), &[u8]> = TableDefinition::new(name);
+            let staging_name = format!("{}.staging", name);
+            let staging: TableDefinition<(&str, &str), &[u8]> = TableDefinition::new(&staging_name);
+            {
+                let source = write_txn.open_table(legacy)?;
+                let mut target = write_txn.open_table(staging)?;
+                for entry in source.iter()? {
+                    let (key, data) = entry?;
+                    match key.value().split_once(':') {
+                        Some(key) => {
+                            target.insert(key, data.value())?;
+                        }
+                        None => tracing::warn!("Dropping {} record {:?}", name, key.value()),
+                    }
+                }
+            }
+            write_txn.delete_table(legacy)?;
+            write_txn.rename_table(staging, split)?;
+        }
+        Ok(())
+    }
+
+    /// Migration 3: build `USER_DOCUMENTS` from the records already stored.
+    pub(crate) fn index_user_documents(write_txn: &WriteTransaction) -> Result<()> {
+        let index: TableDefinition<&str, &[u8]> = TableDefinition::new("user_documents");
+        let mut entries: BTreeMap<String, IndexedDocument> = BTreeMap::new();
+        for name in ["progress", "annotations"] {
+            let records: TableDefinition<(&str, &str), &[u8]> = TableDefinition::new(name);
+            for entry in write_txn.open_table(records)?.iter()? {
+                let (key, _) = entry?;
+                let (username, document) = key.value();
+                let entry = entries
+                    .entry(format!("{}:{}", username, document))
+                    .or_default();
+                match name {
+                    "progress" => entry.progress = true,
+                    _ => entry.annotations = true,
+                }
+            }
+        }
+        let mut index = write_txn.open_table(index)?;
+        for (key, entry) in entries {
+            index.insert(key.as_str(), serde_json::to_vec(&entry)?.as_slice())?;
+        }
+        Ok(())
+    }
+
+    /// Apply server configuration affecting how data is stored.
+    pub fn set_config(&mut self, config: Arc<Config>) {
+        let (size, ttl) = (config.read_cache_size, config.read_cache_ttl);
+        self.progress_cache = ReadCache::new(self.generation.clone(), size, ttl);
+        self.annotations_cache = ReadCache::new(self.generation.clone(), size, ttl);
+        self.config = config;
+    }
+
+    // === User operations ===
+
+    pub fn create_user(&self, username: &str, password_hash: &str) -> Result<bool> {
+        let write_txn = self.begin_write()?;
+        let created = {
+            let mut table = write_txn.open_table(USERS)?;
+            if table.get(username)?.is_some() {
+                false
+            } else {
+                table.insert(username, password_hash)?;
+                Self::journal(
+                    &write_txn,
+                    username,
+                    USERS.name(),
+                    username,
+                    Change::Put(None),
+                )?;
+                true
+            }
+        };
+        write_txn.commit()?;
+        Ok(created)
+    }
+
+    pub fn verify_user(&self, username: &str, password_hash: &str) -> Result<bool> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(USERS)?;
+        match table.get(username)? {
+            Some(stored) => Ok(stored.value() == password_hash),
+            None => Ok(false),
+        }
+    }
+
+    pub fn user_exists(&self, username: &str) -> Result<bool> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(USERS)?;
+        Ok(table.get(username)?.is_some())
+    }
+
+    /// Remove all synced data stored for a user, keeping the account.
+    pub fn delete_user_data(&self, username: &str) -> Result<()> {
+        let write_txn = self.begin_write()?;
+        Self::delete_user_data_in(&write_txn, username)?;
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Remove everything stored for a user: their synced data, their
+    /// public pages, and unless `keep_account`, their feed, settings and
+    /// account. Returns whether the account existed.
+    pub fn purge_user(&self, username: &str, keep_account: bool) -> Result<bool> {
+        let write_txn = self.begin_write()?;
+        let existed = Self::purge_user_in(&write_txn, username, keep_account)?;
+        write_txn.commit()?;
+        Ok(existed)
+    }
+
+    fn purge_user_in(
+        write_txn: &WriteTransaction,
+        username: &str,
+        keep_account: bool,
+    ) -> Result<bool> {
+        let (start, end) = Self::user_key_range(username);
+        // The pages' tokens go with the rest of their data
+        let tokens: Vec<String> = {
+            let tokens = write_txn.open_table(PUBLIC_SHARE_TOKENS)?;
+            let mut found = Vec::new();
+            for entry in tokens.range(start.as_str()..end.as_str())? {
+                found.push(String::from_utf8_lossy(entry?.1.value()).into_owned());
+            }
+            found
+        };
+        let mut shares = write_txn.open_table(PUBLIC_SHARES)?;
+        for token in tokens {
+            shares.remove(token.as_str())?;
+            Self::journal(
+                write_txn,
+                username,
+                PUBLIC_SHARES.name(),
+                &token,
+                Change::Removed,
+            )?;
+        }
+        Self::delete_user_data_in(write_txn, username)?;
+
+        let mut users = write_txn.open_table(USERS)?;
+        let existed = users.get(username)?.is_some();
+        if !keep_account {
+            let token = write_txn
+                .open_table(FEED_TOKENS)?
+                .remove(username)?
+                .map(|t| t.value().to_string());
+            if let Some(token) = token {
+                write_txn.open_table(FEEDS)?.remove(token.as_str())?;
+                Self::journal(
+                    write_txn,
+                    username,
+                    FEED_TOKENS.name(),
+                    username,
+                    Change::Removed,
+                )?;
+                Self::journal(write_txn, username, FEEDS.name(), &token, Change::Removed)?;
+            }
+            if write_txn
+                .open_table(USER_SETTINGS)?
+                .remove(username)?
+                .is_some()
+            {
+                Self::journal(
+                    write_txn,
+                    username,
+                    USER_SETTINGS.name(),
+                    username,
+                    Change::Removed,
+                )?;
+            }
+            if users.remove(username)?.is_some() {
+                Self::journal(write_txn, username, USERS.name(), username, Change::Removed)?;
+            }
+        }
+        Ok(existed)
+    }
+
+    /// Find progress and annotations stored under usernames that have no
+    /// account, as deletions and imports by older versions could leave
+    /// behind. `keep` names users to treat as existing even without an
+    /// account (the demo user). Unless `dry_run`, remove them along with
+    /// everything else stored under those names, as `purge_user` would.
+    pub fn collect_orphans(&self, dry_run: bool, keep: &[&str]) -> Result<Vec<OrphanedUser>> {
+        if dry_run {
+            // Only reads, so it works on a follower or a full disk too
+            let read_txn = self.db.begin_read()?;
+            let tables = DOCUMENT_TABLES
+                .iter()
+                .map(|definition| Ok((definition.name(), read_txn.open_table(*definition)?)))
+                .collect::<Result<Vec<_>>>()?;
+            return Self::find_orphans(&read_txn.open_table(USERS)?, &tables, keep);
+        }
+
+        let write_txn = self.begin_write()?;
+        let orphans = {
+            let tables = DOCUMENT_TABLES
+                .iter()
+                .map(|definition| Ok((definition.name(), write_txn.open_table(*definition)?)))
+                .collect::<Result<Vec<_>>>()?;
+            Self::find_orphans(&write_txn.open_table(USERS)?, &tables, keep)?
+        };
+        for orphan in &orphans {
+            let username = &orphan.username;
+            Self::purge_user_in(&write_txn, username, false)?;
+            // Records `USER_DOCUMENTS` lost track of
+            let next = Self::next_username(username);
+            let range = (username.as_str(), "")..(next.as_str(), "");
+            for definition in DOCUMENT_TABLES {
+                let mut table = write_txn.open_table(*definition)?;
+                let mut documents = Vec::new();
+                for entry in table.range(range.clone())? {
+                    documents.push(entry?.0.value().1.to_string());
+                }
+                for document in documents {
+                    table.remove((username.as_str(), document.as_str()))?;
+                    let key = Self::progress_key(username, &document);
+                    Self::journal(
+                        &write_txn,
+                        username,
+                        definition.name(),
+                        &key,
+                        Change::Removed,
+                    )?;
+                }
+            }
+        }
+        write_txn.commit()?;
+        Ok(orphans)
+    }
+
+    /// The users with records in `tables`, by table name, but no account.
+    fn find_orphans(
+        users: &impl ReadableTable<&'static str, &'static str>,
+        tables: &[(
+            &str,
+            impl ReadableTable<(&'static str, &'static str), &'static [u8]>,
+        )],
+        keep: &[&str],
+    ) -> Result<Vec<OrphanedUser>> {
+        let mut orphans: BTreeMap<String, OrphanedUser> = BTreeMap::new();
+        for (name, table) in tables {
+            for entry in table.iter()? {
+                let (key, _) = entry?;
+                let (username, _) = key.value();
+                if keep.contains(&username) || users.get(username)?.is_some() {
+                    continue;
+                }
+                let orphan = orphans
+                    .entry(username.to_string())
+                    .or_insert_with(|| OrphanedUser {
+                        username: username.to_string(),
+                        progress: 0,
+                        annotations: 0,
+                    });
+                if *name == PROGRESS.name() {
+                    orphan.progress += 1;
+                } else {
+                    orphan.annotations += 1;
+                }
+            }
+        }
+        Ok(orphans.into_values().collect())
+    }
+
+    fn delete_user_data_in(write_txn: &WriteTransaction, username: &str) -> Result<()> {
+        let (start, end) = Self::user_key_range(username);
+        // Before the index goes with the other user tables
+        let indexed = Self::indexed_documents(write_txn, username)?;
+        for definition in USER_TABLES {
+            let mut table = write_txn.open_table(*definition)?;
+            let found = table.range(start.as_str()..end.as_str())?.next().is_some();
+            table.retain_in(start.as_str()..end.as_str(), |_, _| false)?;
+            let journaled = !UNJOURNALED_TABLES
+                .iter()
+                .any(|t| t.name() == definition.name());
+            if found && journaled {
+                Self::journal(
+                    write_txn,
+                    username,
+                    definition.name(),
+                    &start,
+                    Change::RemovedPrefix,
+                )?;
+            }
+        }
+        {
+            let mut progress = write_txn.open_table(PROGRESS)?;
+            let mut annotations = write_txn.open_table(ANNOTATIONS)?;
+            for (document, entry) in &indexed {
+                let key = Self::progress_key(username, document);
+                if entry.progress {
+                    progress.remove((username, document.as_str()))?;
+                    Self::journal(write_txn, username, PROGRESS.name(), &key, Change::Removed)?;
+                }
+                if entry.annotations {
+                    annotations.remove((username, document.as_str()))?;
+                    Self::journal(
+                        write_txn,
+                        username,
+                        ANNOTATIONS.name(),
+                        &key,
+                        Change::Removed,
+                    )?;
+                }
+            }
+        }
+        {
+            let mut aliases = write_txn.open_table(ALIASES)?;
+            let found = aliases
+                .range(start.as_str()..end.as_str())?
+                .next()
+                .is_some();
+            aliases.retain_in(start.as_str()..end.as_str(), |_, _| false)?;
+            if found {
+                Self::journal(
+                    write_txn,
+                    username,
+                    ALIASES.name(),
+                    &start,
+                    Change::RemovedPrefix,
+                )?;
+            }
+        }
+        let groups: Vec<String> = {
+            let members = write_txn.open_table(SHARE_MEMBERS)?;
+            let mut groups = Vec::new();
+            for entry in members.range(start.as_str()..end.as_str())? {
+                groups.push(entry?.1.value().to_string());
+            }
+            groups
+        };
+        for id in groups {
+            Self::leave_share_in(write_txn, username, &id)?;
+        }
+        Ok(())
+    }
+
+    /// The user's documents with their own progress or annotations, from
+    /// `USER_DOCUMENTS`.
+    fn indexed_documents(
+        write_txn: &WriteTransaction,
+        username: &str,
+    ) -> Result<Vec<(String, IndexedDocument)>> {
+        let (start, end) = Self::user_key_range(username);
+        let table = write_txn.open_table(USER_DOCUMENTS)?;
+        let mut documents = Vec::new();
+        for entry in table.range(start.as_str()..end.as_str())? {
+            let (key, data) = entry?;
+            let document = &key.value()[start.len()..];
+            documents.push((document.to_string(), serde_json::from_slice(data.value())?));
+        }
+        Ok(documents)
+    }
+
+    /// Record in `USER_DOCUMENTS` whether the user has a record of `kind`
+    /// for the document. Called whenever one is created or removed.
+    fn index_document(
+        write_txn: &WriteTransaction,
+        username: &str,
+        document: &str,
+        kind: Indexed,
+        present: bool,
+    ) -> Result<()> {
+        let key = format!("{}:{}", username, document);
+        let mut table = write_txn.open_table(USER_DOCUMENTS)?;
+        let mut entry: IndexedDocument = match table.get(key.as_str())? {
+            Some(data) => serde_json::from_slice(data.value())?,
+            None => IndexedDocument::default(),
+        };
+        match kind {
+            Indexed::Progress => entry.progress = present,
+            Indexed::Annotations => entry.annotations = present,
+        }
+        if entry.progress || entry.annotations {
+            table.insert(key.as_str(), serde_json::to_vec(&entry)?.as_slice())?;
+        } else {
+            table.remove(key.as_str())?;
+        }
+        Ok(())
+    }
+
+    /// The smallest username sorting after `username`, so that
+    /// `(username, "")..(next, "")` covers every `(user, document)` key of
+    /// the user.
+    fn next_username(username: &str) -> String {
+        format!("{}\0", username)
+    }
+
+    /// Key range covering every `user:document` key for a user.
+    fn user_key_range(username: &str) -> (String, String) {
+        // ';' is the character right after ':'
+        (format!("{}:", username), format!("{};", username))
+    }
+
+    pub fn get_user_settings(&self, username: &str) -> Result<UserSettings> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(USER_SETTINGS)?;
+        match table.get(username)? {
+            Some(data) => Ok(serde_json::from_slice(data.value())?),
+            None => Ok(UserSettings::default()),
+        }
+    }
+
+    /// Merge the fields present in `changes` into the stored settings.
+    pub fn update_user_settings(
+        &self,
+        username: &str,
+        changes: &UserSettings,
+    ) -> Result<UserSettings> {
+        let write_txn = self.begin_write()?;
+        let settings = {
+            let mut table = write_txn.open_table(USER_SETTINGS)?;
+            let current: UserSettings = match table.get(username)? {
+                Some(data) => serde_json::from_slice(data.value())?,
+                None => UserSettings::default(),
+            };
+            let settings = current.merged_with(changes);
+            let json = serde_json::to_vec(&settings)?;
+            table.insert(username, json.as_slice())?;
+            Self::journal(
+                &write_txn,
+                username,
+                USER_SETTINGS.name(),
+                username,
+                Change::Put(None),
+            )?;
+            settings
+        };
+        write_txn.commit()?;
+        Ok(settings)
+    }
+
+    // === Progress operations (legacy KOSync) ===
+
+    fn progress_key(username: &str, document: &str) -> String {
+        format!("{}:{}", username, document)
+    }
+
+    /// Progress for each of `documents` that has any, in request order.
+    /// Entries report the requested hash even when it is an alias.
+    pub fn get_progress_many(&self, username: &str, documents: &[String]) -> Result<Vec<Progress>> {
+        let read_txn = self.db.begin_read()?;
+        let aliases = read_txn.open_table(ALIASES)?;
+        let table = read_txn.open_table(PROGRESS)?;
+
+        let mut result = Vec::new();
+        for document in documents {
+            let canonical = Self::canonical_document(&aliases, username, document)?;
+            if let Some(data) = table.get((username, canonical.as_str()))? {
+                let mut progress: Progress = serde_json::from_slice(data.value())?;
+                progress.document = Some(document.clone());
+                result.push(progress);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Delete progress last updated before `cutoff`, along with progress
+    /// belonging to users that no longer exist. `keep` names users to treat
+    /// as existing even without an account (the demo user). Expired
+    /// progress goes to its user's trash; an orphan's has nobody to restore
+    /// it. Returns the number of documents purged.
+    pub fn purge_progress(&self, cutoff: Option<i64>, keep: &[&str]) -> Result<usize> {
+        let write_txn = self.begin_write()?;
+        let purged = {
+            let users = write_txn.open_table(USERS)?;
+            let mut table = write_txn.open_table(PROGRESS)?;
+
+            // With the progress to trash, if any
+            let mut stale = Vec::new();
+            for entry in table.iter()? {
+                let (key, data) = entry?;
+                let (username, document) = key.value();
+                let orphaned = !keep.contains(&username) && users.get(username)?.is_none();
+                let expired = cutoff.and_then(|cutoff| {
+                    serde_json::from_slice::<Progress>(data.value())
+                        .ok()
+                        .filter(|p| p.timestamp.is_some_and(|t| t < cutoff))
+                });
+                if orphaned {
+                    stale.push((username.to_string(), document.to_string(), None));
+                } else if expired.is_some() {
+                    stale.push((username.to_string(), document.to_string(), expired));
+                }
+            }
+
+            let mut history = write_txn.open_table(PROGRESS_HISTORY)?;
+            let mut devices = write_txn.open_table(DEVICE_PROGRESS)?;
+            for (username, document, expired) in &stale {
+                if let Some(progress) = expired {
+                    self.trash_in(
+                        &write_txn,
+                        username,
+                        TrashKind::Progress,
+                        document,
+                        progress,
+                    )?;
+                }
+                table.remove((username.as_str(), document.as_str()))?;
+                Self::index_document(&write_txn, username, document, Indexed::Progress, false)?;
+                let key = Self::progress_key(username, document);
+                let entries_start = format!("{}:", key);
+                let entries_end = format!("{};", key);
+                history.retain_in(entries_start.as_str()..entries_end.as_str(), |_, _| false)?;
+                devices.retain_in(entries_start.as_str()..entries_end.as_str(), |_, _| false)?;
+                Self::journal_progress_removal(&write_txn, username, &key)?;
+            }
+            stale.len()
+        };
+        write_txn.commit()?;
+        Ok(purged)
+    }
+
+    pub fn get_progress(&self, username: &str, document: &str) -> Result<Progress> {
+        let key = (username.to_string(), document.to_string());
+        self.progress_cache.get_or_read(key, || {
+            let read_txn = self.db.begin_read()?;
+            let document =
+                Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+            let table = read_txn.open_table(PROGRESS)?;
+
+            match table.get((username, document.as_str()))? {
+                Some(data) => {
+                    let progress: Progress = serde_json::from_slice(data.value())?;
+                    Ok(progress)
+                }
+                None => Ok(Progress::default()),
+            }
+        })
+    }
+
+    /// The latest position uploaded by one device, if per-device progress
+    /// is enabled.
+    pub fn get_device_progress(
+        &self,
+        username: &str,
+        document: &str,
+        device_id: &str,
+    ) -> Result<Progress> {
+        let read_txn = self.db.begin_read()?;
+        let document =
+            Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+        let key = format!("{}:{}", Self::progress_key(username, &document), device_id);
+        let table = read_txn.open_table(DEVICE_PROGRESS)?;
+
+        match table.get(key.as_str())? {
+            Some(data) => Ok(serde_json::from_slice(data.value())?),
+            None => Ok(Progress::default()),
+        }
+    }
+
+    /// All progress records for a user, ordered by document hash.
+    pub fn list_progress(&self, username: &str) -> Result<Vec<Progress>> {
+        let next_user = Self::next_username(username);
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PROGRESS)?;
+
+        let mut result = Vec::new();
+        for entry in table.range((username, "")..(next_user.as_str(), ""))? {
+            let (_, data) = entry?;
+            result.push(serde_json::from_slice(data.value())?);
+        }
+        Ok(result)
+    }
+
+    pub fn set_progress(
+        &self,
+        username: &str,
+        update: &UpdateProgressRequest,
+    ) -> Result<ProgressWrite> {
+        let username = username.to_string();
+        let update = update.clone();
+        self.batched_write(move |db, write_txn| {
+            db.write_progress(write_txn, &username, &update, now())
+        })
+    }
+
+    /// Store progress records brought over from another server, keeping
+    /// their timestamps. A document is skipped when its stored progress is
+    /// as recent as the imported record. Returns how many were stored.
+    pub fn import_progress(&self, username: &str, records: Vec<Progress>) -> Result<usize> {
+        let write_txn = self.begin_write()?;
+        let mut imported = 0;
+        {
+            let aliases = write_txn.open_table(ALIASES)?;
+            let mut table = write_txn.open_table(PROGRESS)?;
+            for record in records {
+                let Some(document) = &record.document else {
+                    continue;
+                };
+                let document = Self::canonical_document(&aliases, username, document)?;
+                let stored: Option<Progress> = match table.get((username, document.as_str()))? {
+                    Some(data) => Some(serde_json::from_slice(data.value())?),
+                    None => None,
+                };
+                if stored
+                    .as_ref()
+                    .is_some_and(|p| p.timestamp >= record.timestamp)
+                {
+                    continue;
+                }
+                // Kept when imported from a dump, but never behind the
+                // stored version
+                let stored_before = stored.as_ref().map(|_| ());
+                let version = (stored.and_then(|p| p.version).unwrap_or(0) + 1)
+                    .max(record.version.unwrap_or(0));
+                let data = Progress {
+                    document: Some(document.clone()),
+                    version: Some(version),
+                    ..record
+                };
+                table.insert(
+                    (username, document.as_str()),
+                    serde_json::to_vec(&data)?.as_slice(),
+                )?;
+                if stored_before.is_none() {
+                    Self::index_document(&write_txn, username, &document, Indexed::Progress, true)?;
+                }
+                let key = Self::progress_key(username, &document);
+                Self::journal(
+                    &write_txn,
+                    username,
+                    PROGRESS.name(),
+                    &key,
+                    Change::Put(Some(version)),
+                )?;
+                imported += 1;
+            }
+        }
+        write_txn.commit()?;
+        Ok(imported)
+    }
+
+    /// Like `set_progress`, but a repeated `idempotency_key` returns the
+    /// first write's outcome instead of storing the update again. The flag
+    /// is true for such replays.
+    pub fn set_progress_idempotent(
+        &self,
+        username: &str,
+        update: &UpdateProgressRequest,
+        idempotency_key: &str,
+    ) -> Result<(ProgressWrite, bool)> {
+        let username = username.to_string();
+        let update = update.clone();
+        let key = format!("{}:{}", username, idempotency_key);
+        self.batched_write(move |db, write_txn| {
+            db.write_progress_idempotent(write_txn, &username, &update, &key)
+        })
+    }
+
+    fn write_progress_idempotent(
+        &self,
+        write_txn: &WriteTransaction,
+        username: &str,
+        update: &UpdateProgressRequest,
+        key: &str,
+    ) -> Result<(ProgressWrite, bool)> {
+        let timestamp = now();
+        let (start, end) = Self::user_key_range(username);
+        let expired_before = timestamp - self.config.idempotency_ttl.as_secs() as i64;
+
+        let previous: Option<IdempotentWrite> = {
+            let mut keys = write_txn.open_table(IDEMPOTENCY_KEYS)?;
+            keys.retain_in(start.as_str()..end.as_str(), |_, value| {
+                serde_json::from_slice::<IdempotentWrite>(value)
+                    .is_ok_and(|record| record.timestamp >= expired_before)
+            })?;
+            let previous = match keys.get(key)? {
+                Some(data) => Some(serde_json::from_slice(data.value())?),
+                None => None,
+            };
+            previous
+        };
+        if let Some(previous) = previous {
+            if previous.requested != update.document {
+                return Err(AppError::InvalidRequest(
+                    "idempotency key reused for another document".into(),
+                ));
+            }
+            let written = ProgressWrite {
+                document: previous.document,
+                timestamp: previous.timestamp,
+                version: previous.version,
+                finished: false,
+            };
+            return Ok((written, true));
+        }
+
+        let written = self.write_progress(write_txn, username, update, timestamp)?;
+        {
+            let record = IdempotentWrite {
+                requested: update.document.clone(),
+                document: written.document.clone(),
+                timestamp,
+                version: written.version,
+            };
+            let mut keys = write_txn.open_table(IDEMPOTENCY_KEYS)?;
+            keys.insert(key, serde_json::to_vec(&record)?.as_slice())?;
+        }
+
+        Ok((written, false))
+    }
+
+    /// Store several progress updates in a single write transaction.
+    ///
+    /// Returns one result per update, in order. Updates refused by a
+    /// progress rule (e.g. furthest-read-only) are reported individually
+    /// while the rest are still committed; storage errors fail the batch.
+    /// All stored records share one timestamp, and later entries for the
+    /// same document overwrite earlier ones.
+    pub fn set_progress_batch(
+        &self,
+        username: &str,
+        updates: &[&UpdateProgressRequest],
+    ) -> Result<Vec<Result<ProgressWrite>>> {
+        let username = username.to_string();
+        let updates: Vec<UpdateProgressRequest> = updates.iter().map(|u| (*u).clone()).collect();
+        self.batched_write(move |db, write_txn| {
+            let timestamp = now();
+            let mut results = Vec::with_capacity(updates.len());
+            for update in &updates {
+                match db.write_progress(write_txn, &username, update, timestamp) {
+                    Ok(written) => results.push(Ok(written)),
+                    Err(e) if e.is_client_error() => results.push(Err(e)),
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(results)
+        })
+    }
+
+    /// Apply one progress update inside an open write transaction.
+    ///
+    /// All checks run before anything is written, so a refused update
+    /// leaves the transaction untouched.
+    fn write_progress(
+        &self,
+        write_txn: &WriteTransaction,
+        username: &str,
+        update: &UpdateProgressRequest,
+        timestamp: i64,
+    ) -> Result<ProgressWrite> {
+        let (document, alias) = match &update.alt_document {
+            Some(alt) => Self::resolve_alt_document(write_txn, username, &update.document, alt)?,
+            None => {
+                let aliases = write_txn.open_table(ALIASES)?;
+                let document = Self::canonical_document(&aliases, username, &update.document)?;
+                (document, None)
+            }
+        };
+        let key = Self::progress_key(username, &document);
+        let mut table = write_txn.open_table(PROGRESS)?;
+
+        let stored: Option<Progress> = match table.get((username, document.as_str()))? {
+            Some(data) => Some(serde_json::from_slice(data.value())?),
+            None => None,
+        };
+        let previous = stored.as_ref().and_then(|p| p.percentage);
+        let stored_version = stored.as_ref().and_then(|p| p.version).unwrap_or(0);
+
+        if update
+            .base_version
+            .is_some_and(|base| base != stored_version)
+        {
+            return Err(AppError::VersionConflict);
+        }
+
+        if !update.force
+            && self.config.reject_stale_progress
+            && update
+                .client_timestamp
+                .zip(stored.as_ref().and_then(|p| p.client_timestamp))
+                .is_some_and(|(new, old)| new < old)
+        {
+            return Err(AppError::StaleProgress);
+        }
+        if !update.force
+            && previous.is_some_and(|p| p > update.percentage)
+            && self.furthest_read_only(write_txn, username)?
+        {
+            return Err(AppError::ProgressBehind);
+        }
+
+        if let Some(alias) = alias {
+            let mut aliases = write_txn.open_table(ALIASES)?;
+            for key in Self::bind_alias(&mut aliases, username, &alias, &document)? {
+                Self::journal(write_txn, username, ALIASES.name(), &key, Change::Put(None))?;
+            }
+        }
+
+        let threshold = self.config.finished_threshold;
+        let finished = update.percentage >= threshold && previous.is_none_or(|p| p < threshold);
+
+        let data = Progress {
+            document: Some(document.clone()),
+            progress: Some(update.progress.clone()),
+            percentage: Some(update.percentage),
+            device: Some(update.device.clone()),
+            device_id: update.device_id.clone(),
+            timestamp: Some(timestamp),
+            client_timestamp: update.client_timestamp,
+            version: Some(stored_version + 1),
+        };
+        let json = serde_json::to_vec(&data)?;
+        table.insert((username, document.as_str()), json.as_slice())?;
+        if stored.is_none() {
+            Self::index_document(write_txn, username, &document, Indexed::Progress, true)?;
+        }
+        let version = Some(stored_version + 1);
+        Self::journal(
+            write_txn,
+            username,
+            PROGRESS.name(),
+            &key,
+            Change::Put(version),
+        )?;
+
+        if self.config.progress_history {
+            let mut history = write_txn.open_table(PROGRESS_HISTORY)?;
+            let entry = Self::append_history(&mut history, &key, &json)?;
+            Self::journal(
+                write_txn,
+                username,
+                PROGRESS_HISTORY.name(),
+                &entry,
+                Change::Put(version),
+            )?;
+        }
+        if let Some(device_id) = &update.device_id {
+            if self.config.device_progress {
+                let mut table = write_txn.open_table(DEVICE_PROGRESS)?;
+                let key = format!("{}:{}", key, device_id);
+                table.insert(key.as_str(), json.as_slice())?;
+                Self::journal(
+                    write_txn,
+                    username,
+                    DEVICE_PROGRESS.name(),
+                    &key,
+                    Change::Put(version),
+                )?;
+            }
+            let device = Device {
+                device_id: device_id.clone(),
+                device: update.device.clone(),
+                last_seen: timestamp,
+                last_document: document.clone(),
+            };
+            let mut table = write_txn.open_table(DEVICES)?;
+            let key = Self::device_key(username, device_id);
+            table.insert(key.as_str(), serde_json::to_vec(&device)?.as_slice())?;
+            Self::journal(write_txn, username, DEVICES.name(), &key, Change::Put(None))?;
+        }
+        if finished {
+            let book = FinishedBook {
+                document: document.clone(),
+                finished_at: timestamp,
+                device: Some(update.device.clone()),
+                metadata: None,
+                rating: None,
+            };
+            let mut table = write_txn.open_table(FINISHED)?;
+            table.insert(key.as_str(), serde_json::to_vec(&book)?.as_slice())?;
+            Self::journal(
+                write_txn,
+                username,
+                FINISHED.name(),
+                &key,
+                Change::Put(None),
+            )?;
+        }
+        Ok(ProgressWrite {
+            document,
+            timestamp,
+            version: stored_version + 1,
+            finished,
+        })
+    }
+
+    /// Work out which of a document's two KOReader hashes is canonical,
+    /// without writing anything. Returns the canonical hash and, if the two
+    /// aren't linked yet, the other hash to bind to it once the update is
+    /// accepted.
+    ///
+    /// Whichever side already has progress stays canonical, so a device
+    /// that only knows one hash never orphans what another device stored
+    /// under the other.
+    fn resolve_alt_document(
+        write_txn: &WriteTransaction,
+        username: &str,
+        document: &str,
+        alt: &str,
+    ) -> Result<(String, Option<String>)> {
+        let aliases = write_txn.open_table(ALIASES)?;
+        let document = Self::canonical_document(&aliases, username, document)?;
+        let alt = Self::canonical_document(&aliases, username, alt)?;
+        if document == alt {
+            return Ok((document, None));
+        }
+
+        let progress = write_txn.open_table(PROGRESS)?;
+        let document_stored = progress.get((username, document.as_str()))?.is_some();
+        let alt_stored = progress.get((username, alt.as_str()))?.is_some();
+
+        Ok(if alt_stored && !document_stored {
+            (alt, Some(document))
+        } else {
+            (document, Some(alt))
+        })
+    }
+
+    /// Whether backwards progress moves are refused for this user, taking
+    /// the user's own setting over the server default.
+    fn furthest_read_only(&self, write_txn: &WriteTransaction, username: &str) -> Result<bool> {
+        let table = write_txn.open_table(USER_SETTINGS)?;
+        let settings: UserSettings = match table.get(username)? {
+            Some(data) => serde_json::from_slice(data.value())?,
+            None => UserSettings::default(),
+        };
+        Ok(settings
+            .furthest_read_only
+            .unwrap_or(self.config.furthest_read_only))
+    }
+
+    /// Append a progress snapshot under `user:document:<seq>`, where `seq`
+    /// is one past the last entry recorded for that document. Returns the
+    /// key it was stored under.
+    fn append_history(
+        history: &mut Table<&str, &[u8]>,
+        progress_key: &str,
+        json: &[u8],
+    ) -> Result<String> {
+        let start = format!("{}:", progress_key);
+        let end = format!("{};", progress_key);
+        let next_seq = match history.range(start.as_str()..end.as_str())?.next_back() {
+            Some(entry) => {
+                let (key, _) = entry?;
+                key.value()[start.len()..].parse::<u64>().unwrap_or(0) + 1
+            }
+            None => 0,
+        };
+        let key = format!("{}{:020}", start, next_seq);
+        history.insert(key.as_str(), json)?;
+        Ok(key)
+    }
+
+    /// The most recent `limit` history entries for a document, oldest first.
+    pub fn get_progress_history(
+        &self,
+        username: &str,
+        document: &str,
+        limit: usize,
+    ) -> Result<Vec<Progress>> {
+        let read_txn = self.db.begin_read()?;
+        let document =
+            Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+        let progress_key = Self::progress_key(username, &document);
+        let start = format!("{}:", progress_key);
+        let end = format!("{};", progress_key);
+        let table = read_txn.open_table(PROGRESS_HISTORY)?;
+
+        let mut entries = Vec::new();
+        for entry in table.range(start.as_str()..end.as_str())?.rev().take(limit) {
+            let (_, data) = entry?;
+            entries.push(serde_json::from_slice(data.value())?);
+        }
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Journal the removal of a document's progress record, its history and
+    /// its devices' positions; `key` is its `progress_key`.
+    fn journal_progress_removal(
+        write_txn: &WriteTransaction,
+        username: &str,
+        key: &str,
+    ) -> Result<()> {
+        Self::journal(write_txn, username, PROGRESS.name(), key, Change::Removed)?;
+        let entries = format!("{}:", key);
+        for table in [PROGRESS_HISTORY, DEVICE_PROGRESS] {
+            Self::journal(
+                write_txn,
+                username,
+                table.name(),
+                &entries,
+                Change::RemovedPrefix,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Remove the progress record (and its history) for a document, moving
+    /// the record to the trash. Returns whether a record existed.
+    pub fn delete_progress(&self, username: &str, document: &str) -> Result<bool> {
+        let write_txn = self.begin_write()?;
+        let document =
+            Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+        let key = Self::progress_key(username, &document);
+        let entries_start = format!("{}:", key);
+        let entries_end = format!("{};", key);
+
+        let removed = {
+            let mut table = write_txn.open_table(PROGRESS)?;
+            let removed: Option<Progress> = match table.remove((username, document.as_str()))? {
+                Some(data) => Some(serde_json::from_slice(data.value())?),
+                None => None,
+            };
+            if let Some(progress) = &removed {
+                Self::index_document(&write_txn, username, &document, Indexed::Progress, false)?;
+                self.trash_in(
+                    &write_txn,
+                    username,
+                    TrashKind::Progress,
+                    &document,
+                    progress,
+                )?;
+            }
+            let mut history = write_txn.open_table(PROGRESS_HISTORY)?;
+            history.retain_in(entries_start.as_str()..entries_end.as_str(), |_, _| false)?;
+            let mut devices = write_txn.open_table(DEVICE_PROGRESS)?;
+            devices.retain_in(entries_start.as_str()..entries_end.as_str(), |_, _| false)?;
+            if removed.is_some() {
+                Self::journal_progress_removal(&write_txn, username, &key)?;
+            }
+            removed.is_some()
+        };
+        write_txn.commit()?;
+
+        Ok(removed)
+    }
+
+    // === Document aliases ===
+
+    fn alias_key(username: &str, alias: &str) -> String {
+        format!("{}:{}", username, alias)
+    }
+
+    /// Follow an alias binding to the hash the document's data is stored under.
+    fn canonical_document(
+        aliases: &impl ReadableTable<&'static str, &'static str>,
+        username: &str,
+        document: &str,
+    ) -> Result<String> {
+        let key = Self::alias_key(username, document);
+        Ok(match aliases.get(key.as_str())? {
+            Some(target) => target.value().to_string(),
+            None => document.to_string(),
+        })
+    }
+
+    /// Bind each alias to `document`, which is first resolved to its own
+    /// canonical hash.
+    pub fn add_aliases(&self, username: &str, document: &str, aliases: &[String]) -> Result<()> {
+        let write_txn = self.begin_write()?;
+        {
+            let mut table = write_txn.open_table(ALIASES)?;
+            let canonical = Self::canonical_document(&table, username, document)?;
+            for alias in aliases {
+                for key in Self::bind_alias(&mut table, username, alias, &canonical)? {
+                    Self::journal(
+                        &write_txn,
+                        username,
+                        ALIASES.name(),
+                        &key,
+                        Change::Put(None),
+                    )?;
+                }
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Point `alias` at `canonical`. Aliases that previously pointed at
+    /// `alias` are re-pointed too, so lookups never need more than one hop.
+    /// Returns the keys written.
+    fn bind_alias(
+        table: &mut Table<&str, &str>,
+        username: &str,
+        alias: &str,
+        canonical: &str,
+    ) -> Result<Vec<String>> {
+        if alias == canonical {
+            return Ok(Vec::new());
+        }
+        let (start, end) = Self::user_key_range(username);
+        let mut repoint = Vec::new();
+        for entry in table.range(start.as_str()..end.as_str())? {
+            let (key, target) = entry?;
+            if target.value() == alias {
+                repoint.push(key.value().to_string());
+            }
+        }
+        for key in &repoint {
+            table.insert(key.as_str(), canonical)?;
+        }
+        let key = Self::alias_key(username, alias);
+        table.insert(key.as_str(), canonical)?;
+        repoint.push(key);
+        Ok(repoint)
+    }
+
+    /// All alias bindings for a user, ordered by alias.
+    pub fn list_aliases(&self, username: &str) -> Result<Vec<DocumentAlias>> {
+        let (start, end) = Self::user_key_range(username);
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(ALIASES)?;
+
+        let mut aliases = Vec::new();
+        for entry in table.range(start.as_str()..end.as_str())? {
+            let (key, target) = entry?;
+            aliases.push(DocumentAlias {
+                alias: key.value()[username.len() + 1..].to_string(),
+                document: target.value().to_string(),
+            });
+        }
+        Ok(aliases)
+    }
+
+    /// Remove an alias binding. Returns whether one existed.
+    pub fn delete_alias(&self, username: &str, alias: &str) -> Result<bool> {
+        let key = Self::alias_key(username, alias);
+
+        let write_txn = self.begin_write()?;
+        let removed = {
+            let mut table = write_txn.open_table(ALIASES)?;
+            let removed = table.remove(key.as_str())?.is_some();
+            if removed {
+                Self::journal(&write_txn, username, ALIASES.name(), &key, Change::Removed)?;
+            }
+            removed
+        };
+        write_txn.commit()?;
+        Ok(removed)
+    }
+
+    // === Document metadata ===
+
+    fn metadata_key(username: &str, document: &str) -> String {
+        format!("{}:{}", username, document)
+    }
+
+    pub fn get_metadata(&self, username: &str, document: &str) -> Result<Option<DocumentMetadata>> {
+        let read_txn = self.db.begin_read()?;
+        let document =
+            Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+        let key = Self::metadata_key(username, &document);
+        let table = read_txn.open_table(DOCUMENT_METADATA)?;
+        match table.get(key.as_str())? {
+            Some(data) => Ok(Some(serde_json::from_slice(data.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace a document's metadata, stamping it with the current time.
+    pub fn set_metadata(
+        &self,
+        username: &str,
+        document: &str,
+        metadata: &DocumentMetadata,
+    ) -> Result<DocumentMetadata> {
+        let stored = DocumentMetadata {
+            updated_at: Some(now()),
+            ..metadata.clone()
+        };
+        let json = serde_json::to_vec(&stored)?;
+
+        let write_txn = self.begin_write()?;
+        {
+            let document =
+                Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+            let key = Self::metadata_key(username, &document);
+            let mut table = write_txn.open_table(DOCUMENT_METADATA)?;
+            table.insert(key.as_str(), json.as_slice())?;
+            Self::journal(
+                &write_txn,
+                username,
+                DOCUMENT_METADATA.name(),
+                &key,
+                Change::Put(None),
+            )?;
+        }
+        write_txn.commit()?;
+        Ok(stored)
+    }
+
+    /// Metadata for every document of a user, keyed by document hash.
+    /// Every finished document, oldest finish first.
+    pub fn list_finished(&self, username: &str) -> Result<Vec<FinishedBook>> {
+        let (start, end) = Self::user_key_range(username);
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(FINISHED)?;
+
+        let mut books: Vec<FinishedBook> = Vec::new();
+        for entry in table.range(start.as_str()..end.as_str())? {
+            let (_, data) = entry?;
+            books.push(serde_json::from_slice(data.value())?);
+        }
+        books.sort_by_key(|b| b.finished_at);
+        Ok(books)
+    }
+
+    pub fn list_metadata(&self, username: &str) -> Result<BTreeMap<String, DocumentMetadata>> {
+        let (start, end) = Self::user_key_range(username);
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(DOCUMENT_METADATA)?;
+
+        let mut result = BTreeMap::new();
+        for entry in table.range(start.as_str()..end.as_str())? {
+            let (key, data) = entry?;
+            let document = key.value()[username.len() + 1..].to_string();
+            result.insert(document, serde_json::from_slice(data.value())?);
+        }
+        Ok(result)
+    }
+
+    pub fn get_status(&self, username: &str, document: &str) -> Result<Option<DocumentStatus>> {
+        let read_txn = self.db.begin_read()?;
+        let document =
+            Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+        let key = Self::metadata_key(username, &document);
+        let table = read_txn.open_table(DOCUMENT_STATUS)?;
+        match table.get(key.as_str())? {
+            Some(data) => Ok(Some(serde_json::from_slice(data.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Set a document's status, or clear it with `None`.
+    pub fn set_status(
+        &self,
+        username: &str,
+        document: &str,
+        status: Option<BookStatus>,
+    ) -> Result<Option<DocumentStatus>> {
+        let write_txn = self.begin_write()?;
+        let record = {
+            let document =
+                Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+            let key = Self::metadata_key(username, &document);
+            let mut table = write_txn.open_table(DOCUMENT_STATUS)?;
+            match status {
+                Some(status) => {
+                    let record = DocumentStatus {
+                        document,
+                        status,
+                        updated_at: now(),
+                        metadata: None,
+                    };
+                    table.insert(key.as_str(), serde_json::to_vec(&record)?.as_slice())?;
+                    Self::journal(
+                        &write_txn,
+                        username,
+                        DOCUMENT_STATUS.name(),
+                        &key,
+                        Change::Put(None),
+                    )?;
+                    Some(record)
+                }
+                None => {
+                    if table.remove(key.as_str())?.is_some() {
+                        Self::journal(
+                            &write_txn,
+                            username,
+                            DOCUMENT_STATUS.name(),
+                            &key,
+                            Change::Removed,
+                        )?;
+                    }
+                    None
+                }
+            }
+        };
+        write_txn.commit()?;
+        Ok(record)
+    }
+
+    pub fn list_status(&self, username: &str) -> Result<Vec<DocumentStatus>> {
+        let (start, end) = Self::user_key_range(username);
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(DOCUMENT_STATUS)?;
+
+        let mut records: Vec<DocumentStatus> = Vec::new();
+        for entry in table.range(start.as_str()..end.as_str())? {
+            let (_, data) = entry?;
+            records.push(serde_json::from_slice(data.value())?);
+        }
+        records.sort_by_key(|r| std::cmp::Reverse(r.updated_at));
+        Ok(records)
+    }
+
+    pub fn get_review(&self, username: &str, document: &str) -> Result<Option<Review>> {
+        let read_txn = self.db.begin_read()?;
+        let document =
+            Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+        let key = Self::metadata_key(username, &document);
+        let table = read_txn.open_table(REVIEWS)?;
+        match table.get(key.as_str())? {
+            Some(data) => Ok(Some(serde_json::from_slice(data.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Store a rating and review for a document, replacing any previous one.
+    pub fn set_review(
+        &self,
+        username: &str,
+        document: &str,
+        rating: Option<u8>,
+        review: Option<String>,
+    ) -> Result<Review> {
+        let write_txn = self.begin_write()?;
+        let record = {
+            let document =
+                Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+            let key = Self::metadata_key(username, &document);
+            let record = Review {
+                document,
+                rating,
+                review,
+                updated_at: now(),
+                metadata: None,
+            };
+            let mut table = write_txn.open_table(REVIEWS)?;
+            table.insert(key.as_str(), serde_json::to_vec(&record)?.as_slice())?;
+            Self::journal(
+                &write_txn,
+                username,
+                REVIEWS.name(),
+                &key,
+                Change::Put(None),
+            )?;
+            record
+        };
+        write_txn.commit()?;
+        Ok(record)
+    }
+
+    pub fn delete_review(&self, username: &str, document: &str) -> Result<()> {
+        let write_txn = self.begin_write()?;
+        {
+            let document =
+                Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+            let key = Self::metadata_key(username, &document);
+            let mut table = write_txn.open_table(REVIEWS)?;
+            if table.remove(key.as_str())?.is_some() {
+                Self::journal(&write_txn, username, REVIEWS.name(), &key, Change::Removed)?;
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    pub fn list_reviews(&self, username: &str) -> Result<Vec<Review>> {
+        let (start, end) = Self::user_key_range(username);
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(REVIEWS)?;
+
+        let mut reviews: Vec<Review> = Vec::new();
+        for entry in table.range(start.as_str()..end.as_str())? {
+            let (_, data) = entry?;
+            reviews.push(serde_json::from_slice(data.value())?);
+        }
+        reviews.sort_by_key(|r| std::cmp::Reverse(r.updated_at));
+        Ok(reviews)
+    }
+
+    pub fn get_tags(&self, username: &str, document: &str) -> Result<DocumentTags> {
+        let read_txn = self.db.begin_read()?;
+        let document =
+            Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+        let key = Self::metadata_key(username, &document);
+        let table = read_txn.open_table(DOCUMENT_TAGS)?;
+        let tags = match table.get(key.as_str())? {
+            Some(data) => serde_json::from_slice(data.value())?,
+            None => Vec::new(),
+        };
+        Ok(DocumentTags {
+            document,
+            tags,
+            metadata: None,
+        })
+    }
+
+    /// Replace a document's tags, keeping the tag index in step.
+    pub fn set_tags(
+        &self,
+        username: &str,
+        document: &str,
+        tags: Vec<String>,
+    ) -> Result<DocumentTags> {
+        let write_txn = self.begin_write()?;
+        let document = {
+            let document =
+                Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+            let key = Self::metadata_key(username, &document);
+            let mut table = write_txn.open_table(DOCUMENT_TAGS)?;
+            let mut index = write_txn.open_table(TAG_INDEX)?;
+
+            let previous: Vec<String> = match table.get(key.as_str())? {
+                Some(data) => serde_json::from_slice(data.value())?,
+                None => Vec::new(),
+            };
+            for tag in &previous {
+                index.remove(Self::tag_key(username, tag, &document).as_str())?;
+            }
+            for tag in &tags {
+                index.insert(Self::tag_key(username, tag, &document).as_str(), &[][..])?;
+            }
+            let change = if tags.is_empty() {
+                table.remove(key.as_str())?;
+                Change::Removed
+            } else {
+                table.insert(key.as_str(), serde_json::to_vec(&tags)?.as_slice())?;
+                Change::Put(None)
+            };
+            Self::journal(&write_txn, username, DOCUMENT_TAGS.name(), &key, change)?;
+            document
+        };
+        write_txn.commit()?;
+        Ok(DocumentTags {
+            document,
+            tags,
+            metadata: None,
+        })
+    }
+
+    /// Every tagged document, or only those carrying `tag`.
+    pub fn list_tagged(&self, username: &str, tag: Option<&str>) -> Result<Vec<DocumentTags>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(DOCUMENT_TAGS)?;
+
+        let keys: Vec<String> = match tag {
+            Some(tag) => {
+                let index = read_txn.open_table(TAG_INDEX)?;
+                let start = format!("{}:{}:", username, tag);
+                let end = format!("{}:{};", username, tag);
+                let mut keys = Vec::new();
+                for entry in index.range(start.as_str()..end.as_str())? {
+                    let (key, _) = entry?;
+                    keys.push(Self::metadata_key(username, &key.value()[start.len()..]));
+                }
+                keys
+            }
+            None => {
+                let (start, end) = Self::user_key_range(username);
+                let mut keys = Vec::new();
+                for entry in table.range(start.as_str()..end.as_str())? {
+                    keys.push(entry?.0.value().to_string());
+                }
+                keys
+            }
+        };
+
+        let mut documents = Vec::new();
+        for key in keys {
+            if let Some(data) = table.get(key.as_str())? {
+                documents.push(DocumentTags {
+                    document: key[username.len() + 1..].to_string(),
+                    tags: serde_json::from_slice(data.value())?,
+                    metadata: None,
+                });
+            }
+        }
+        Ok(documents)
+    }
+
+    fn tag_key(username: &str, tag: &str, document: &str) -> String {
+        format!("{}:{}:{}", username, tag, document)
+    }
+
+    pub fn get_note(&self, username: &str, document: &str) -> Result<DocumentNote> {
+        let read_txn = self.db.begin_read()?;
+        let document =
+            Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+        let key = Self::metadata_key(username, &document);
+        let table = read_txn.open_table(DOCUMENT_NOTES)?;
+        match table.get(key.as_str())? {
+            Some(data) => Ok(serde_json::from_slice(data.value())?),
+            None => Ok(DocumentNote::default()),
+        }
+    }
+
+    /// Replace a document's note. An empty `text` removes it.
+    pub fn set_note(
+        &self,
+        username: &str,
+        document: &str,
+        text: &str,
+        base_version: Option<u64>,
+    ) -> Result<DocumentNote> {
+        let write_txn = self.begin_write()?;
+        let note = {
+            let document =
+                Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+            let key = Self::metadata_key(username, &document);
+            let mut table = write_txn.open_table(DOCUMENT_NOTES)?;
+
+            let current: DocumentNote = match table.get(key.as_str())? {
+                Some(data) => serde_json::from_slice(data.value())?,
+                None => DocumentNote::default(),
+            };
+            if base_version.is_some_and(|base| base != current.version) {
+                return Err(AppError::VersionConflict);
+            }
+
+            let note = DocumentNote {
+                text: text.to_string(),
+                version: current.version + 1,
+                updated_at: now(),
+            };
+            let change = if text.is_empty() {
+                table.remove(key.as_str())?;
+                Change::Removed
+            } else {
+                table.insert(key.as_str(), serde_json::to_vec(&note)?.as_slice())?;
+                Change::Put(Some(note.version))
+            };
+            Self::journal(&write_txn, username, DOCUMENT_NOTES.name(), &key, change)?;
+            note
+        };
+        write_txn.commit()?;
+        Ok(note)
+    }
+
+    // === Annotations operations (extended API) ===
+
+    fn annotations_key(username: &str, document: &str) -> String {
+        format!("{}:{}", username, document)
+    }
+
+    /// Where a user's annotations for a document live: their own record, or
+    /// the share group's once they have joined one.
+    fn annotations_location(
+        members: &impl ReadableTable<&'static str, &'static str>,
+        groups: &impl ReadableTable<&'static str, &'static [u8]>,
+        username: &str,
+        document: &str,
+    ) -> Result<AnnotationsRecord> {
+        let key = Self::annotations_key(username, document);
+        if let Some(group) = Self::share_group(members, groups, &key)? {
+            if group.member(username).is_some_and(|m| m.joined) {
+                return Ok(AnnotationsRecord::Shared(group.id));
+            }
+        }
+        Ok(AnnotationsRecord::Own(
+            username.to_string(),
+            document.to_string(),
+        ))
+    }
+
+    pub fn get_annotations(&self, username: &str, document: &str) -> Result<DocumentAnnotations> {
+        let key = (username.to_string(), document.to_string());
+        self.annotations_cache.get_or_read(key, || {
+            let read_txn = self.db.begin_read()?;
+            let document =
+                Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+            let record = Self::annotations_location(
+                &read_txn.open_table(SHARE_MEMBERS)?,
+                &read_txn.open_table(SHARE_GROUPS)?,
+                username,
+                &document,
+            )?;
+            Ok(record.read(&read_txn)?.unwrap_or_default())
+        })
+    }
+
+    /// Version and time of the document's last annotation change, without
+    /// reading the annotations themselves.
+    pub fn annotations_version(&self, username: &str, document: &str) -> Result<AnnotationsStamp> {
+        let read_txn = self.db.begin_read()?;
+        let document =
+            Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+        let record = Self::annotations_location(
+            &read_txn.open_table(SHARE_MEMBERS)?,
+            &read_txn.open_table(SHARE_GROUPS)?,
+            username,
+            &document,
+        )?;
+        Ok(record.read(&read_txn)?.unwrap_or_default())
+    }
+
+    pub fn set_annotations(
+        &self,
+        username: &str,
+        document: &str,
+        annotations: &DocumentAnnotations,
+    ) -> Result<()> {
+        let json = encode_annotations(annotations)?;
+
+        let write_txn = self.begin_write()?;
+        {
+            let document =
+                Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+            let record = Self::annotations_location(
+                &write_txn.open_table(SHARE_MEMBERS)?,
+                &write_txn.open_table(SHARE_GROUPS)?,
+                username,
+                &document,
+            )?;
+            let previous = record
+                .write(&write_txn, username, &json, annotations.version)?
+                .unwrap_or_default();
+            let viewers = Self::annotation_viewers(&write_txn, username, &document)?;
+            Self::reindex_annotations(
+                &write_txn,
+                &viewers,
+                &previous.annotations,
+                &annotations.annotations,
+            )?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    pub fn update_annotations(
+        &self,
+        username: &str,
+        document: &str,
+        new_annotations: Vec<crate::models::Annotation>,
+        new_deleted: Vec<String>,
+        base_version: Option<u64>,
+        options: MergeOptions,
+    ) -> Result<AnnotationsWrite> {
+        let write_txn = self.begin_write()?;
+        let result = self.write_annotations(
+            &write_txn,
+            username,
+            document,
+            new_annotations,
+            new_deleted,
+            base_version,
+            options,
+        )?;
+        write_txn.commit()?;
+        Ok(result)
+    }
+
+    /// Store imported annotations together with the reading position
+    /// imported along with them, which is skipped if the document already
+    /// has one: either both are stored or neither.
+    pub fn import_annotations_with_progress(
+        &self,
+        username: &str,
+        document: &str,
+        annotations: Vec<crate::models::Annotation>,
+        progress: &UpdateProgressRequest,
+    ) -> Result<(AnnotationsWrite, Option<ProgressWrite>)> {
+        let write_txn = self.begin_write()?;
+        let result = self.write_annotations(
+            &write_txn,
+            username,
+            document,
+            annotations,
+            Vec::new(),
+            None,
+            MergeOptions::default(),
+        )?;
+        let seeded = match self.write_progress(&write_txn, username, progress, result.timestamp) {
+            Ok(written) => Some(written),
+            // The document has a position already
+            Err(AppError::VersionConflict) => None,
+            Err(e) => return Err(e),
+        };
+        write_txn.commit()?;
+        Ok((result, seeded))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write_annotations(
+        &self,
+        write_txn: &WriteTransaction,
+        username: &str,
+        document: &str,
+        new_annotations: Vec<crate::models::Annotation>,
+        new_deleted: Vec<String>,
+        base_version: Option<u64>,
+        options: MergeOptions,
+    ) -> Result<AnnotationsWrite> {
+        let timestamp = now();
+        let document =
+            Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+        let record = Self::annotations_location(
+            &write_txn.open_table(SHARE_MEMBERS)?,
+            &write_txn.open_table(SHARE_GROUPS)?,
+            username,
+            &document,
+        )?;
+        // Get current state
+        let mut current: DocumentAnnotations = record.read_in(write_txn)?.unwrap_or_default();
+        let mut new_annotations = new_annotations;
+        assign_annotation_ids(&mut current.annotations, &mut new_annotations);
+        let previous = current.annotations.clone();
+
+        let (new_doc, merge) = self.merge_upload(
+            current,
+            new_annotations,
+            new_deleted,
+            base_version,
+            options,
+            timestamp,
+        )?;
+
+        let json = encode_annotations(&new_doc)?;
+        record.write(write_txn, username, &json, new_doc.version)?;
+        self.record_annotation_history(write_txn, username, &record.key(), &json, new_doc.version)?;
+
+        let viewers = Self::annotation_viewers(write_txn, username, &document)?;
+        Self::reindex_annotations(write_txn, &viewers, &previous, &new_doc.annotations)?;
+
+        Ok(AnnotationsWrite {
+            version: new_doc.version,
+            timestamp,
+            ids: merge.ids,
+            conflicts: merge.conflicts,
+            duplicates: merge.duplicates,
+        })
+    }
+
+    /// What `update_annotations` would store, without storing it.
+    /// Annotations uploaded without an id are given one that the real
+    /// upload won't reuse.
+    pub fn preview_annotations(
+        &self,
+        username: &str,
+        document: &str,
+        new_annotations: Vec<crate::models::Annotation>,
+        new_deleted: Vec<String>,
+        base_version: Option<u64>,
+        options: MergeOptions,
+    ) -> Result<(DocumentAnnotations, AnnotationsWrite)> {
+        let timestamp = now();
+        let current = self.get_annotations(username, document)?;
+        let (new_doc, merge) = self.merge_upload(
+            current,
+            new_annotations,
+            new_deleted,
+            base_version,
+            options,
+            timestamp,
+        )?;
+        let write = AnnotationsWrite {
+            version: new_doc.version,
+            timestamp,
+            ids: merge.ids,
+            conflicts: merge.conflicts,
+            duplicates: merge.duplicates,
+        };
+        Ok((new_doc, write))
+    }
+
+    /// `current` with an upload merged in, as the next version.
+    fn merge_upload(
+        &self,
+        current: DocumentAnnotations,
+        new_annotations: Vec<crate::models::Annotation>,
+        new_deleted: Vec<String>,
+        base_version: Option<u64>,
+        options: MergeOptions,
+        timestamp: i64,
+    ) -> Result<(DocumentAnnotations, Merge)> {
+        // Check version if provided (optimistic locking)
+        if let Some(base) = base_version {
+            if base != current.version && current.version > 0 {
+                return Err(AppError::VersionConflict);
+            }
+        }
+
+        // Merge annotations; whatever this upload adds or changes is
+        // stamped with the new version
+        let version = current.version + 1;
+        let stored = current.annotations.len();
+        let mut new_annotations = new_annotations;
+        for anno in &mut new_annotations {
+            anno.created_by = None;
+            anno.modified_by = None;
+        }
+        let strategy = options.strategy.unwrap_or(self.config.merge_strategy);
+        let mut merge = merge_annotations(
+            current.annotations,
+            new_annotations,
+            &current.deleted,
+            &new_deleted,
+            version,
+            Incoming::Upload,
+            strategy.strategy(),
+        )?;
+        if options.dedup {
+            merge.dedup();
+        }
+        if let Some(device) = &options.device_id {
+            for anno in &mut merge.annotations {
+                if anno.version == Some(version) {
+                    anno.created_by.get_or_insert_with(|| device.clone());
+                    anno.modified_by = Some(device.clone());
+                }
+            }
+        }
+        // Documents already over a lowered limit can still be edited and
+        // pruned, just not grown
+        let limit = self.config.max_annotations_per_document;
+        if limit > 0 && merge.annotations.len() > limit.max(stored) {
+            return Err(AppError::AnnotationLimit(limit));
+        }
+
+        // Merge deleted lists; collapsed duplicates are deleted too, so
+        // other devices drop their copies
+        let mut all_deleted = current.deleted;
+        let mut deleted_versions = current.deleted_versions;
+        let mut deleted_at = current.deleted_at;
+        let mut new_deleted = new_deleted;
+        new_deleted.extend(merge.duplicates.keys().cloned());
+        for d in new_deleted {
+            if !all_deleted.contains(&d) {
+                deleted_versions.insert(d.clone(), version);
+                deleted_at.insert(d.clone(), timestamp);
+                all_deleted.push(d);
+            }
+        }
+
+        let new_doc = DocumentAnnotations {
+            version,
+            annotations: std::mem::take(&mut merge.annotations),
+            deleted: all_deleted,
+            deleted_versions,
+            deleted_at,
+            updated_at: timestamp,
+            next_cursor: None,
+            next_deleted_cursor: None,
+        };
+        Ok((new_doc, merge))
+    }
+}
+
+// === Annotation history ===
+
+impl Database {
+    fn history_key(key: &str, version: u64) -> String {
+        // Zero-padded so versions sort numerically
+        format!("{}:{:020}", key, version)
+    }
+
+    /// Keep a copy of an annotation set, dropping copies beyond the
+    /// configured limit.
+    fn record_annotation_history(
+        &self,
+        write_txn: &WriteTransaction,
+        username: &str,
+        key: &str,
+        json: &[u8],
+        version: u64,
+    ) -> Result<()> {
+        let limit = self.config.annotation_history;
+        if limit == 0 {
+            return Ok(());
+        }
+        let mut history = write_txn.open_table(ANNOTATION_HISTORY)?;
+        let entry = Self::history_key(key, version);
+        history.insert(entry.as_str(), json)?;
+        Self::journal(
+            write_txn,
+            username,
+            ANNOTATION_HISTORY.name(),
+            &entry,
+            Change::Put(Some(version)),
+        )?;
+
+        let (start, end) = (format!("{}:", key), format!("{};", key));
+        let count = history.range(start.as_str()..end.as_str())?.count();
+        // Oldest first, so drop from the front
+        let mut excess = count.saturating_sub(limit);
+        let mut dropped = Vec::new();
+        history.retain_in(start.as_str()..end.as_str(), |entry, _| {
+            if excess > 0 {
+                excess -= 1;
+                dropped.push(entry.to_string());
+                false
+            } else {
+                true
+            }
+        })?;
+        for entry in dropped {
+            Self::journal(
+                write_txn,
+                username,
+                ANNOTATION_HISTORY.name(),
+                &entry,
+                Change::Removed,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Kept versions of a document's annotations, newest first.
+    pub fn annotation_versions(
+        &self,
+        username: &str,
+        document: &str,
+    ) -> Result<Vec<AnnotationVersion>> {
+        let read_txn = self.db.begin_read()?;
+        let document =
+            Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+        let key = Self::annotations_location(
+            &read_txn.open_table(SHARE_MEMBERS)?,
+            &read_txn.open_table(SHARE_GROUPS)?,
+            username,
+            &document,
+        )?
+        .key();
+        let history = read_txn.open_table(ANNOTATION_HISTORY)?;
+
+        let (start, end) = (format!("{}:", key), format!("{};", key));
+        let mut versions = Vec::new();
+        for entry in history.range(start.as_str()..end.as_str())?.rev() {
+            let (_, data) = entry?;
+            let doc: DocumentAnnotations = decode_annotations(data.value())?;
+            versions.push(AnnotationVersion {
+                version: doc.version,
+                updated_at: doc.updated_at,
+                annotations: doc.annotations.len(),
+                deleted: doc.deleted.len(),
+            });
+        }
+        Ok(versions)
+    }
+
+    fn kept_version(
+        history: &impl ReadableTable<&'static str, &'static [u8]>,
+        key: &str,
+        version: u64,
+    ) -> Result<DocumentAnnotations> {
+        match history.get(Self::history_key(key, version).as_str())? {
+            Some(data) => decode_annotations(data.value()),
+            None => Err(AppError::InvalidRequest(format!(
+                "version {} is not kept",
+                version
+            ))),
+        }
+    }
+
+    /// The annotation sets kept for versions `from` and `to`. Version 0 is
+    /// the empty set every document starts from.
+    pub fn annotation_version_pair(
+        &self,
+        username: &str,
+        document: &str,
+        from: u64,
+        to: u64,
+    ) -> Result<(DocumentAnnotations, DocumentAnnotations)> {
+        let read_txn = self.db.begin_read()?;
+        let document =
+            Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+        let key = Self::annotations_location(
+            &read_txn.open_table(SHARE_MEMBERS)?,
+            &read_txn.open_table(SHARE_GROUPS)?,
+            username,
+            &document,
+        )?
+        .key();
+        let history = read_txn.open_table(ANNOTATION_HISTORY)?;
+        let kept = |version| match version {
+            0 => Ok(DocumentAnnotations::default()),
+            version => Self::kept_version(&history, &key, version),
+        };
+        Ok((kept(from)?, kept(to)?))
+    }
+
+    /// Restore the annotation set kept for `version` as a new version.
+    ///
+    /// Annotations added since become deletions, so devices syncing
+    /// incrementally drop them too. Returns the new version, its timestamp
+    /// and the restored ids.
+    pub fn revert_annotations(
+        &self,
+        username: &str,
+        document: &str,
+        target: u64,
+    ) -> Result<(u64, i64, Vec<String>)> {
+        let timestamp = now();
+
+        let write_txn = self.begin_write()?;
+        let document =
+            Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+        let record = Self::annotations_location(
+            &write_txn.open_table(SHARE_MEMBERS)?,
+            &write_txn.open_table(SHARE_GROUPS)?,
+            username,
+            &document,
+        )?;
+        let key = record.key();
+        let result = {
+            let snapshot =
+                Self::kept_version(&write_txn.open_table(ANNOTATION_HISTORY)?, &key, target)?;
+            let current: DocumentAnnotations = record.read_in(&write_txn)?.unwrap_or_default();
+
+            let version = current.version + 1;
+            let mut restored = snapshot.annotations;
+            for anno in &mut restored {
+                anno.version = Some(version);
+            }
+            let restored_ids: HashSet<&str> = restored
+                .iter()
+                .flat_map(|a| [a.id.as_deref(), Some(a.datetime.as_str())])
+                .flatten()
+                .collect();
+
+            let mut deleted = current.deleted;
+            let mut deleted_versions = current.deleted_versions;
+            let mut deleted_at = current.deleted_at;
+            deleted.retain(|id| !restored_ids.contains(id.as_str()));
+            deleted_versions.retain(|id, _| !restored_ids.contains(id.as_str()));
+            deleted_at.retain(|id, _| !restored_ids.contains(id.as_str()));
+            for anno in &current.annotations {
+                let Some(id) = &anno.id else { continue };
+                if !restored_ids.contains(id.as_str()) && !deleted.contains(id) {
+                    deleted_versions.insert(id.clone(), version);
+                    deleted_at.insert(id.clone(), timestamp);
+                    deleted.push(id.clone());
+                }
+            }
+
+            let ids = restored.iter().filter_map(|a| a.id.clone()).collect();
+            let new_doc = DocumentAnnotations {
+                version,
+                annotations: restored,
+                deleted,
+                deleted_versions,
+                deleted_at,
+                updated_at: timestamp,
+                next_cursor: None,
+                next_deleted_cursor: None,
+            };
+            let json = encode_annotations(&new_doc)?;
+            record.write(&write_txn, username, &json, version)?;
+            self.record_annotation_history(&write_txn, username, &key, &json, version)?;
+
+            let viewers = Self::annotation_viewers(&write_txn, username, &document)?;
+            Self::reindex_annotations(
+                &write_txn,
+                &viewers,
+                &current.annotations,
+                &new_doc.annotations,
+            )?;
+            (version, timestamp, ids)
+        };
+        write_txn.commit()?;
+        Ok(result)
+    }
+}
+
+impl Database {
+    /// Remove every annotation of a document as a new version, moving them
+    /// to the trash. With `keep_tombstones`, existing deletions are kept and
+    /// the removed annotations become deletions too, so syncing devices drop
+    /// them; otherwise all deletions are forgotten. Returns the new version
+    /// and its timestamp.
+    pub fn clear_annotations(
+        &self,
+        username: &str,
+        document: &str,
+        keep_tombstones: bool,
+    ) -> Result<(u64, i64)> {
+        let timestamp = now();
+
+        let write_txn = self.begin_write()?;
+        let document =
+            Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+        let record = Self::annotations_location(
+            &write_txn.open_table(SHARE_MEMBERS)?,
+            &write_txn.open_table(SHARE_GROUPS)?,
+            username,
+            &document,
+        )?;
+        let version = {
+            let current: DocumentAnnotations = record.read_in(&write_txn)?.unwrap_or_default();
+            if !current.annotations.is_empty() {
+                let trashed = DocumentAnnotations {
+                    version: current.version,
+                    annotations: current.annotations.clone(),
+                    updated_at: current.updated_at,
+                    ..Default::default()
+                };
+                self.trash_in(
+                    &write_txn,
+                    username,
+                    TrashKind::Annotations,
+                    &document,
+                    &trashed,
+                )?;
+            }
+
+            let version = current.version + 1;
+            let mut new_doc = DocumentAnnotations {
+                version,
+                updated_at: timestamp,
+                ..Default::default()
+            };
+            if keep_tombstones {
+                new_doc.deleted = current.deleted;
+                new_doc.deleted_versions = current.deleted_versions;
+                new_doc.deleted_at = current.deleted_at;
+                for id in current.annotations.iter().filter_map(|a| a.id.clone()) {
+                    if !new_doc.deleted.contains(&id) {
+                        new_doc.deleted_versions.insert(id.clone(), version);
+                        new_doc.deleted_at.insert(id.clone(), timestamp);
+                        new_doc.deleted.push(id);
+                    }
+                }
+            }
+
+            // Keep the record, so versions only ever move forward
+            let json = encode_annotations(&new_doc)?;
+            record.write(&write_txn, username, &json, version)?;
+            self.record_annotation_history(&write_txn, username, &record.key(), &json, version)?;
+
+            let viewers = Self::annotation_viewers(&write_txn, username, &document)?;
+            Self::reindex_annotations(&write_txn, &viewers, &current.annotations, &[])?;
+            version
+        };
+        write_txn.commit()?;
+        Ok((version, timestamp))
+    }
+}
+
+// === Change journal ===
+
+impl Database {
+    /// Append a change to `JOURNAL` under the next sequence number.
+    ///
+    /// Every method changing users' data calls this for each entry it
+    /// writes or removes, in every table but those in `UNJOURNALED_TABLES`
+    /// and `QUARANTINE`. Migrations aren't journaled either, as every
+    /// server applies them itself.
+    fn journal(
+        write_txn: &WriteTransaction,
+        username: &str,
+        table: &str,
+        key: &str,
+        change: Change,
+    ) -> Result<()> {
+        let mut meta = write_txn.open_table(META)?;
+        let sequence = meta.get(JOURNAL_SEQUENCE)?.map_or(0, |v| v.value()) + 1;
+        meta.insert(JOURNAL_SEQUENCE, sequence)?;
+        let entry = JournalEntry {
+            sequence,
+            username: username.to_string(),
+            table: table.to_string(),
+            key: key.to_string(),
+            version: match change {
+                Change::Put(version) => version,
+                Change::Removed | Change::RemovedPrefix => None,
+            },
+            timestamp: now(),
+            deleted: !matches!(change, Change::Put(_)),
+            prefix: matches!(change, Change::RemovedPrefix),
+        };
+        let mut journal = write_txn.open_table(JOURNAL)?;
+        journal.insert(sequence, serde_json::to_vec(&entry)?.as_slice())?;
+        Ok(())
+    }
+
+    /// The sequence number of the latest change, 0 before the first.
+    pub fn journal_sequence(&self) -> Result<u64> {
+        let read_txn = self.db.begin_read()?;
+        let meta = read_txn.open_table(META)?;
+        Ok(meta.get(JOURNAL_SEQUENCE)?.map_or(0, |v| v.value()))
+    }
+
+    /// Up to `limit` journal entries after sequence number `after`, oldest
+    /// first. Entries older than the journal's retention are gone, so the
+    /// first may be well past `after + 1`.
+    pub fn journal_since(&self, after: u64, limit: usize) -> Result<Vec<JournalEntry>> {
+        let read_txn = self.db.begin_read()?;
+        let journal = read_txn.open_table(JOURNAL)?;
+        let mut entries = Vec::new();
+        for entry in journal.range(after.saturating_add(1)..)?.take(limit) {
+            let (_, data) = entry?;
+            entries.push(serde_json::from_slice(data.value())?);
+        }
+        Ok(entries)
+    }
+
+    /// Delete journal entries recorded before `cutoff`. Returns how many
+    /// were deleted.
+    pub fn prune_journal(&self, cutoff: i64) -> Result<usize> {
+        let write_txn = self.begin_local_write()?;
+        let pruned = {
+            let mut journal = write_txn.open_table(JOURNAL)?;
+            // Entries are in commit order, so the old ones come first
+            let mut last = None;
+            let mut count = 0;
+            for entry in journal.iter()? {
+                let (sequence, data) = entry?;
+                let entry: JournalEntry = serde_json::from_slice(data.value())?;
+                if entry.timestamp >= cutoff {
+                    break;
+                }
+                last = Some(sequence.value());
+                count += 1;
+            }
+            if let Some(last) = last {
+                journal.retain_in(..=last, |_, _| false)?;
+            }
+            count
+        };
+        write_txn.commit()?;
+        Ok(pruned)
+    }
+}
+
+// === Following a leader ===
+
+impl Database {
+    /// Up to `limit` journal entries after sequence number `after`, each
+    /// with the value its key holds now, for a follower to apply.
+    pub fn journal_changes(&self, after: u64, limit: usize) -> Result<JournalResponse> {
+        let read_txn = self.db.begin_read()?;
+        let meta = read_txn.open_table(META)?;
+        let sequence = meta.get(JOURNAL_SEQUENCE)?.map_or(0, |v| v.value());
+        let journal = read_txn.open_table(JOURNAL)?;
+        let mut changes = Vec::new();
+        for entry in journal.range(after.saturating_add(1)..)?.take(limit) {
+            let entry: JournalEntry = serde_json::from_slice(entry?.1.value())?;
+            let value = if entry.deleted {
+                None
+            } else {
+                Self::journaled_value(&read_txn, &entry.table, &entry.key)?
+            };
+            changes.push(JournalChange {
+                entry,
+                value: value.map(|value| BASE64.encode(value)),
+            });
+        }
+        Ok(JournalResponse { sequence, changes })
+    }
+
+    fn journaled_value(
+        read_txn: &ReadTransaction,
+        table: &str,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(match JournaledTable::named(table)? {
+            JournaledTable::Bytes(definition) => read_txn
+                .open_table(definition)?
+                .get(key)?
+                .map(|data| data.value().to_vec()),
+            JournaledTable::Document(definition) => read_txn
+                .open_table(definition)?
+                .get(split_document_key(key)?)?
+                .map(|data| data.value().to_vec()),
+            JournaledTable::Text(definition) => read_txn
+                .open_table(definition)?
+                .get(key)?
+                .map(|data| data.value().as_bytes().to_vec()),
+        })
+    }
+
+    /// Apply changes from a leader's `journal_changes`, and add them to
+    /// this server's own journal under the leader's sequence numbers, so
+    /// that the follower can take over as leader. Returns the sequence
+    /// number reached.
+    ///
+    /// Changes must carry on from this server's latest sequence number; a
+    /// follower that fell behind the leader's journal retention has to
+    /// start again from a backup of the leader.
+    pub fn apply_journal(&self, changes: &[JournalChange]) -> Result<u64> {
+        let write_txn = self.begin_local_write()?;
+        let sequence = {
+            let mut sequence = write_txn
+                .open_table(META)?
+                .get(JOURNAL_SEQUENCE)?
+                .map_or(0, |v| v.value());
+            for change in changes {
+                let entry = &change.entry;
+                if entry.sequence != sequence + 1 {
+                    return Err(AppError::InvalidRequest(format!(
+                        "the leader's journal continues at {}, but this server is at {}",
+                        entry.sequence, sequence
+                    )));
+                }
+                if entry.prefix {
+                    Self::replicate_prefix_removal(&write_txn, &entry.table, &entry.key)?;
+                } else {
+                    let value = match &change.value {
+                        Some(value) => Some(BASE64.decode(value).map_err(|e| {
+                            AppError::InvalidRequest(format!(
+                                "bad value for {} in {}: {}",
+                                entry.key, entry.table, e
+                            ))
+                        })?),
+                        None => None,
+                    };
+                    Self::replicate(&write_txn, &entry.table, &entry.key, value.as_deref())?;
+                }
+                let mut journal = write_txn.open_table(JOURNAL)?;
+                journal.insert(entry.sequence, serde_json::to_vec(entry)?.as_slice())?;
+                sequence = entry.sequence;
+            }
+            write_txn
+                .open_table(META)?
+                .insert(JOURNAL_SEQUENCE, sequence)?;
+            sequence
+        };
+        write_txn.commit()?;
+        Ok(sequence)
+    }
+
+    /// Write `value` under `key`, or remove it, keeping the indexes the
+    /// journal leaves out in step.
+    fn replicate(
+        write_txn: &WriteTransaction,
+        table: &str,
+        key: &str,
+        value: Option<&[u8]>,
+    ) -> Result<()> {
+        match JournaledTable::named(table)? {
+            JournaledTable::Text(definition) => {
+                let mut records = write_txn.open_table(definition)?;
+                match value {
+                    Some(value) => {
+                        let value = std::str::from_utf8(value).map_err(|_| {
+                            AppError::InvalidRequest(format!("{} in {} isn't text", key, table))
+                        })?;
+                        records.insert(key, value)?;
+                    }
+                    None => {
+                        records.remove(key)?;
+                    }
+                }
+            }
+            JournaledTable::Document(definition) => {
+                let (username, document) = split_document_key(key)?;
+                let old = {
+                    let mut records = write_txn.open_table(definition)?;
+                    let old = match value {
+                        Some(value) => records.insert((username, document), value)?,
+                        None => records.remove((username, document))?,
+                    };
+                    old.map(|data| data.value().to_vec())
+                };
+                if definition.name() == PROGRESS.name() {
+                    let present = value.is_some();
+                    Self::index_document(
+                        write_txn,
+                        username,
+                        document,
+                        Indexed::Progress,
+                        present,
+                    )?;
+                } else {
+                    let present = value.is_some();
+                    Self::index_document(
+                        write_txn,
+                        username,
+                        document,
+                        Indexed::Annotations,
+                        present,
+                    )?;
+                    Self::reindex_annotations(
+                        write_txn,
+                        &[(username.to_string(), document.to_string())],
+                        &replicated_annotations(old.as_deref())?,
+                        &replicated_annotations(value)?,
+                    )?;
+                }
+            }
+            JournaledTable::Bytes(definition) => {
+                let old = {
+                    let mut records = write_txn.open_table(definition)?;
+                    let old = match value {
+                        Some(value) => records.insert(key, value)?,
+                        None => records.remove(key)?,
+                    };
+                    old.map(|data| data.value().to_vec())
+                };
+                let name = definition.name();
+                if name == DOCUMENT_TAGS.name() {
+                    let (username, document) = split_document_key(key)?;
+                    let tags = |data: Option<&[u8]>| -> Result<Vec<String>> {
+                        Ok(match data {
+                            Some(data) => serde_json::from_slice(data)?,
+                            None => Vec::new(),
+                        })
+                    };
+                    let mut index = write_txn.open_table(TAG_INDEX)?;
+                    for tag in tags(old.as_deref())? {
+                        index.remove(Self::tag_key(username, &tag, document).as_str())?;
+                    }
+                    for tag in tags(value)? {
+                        index.insert(Self::tag_key(username, &tag, document).as_str(), &[][..])?;
+                    }
+                } else if name == SHARED_ANNOTATIONS.name() {
+                    let groups = write_txn.open_table(SHARE_GROUPS)?;
+                    let group = groups.get(key)?.map(|data| data.value().to_vec());
+                    drop(groups);
+                    Self::reindex_annotations(
+                        write_txn,
+                        &joined_viewers(group.as_deref())?,
+                        &replicated_annotations(old.as_deref())?,
+                        &replicated_annotations(value)?,
+                    )?;
+                } else if name == SHARE_GROUPS.name() {
+                    // Members who joined or left gain or lose the shared
+                    // annotations
+                    let shared = write_txn.open_table(SHARED_ANNOTATIONS)?;
+                    let annotations = replicated_annotations(
+                        shared
+                            .get(key)?
+                            .map(|data| data.value().to_vec())
+                            .as_deref(),
+                    )?;
+                    drop(shared);
+                    let before = joined_viewers(old.as_deref())?;
+                    let after = joined_viewers(value)?;
+                    let left: Vec<_> = before
+                        .iter()
+                        .filter(|v| !after.contains(v))
+                        .cloned()
+                        .collect();
+                    let joined: Vec<_> = after
+                        .iter()
+                        .filter(|v| !before.contains(v))
+                        .cloned()
+                        .collect();
+                    Self::reindex_annotations(write_txn, &left, &annotations, &[])?;
+                    Self::reindex_annotations(write_txn, &joined, &[], &annotations)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove every entry whose key starts with `prefix`, as `replicate`
+    /// would one by one.
+    fn replicate_prefix_removal(
+        write_txn: &WriteTransaction,
+        table: &str,
+        prefix: &str,
+    ) -> Result<()> {
+        let keys = match JournaledTable::named(table)? {
+            JournaledTable::Bytes(definition) => {
+                keys_with_prefix(&write_txn.open_table(definition)?, prefix)?
+            }
+            JournaledTable::Text(definition) => {
+                keys_with_prefix(&write_txn.open_table(definition)?, prefix)?
+            }
+            JournaledTable::Document(definition) => {
+                let (username, document) = split_document_key(prefix)?;
+                let records = write_txn.open_table(definition)?;
+                let mut keys = Vec::new();
+                for entry in records.range((username, document)..)? {
+                    let (key, _) = entry?;
+                    let (user, document_key) = key.value();
+                    if user != username || !document_key.starts_with(document) {
+                        break;
+                    }
+                    keys.push(format!("{}:{}", user, document_key));
+                }
+                keys
+            }
+        };
+        for key in keys {
+            Self::replicate(write_txn, table, &key, None)?;
+        }
+        Ok(())
+    }
+}
+
+// === Trash ===
+
+impl Database {
+    /// Keep a deleted record in the user's trash, unless the trash is
+    /// disabled.
+    fn trash_in(
+        &self,
+        write_txn: &WriteTransaction,
+        username: &str,
+        kind: TrashKind,
+        document: &str,
+        record: &impl Serialize,
+    ) -> Result<()> {
+        if self.config.trash_retention.is_zero() {
+            return Ok(());
+        }
+        let item = TrashItem {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            kind,
+            document: document.to_string(),
+            deleted_at: now(),
+            record: serde_json::to_value(record)?,
+        };
+        let key = format!("{}:{}", username, item.id);
+        let mut table = write_txn.open_table(TRASH)?;
+        table.insert(key.as_str(), serde_json::to_vec(&item)?.as_slice())?;
+        Self::journal(write_txn, username, TRASH.name(), &key, Change::Put(None))
+    }
+
+    /// The user's trash, most recently deleted first.
+    pub fn list_trash(&self, username: &str) -> Result<Vec<TrashItem>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(TRASH)?;
+        let (start, end) = Self::user_key_range(username);
+        let mut items = Vec::new();
+        for entry in table.range(start.as_str()..end.as_str())? {
+            let (_, data) = entry?;
+            items.push(serde_json::from_slice::<TrashItem>(data.value())?);
+        }
+        items.sort_by_key(|item| std::cmp::Reverse(item.deleted_at));
+        Ok(items)
+    }
+
+    /// Put a trashed record back as a new version, so syncing devices pick
+    /// it up. Restored progress replaces any progress stored since; restored
+    /// annotations are added to those uploaded since, and their deletions
+    /// forgotten.
+    pub fn restore_trash(&self, username: &str, id: &str) -> Result<RestoreResponse> {
+        let timestamp = now();
+        let key = format!("{}:{}", username, id);
+
+        let write_txn = self.begin_write()?;
+        let restored = {
+            let item: TrashItem = {
+                let mut table = write_txn.open_table(TRASH)?;
+                let data = table.remove(key.as_str())?;
+                match data {
+                    Some(data) => serde_json::from_slice(data.value())?,
+                    None => return Err(AppError::TrashItemNotFound),
+                }
+            };
+            Self::journal(&write_txn, username, TRASH.name(), &key, Change::Removed)?;
+            let document = Self::canonical_document(
+                &write_txn.open_table(ALIASES)?,
+                username,
+                &item.document,
+            )?;
+            let version = match item.kind {
+                TrashKind::Progress => {
+                    let trashed: Progress = serde_json::from_value(item.record)?;
+                    let mut table = write_txn.open_table(PROGRESS)?;
+                    let stored: Option<Progress> = match table.get((username, document.as_str()))? {
+                        Some(data) => Some(serde_json::from_slice(data.value())?),
+                        None => None,
+                    };
+                    // Past both, as devices may still hold the trashed version
+                    let version = (stored.as_ref().and_then(|p| p.version).unwrap_or(0) + 1)
+                        .max(trashed.version.unwrap_or(0) + 1);
+                    let data = Progress {
+                        document: Some(document.clone()),
+                        timestamp: Some(timestamp),
+                        version: Some(version),
+                        ..trashed
+                    };
+                    table.insert(
+                        (username, document.as_str()),
+                        serde_json::to_vec(&data)?.as_slice(),
+                    )?;
+                    if stored.is_none() {
+                        Self::index_document(
+                            &write_txn,
+                            username,
+                            &document,
+                            Indexed::Progress,
+                            true,
+                        )?;
+                    }
+                    Self::journal(
+                        &write_txn,
+                        username,
+                        PROGRESS.name(),
+                        &Self::progress_key(username, &document),
+                        Change::Put(Some(version)),
+                    )?;
+                    version
+                }
+                TrashKind::Annotations => {
+                    let trashed: DocumentAnnotations = serde_json::from_value(item.record)?;
+                    let record = Self::annotations_location(
+                        &write_txn.open_table(SHARE_MEMBERS)?,
+                        &write_txn.open_table(SHARE_GROUPS)?,
+                        username,
+                        &document,
+                    )?;
+                    let mut new_doc: DocumentAnnotations =
+                        record.read_in(&write_txn)?.unwrap_or_default();
+                    let version = new_doc.version + 1;
+                    let present: HashSet<String> = new_doc
+                        .annotations
+                        .iter()
+                        .filter_map(|a| a.id.clone())
+                        .collect();
+                    let mut restored = Vec::new();
+                    for mut annotation in trashed.annotations {
+                        if annotation
+                            .id
+                            .as_ref()
+                            .is_some_and(|id| present.contains(id))
+                        {
+                            continue;
+                        }
+                        annotation.version = Some(version);
+                        restored.push(annotation);
+                    }
+                    for id in restored.iter().filter_map(|a| a.id.as_ref()) {
+                        new_doc.deleted.retain(|deleted| deleted != id);
+                        new_doc.deleted_versions.remove(id);
+                        new_doc.deleted_at.remove(id);
+                    }
+                    new_doc.annotations.extend(restored.iter().cloned());
+                    new_doc.version = version;
+                    new_doc.updated_at = timestamp;
+
+                    let json = encode_annotations(&new_doc)?;
+                    record.write(&write_txn, username, &json, version)?;
+                    self.record_annotation_history(
+                        &write_txn,
+                        username,
+                        &record.key(),
+                        &json,
+                        version,
+                    )?;
+                    let viewers = Self::annotation_viewers(&write_txn, username, &document)?;
+                    Self::reindex_annotations(&write_txn, &viewers, &[], &restored)?;
+                    version
+                }
+            };
+            RestoreResponse {
+                id: item.id,
+                kind: item.kind,
+                document,
+                version,
+                timestamp,
+            }
+        };
+        write_txn.commit()?;
+        Ok(restored)
+    }
+
+    /// Permanently delete trash items deleted before `cutoff`. Returns how
+    /// many were deleted.
+    pub fn empty_trash(&self, cutoff: i64) -> Result<usize> {
+        let write_txn = self.begin_write()?;
+        let mut emptied = Vec::new();
+        {
+            let mut table = write_txn.open_table(TRASH)?;
+            table.retain(|key, data| {
+                // Unreadable items are left for fsck
+                let keep = serde_json::from_slice::<TrashItem>(data)
+                    .map_or(true, |item| item.deleted_at >= cutoff);
+                if !keep {
+                    emptied.push(key.to_string());
+                }
+                keep
+            })?;
+        }
+        for key in &emptied {
+            let username = key.split(':').next().unwrap_or_default();
+            Self::journal(&write_txn, username, TRASH.name(), key, Change::Removed)?;
+        }
+        write_txn.commit()?;
+        Ok(emptied.len())
+    }
+}
+
+// === Public pages ===
+
+impl Database {
+    /// The document's public page, created on first use.
+    pub fn create_public_share(&self, username: &str, document: &str) -> Result<PublicShare> {
+        let write_txn = self.begin_write()?;
+        let share = {
+            let document =
+                Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+            let key = Self::annotations_key(username, &document);
+            let mut tokens = write_txn.open_table(PUBLIC_SHARE_TOKENS)?;
+            let mut shares = write_txn.open_table(PUBLIC_SHARES)?;
+
+            let token = tokens
+                .get(key.as_str())?
+                .map(|token| String::from_utf8_lossy(token.value()).into_owned());
+            let existing = match token {
+                Some(token) => match shares.get(token.as_str())? {
+                    Some(data) => Some(serde_json::from_slice::<PublicShare>(data.value())?),
+                    None => None,
+                },
+                None => None,
+            };
+            match existing {
+                Some(share) => share,
+                None => {
+                    let share = PublicShare {
+                        token: uuid::Uuid::new_v4().simple().to_string(),
+                        username: username.to_string(),
+                        document,
+                        created_at: now(),
+                    };
+                    shares.insert(share.token.as_str(), serde_json::to_vec(&share)?.as_slice())?;
+                    tokens.insert(key.as_str(), share.token.as_bytes())?;
+                    Self::journal(
+                        &write_txn,
+                        username,
+                        PUBLIC_SHARES.name(),
+                        &share.token,
+                        Change::Put(None),
+                    )?;
+                    Self::journal(
+                        &write_txn,
+                        username,
+                        PUBLIC_SHARE_TOKENS.name(),
+                        &key,
+                        Change::Put(None),
+                    )?;
+                    share
+                }
+            }
+        };
+        write_txn.commit()?;
+        Ok(share)
+    }
+
+    /// Take a document's public page down. Returns whether it had one.
+    pub fn delete_public_share(&self, username: &str, document: &str) -> Result<bool> {
+        let write_txn = self.begin_write()?;
+        let removed = {
+            let document =
+                Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+            let key = Self::annotations_key(username, &document);
+            let token = write_txn
+                .open_table(PUBLIC_SHARE_TOKENS)?
+                .remove(key.as_str())?
+                .map(|token| String::from_utf8_lossy(token.value()).into_owned());
+            if let Some(token) = &token {
+                write_txn
+                    .open_table(PUBLIC_SHARES)?
+                    .remove(token.as_str())?;
+                Self::journal(
+                    &write_txn,
+                    username,
+                    PUBLIC_SHARE_TOKENS.name(),
+                    &key,
+                    Change::Removed,
+                )?;
+                Self::journal(
+                    &write_txn,
+                    username,
+                    PUBLIC_SHARES.name(),
+                    token,
+                    Change::Removed,
+                )?;
+            }
+            token.is_some()
+        };
+        write_txn.commit()?;
+        Ok(removed)
+    }
+
+    /// The page a token stands for, if it is still published.
+    pub fn public_share(&self, token: &str) -> Result<Option<PublicShare>> {
+        let read_txn = self.db.begin_read()?;
+        let share: PublicShare = match read_txn.open_table(PUBLIC_SHARES)?.get(token)? {
+            Some(data) => serde_json::from_slice(data.value())?,
+            None => return Ok(None),
+        };
+        // Deleting the user's data takes the page down with it
+        let key = Self::annotations_key(&share.username, &share.document);
+        let published = read_txn
+            .open_table(PUBLIC_SHARE_TOKENS)?
+            .get(key.as_str())?
+            .is_some_and(|t| t.value() == token.as_bytes());
+        Ok(published.then_some(share))
+    }
+
+    /// The token of a user's highlights feed, created on first use. With
+    /// `rotate`, any existing token stops working and a new one is made.
+    pub fn feed_token(&self, username: &str, rotate: bool) -> Result<String> {
+        let write_txn = self.begin_write()?;
+        let token = {
+            let mut tokens = write_txn.open_table(FEED_TOKENS)?;
+            let mut feeds = write_txn.open_table(FEEDS)?;
+            let existing = tokens.get(username)?.map(|t| t.value().to_string());
+            match existing {
+                Some(token) if !rotate => token,
+                existing => {
+                    if let Some(old) = existing {
+                        feeds.remove(old.as_str())?;
+                        Self::journal(&write_txn, username, FEEDS.name(), &old, Change::Removed)?;
+                    }
+                    let token = uuid::Uuid::new_v4().simple().to_string();
+                    feeds.insert(token.as_str(), username)?;
+                    tokens.insert(username, token.as_str())?;
+                    Self::journal(
+                        &write_txn,
+                        username,
+                        FEEDS.name(),
+                        &token,
+                        Change::Put(None),
+                    )?;
+                    Self::journal(
+                        &write_txn,
+                        username,
+                        FEED_TOKENS.name(),
+                        username,
+                        Change::Put(None),
+                    )?;
+                    token
+                }
+            }
+        };
+        write_txn.commit()?;
+        Ok(token)
+    }
+
+    /// Turn a user's feed off. Returns whether there was one.
+    pub fn delete_feed_token(&self, username: &str) -> Result<bool> {
+        let write_txn = self.begin_write()?;
+        let removed = {
+            let token = write_txn
+                .open_table(FEED_TOKENS)?
+                .remove(username)?
+                .map(|t| t.value().to_string());
+            if let Some(token) = &token {
+                write_txn.open_table(FEEDS)?.remove(token.as_str())?;
+                Self::journal(
+                    &write_txn,
+                    username,
+                    FEED_TOKENS.name(),
+                    username,
+                    Change::Removed,
+                )?;
+                Self::journal(&write_txn, username, FEEDS.name(), token, Change::Removed)?;
+            }
+            token.is_some()
+        };
+        write_txn.commit()?;
+        Ok(removed)
+    }
+
+    /// The user whose feed a token opens.
+    pub fn feed_user(&self, token: &str) -> Result<Option<String>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(FEEDS)?;
+        Ok(table.get(token)?.map(|u| u.value().to_string()))
+    }
+}
+
+// === Attachments ===
+
+impl Database {
+    fn attachment_key(username: &str, document: &str, id: &str) -> String {
+        format!("{}:{}:{}", username, document, id)
+    }
+
+    fn blob_key(username: &str, sha256: &str) -> String {
+        format!("{}:{}", username, sha256)
+    }
+
+    /// Store an attachment, replacing any previous content under `id`.
+    pub fn put_attachment(
+        &self,
+        username: &str,
+        document: &str,
+        id: &str,
+        content_type: &str,
+        data: &[u8],
+    ) -> Result<Attachment> {
+        let attachment = Attachment {
+            id: id.to_string(),
+            sha256: Sha256::digest(data)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            content_type: content_type.to_string(),
+            size: data.len(),
+            created_at: now(),
+        };
+
+        let write_txn = self.begin_write()?;
+        {
+            let document =
+                Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+            let mut blobs = write_txn.open_table(ATTACHMENT_BLOBS)?;
+            let blob_key = Self::blob_key(username, &attachment.sha256);
+            if blobs.get(blob_key.as_str())?.is_none() {
+                blobs.insert(blob_key.as_str(), data)?;
+                Self::journal(
+                    &write_txn,
+                    username,
+                    ATTACHMENT_BLOBS.name(),
+                    &blob_key,
+                    Change::Put(None),
+                )?;
+            }
+
+            let mut table = write_txn.open_table(ATTACHMENTS)?;
+            let key = Self::attachment_key(username, &document, id);
+            let previous =
+                match table.insert(key.as_str(), serde_json::to_vec(&attachment)?.as_slice())? {
+                    Some(data) => Some(serde_json::from_slice::<Attachment>(data.value())?),
+                    None => None,
+                };
+            Self::journal(
+                &write_txn,
+                username,
+                ATTACHMENTS.name(),
+                &key,
+                Change::Put(None),
+            )?;
+            if let Some(previous) = previous.filter(|p| p.sha256 != attachment.sha256) {
+                Self::release_blob(&write_txn, &table, &mut blobs, username, &previous.sha256)?;
+            }
+        }
+        write_txn.commit()?;
+        Ok(attachment)
+    }
+
+    /// Drop a blob once no attachment of the user refers to it.
+    fn release_blob(
+        write_txn: &WriteTransaction,
+        attachments: &impl ReadableTable<&'static str, &'static [u8]>,
+        blobs: &mut Table<&str, &[u8]>,
+        username: &str,
+        sha256: &str,
+    ) -> Result<()> {
+        let (start, end) = Self::user_key_range(username);
+        for entry in attachments.range(start.as_str()..end.as_str())? {
+            let (_, data) = entry?;
+            let attachment: Attachment = serde_json::from_slice(data.value())?;
+            if attachment.sha256 == sha256 {
+                return Ok(());
+            }
+        }
+        let key = Self::blob_key(username, sha256);
+        blobs.remove(key.as_str())?;
+        Self::journal(
+            write_txn,
+            username,
+            ATTACHMENT_BLOBS.name(),
+            &key,
+            Change::Removed,
+        )
+    }
+
+    pub fn get_attachment(
+        &self,
+        username: &str,
+        document: &str,
+        id: &str,
+    ) -> Result<Option<(Attachment, Vec<u8>)>> {
+        let read_txn = self.db.begin_read()?;
+        let document =
+            Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+        let key = Self::attachment_key(username, &document, id);
+        let attachment: Attachment = match read_txn.open_table(ATTACHMENTS)?.get(key.as_str())? {
+            Some(data) => serde_json::from_slice(data.value())?,
+            None => return Ok(None),
+        };
+        let blob_key = Self::blob_key(username, &attachment.sha256);
+        let data = match read_txn
+            .open_table(ATTACHMENT_BLOBS)?
+            .get(blob_key.as_str())?
+        {
+            Some(data) => data.value().to_vec(),
+            None => return Ok(None),
+        };
+        Ok(Some((attachment, data)))
+    }
+
+    pub fn list_attachments(&self, username: &str, document: &str) -> Result<Vec<Attachment>> {
+        let read_txn = self.db.begin_read()?;
+        let document =
+            Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+        let prefix = Self::attachment_key(username, &document, "");
+        let end = format!("{};", prefix.trim_end_matches(':'));
+        let table = read_txn.open_table(ATTACHMENTS)?;
+
+        let mut attachments = Vec::new();
+        for entry in table.range(prefix.as_str()..end.as_str())? {
+            let (_, data) = entry?;
+            attachments.push(serde_json::from_slice(data.value())?);
+        }
+        Ok(attachments)
+    }
+
+    /// Remove an attachment. Returns whether it existed.
+    pub fn delete_attachment(&self, username: &str, document: &str, id: &str) -> Result<bool> {
+        let write_txn = self.begin_write()?;
+        let removed = {
+            let document =
+                Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+            let key = Self::attachment_key(username, &document, id);
+            let mut table = write_txn.open_table(ATTACHMENTS)?;
+            let removed = match table.remove(key.as_str())? {
+                Some(data) => Some(serde_json::from_slice::<Attachment>(data.value())?),
+                None => None,
+            };
+            if let Some(attachment) = &removed {
+                Self::journal(
+                    &write_txn,
+                    username,
+                    ATTACHMENTS.name(),
+                    &key,
+                    Change::Removed,
+                )?;
+                let mut blobs = write_txn.open_table(ATTACHMENT_BLOBS)?;
+                Self::release_blob(&write_txn, &table, &mut blobs, username, &attachment.sha256)?;
+            }
+            removed.is_some()
+        };
+        write_txn.commit()?;
+        Ok(removed)
+    }
+}
+
+// === Tombstone pruning ===
+
+impl Database {
+    /// Remember that a device has fetched a document's annotations up to
+    /// `version`. Devices poll, so an unchanged mark costs no commit, and a
+    /// changed one leaves the read caches alone: only pruning reads marks.
+    pub fn record_annotation_sync(
+        &self,
+        username: &str,
+        document: &str,
+        device_id: &str,
+        version: u64,
+    ) -> Result<()> {
+        let key = {
+            let read_txn = self.db.begin_read()?;
+            let document =
+                Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+            let key = format!(
+                "{}:{}",
+                Self::annotations_key(username, &document),
+                device_id
+            );
+            let synced = read_txn.open_table(ANNOTATION_SYNCS)?.get(key.as_str())?;
+            if synced.is_some_and(|v| v.value() == version.to_be_bytes().as_slice()) {
+                return Ok(());
+            }
+            key
+        };
+
+        let write_txn = self.begin_write()?;
+        write_txn
+            .open_table(ANNOTATION_SYNCS)?
+            .insert(key.as_str(), version.to_be_bytes().as_slice())?;
+        write_txn.commit_uncached()
+    }
+
+    /// Lowest version fetched by any device of the viewers, or `None` if no
+    /// device has reported fetching the document.
+    fn synced_version(
+        syncs: &impl ReadableTable<&'static str, &'static [u8]>,
+        viewers: &[(String, String)],
+    ) -> Result<Option<u64>> {
+        let mut lowest: Option<u64> = None;
+        for (username, document) in viewers {
+            let key = Self::annotations_key(username, document);
+            let (start, end) = (format!("{}:", key), format!("{};", key));
+            for entry in syncs.range(start.as_str()..end.as_str())? {
+                let (_, data) = entry?;
+                let version = data.value().try_into().map(u64::from_be_bytes).unwrap_or(0);
+                lowest = Some(lowest.map_or(version, |v| v.min(version)));
+            }
+        }
+        Ok(lowest)
+    }
+
+    /// Drop tombstones recorded before `cutoff` that every device syncing
+    /// the document has already fetched. Returns how many were dropped.
+    pub fn prune_tombstones(&self, cutoff: i64) -> Result<usize> {
+        let write_txn = self.begin_write()?;
+        let mut pruned = 0;
+        {
+            let syncs = write_txn.open_table(ANNOTATION_SYNCS)?;
+            let groups = write_txn.open_table(SHARE_GROUPS)?;
+
+            let mut table = write_txn.open_table(ANNOTATIONS)?;
+            let mut updates = Vec::new();
+            for entry in table.iter()? {
+                let (key, data) = entry?;
+                let (username, document) = key.value();
+                let viewers = [(username.to_string(), document.to_string())];
+                if let Some(record) =
+                    Self::prune_record(&syncs, &viewers, data.value(), cutoff, &mut pruned)?
+                {
+                    let [viewer] = viewers;
+                    updates.push((viewer, record));
+                }
+            }
+            for ((username, document), (json, version)) in updates {
+                table.insert((username.as_str(), document.as_str()), json.as_slice())?;
+                Self::journal(
+                    &write_txn,
+                    &username,
+                    ANNOTATIONS.name(),
+                    &Self::annotations_key(&username, &document),
+                    Change::Put(Some(version)),
+                )?;
+            }
+
+            let mut table = write_txn.open_table(SHARED_ANNOTATIONS)?;
+            let mut updates = Vec::new();
+            for entry in table.iter()? {
+                let (key, data) = entry?;
+                let (owner, viewers): (String, Vec<(String, String)>) =
+                    match groups.get(key.value())? {
+                        Some(group) => {
+                            let group: ShareGroup = serde_json::from_slice(group.value())?;
+                            let viewers = group
+                                .members
+                                .into_iter()
+                                .filter(|m| m.joined)
+                                .map(|m| (m.username, m.document))
+                                .collect();
+                            (group.owner, viewers)
+                        }
+                        None => (String::new(), Vec::new()),
+                    };
+                if let Some(record) =
+                    Self::prune_record(&syncs, &viewers, data.value(), cutoff, &mut pruned)?
+                {
+                    updates.push((key.value().to_string(), owner, record));
+                }
+            }
+            for (key, owner, (json, version)) in updates {
+                table.insert(key.as_str(), json.as_slice())?;
+                Self::journal(
+                    &write_txn,
+                    &owner,
+                    SHARED_ANNOTATIONS.name(),
+                    &key,
+                    Change::Put(Some(version)),
+                )?;
+            }
+        }
+        write_txn.commit()?;
+        Ok(pruned)
+    }
+
+    /// A stored record with its prunable tombstones dropped, and its
+    /// version, or `None` if it has none. Adds the number dropped to
+    /// `pruned`.
+    fn prune_record(
+        syncs: &impl ReadableTable<&'static str, &'static [u8]>,
+        viewers: &[(String, String)],
+        data: &[u8],
+        cutoff: i64,
+        pruned: &mut usize,
+    ) -> Result<Option<(Vec<u8>, u64)>> {
+        let mut doc: DocumentAnnotations = decode_annotations(data)?;
+        if doc.deleted.is_empty() {
+            return Ok(None);
+        }
+        let version = Self::synced_version(syncs, viewers)?.unwrap_or(doc.version);
+        let count = doc.prune_tombstones(cutoff, version);
+        if count == 0 {
+            return Ok(None);
+        }
+        *pruned += count;
+        Ok(Some((encode_annotations(&doc)?, doc.version)))
+    }
+}
+
+// === Annotation search ===
+
+impl Database {
+    fn index_key(username: &str, term: &str, document: &str, id: &str) -> String {
+        format!("{}:{}:{}:{}", username, term, document, id)
+    }
+
+    /// `(user, document)` pairs that see the annotations `username` has
+    /// for `document`: just them, or every joined member of their share
+    /// group.
+    fn annotation_viewers(
+        write_txn: &WriteTransaction,
+        username: &str,
+        document: &str,
+    ) -> Result<Vec<(String, String)>> {
+        let key = Self::annotations_key(username, document);
+        let group = Self::share_group(
+            &write_txn.open_table(SHARE_MEMBERS)?,
+            &write_txn.open_table(SHARE_GROUPS)?,
+            &key,
+        )?;
+        Ok(match group {
+            Some(group) if group.member(username).is_some_and(|m| m.joined) => group
+                .members
+                .iter()
+                .filter(|m| m.joined)
+                .map(|m| (m.username.clone(), m.document.clone()))
+                .collect(),
+            _ => vec![(username.to_string(), document.to_string())],
+        })
+    }
+
+    /// Replace the index entries for `old` with entries for `new`, for
+    /// each viewer.
+    fn reindex_annotations(
+        write_txn: &WriteTransaction,
+        viewers: &[(String, String)],
+        old: &[crate::models::Annotation],
+        new: &[crate::models::Annotation],
+    ) -> Result<()> {
+        let mut index = write_txn.open_table(ANNOTATION_INDEX)?;
+        for (username, document) in viewers {
+            for anno in old {
+                let Some(id) = &anno.id else { continue };
+                for term in search::annotation_terms(anno) {
+                    index.remove(Self::index_key(username, &term, document, id).as_str())?;
+                }
+            }
+            for anno in new {
+                let Some(id) = &anno.id else { continue };
+                for term in search::annotation_terms(anno) {
+                    let key = Self::index_key(username, &term, document, id);
+                    index.insert(key.as_str(), &[][..])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Index every stored annotation if the index is empty, as it is after
+    /// upgrading from a version without search. Annotations stored before
+    /// ids were assigned are indexed on their next write.
+    fn build_annotation_index(write_txn: &WriteTransaction) -> Result<()> {
+        if !write_txn.open_table(ANNOTATION_INDEX)?.is_empty()? {
+            return Ok(());
+        }
+
+        let mut sets: Vec<(Vec<(String, String)>, DocumentAnnotations)> = Vec::new();
+        for entry in write_txn.open_table(ANNOTATIONS)?.iter()? {
+            let (key, data) = entry?;
+            let (username, document) = key.value();
+            let viewer = (username.to_string(), document.to_string());
+            sets.push((vec![viewer], decode_annotations(data.value())?));
+        }
+        let shared = write_txn.open_table(SHARED_ANNOTATIONS)?;
+        for entry in write_txn.open_table(SHARE_GROUPS)?.iter()? {
+            let group: ShareGroup = serde_json::from_slice(entry?.1.value())?;
+            if let Some(data) = shared.get(group.id.as_str())? {
+                let viewers = group
+                    .members
+                    .iter()
+                    .filter(|m| m.joined)
+                    .map(|m| (m.username.clone(), m.document.clone()))
+                    .collect();
+                sets.push((viewers, decode_annotations(data.value())?));
+            }
+        }
+
+        for (viewers, annotations) in sets {
+            Self::reindex_annotations(write_txn, &viewers, &[], &annotations.annotations)?;
+        }
+        Ok(())
+    }
+
+    /// Ids of annotations matching every query term, by document. With a
+    /// `document`, only that document is searched.
+    pub fn search_annotations(
+        &self,
+        username: &str,
+        document: Option<&str>,
+        terms: &[String],
+    ) -> Result<BTreeMap<String, HashSet<String>>> {
+        let read_txn = self.db.begin_read()?;
+        let document = match document {
+            Some(document) => Some(Self::canonical_document(
+                &read_txn.open_table(ALIASES)?,
+                username,
+                document,
+            )?),
+            None => None,
+        };
+        let index = read_txn.open_table(ANNOTATION_INDEX)?;
+
+        let mut matches: Option<HashSet<(String, String)>> = None;
+        for term in terms {
+            let start = format!("{}:{}", username, term);
+            let end = format!("{}{}", start, char::MAX);
+            let mut found = HashSet::new();
+            for entry in index.range(start.as_str()..end.as_str())? {
+                let (key, _) = entry?;
+                let mut parts = key.value()[username.len() + 1..].splitn(3, ':');
+                let (Some(_), Some(doc), Some(id)) = (parts.next(), parts.next(), parts.next())
+                else {
+                    continue;
+                };
+                if document.as_deref().is_none_or(|d| d == doc) {
+                    found.insert((doc.to_string(), id.to_string()));
+                }
+            }
+            matches = Some(match matches {
+                Some(previous) => previous.intersection(&found).cloned().collect(),
+                None => found,
+            });
+        }
+
+        let mut result: BTreeMap<String, HashSet<String>> = BTreeMap::new();
+        for (doc, id) in matches.unwrap_or_default() {
+            result.entry(doc).or_default().insert(id);
+        }
+        Ok(result)
+    }
+}
+
+// === Shared documents ===
+
+impl Database {
+    /// The group a `user:document` membership key points at.
+    fn share_group(
+        members: &impl ReadableTable<&'static str, &'static str>,
+        groups: &impl ReadableTable<&'static str, &'static [u8]>,
+        member_key: &str,
+    ) -> Result<Option<ShareGroup>> {
+        let Some(id) = members.get(member_key)? else {
+            return Ok(None);
+        };
+        match groups.get(id.value())? {
+            Some(data) => Ok(Some(serde_json::from_slice(data.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_share_group(
+        write_txn: &WriteTransaction,
+        username: &str,
+        group: &ShareGroup,
+    ) -> Result<()> {
+        let mut groups = write_txn.open_table(SHARE_GROUPS)?;
+        groups.insert(group.id.as_str(), serde_json::to_vec(group)?.as_slice())?;
+        Self::journal(
+            write_txn,
+            username,
+            SHARE_GROUPS.name(),
+            &group.id,
+            Change::Put(None),
+        )
+    }
+
+    fn take_annotations(
+        table: &mut Table<&str, &[u8]>,
+        key: &str,
+    ) -> Result<Option<DocumentAnnotations>> {
+        match table.remove(key)? {
+            Some(data) => Ok(Some(decode_annotations(data.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Share annotations for `document` with other users. The owner joins
+    /// immediately and their annotations become the group's; everyone else
+    /// is only invited.
+    pub fn create_share(
+        &self,
+        owner: &str,
+        document: &str,
+        invitees: &[String],
+    ) -> Result<ShareGroup> {
+        let write_txn = self.begin_write()?;
+        let group = {
+            let users = write_txn.open_table(USERS)?;
+            let aliases = write_txn.open_table(ALIASES)?;
+            let mut members = write_txn.open_table(SHARE_MEMBERS)?;
+
+            let mut group = ShareGroup {
+                id: uuid::Uuid::new_v4().simple().to_string(),
+                owner: owner.to_string(),
+                members: Vec::new(),
+                created_at: now(),
+            };
+            for username in std::iter::once(owner).chain(invitees.iter().map(String::as_str)) {
+                if group.member(username).is_some() {
+                    continue;
+                }
+                if users.get(username)?.is_none() {
+                    return Err(AppError::InvalidRequest(format!(
+                        "unknown user: {}",
+                        username
+                    )));
+                }
+                let member_document = Self::canonical_document(&aliases, username, document)?;
+                let key = Self::annotations_key(username, &member_document);
+                if members.get(key.as_str())?.is_some() {
+                    return Err(AppError::InvalidRequest(format!(
+                        "{} already shares this document",
+                        username
+                    )));
+                }
+                members.insert(key.as_str(), group.id.as_str())?;
+                Self::journal(
+                    &write_txn,
+                    owner,
+                    SHARE_MEMBERS.name(),
+                    &key,
+                    Change::Put(None),
+                )?;
+                group.members.push(ShareMember {
+                    username: username.to_string(),
+                    document: member_document,
+                    joined: username == owner,
+                });
+            }
+
+            let mut annotations = write_txn.open_table(ANNOTATIONS)?;
+            let owner_document = group.members[0].document.as_str();
+            if let Some(data) = annotations.remove((owner, owner_document))? {
+                Self::index_document(
+                    &write_txn,
+                    owner,
+                    owner_document,
+                    Indexed::Annotations,
+                    false,
+                )?;
+                let existing: DocumentAnnotations = decode_annotations(data.value())?;
+                let mut shared = write_txn.open_table(SHARED_ANNOTATIONS)?;
+                shared.insert(group.id.as_str(), encode_annotations(&existing)?.as_slice())?;
+                Self::journal(
+                    &write_txn,
+                    owner,
+                    ANNOTATIONS.name(),
+                    &Self::annotations_key(owner, owner_document),
+                    Change::Removed,
+                )?;
+                Self::journal(
+                    &write_txn,
+                    owner,
+                    SHARED_ANNOTATIONS.name(),
+                    &group.id,
+                    Change::Put(Some(existing.version)),
+                )?;
+            }
+            group
+        };
+        Self::save_share_group(&write_txn, owner, &group)?;
+        write_txn.commit()?;
+        Ok(group)
+    }
+
+    /// Accept an invitation, merging the user's own annotations into the
+    /// group's.
+    pub fn join_share(&self, username: &str, id: &str) -> Result<ShareGroup> {
+        let write_txn = self.begin_write()?;
+        let group = {
+            let mut group: ShareGroup = match write_txn.open_table(SHARE_GROUPS)?.get(id)? {
+                Some(data) => serde_json::from_slice(data.value())?,
+                None => return Err(AppError::Forbidden),
+            };
+            let Some(member) = group.members.iter_mut().find(|m| m.username == username) else {
+                return Err(AppError::Forbidden);
+            };
+            if !member.joined {
+                member.joined = true;
+                let document = member.document.clone();
+                let own = {
+                    let mut annotations = write_txn.open_table(ANNOTATIONS)?;
+                    let data = annotations.remove((username, document.as_str()))?;
+                    if data.is_some() {
+                        Self::index_document(
+                            &write_txn,
+                            username,
+                            &document,
+                            Indexed::Annotations,
+                            false,
+                        )?;
+                        Self::journal(
+                            &write_txn,
+                            username,
+                            ANNOTATIONS.name(),
+                            &Self::annotations_key(username, &document),
+                            Change::Removed,
+                        )?;
+                    }
+                    data.map(|data| decode_annotations::<DocumentAnnotations>(data.value()))
+                        .transpose()?
+                };
+                let mut shared = write_txn.open_table(SHARED_ANNOTATIONS)?;
+                let current = Self::take_annotations(&mut shared, id)?;
+
+                let own_annotations = own.as_ref().map(|a| a.annotations.clone());
+                let current_annotations = current.as_ref().map(|a| a.annotations.clone());
+                let merged = match (current, own) {
+                    (Some(current), Some(own)) => Some(merge_documents(current, own)?),
+                    (current, None) => current,
+                    (None, own) => own,
+                };
+                if let Some(merged) = &merged {
+                    shared.insert(id, encode_annotations(merged)?.as_slice())?;
+                    Self::journal(
+                        &write_txn,
+                        username,
+                        SHARED_ANNOTATIONS.name(),
+                        id,
+                        Change::Put(Some(merged.version)),
+                    )?;
+                }
+
+                // Everyone's view is now the merged set
+                let merged = merged.map(|m| m.annotations).unwrap_or_default();
+                let others: Vec<(String, String)> = group
+                    .members
+                    .iter()
+                    .filter(|m| m.joined && m.username != username)
+                    .map(|m| (m.username.clone(), m.document.clone()))
+                    .collect();
+                Self::reindex_annotations(
+                    &write_txn,
+                    &others,
+                    &current_annotations.unwrap_or_default(),
+                    &merged,
+                )?;
+                Self::reindex_annotations(
+                    &write_txn,
+                    &[(username.to_string(), document)],
+                    &own_annotations.unwrap_or_default(),
+                    &merged,
+                )?;
+            }
+            group
+        };
+        Self::save_share_group(&write_txn, username, &group)?;
+        write_txn.commit()?;
+        Ok(group)
+    }
+
+    /// Leave a share group, or dissolve it if `username` owns it. Members
+    /// who had joined keep a copy of the shared annotations.
+    pub fn leave_share(&self, username: &str, id: &str) -> Result<()> {
+        let write_txn = self.begin_write()?;
+        Self::leave_share_in(&write_txn, username, id)?;
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    fn leave_share_in(write_txn: &WriteTransaction, username: &str, id: &str) -> Result<()> {
+        let mut group: ShareGroup = match write_txn.open_table(SHARE_GROUPS)?.get(id)? {
+            Some(data) => serde_json::from_slice(data.value())?,
+            None => return Err(AppError::Forbidden),
+        };
+        if group.member(username).is_none() {
+            return Err(AppError::Forbidden);
+        }
+
+        let leaving: Vec<ShareMember> = if group.owner == username {
+            std::mem::take(&mut group.members)
+        } else {
+            group
+                .members
+                .extract_if(.., |m| m.username == username)
+                .collect()
+        };
+
+        let mut members = write_txn.open_table(SHARE_MEMBERS)?;
+        let mut annotations = write_txn.open_table(ANNOTATIONS)?;
+        let mut shared = write_txn.open_table(SHARED_ANNOTATIONS)?;
+        let copy = shared.get(id)?.map(|data| data.value().to_vec());
+        let version = match &copy {
+            Some(copy) => Some(decode_annotations::<AnnotationsStamp>(copy)?.version),
+            None => None,
+        };
+        for member in leaving {
+            let key = Self::annotations_key(&member.username, &member.document);
+            members.remove(key.as_str())?;
+            Self::journal(
+                write_txn,
+                username,
+                SHARE_MEMBERS.name(),
+                &key,
+                Change::Removed,
+            )?;
+            if let (true, Some(copy)) = (member.joined, &copy) {
+                annotations.insert(
+                    (member.username.as_str(), member.document.as_str()),
+                    copy.as_slice(),
+                )?;
+                Self::index_document(
+                    write_txn,
+                    &member.username,
+                    &member.document,
+                    Indexed::Annotations,
+                    true,
+                )?;
+                Self::journal(
+                    write_txn,
+                    username,
+                    ANNOTATIONS.name(),
+                    &key,
+                    Change::Put(version),
+                )?;
+            }
+        }
+
+        if group.members.is_empty() {
+            if shared.remove(id)?.is_some() {
+                Self::journal(
+                    write_txn,
+                    username,
+                    SHARED_ANNOTATIONS.name(),
+                    id,
+                    Change::Removed,
+                )?;
+            }
+            write_txn.open_table(SHARE_GROUPS)?.remove(id)?;
+            Self::journal(
+                write_txn,
+                username,
+                SHARE_GROUPS.name(),
+                id,
+                Change::Removed,
+            )?;
+        } else {
+            Self::save_share_group(write_txn, username, &group)?;
+        }
+        Ok(())
+    }
+
+    /// Share groups the user owns, belongs to or is invited to.
+    pub fn list_shares(&self, username: &str) -> Result<Vec<ShareGroup>> {
+        let (start, end) = Self::user_key_range(username);
+        let read_txn = self.db.begin_read()?;
+        let members = read_txn.open_table(SHARE_MEMBERS)?;
+        let groups = read_txn.open_table(SHARE_GROUPS)?;
+
+        let mut shares = Vec::new();
+        for entry in members.range(start.as_str()..end.as_str())? {
+            let (key, _) = entry?;
+            if let Some(group) = Self::share_group(&members, &groups, key.value())? {
+                shares.push(group);
+            }
+        }
+        Ok(shares)
+    }
+
+    /// Documents the user has annotations for, their own or shared.
+    pub fn annotated_documents(&self, username: &str) -> Result<Vec<String>> {
+        let (start, end) = Self::user_key_range(username);
+        let read_txn = self.db.begin_read()?;
+        let members = read_txn.open_table(SHARE_MEMBERS)?;
+        let groups = read_txn.open_table(SHARE_GROUPS)?;
+
+        let mut documents = BTreeSet::new();
+        for entry in read_txn
+            .open_table(USER_DOCUMENTS)?
+            .range(start.as_str()..end.as_str())?
+        {
+            let (key, data) = entry?;
+            let indexed: IndexedDocument = serde_json::from_slice(data.value())?;
+            if indexed.annotations {
+                documents.insert(key.value()[start.len()..].to_string());
+            }
+        }
+        for entry in members.range(start.as_str()..end.as_str())? {
+            let (key, _) = entry?;
+            if let Some(group) = Self::share_group(&members, &groups, key.value())? {
+                if let Some(member) = group.member(username).filter(|m| m.joined) {
+                    documents.insert(member.document.clone());
+                }
+            }
+        }
+        Ok(documents.into_iter().collect())
+    }
+
+    /// Users whose clients should hear about annotation changes `username`
+    /// makes to `document`: the joined members of its share group, or just
+    /// the user.
+    pub fn annotation_audience(&self, username: &str, document: &str) -> Result<Vec<String>> {
+        let read_txn = self.db.begin_read()?;
+        let document =
+            Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+        let key = Self::annotations_key(username, &document);
+        let group = Self::share_group(
+            &read_txn.open_table(SHARE_MEMBERS)?,
+            &read_txn.open_table(SHARE_GROUPS)?,
+            &key,
+        )?;
+        Ok(match group {
+            Some(group) if group.member(username).is_some_and(|m| m.joined) => group
+                .members
+                .into_iter()
+                .filter(|m| m.joined)
+                .map(|m| m.username)
+                .collect(),
+            _ => vec![username.to_string()],
+        })
+    }
+}
+
+/// Combine two annotation records as if `other` had been uploaded to `current`.
+fn merge_documents(
+    current: DocumentAnnotations,
+    other: DocumentAnnotations,
+) -> Result<DocumentAnnotations> {
+    let version = current.version.max(other.version) + 1;
+    let annotations = merge_annotations(
+        current.annotations,
+        other.annotations,
+        &current.deleted,
+        &other.deleted,
+        version,
+        Incoming::Replica,
+        &NewestWins,
+    )?
+    .annotations;
+    let timestamp = now();
+    let mut deleted = current.deleted;
+    let mut deleted_versions = current.deleted_versions;
+    let mut deleted_at = current.deleted_at;
+    for d in other.deleted {
+        if !deleted.contains(&d) {
+            deleted_versions.insert(d.clone(), version);
+            let at = other.deleted_at.get(&d).copied().unwrap_or(timestamp);
+            deleted_at.insert(d.clone(), at);
+            deleted.push(d);
+        }
+    }
+    Ok(DocumentAnnotations {
+        version,
+        annotations,
+        deleted,
+        deleted_versions,
+        deleted_at,
+        updated_at: timestamp,
+        next_cursor: None,
+        next_deleted_cursor: None,
+    })
+}
+
+// === Reading statistics (KOReader statistics plugin) ===
+
+impl Database {
+    fn stat_book_key(username: &str, md5: &str) -> String {
+        format!("{}:{}", username, md5)
+    }
+
+    /// Page stat keys sort by book, then start time, then page, so a book's
+    /// reading log is one contiguous range in chronological order.
+    fn stat_page_key(username: &str, stat: &PageStat) -> String {
+        format!(
+            "{}:{}:{:020}:{:010}",
+            username, stat.md5, stat.start_time, stat.page
+        )
+    }
+
+    /// Merge uploaded statistics into the stored set.
+    ///
+    /// Books are matched by MD5; incoming metadata replaces stored values
+    /// when present and `last_open` keeps the later time. Page stats are
+    /// matched by (book, start time, page) and keep the longer duration, so
+    /// re-uploading the same local database is a no-op.
+    pub fn merge_statistics(
+        &self,
+        username: &str,
+        upload: &StatisticsUpload,
+    ) -> Result<StatisticsMergeResult> {
+        let mut result = StatisticsMergeResult::default();
+
+        let write_txn = self.begin_write()?;
+        {
+            let mut books = write_txn.open_table(STAT_BOOKS)?;
+            for book in &upload.books {
+                let key = Self::stat_book_key(username, &book.md5);
+                let merged = match books.get(key.as_str())? {
+                    Some(data) => {
+                        let stored: StatBook = serde_json::from_slice(data.value())?;
+                        stored.merged_with(book)
+                    }
+                    None => {
+                        result.books_added += 1;
+                        book.clone()
+                    }
+                };
+                let json = serde_json::to_vec(&merged)?;
+                books.insert(key.as_str(), json.as_slice())?;
+                Self::journal(
+                    &write_txn,
+                    username,
+                    STAT_BOOKS.name(),
+                    &key,
+                    Change::Put(None),
+                )?;
+            }
+
+            let mut pages = write_txn.open_table(STAT_PAGES)?;
+            for stat in &upload.page_stats {
+                let key = Self::stat_page_key(username, stat);
+                let stored: Option<PageStat> = match pages.get(key.as_str())? {
+                    Some(data) => Some(serde_json::from_slice(data.value())?),
+                    None => None,
+                };
+                let merged = match stored {
+                    Some(stored) if stored.duration >= stat.duration => continue,
+                    Some(_) => stat.clone(),
+                    None => {
+                        result.page_stats_added += 1;
+                        stat.clone()
+                    }
+                };
+                let json = serde_json::to_vec(&merged)?;
+                pages.insert(key.as_str(), json.as_slice())?;
+                Self::journal(
+                    &write_txn,
+                    username,
+                    STAT_PAGES.name(),
+                    &key,
+                    Change::Put(None),
+                )?;
+            }
+        }
+        write_txn.commit()?;
+
+        Ok(result)
+    }
+
+    /// All statistics for a user, with page stats optionally limited to
+    /// sessions starting at or after `since`.
+    pub fn get_statistics(&self, username: &str, since: Option<i64>) -> Result<Statistics> {
+        let (start, end) = Self::user_key_range(username);
+        let read_txn = self.db.begin_read()?;
+
+        let mut books: Vec<StatBook> = Vec::new();
+        let table = read_txn.open_table(STAT_BOOKS)?;
+        for entry in table.range(start.as_str()..end.as_str())? {
+            let (_, data) = entry?;
+            books.push(serde_json::from_slice(data.value())?);
+        }
+
+        // Totals are derived from the merged page log rather than trusted
+        // from whichever device uploaded last.
+        let mut read_time: HashMap<String, i64> = HashMap::new();
+        let mut read_pages: HashMap<String, HashSet<i64>> = HashMap::new();
+        let mut page_stats: Vec<PageStat> = Vec::new();
+        let table = read_txn.open_table(STAT_PAGES)?;
+        for entry in table.range(start.as_str()..end.as_str())? {
+            let (_, data) = entry?;
+            let stat: PageStat = serde_json::from_slice(data.value())?;
+            *read_time.entry(stat.md5.clone()).or_default() += stat.duration;
+            read_pages
+                .entry(stat.md5.clone())
+                .or_default()
+                .insert(stat.page);
+            if since.is_none_or(|since| stat.start_time >= since) {
+                page_stats.push(stat);
+            }
+        }
+        for book in &mut books {
+            book.total_read_time = read_time.get(&book.md5).copied().unwrap_or(0);
+            book.total_read_pages = read_pages.get(&book.md5).map_or(0, |p| p.len() as i64);
+        }
+
+        Ok(Statistics { books, page_stats })
+    }
+}
+
+// === Reading sessions ===
+
+impl Database {
+    /// Session keys are `user:<start>:<document>` so a user's sessions form
+    /// one contiguous range ordered by start time.
+    fn session_key(username: &str, start: i64, document: &str) -> String {
+        format!("{}:{:020}:{}", username, start.max(0), document)
+    }
+
+    /// Store a session. Reporting the same document and start time again
+    /// replaces the earlier record.
+    pub fn add_session(&self, username: &str, session: &ReadingSession) -> Result<()> {
+        let key = Self::session_key(username, session.start, &session.document);
+        let json = serde_json::to_vec(session)?;
+
+        let write_txn = self.begin_write()?;
+        {
+            let mut table = write_txn.open_table(SESSIONS)?;
+            table.insert(key.as_str(), json.as_slice())?;
+            Self::journal(
+                &write_txn,
+                username,
+                SESSIONS.name(),
+                &key,
+                Change::Put(None),
+            )?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Sessions starting within `[from, to]`, oldest first.
+    pub fn list_sessions(
+        &self,
+        username: &str,
+        from: Option<i64>,
+        to: Option<i64>,
+        document: Option<&str>,
+    ) -> Result<Vec<ReadingSession>> {
+        let start = format!("{}:{:020}", username, from.unwrap_or(0).max(0));
+        let end = match to {
+            // Every key for start time `to` sorts before `<to>;`
+            Some(to) => format!("{}:{:020};", username, to.max(0)),
+            None => Self::user_key_range(username).1,
+        };
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(SESSIONS)?;
+
+        let mut sessions = Vec::new();
+        for entry in table.range(start.as_str()..end.as_str())? {
+            let (_, data) = entry?;
+            let session: ReadingSession = serde_json::from_slice(data.value())?;
+            if document.is_none_or(|d| d == session.document) {
+                sessions.push(session);
+            }
+        }
+        Ok(sessions)
+    }
 }
 
+// === Devices ===
+
 impl Database {
-    pub fn open(path: &str) -> Result<Self> {
-        let db = RedbDatabase::create(path)?;
+    fn device_key(username: &str, device_id: &str) -> String {
+        format!("{}:{}", username, device_id)
+    }
 
-        // Initialize tables
-        let write_txn = db.begin_write()?;
+    /// Devices that have synced progress, most recently seen first.
+    pub fn list_devices(&self, username: &str) -> Result<Vec<Device>> {
+        let (start, end) = Self::user_key_range(username);
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(DEVICES)?;
+
+        let mut devices: Vec<Device> = Vec::new();
+        for entry in table.range(start.as_str()..end.as_str())? {
+            let (_, data) = entry?;
+            devices.push(serde_json::from_slice(data.value())?);
+        }
+        devices.sort_by_key(|d| std::cmp::Reverse(d.last_seen));
+        Ok(devices)
+    }
+
+    /// Forget a device. Returns whether it was known.
+    ///
+    /// Progress it uploaded is kept; the device reappears if it syncs again.
+    pub fn delete_device(&self, username: &str, device_id: &str) -> Result<bool> {
+        let key = Self::device_key(username, device_id);
+
+        let write_txn = self.begin_write()?;
+        let removed = {
+            let mut table = write_txn.open_table(DEVICES)?;
+            let removed = table.remove(key.as_str())?.is_some();
+            if removed {
+                Self::journal(&write_txn, username, DEVICES.name(), &key, Change::Removed)?;
+            }
+
+            // It no longer holds back tombstone pruning either
+            let (start, end) = Self::user_key_range(username);
+            let suffix = format!(":{}", device_id);
+            write_txn
+                .open_table(ANNOTATION_SYNCS)?
+                .retain_in(start.as_str()..end.as_str(), |key, _| {
+                    !key.ends_with(&suffix)
+                })?;
+            removed
+        };
+        write_txn.commit()?;
+        Ok(removed)
+    }
+}
+
+// === Integrations ===
+
+impl Database {
+    fn integration_key(username: &str, name: &str) -> String {
+        format!("{}:{}", username, name)
+    }
+
+    /// Store a user's settings for a named third-party integration.
+    pub fn set_integration<T: Serialize>(
+        &self,
+        username: &str,
+        name: &str,
+        value: &T,
+    ) -> Result<()> {
+        let key = Self::integration_key(username, name);
+        let json = serde_json::to_vec(value)?;
+
+        let write_txn = self.begin_write()?;
         {
-            let _ = write_txn.open_table(USERS)?;
-            let _ = write_txn.open_table(PROGRESS)?;
-            let _ = write_txn.open_table(ANNOTATIONS)?;
+            let mut table = write_txn.open_table(INTEGRATIONS)?;
+            table.insert(key.as_str(), json.as_slice())?;
+            Self::journal(
+                &write_txn,
+                username,
+                INTEGRATIONS.name(),
+                &key,
+                Change::Put(None),
+            )?;
         }
         write_txn.commit()?;
+        Ok(())
+    }
 
-        Ok(Self { db })
+    pub fn get_integration<T: DeserializeOwned>(
+        &self,
+        username: &str,
+        name: &str,
+    ) -> Result<Option<T>> {
+        let key = Self::integration_key(username, name);
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(INTEGRATIONS)?;
+        match table.get(key.as_str())? {
+            Some(data) => Ok(Some(serde_json::from_slice(data.value())?)),
+            None => Ok(None),
+        }
     }
 
-    // === User operations ===
+    /// Remove an integration. Returns whether it was configured.
+    pub fn delete_integration(&self, username: &str, name: &str) -> Result<bool> {
+        let key = Self::integration_key(username, name);
 
-    pub fn create_user(&self, username: &str, password_hash: &str) -> Result<bool> {
-        let write_txn = self.db.begin_write()?;
-        let created = {
-            let mut table = write_txn.open_table(USERS)?;
-            if table.get(username)?.is_some() {
-                false
-            } else {
-                table.insert(username, password_hash)?;
-                true
+        let write_txn = self.begin_write()?;
+        let removed = {
+            let mut table = write_txn.open_table(INTEGRATIONS)?;
+            let removed = table.remove(key.as_str())?.is_some();
+            if removed {
+                Self::journal(
+                    &write_txn,
+                    username,
+                    INTEGRATIONS.name(),
+                    &key,
+                    Change::Removed,
+                )?;
             }
+            removed
         };
         write_txn.commit()?;
-        Ok(created)
+        Ok(removed)
     }
+}
 
-    pub fn verify_user(&self, username: &str, password_hash: &str) -> Result<bool> {
+// === Readwise ===
+
+impl Database {
+    /// Ids of a document's annotations that have been pushed to Readwise.
+    pub fn readwise_pushed(&self, username: &str, document: &str) -> Result<HashSet<String>> {
+        let key = Self::metadata_key(username, document);
         let read_txn = self.db.begin_read()?;
-        let table = read_txn.open_table(USERS)?;
-        match table.get(username)? {
-            Some(stored) => Ok(stored.value() == password_hash),
-            None => Ok(false),
+        let table = read_txn.open_table(READWISE_PUSHED)?;
+        match table.get(key.as_str())? {
+            Some(data) => Ok(serde_json::from_slice(data.value())?),
+            None => Ok(HashSet::new()),
         }
     }
 
-    // === Progress operations (legacy KOSync) ===
+    pub fn add_readwise_pushed(
+        &self,
+        username: &str,
+        document: &str,
+        ids: &[String],
+    ) -> Result<()> {
+        let key = Self::metadata_key(username, document);
 
-    fn progress_key(username: &str, document: &str) -> String {
-        format!("{}:{}", username, document)
+        let write_txn = self.begin_write()?;
+        {
+            let mut table = write_txn.open_table(READWISE_PUSHED)?;
+            let mut pushed: HashSet<String> = match table.get(key.as_str())? {
+                Some(data) => serde_json::from_slice(data.value())?,
+                None => HashSet::new(),
+            };
+            pushed.extend(ids.iter().cloned());
+            table.insert(key.as_str(), serde_json::to_vec(&pushed)?.as_slice())?;
+            Self::journal(
+                &write_txn,
+                username,
+                READWISE_PUSHED.name(),
+                &key,
+                Change::Put(None),
+            )?;
+        }
+        write_txn.commit()?;
+        Ok(())
     }
 
-    pub fn get_progress(&self, username: &str, document: &str) -> Result<Progress> {
-        let key = Self::progress_key(username, document);
+    /// How often each of a document's highlights has been served for
+    /// review, by annotation id.
+    pub fn highlight_reviews(
+        &self,
+        username: &str,
+        document: &str,
+    ) -> Result<HashMap<String, HighlightReview>> {
+        let key = Self::metadata_key(username, document);
         let read_txn = self.db.begin_read()?;
-        let table = read_txn.open_table(PROGRESS)?;
-
+        let table = read_txn.open_table(HIGHLIGHT_REVIEWS)?;
         match table.get(key.as_str())? {
-            Some(data) => {
-                let progress: Progress = serde_json::from_slice(data.value())?;
-                Ok(progress)
+            Some(data) => Ok(serde_json::from_slice(data.value())?),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    /// Count a review of each `(document, annotation id)`.
+    pub fn record_highlight_reviews(
+        &self,
+        username: &str,
+        reviewed: &[(String, String)],
+        timestamp: i64,
+    ) -> Result<()> {
+        let write_txn = self.begin_write()?;
+        {
+            let mut table = write_txn.open_table(HIGHLIGHT_REVIEWS)?;
+            for (document, id) in reviewed {
+                let key = Self::metadata_key(username, document);
+                let mut reviews: HashMap<String, HighlightReview> = match table.get(key.as_str())? {
+                    Some(data) => serde_json::from_slice(data.value())?,
+                    None => HashMap::new(),
+                };
+                let review = reviews.entry(id.clone()).or_default();
+                review.count += 1;
+                review.last_reviewed = timestamp;
+                table.insert(key.as_str(), serde_json::to_vec(&reviews)?.as_slice())?;
+                Self::journal(
+                    &write_txn,
+                    username,
+                    HIGHLIGHT_REVIEWS.name(),
+                    &key,
+                    Change::Put(None),
+                )?;
             }
-            None => Ok(Progress::default()),
         }
+        write_txn.commit()?;
+        Ok(())
     }
 
-    pub fn set_progress(
+    pub fn queue_readwise_retry(
         &self,
         username: &str,
         document: &str,
-        progress: &str,
-        percentage: f64,
-        device: &str,
-        device_id: Option<&str>,
-    ) -> Result<i64> {
-        let key = Self::progress_key(username, document);
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+        retry: &ReadwiseRetry,
+    ) -> Result<()> {
+        let key = Self::metadata_key(username, document);
 
-        let data = Progress {
-            document: Some(document.to_string()),
-            progress: Some(progress.to_string()),
-            percentage: Some(percentage),
-            device: Some(device.to_string()),
-            device_id: device_id.map(String::from),
-            timestamp: Some(timestamp),
-        };
+        let write_txn = self.begin_write()?;
+        {
+            let mut table = write_txn.open_table(READWISE_QUEUE)?;
+            table.insert(key.as_str(), serde_json::to_vec(retry)?.as_slice())?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
 
-        let json = serde_json::to_vec(&data)?;
+    /// Remove and return the retries due at `now`, as `(user, document, retry)`.
+    pub fn take_due_readwise_retries(
+        &self,
+        now: i64,
+    ) -> Result<Vec<(String, String, ReadwiseRetry)>> {
+        let write_txn = self.begin_write()?;
+        let mut due = Vec::new();
+        {
+            let mut table = write_txn.open_table(READWISE_QUEUE)?;
+            for entry in table.iter()? {
+                let (key, data) = entry?;
+                let retry: ReadwiseRetry = serde_json::from_slice(data.value())?;
+                if retry.retry_at <= now {
+                    if let Some((username, document)) = key.value().split_once(':') {
+                        due.push((username.to_string(), document.to_string(), retry));
+                    }
+                }
+            }
+            for (username, document, _) in &due {
+                table.remove(Self::metadata_key(username, document).as_str())?;
+            }
+        }
+        write_txn.commit()?;
+        Ok(due)
+    }
+}
+
+// === Calibre book mappings ===
+
+impl Database {
+    pub fn set_calibre_book(
+        &self,
+        username: &str,
+        document: &str,
+        book: &CalibreBook,
+    ) -> Result<()> {
+        let json = serde_json::to_vec(book)?;
 
-        let write_txn = self.db.begin_write()?;
+        let write_txn = self.begin_write()?;
         {
-            let mut table = write_txn.open_table(PROGRESS)?;
+            let document =
+                Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+            let key = Self::progress_key(username, &document);
+            let mut table = write_txn.open_table(CALIBRE_BOOKS)?;
             table.insert(key.as_str(), json.as_slice())?;
+            Self::journal(
+                &write_txn,
+                username,
+                CALIBRE_BOOKS.name(),
+                &key,
+                Change::Put(None),
+            )?;
         }
         write_txn.commit()?;
-
-        Ok(timestamp)
+        Ok(())
     }
 
-    // === Annotations operations (extended API) ===
-
-    fn annotations_key(username: &str, document: &str) -> String {
-        format!("{}:{}", username, document)
+    pub fn get_calibre_book(&self, username: &str, document: &str) -> Result<Option<CalibreBook>> {
+        let read_txn = self.db.begin_read()?;
+        let document =
+            Self::canonical_document(&read_txn.open_table(ALIASES)?, username, document)?;
+        let key = Self::progress_key(username, &document);
+        let table = read_txn.open_table(CALIBRE_BOOKS)?;
+        match table.get(key.as_str())? {
+            Some(data) => Ok(Some(serde_json::from_slice(data.value())?)),
+            None => Ok(None),
+        }
     }
 
-    pub fn get_annotations(&self, username: &str, document: &str) -> Result<DocumentAnnotations> {
-        let key = Self::annotations_key(username, document);
+    pub fn list_calibre_books(&self, username: &str) -> Result<Vec<CalibreBookMapping>> {
+        let (start, end) = Self::user_key_range(username);
         let read_txn = self.db.begin_read()?;
-        let table = read_txn.open_table(ANNOTATIONS)?;
+        let table = read_txn.open_table(CALIBRE_BOOKS)?;
 
-        match table.get(key.as_str())? {
-            Some(data) => {
-                let annotations: DocumentAnnotations = serde_json::from_slice(data.value())?;
-                Ok(annotations)
-            }
-            None => Ok(DocumentAnnotations::default()),
+        let mut books = Vec::new();
+        for entry in table.range(start.as_str()..end.as_str())? {
+            let (key, data) = entry?;
+            books.push(CalibreBookMapping {
+                document: key.value()[username.len() + 1..].to_string(),
+                book: serde_json::from_slice(data.value())?,
+            });
         }
+        Ok(books)
     }
 
-    pub fn set_annotations(
-        &self,
-        username: &str,
-        document: &str,
-        annotations: &DocumentAnnotations,
-    ) -> Result<()> {
-        let key = Self::annotations_key(username, document);
-        let json = serde_json::to_vec(annotations)?;
+    /// Remove a document's Calibre mapping. Returns whether it existed.
+    pub fn delete_calibre_book(&self, username: &str, document: &str) -> Result<bool> {
+        let write_txn = self.begin_write()?;
+        let removed = {
+            let document =
+                Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+            let key = Self::progress_key(username, &document);
+            let mut table = write_txn.open_table(CALIBRE_BOOKS)?;
+            let removed = table.remove(key.as_str())?.is_some();
+            if removed {
+                Self::journal(
+                    &write_txn,
+                    username,
+                    CALIBRE_BOOKS.name(),
+                    &key,
+                    Change::Removed,
+                )?;
+            }
+            removed
+        };
+        write_txn.commit()?;
+        Ok(removed)
+    }
+}
+
+// === Webhooks ===
+
+impl Database {
+    fn webhook_key(username: &str, id: &str) -> String {
+        format!("{}:{}", username, id)
+    }
 
-        let write_txn = self.db.begin_write()?;
+    pub fn add_webhook(&self, username: &str, webhook: &Webhook) -> Result<()> {
+        let key = Self::webhook_key(username, &webhook.id);
+        let json = serde_json::to_vec(webhook)?;
+
+        let write_txn = self.begin_write()?;
         {
-            let mut table = write_txn.open_table(ANNOTATIONS)?;
+            let mut table = write_txn.open_table(WEBHOOKS)?;
             table.insert(key.as_str(), json.as_slice())?;
+            Self::journal(
+                &write_txn,
+                username,
+                WEBHOOKS.name(),
+                &key,
+                Change::Put(None),
+            )?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    pub fn list_webhooks(&self, username: &str) -> Result<Vec<Webhook>> {
+        let (start, end) = Self::user_key_range(username);
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(WEBHOOKS)?;
+
+        let mut webhooks = Vec::new();
+        for entry in table.range(start.as_str()..end.as_str())? {
+            let (_, data) = entry?;
+            webhooks.push(serde_json::from_slice(data.value())?);
         }
+        Ok(webhooks)
+    }
+
+    /// Remove a webhook. Returns whether it existed.
+    pub fn delete_webhook(&self, username: &str, id: &str) -> Result<bool> {
+        let key = Self::webhook_key(username, id);
+
+        let write_txn = self.begin_write()?;
+        let removed = {
+            let mut table = write_txn.open_table(WEBHOOKS)?;
+            let removed = table.remove(key.as_str())?.is_some();
+            if removed {
+                Self::journal(&write_txn, username, WEBHOOKS.name(), &key, Change::Removed)?;
+            }
+            removed
+        };
         write_txn.commit()?;
+        Ok(removed)
+    }
+}
 
+// === Dump ===
+
+impl Database {
+    /// Hand every account, followed by its progress records and its own
+    /// documents' annotations, to `emit`, all read in one transaction.
+    /// Annotations kept by a share group are not included.
+    pub fn dump(&self, mut emit: impl FnMut(dump::Record) -> Result<()>) -> Result<()> {
+        let read_txn = self.db.begin_read()?;
+        let users = read_txn.open_table(USERS)?;
+        let progress = read_txn.open_table(PROGRESS)?;
+        let annotations = read_txn.open_table(ANNOTATIONS)?;
+        for entry in users.iter()? {
+            let (username, key) = entry?;
+            let username = username.value();
+            emit(dump::Record::User {
+                username: username.to_string(),
+                key: key.value().to_string(),
+            })?;
+            let next_user = Self::next_username(username);
+            for entry in progress.range((username, "")..(next_user.as_str(), ""))? {
+                let (_, data) = entry?;
+                emit(dump::Record::Progress {
+                    username: username.to_string(),
+                    progress: serde_json::from_slice(data.value())?,
+                })?;
+            }
+            for entry in annotations.range((username, "")..(next_user.as_str(), ""))? {
+                let (key, data) = entry?;
+                emit(dump::Record::Annotations {
+                    username: username.to_string(),
+                    document: key.value().1.to_string(),
+                    annotations: decode_annotations(data.value())?,
+                })?;
+            }
+        }
         Ok(())
     }
 
-    pub fn update_annotations(
+    /// Store a document's annotations brought over from a dump, unless the
+    /// stored ones are as recent. Returns whether they were stored.
+    pub fn import_annotations(
         &self,
         username: &str,
         document: &str,
-        new_annotations: Vec<crate::models::Annotation>,
-        new_deleted: Vec<String>,
-        base_version: Option<u64>,
-    ) -> Result<(u64, i64)> {
-        let key = Self::annotations_key(username, document);
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+        annotations: &DocumentAnnotations,
+    ) -> Result<bool> {
+        let write_txn = self.begin_write()?;
+        {
+            let document =
+                Self::canonical_document(&write_txn.open_table(ALIASES)?, username, document)?;
+            let record = Self::annotations_location(
+                &write_txn.open_table(SHARE_MEMBERS)?,
+                &write_txn.open_table(SHARE_GROUPS)?,
+                username,
+                &document,
+            )?;
+            let stored = record.read_in(&write_txn)?;
+            if stored
+                .as_ref()
+                .is_some_and(|stored| stored.updated_at >= annotations.updated_at)
+            {
+                return Ok(false);
+            }
+            // Never behind a version a client may already have seen
+            let version = stored
+                .as_ref()
+                .map_or(0, |stored| stored.version + 1)
+                .max(annotations.version);
+            let imported = DocumentAnnotations {
+                version,
+                next_cursor: None,
+                next_deleted_cursor: None,
+                ..annotations.clone()
+            };
+            record.write(
+                &write_txn,
+                username,
+                &encode_annotations(&imported)?,
+                version,
+            )?;
+            let previous = stored.unwrap_or_default().annotations;
+            let viewers = Self::annotation_viewers(&write_txn, username, &document)?;
+            Self::reindex_annotations(&write_txn, &viewers, &previous, &imported.annotations)?;
+        }
+        write_txn.commit()?;
+        Ok(true)
+    }
+}
 
-        let write_txn = self.db.begin_write()?;
-        let (version, ts) = {
-            let mut table = write_txn.open_table(ANNOTATIONS)?;
+// === Integrity check ===
 
-            // Get current state
-            let current: DocumentAnnotations = match table.get(key.as_str())? {
-                Some(data) => serde_json::from_slice(data.value())?,
-                None => DocumentAnnotations::default(),
-            };
+/// Why a value doesn't belong in its table, if it doesn't.
+type ValueCheck = fn(&[u8]) -> std::result::Result<(), String>;
+
+impl Database {
+    /// Check the database file, then every stored entry: that its key has
+    /// its table's format and its value parses as what the table holds.
+    ///
+    /// With `quarantine`, bad entries are moved to the quarantine table,
+    /// where the server no longer trips over them but they can still be
+    /// inspected. Otherwise nothing is changed.
+    pub fn fsck(&mut self, quarantine: bool) -> Result<FsckReport> {
+        let mut report = FsckReport {
+            repaired: !self.db.check_integrity()?,
+            ..FsckReport::default()
+        };
+        // Entries to quarantine: table, key as reported, value
+        let mut bad: Vec<(String, String, Vec<u8>)> = Vec::new();
 
-            // Check version if provided (optimistic locking)
-            if let Some(base) = base_version {
-                if base != current.version && current.version > 0 {
-                    return Err(AppError::VersionConflict);
+        let write_txn = self.begin_write()?;
+        for table in USER_TABLES.iter().chain(SHARED_TABLES) {
+            let name = table.name();
+            let value_check = fsck_value_check(name);
+            let user_table = USER_TABLES.iter().any(|t| t.name() == name);
+            let mut found = Vec::new();
+            for entry in write_txn.open_table(*table)?.iter()? {
+                let (key, value) = entry?;
+                report.checked += 1;
+                let key_ok = if user_table {
+                    is_user_key(key.value())
+                } else {
+                    !key.value().is_empty()
+                };
+                let problem = match value_check(value.value()) {
+                    _ if !key_ok => Err("malformed key".to_string()),
+                    result => result,
+                };
+                if let Err(reason) = problem {
+                    found.push((key.value().to_string(), value.value().to_vec(), reason));
                 }
             }
+            let mut table = write_txn.open_table(*table)?;
+            for (key, value, reason) in found {
+                if quarantine {
+                    table.remove(key.as_str())?;
+                }
+                bad.push((name.to_string(), key.clone(), value));
+                report.problems.push(FsckProblem {
+                    table: name.to_string(),
+                    key,
+                    reason,
+                });
+            }
+        }
+        for table in DOCUMENT_TABLES {
+            let name = table.name();
+            let value_check = fsck_value_check(name);
+            let mut found = Vec::new();
+            for entry in write_txn.open_table(*table)?.iter()? {
+                let (key, value) = entry?;
+                report.checked += 1;
+                let (username, document) = key.value();
+                let problem = match value_check(value.value()) {
+                    _ if !is_name(username) || !is_name(document) => {
+                        Err("malformed key".to_string())
+                    }
+                    result => result,
+                };
+                if let Err(reason) = problem {
+                    let key = (username.to_string(), document.to_string());
+                    found.push((key, value.value().to_vec(), reason));
+                }
+            }
+            let mut table = write_txn.open_table(*table)?;
+            for ((username, document), value, reason) in found {
+                if quarantine {
+                    table.remove((username.as_str(), document.as_str()))?;
+                    let kind = match name {
+                        "progress" => Indexed::Progress,
+                        _ => Indexed::Annotations,
+                    };
+                    Database::index_document(&write_txn, &username, &document, kind, false)?;
+                }
+                let key = format!("{}:{}", username, document);
+                bad.push((name.to_string(), key.clone(), value));
+                report.problems.push(FsckProblem {
+                    table: name.to_string(),
+                    key,
+                    reason,
+                });
+            }
+        }
+        for table in STRING_TABLES {
+            let name = table.name();
+            let mut found = Vec::new();
+            for entry in write_txn.open_table(*table)?.iter()? {
+                let (key, value) = entry?;
+                report.checked += 1;
+                let (key, value) = (key.value(), value.value());
+                let valid = match name {
+                    "users" => is_name(key) && !value.is_empty(),
+                    "feeds" => !key.is_empty() && is_name(value),
+                    "feed_tokens" => is_name(key) && !value.is_empty(),
+                    _ => is_user_key(key) && !value.is_empty(),
+                };
+                if !valid {
+                    found.push((key.to_string(), value.to_string()));
+                }
+            }
+            let mut table = write_txn.open_table(*table)?;
+            for (key, value) in found {
+                if quarantine {
+                    table.remove(key.as_str())?;
+                }
+                bad.push((name.to_string(), key.clone(), value.into_bytes()));
+                report.problems.push(FsckProblem {
+                    table: name.to_string(),
+                    key,
+                    reason: "malformed entry".to_string(),
+                });
+            }
+        }
 
-            // Merge annotations
-            let merged = merge_annotations(
-                current.annotations,
-                new_annotations,
-                &current.deleted,
-                &new_deleted,
-            );
-
-            // Merge deleted lists
-            let mut all_deleted = current.deleted;
-            for d in new_deleted {
-                if !all_deleted.contains(&d) {
-                    all_deleted.push(d);
+        if quarantine && !report.problems.is_empty() {
+            {
+                let mut table = write_txn.open_table(QUARANTINE)?;
+                for (name, key, value) in &bad {
+                    table.insert(format!("{}/{}", name, key).as_str(), value.as_slice())?;
                 }
             }
+            write_txn.commit()?;
+            report.quarantined = true;
+        }
+        Ok(report)
+    }
+}
 
-            let new_doc = DocumentAnnotations {
-                version: current.version + 1,
-                annotations: merged,
-                deleted: all_deleted,
-                updated_at: timestamp,
-            };
+/// A username or document hash.
+fn is_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(':')
+}
 
-            let json = serde_json::to_vec(&new_doc)?;
-            table.insert(key.as_str(), json.as_slice())?;
+/// A `user:...` key.
+fn is_user_key(key: &str) -> bool {
+    key.split_once(':')
+        .is_some_and(|(user, rest)| !user.is_empty() && !rest.is_empty())
+}
 
-            (new_doc.version, timestamp)
-        };
-        write_txn.commit()?;
+fn fsck_json<T: DeserializeOwned>(value: &[u8]) -> std::result::Result<(), String> {
+    serde_json::from_slice::<T>(value)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
 
-        Ok((version, ts))
+/// What the values of the table called `name` must parse as. Tables not
+/// listed are at least checked to hold JSON.
+fn fsck_value_check(name: &str) -> ValueCheck {
+    match name {
+        "progress" | "progress_history" | "device_progress" => fsck_json::<Progress>,
+        "annotations" | "annotation_history" | "shared_annotations" => |value| {
+            decode_annotations::<DocumentAnnotations>(value)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        },
+        "user_settings" => fsck_json::<UserSettings>,
+        "document_metadata" => fsck_json::<DocumentMetadata>,
+        "calibre_books" => fsck_json::<CalibreBook>,
+        "devices" => fsck_json::<Device>,
+        "finished" => fsck_json::<FinishedBook>,
+        "webhooks" => fsck_json::<Webhook>,
+        "stat_books" => fsck_json::<StatBook>,
+        "stat_pages" => fsck_json::<PageStat>,
+        "sessions" => fsck_json::<ReadingSession>,
+        "document_notes" => fsck_json::<DocumentNote>,
+        "document_status" => fsck_json::<DocumentStatus>,
+        "reviews" => fsck_json::<Review>,
+        "document_tags" => fsck_json::<Vec<String>>,
+        "readwise_pushed" => fsck_json::<HashSet<String>>,
+        "readwise_queue" => fsck_json::<ReadwiseRetry>,
+        "highlight_reviews" => fsck_json::<HashMap<String, HighlightReview>>,
+        "idempotency_keys" => fsck_json::<IdempotentWrite>,
+        "public_shares" => fsck_json::<PublicShare>,
+        "attachments" => fsck_json::<Attachment>,
+        "share_groups" => fsck_json::<ShareGroup>,
+        "public_share_tokens" => |value| {
+            std::str::from_utf8(value)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        },
+        "annotation_syncs" => |value| match value.len() {
+            8 => Ok(()),
+            n => Err(format!("expected an 8-byte version, found {} bytes", n)),
+        },
+        // Empty index entries, raw attachment content and quarantined
+        // entries of any kind
+        "tag_index" | "annotation_index" | "attachment_blobs" | "quarantine" => |_| Ok(()),
+        _ => fsck_json::<serde_json::Value>,
     }
 }
 
-/// Merge annotations from two sources using timestamp-based conflict resolution
-fn merge_annotations(
-    server: Vec<crate::models::Annotation>,
-    client: Vec<crate::models::Annotation>,
-    server_deleted: &[String],
-    client_deleted: &[String],
-) -> Vec<crate::models::Annotation> {
-    use std::collections::HashMap;
+/// Where a user's annotations for a document are stored.
+enum AnnotationsRecord {
+    /// Their own record in `ANNOTATIONS`, by user and document.
+    Own(String, String),
+    /// Their share group's record in `SHARED_ANNOTATIONS`, by group id.
+    Shared(String),
+}
 
-    // Index by position key
-    fn position_key(a: &crate::models::Annotation) -> String {
-        format!(
-            "{}|{:?}|{:?}",
-            serde_json::to_string(&a.page).unwrap_or_default(),
-            a.pos0,
-            a.pos1
-        )
+impl AnnotationsRecord {
+    /// The record's key in the tables kept alongside it, such as
+    /// `ANNOTATION_HISTORY`: `user:document`, or the group id.
+    fn key(&self) -> String {
+        match self {
+            Self::Own(username, document) => Database::annotations_key(username, document),
+            Self::Shared(id) => id.clone(),
+        }
+    }
+
+    fn read<T: DeserializeOwned>(&self, read_txn: &ReadTransaction) -> Result<Option<T>> {
+        let record = match self {
+            Self::Own(username, document) => {
+                let table = read_txn.open_table(ANNOTATIONS)?;
+                let data = table.get((username.as_str(), document.as_str()))?;
+                data.map(|data| decode_annotations(data.value()))
+            }
+            Self::Shared(id) => {
+                let table = read_txn.open_table(SHARED_ANNOTATIONS)?;
+                let data = table.get(id.as_str())?;
+                data.map(|data| decode_annotations(data.value()))
+            }
+        };
+        record.transpose()
+    }
+
+    /// Like `read`, inside a write transaction.
+    fn read_in(&self, write_txn: &WriteTransaction) -> Result<Option<DocumentAnnotations>> {
+        let record = match self {
+            Self::Own(username, document) => {
+                let table = write_txn.open_table(ANNOTATIONS)?;
+                let data = table.get((username.as_str(), document.as_str()))?;
+                data.map(|data| decode_annotations(data.value()))
+            }
+            Self::Shared(id) => {
+                let table = write_txn.open_table(SHARED_ANNOTATIONS)?;
+                let data = table.get(id.as_str())?;
+                data.map(|data| decode_annotations(data.value()))
+            }
+        };
+        record.transpose()
     }
 
-    fn effective_time(a: &crate::models::Annotation) -> &str {
-        a.datetime_updated.as_deref().unwrap_or(&a.datetime)
+    /// Store an encoded record at `version`, written by `username`,
+    /// returning the one it replaced.
+    fn write(
+        &self,
+        write_txn: &WriteTransaction,
+        username: &str,
+        json: &[u8],
+        version: u64,
+    ) -> Result<Option<DocumentAnnotations>> {
+        let table = match self {
+            Self::Own(..) => ANNOTATIONS.name(),
+            Self::Shared(_) => SHARED_ANNOTATIONS.name(),
+        };
+        Database::journal(
+            write_txn,
+            username,
+            table,
+            &self.key(),
+            Change::Put(Some(version)),
+        )?;
+        let previous = match self {
+            Self::Own(username, document) => {
+                let mut table = write_txn.open_table(ANNOTATIONS)?;
+                let data = table.insert((username.as_str(), document.as_str()), json)?;
+                if data.is_none() {
+                    Database::index_document(
+                        write_txn,
+                        username,
+                        document,
+                        Indexed::Annotations,
+                        true,
+                    )?;
+                }
+                data.map(|data| decode_annotations(data.value()))
+            }
+            Self::Shared(id) => {
+                let mut table = write_txn.open_table(SHARED_ANNOTATIONS)?;
+                let data = table.insert(id.as_str(), json)?;
+                data.map(|data| decode_annotations(data.value()))
+            }
+        };
+        previous.transpose()
     }
+}
 
-    let mut merged: HashMap<String, crate::models::Annotation> = HashMap::new();
+/// First byte of a zstd-compressed annotation record. Records written
+/// before compression are plain JSON objects, starting with `{`.
+const COMPRESSED_ANNOTATIONS: u8 = 0x01;
 
-    // Add server annotations (skip if deleted by client)
-    for anno in server {
-        if !client_deleted.contains(&anno.datetime) {
-            merged.insert(position_key(&anno), anno);
-        }
+/// Records shorter than this are stored as plain JSON: compressing them
+/// saves next to nothing.
+const COMPRESS_ANNOTATIONS_OVER: usize = 512;
+
+const ANNOTATIONS_ZSTD_LEVEL: i32 = 3;
+
+/// Annotation records are the largest values in the database, and every
+/// read and write of one goes through these two. Large ones are stored
+/// compressed, behind a marker byte.
+fn encode_annotations(annotations: &DocumentAnnotations) -> Result<Vec<u8>> {
+    let json = serde_json::to_vec(annotations)?;
+    if json.len() <= COMPRESS_ANNOTATIONS_OVER {
+        return Ok(json);
     }
+    let mut data = vec![COMPRESSED_ANNOTATIONS];
+    zstd::stream::copy_encode(json.as_slice(), &mut data, ANNOTATIONS_ZSTD_LEVEL)?;
+    Ok(data)
+}
 
-    // Merge client annotations
-    for anno in client {
-        if server_deleted.contains(&anno.datetime) {
-            continue; // Skip if deleted on server
+fn decode_annotations<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    match data.split_first() {
+        Some((&COMPRESSED_ANNOTATIONS, compressed)) => {
+            Ok(serde_json::from_slice(&zstd::decode_all(compressed)?)?)
         }
+        _ => Ok(serde_json::from_slice(data)?),
+    }
+}
 
-        let key = position_key(&anno);
-        if let Some(existing) = merged.get(&key) {
-            // Keep newer one
-            if effective_time(&anno) > effective_time(existing) {
-                merged.insert(key, anno);
-            }
-        } else {
-            merged.insert(key, anno);
+/// Split a journaled `user:document` key.
+fn split_document_key(key: &str) -> Result<(&str, &str)> {
+    key.split_once(':')
+        .ok_or_else(|| AppError::InvalidRequest(format!("{} isn't a user:document key", key)))
+}
+
+/// The keys of a table starting with `prefix`.
+fn keys_with_prefix<V: Value + 'static>(
+    table: &impl ReadableTable<&'static str, V>,
+    prefix: &str,
+) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    for entry in table.range(prefix..)? {
+        let (key, _) = entry?;
+        if !key.value().starts_with(prefix) {
+            break;
         }
+        keys.push(key.value().to_string());
     }
+    Ok(keys)
+}
+
+/// The annotations in a replicated `ANNOTATIONS` or `SHARED_ANNOTATIONS`
+/// value, none if it was removed.
+fn replicated_annotations(data: Option<&[u8]>) -> Result<Vec<crate::models::Annotation>> {
+    Ok(match data {
+        Some(data) => decode_annotations::<DocumentAnnotations>(data)?.annotations,
+        None => Vec::new(),
+    })
+}
+
+/// The `(user, document)` pairs of a replicated `SHARE_GROUPS` value's
+/// joined members.
+fn joined_viewers(data: Option<&[u8]>) -> Result<Vec<(String, String)>> {
+    let Some(data) = data else {
+        return Ok(Vec::new());
+    };
+    let group: ShareGroup = serde_json::from_slice(data)?;
+    Ok(group
+        .members
+        .into_iter()
+        .filter(|m| m.joined)
+        .map(|m| (m.username, m.document))
+        .collect())
+}
+
+/// Copy every entry of a table from one database into another.
+fn copy_table<K: Key + 'static, V: Value + 'static>(
+    from: &ReadTransaction,
+    to: &WriteTransaction,
+    definition: TableDefinition<K, V>,
+) -> Result<()> {
+    let source = from.open_table(definition)?;
+    let mut target = to.open_table(definition)?;
+    for entry in source.iter()? {
+        let (key, value) = entry?;
+        target.insert(key.value(), value.value())?;
+    }
+    Ok(())
+}
 
-    merged.into_values().collect()
+pub(crate) fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
 }
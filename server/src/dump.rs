@@ -0,0 +1,210 @@
+//! Full dumps of accounts, progress and annotations as JSON lines, for
+//! backups that outlive the storage format, moving between servers, and
+//! reading with `jq`.
+//!
+//! Every line is one JSON object whose `type` says what it holds. The first
+//! line is always the header; the others follow it user by user, each
+//! user's line before their records:
+//!
+//! ```text
+//! {"type":"header","format":"kosync-dump","version":1,"created_at":1706745599}
+//! {"type":"user","username":"alice","key":"<md5 of the password>"}
+//! {"type":"progress","username":"alice","progress":{"document":"<hash>","progress":"/body/DocFragment[12]","percentage":0.4,"device":"Kobo","timestamp":1706745000,"version":3}}
+//! {"type":"annotations","username":"alice","document":"<hash>","annotations":{"version":2,"annotations":[...],"deleted":[],"updated_at":1706745000}}
+//! ```
+//!
+//! `progress` and `annotations` are the records as the API returns them.
+//! Readers should skip lines of a `type` they don't know, so later versions
+//! can add kinds of records without bumping `version`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::{self, Database};
+use crate::error::{AppError, Result};
+use crate::models::{DocumentAnnotations, Progress};
+
+pub const FORMAT: &str = "kosync-dump";
+pub const VERSION: u32 = 1;
+
+/// One line of a dump.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    Header {
+        format: String,
+        version: u32,
+        created_at: i64,
+    },
+    User {
+        username: String,
+        key: String,
+    },
+    Progress {
+        username: String,
+        progress: Progress,
+    },
+    Annotations {
+        username: String,
+        document: String,
+        annotations: DocumentAnnotations,
+    },
+    /// A line of a kind added after this version.
+    #[serde(other)]
+    Unknown,
+}
+
+/// What an export wrote.
+#[derive(Debug, Default)]
+pub struct ExportSummary {
+    pub users: usize,
+    pub progress: usize,
+    pub annotations: usize,
+}
+
+/// What an import did.
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    /// Accounts created.
+    pub users: usize,
+    /// Accounts that already existed here with the same key; their records
+    /// are imported too.
+    pub existing_users: usize,
+    /// Progress records stored.
+    pub progress: usize,
+    /// Documents whose annotations were stored.
+    pub annotations: usize,
+    /// Lines that weren't imported, and why.
+    pub skipped: Vec<String>,
+}
+
+/// Write a dump of every account, progress record and document's
+/// annotations in `db` to `out`, as of a single moment.
+pub fn export(db: &Database, mut out: impl Write) -> Result<ExportSummary> {
+    let mut summary = ExportSummary::default();
+    write_line(
+        &mut out,
+        &Record::Header {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            created_at: db::now(),
+        },
+    )?;
+    db.dump(|record| {
+        match &record {
+            Record::User { .. } => summary.users += 1,
+            Record::Progress { .. } => summary.progress += 1,
+            Record::Annotations { .. } => summary.annotations += 1,
+            Record::Header { .. } | Record::Unknown => {}
+        }
+        write_line(&mut out, &record)
+    })?;
+    out.flush()?;
+    Ok(summary)
+}
+
+/// Load a dump written by `export` into `db`.
+///
+/// Like `legacy_redis::import`, accounts whose name is already taken here
+/// with a different key are left alone, records included, and a record
+/// replaces what is stored only when it is newer.
+pub fn import(db: &Database, input: impl BufRead) -> Result<ImportSummary> {
+    let mut summary = ImportSummary::default();
+    let mut lines = input.lines().enumerate();
+    match lines.next() {
+        Some((_, line)) => match serde_json::from_str(&line?) {
+            Ok(Record::Header {
+                format, version, ..
+            }) if format == FORMAT && version <= VERSION => {}
+            Ok(Record::Header { version, .. }) if version > VERSION => {
+                return Err(AppError::InvalidRequest(format!(
+                    "dump version {} is newer than this server's {}",
+                    version, VERSION
+                )))
+            }
+            _ => {
+                return Err(AppError::InvalidRequest(
+                    "not a kosync dump: the first line isn't its header".into(),
+                ))
+            }
+        },
+        None => return Err(AppError::InvalidRequest("the dump is empty".into())),
+    }
+
+    // Users whose records are imported
+    let mut users = BTreeSet::new();
+    let mut progress: BTreeMap<String, Vec<Progress>> = BTreeMap::new();
+    for (index, line) in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let number = index + 1;
+        let record = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                summary.skipped.push(format!("line {}: {}", number, e));
+                continue;
+            }
+        };
+        match record {
+            Record::User { username, key } => {
+                if db.create_user(&username, &key)? {
+                    summary.users += 1;
+                } else if db.verify_user(&username, &key)? {
+                    summary.existing_users += 1;
+                } else {
+                    summary.skipped.push(format!(
+                        "line {}: user {} exists with another key",
+                        number, username
+                    ));
+                    continue;
+                }
+                users.insert(username);
+            }
+            Record::Progress {
+                username,
+                progress: record,
+            } => {
+                if !users.contains(&username) {
+                    summary
+                        .skipped
+                        .push(format!("line {}: user {} not imported", number, username));
+                    continue;
+                }
+                progress.entry(username).or_default().push(record);
+            }
+            Record::Annotations {
+                username,
+                document,
+                annotations,
+            } => {
+                if !users.contains(&username) {
+                    summary
+                        .skipped
+                        .push(format!("line {}: user {} not imported", number, username));
+                    continue;
+                }
+                if db.import_annotations(&username, &document, &annotations)? {
+                    summary.annotations += 1;
+                }
+            }
+            Record::Header { .. } => summary
+                .skipped
+                .push(format!("line {}: a second header", number)),
+            Record::Unknown => {}
+        }
+    }
+    for (username, records) in progress {
+        summary.progress += db.import_progress(&username, records)?;
+    }
+    Ok(summary)
+}
+
+fn write_line(out: &mut impl Write, record: &Record) -> Result<()> {
+    serde_json::to_writer(&mut *out, record)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
@@ -10,13 +10,13 @@ use crate::models::ErrorResponse;
 #[derive(Debug, Error)]
 pub enum AppError {
     #[error("Database error: {0}")]
-    Database(#[from] redb::Error),
+    Database(Box<redb::Error>),
 
     #[error("Database error: {0}")]
     DatabaseError(#[from] redb::DatabaseError),
 
     #[error("Database transaction error: {0}")]
-    Transaction(#[from] redb::TransactionError),
+    Transaction(Box<redb::TransactionError>),
 
     #[error("Database table error: {0}")]
     Table(#[from] redb::TableError),
@@ -30,6 +30,15 @@ pub enum AppError {
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
 
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Database schema version {0} is newer than this server supports")]
+    UnsupportedSchema(u64),
+
+    #[error("Write batch failed: {0}")]
+    WriteBatch(String),
+
     #[error("Unauthorized")]
     Unauthorized,
 
@@ -44,6 +53,49 @@ pub enum AppError {
 
     #[error("Version conflict")]
     VersionConflict,
+
+    #[error("Forbidden")]
+    Forbidden,
+
+    #[error("Progress is behind the stored position")]
+    ProgressBehind,
+
+    #[error("Invalid percentage: {0}")]
+    InvalidPercentage(f64),
+
+    #[error("Upstream service error: {0}")]
+    Upstream(String),
+
+    #[error("Progress is older than the stored update")]
+    StaleProgress,
+
+    #[error("Progress not found")]
+    ProgressNotFound,
+
+    #[error("Documents can have at most {0} annotations")]
+    AnnotationLimit(usize),
+
+    #[error("Trash item not found")]
+    TrashItemNotFound,
+
+    #[error("Server is read-only: {0}")]
+    ReadOnly(String),
+
+    #[error("Attachment not found")]
+    AttachmentNotFound,
+}
+
+// The two largest redb errors are boxed to keep `Result<T>` small.
+impl From<redb::Error> for AppError {
+    fn from(e: redb::Error) -> Self {
+        Self::Database(Box::new(e))
+    }
+}
+
+impl From<redb::TransactionError> for AppError {
+    fn from(e: redb::TransactionError) -> Self {
+        Self::Transaction(Box::new(e))
+    }
 }
 
 impl AppError {
@@ -54,6 +106,16 @@ impl AppError {
             Self::InvalidRequest(_) => StatusCode::FORBIDDEN,
             Self::DocumentMissing => StatusCode::FORBIDDEN,
             Self::VersionConflict => StatusCode::CONFLICT,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::ProgressBehind => StatusCode::CONFLICT,
+            Self::InvalidPercentage(_) => StatusCode::FORBIDDEN,
+            Self::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Self::StaleProgress => StatusCode::CONFLICT,
+            Self::ProgressNotFound => StatusCode::NOT_FOUND,
+            Self::AnnotationLimit(_) => StatusCode::FORBIDDEN,
+            Self::TrashItemNotFound => StatusCode::NOT_FOUND,
+            Self::ReadOnly(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::AttachmentNotFound => StatusCode::NOT_FOUND,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -66,21 +128,45 @@ impl AppError {
             | Self::Table(_)
             | Self::Storage(_)
             | Self::Commit(_)
-            | Self::Serialization(_) => 2000,
+            | Self::Serialization(_)
+            | Self::Io(_)
+            | Self::UnsupportedSchema(_)
+            | Self::WriteBatch(_) => 2000,
             Self::Unauthorized => 2001,
             Self::UserExists => 2002,
             Self::InvalidRequest(_) => 2003,
             Self::DocumentMissing => 2004,
             Self::VersionConflict => 2005,
+            Self::Forbidden => 2006,
+            Self::ProgressBehind => 2007,
+            Self::InvalidPercentage(_) => 2008,
+            Self::Upstream(_) => 2009,
+            Self::StaleProgress => 2010,
+            Self::ProgressNotFound => 2011,
+            Self::AnnotationLimit(_) => 2012,
+            Self::TrashItemNotFound => 2013,
+            Self::ReadOnly(_) => 2014,
+            Self::AttachmentNotFound => 2015,
         }
     }
 }
 
+impl AppError {
+    /// Whether the error was caused by the request rather than the server.
+    pub fn is_client_error(&self) -> bool {
+        self.status_code().is_client_error()
+    }
+
+    /// The JSON error envelope, for embedding in multi-item responses.
+    pub fn to_error_response(&self) -> ErrorResponse {
+        ErrorResponse::new(self.error_code(), self.to_string())
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let status = self.status_code();
-        let body = ErrorResponse::new(self.error_code(), self.to_string());
-        (status, Json(body)).into_response()
+        (status, Json(self.to_error_response())).into_response()
     }
 }
 
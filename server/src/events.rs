@@ -0,0 +1,39 @@
+use tokio::sync::broadcast;
+
+use crate::models::SyncEvent;
+
+/// Events buffered per subscriber before the slowest ones start skipping.
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct UserEvent {
+    pub username: String,
+    pub event: SyncEvent,
+}
+
+/// Fan-out of sync events to everything listening (WebSocket clients, ...).
+///
+/// Publishing never blocks; with no subscribers events are simply dropped.
+pub struct EventHub {
+    sender: broadcast::Sender<UserEvent>,
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { sender }
+    }
+}
+
+impl EventHub {
+    pub fn publish(&self, username: &str, event: SyncEvent) {
+        let _ = self.sender.send(UserEvent {
+            username: username.to_string(),
+            event,
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<UserEvent> {
+        self.sender.subscribe()
+    }
+}
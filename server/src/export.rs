@@ -0,0 +1,552 @@
+use crate::models::{Annotation, DocumentMetadata, ProgressExportRow, Review};
+use crate::position;
+use crate::streaks;
+
+/// A finished book as written to reading-log exports.
+pub struct ReadingLogRow {
+    pub title: String,
+    pub author: String,
+    pub isbn: String,
+    /// `YYYY/MM/DD`, as both Goodreads and StoryGraph expect.
+    pub date_read: String,
+    /// 1-5 stars.
+    pub rating: Option<u8>,
+}
+
+const PROGRESS_CSV_HEADER: &[&str] = &[
+    "document",
+    "title",
+    "author",
+    "progress",
+    "percentage",
+    "device",
+    "timestamp",
+];
+
+/// Render a progress export as RFC 4180 CSV, with a header row.
+pub fn progress_csv(rows: &[ProgressExportRow]) -> String {
+    let mut out = String::new();
+    write_record(&mut out, PROGRESS_CSV_HEADER.iter().copied());
+    for row in rows {
+        let percentage = row.percentage.map(|p| p.to_string());
+        let timestamp = row.timestamp.map(|t| t.to_string());
+        write_record(
+            &mut out,
+            [
+                Some(row.document.as_str()),
+                row.title.as_deref(),
+                row.author.as_deref(),
+                row.progress.as_deref(),
+                percentage.as_deref(),
+                row.device.as_deref(),
+                timestamp.as_deref(),
+            ]
+            .into_iter()
+            .map(Option::unwrap_or_default),
+        );
+    }
+    out
+}
+
+const GOODREADS_CSV_HEADER: &[&str] = &[
+    "Title",
+    "Author",
+    "ISBN",
+    "My Rating",
+    "Date Read",
+    "Date Added",
+    "Bookshelves",
+    "Exclusive Shelf",
+];
+
+/// Render finished books in Goodreads' library import format.
+pub fn goodreads_csv(rows: &[ReadingLogRow]) -> String {
+    let mut out = String::new();
+    write_record(&mut out, GOODREADS_CSV_HEADER.iter().copied());
+    for row in rows {
+        // Goodreads uses 0 for "not rated"
+        let rating = row.rating.unwrap_or(0).to_string();
+        write_record(
+            &mut out,
+            [
+                row.title.as_str(),
+                &row.author,
+                &row.isbn,
+                &rating,
+                &row.date_read,
+                &row.date_read,
+                "read",
+                "read",
+            ],
+        );
+    }
+    out
+}
+
+const STORYGRAPH_CSV_HEADER: &[&str] = &[
+    "Title",
+    "Authors",
+    "ISBN/UID",
+    "Format",
+    "Read Status",
+    "Date Added",
+    "Last Date Read",
+    "Dates Read",
+    "Read Count",
+    "Star Rating",
+];
+
+/// Render finished books in The StoryGraph's import format.
+pub fn storygraph_csv(rows: &[ReadingLogRow]) -> String {
+    let mut out = String::new();
+    write_record(&mut out, STORYGRAPH_CSV_HEADER.iter().copied());
+    for row in rows {
+        let rating = row.rating.map(|r| r.to_string()).unwrap_or_default();
+        let dates_read = format!("{}-{}", row.date_read, row.date_read);
+        write_record(
+            &mut out,
+            [
+                row.title.as_str(),
+                &row.author,
+                &row.isbn,
+                "digital",
+                "read",
+                &row.date_read,
+                &row.date_read,
+                &dates_read,
+                "1",
+                &rating,
+            ],
+        );
+    }
+    out
+}
+
+const REVIEWS_CSV_HEADER: &[&str] = &[
+    "document",
+    "title",
+    "author",
+    "rating",
+    "review",
+    "updated_at",
+];
+
+/// Render ratings and reviews as CSV, with a header row.
+pub fn reviews_csv(reviews: &[Review]) -> String {
+    let mut out = String::new();
+    write_record(&mut out, REVIEWS_CSV_HEADER.iter().copied());
+    for review in reviews {
+        let metadata = review.metadata.as_ref();
+        let rating = review.rating.map(|r| r.to_string()).unwrap_or_default();
+        let updated_at = review.updated_at.to_string();
+        write_record(
+            &mut out,
+            [
+                review.document.as_str(),
+                metadata
+                    .and_then(|m| m.title.as_deref())
+                    .unwrap_or_default(),
+                metadata
+                    .and_then(|m| m.author.as_deref())
+                    .unwrap_or_default(),
+                &rating,
+                review.review.as_deref().unwrap_or_default(),
+                &updated_at,
+            ],
+        );
+    }
+    out
+}
+
+fn reading_order(annotations: &[Annotation]) -> Vec<&Annotation> {
+    let mut sorted: Vec<&Annotation> = annotations.iter().collect();
+    sorted.sort_by(|a, b| position::reading_order(a, b));
+    sorted
+}
+
+/// Render a document's highlights and notes as Markdown, with a section
+/// per chapter in reading order.
+pub fn annotations_markdown(
+    document: &str,
+    metadata: &DocumentMetadata,
+    annotations: &[Annotation],
+) -> String {
+    let mut out = format!("# {}\n", metadata.title.as_deref().unwrap_or(document));
+    push_highlights(&mut out, metadata, annotations, false);
+    out
+}
+
+/// A standalone HTML page of a document's highlights and notes in reading
+/// order, for sharing with people who don't use KOReader.
+pub fn annotations_html(
+    document: &str,
+    metadata: &DocumentMetadata,
+    annotations: &[Annotation],
+) -> String {
+    let title = escape_html(metadata.title.as_deref().unwrap_or(document));
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <meta name=\"robots\" content=\"noindex\">\n<title>{title}</title>\n<style>\n\
+         body {{ max-width: 40em; margin: 2em auto; padding: 0 1em; font-family: Georgia, serif; \
+         line-height: 1.5; color: #222; }}\n\
+         blockquote {{ margin: 1.5em 0 0.5em; padding-left: 1em; border-left: 3px solid #ccc; }}\n\
+         .note {{ margin: 0 0 1.5em 1em; font-family: sans-serif; font-size: 0.9em; color: #555; }}\n\
+         </style>\n</head>\n<body>\n<h1>{title}</h1>\n"
+    );
+    if let Some(author) = &metadata.author {
+        out.push_str(&format!("<p><em>{}</em></p>\n", escape_html(author)));
+    }
+
+    let mut chapter: Option<&str> = None;
+    for anno in reading_order(annotations) {
+        let text = anno.text.as_deref().filter(|t| !t.trim().is_empty());
+        let note = anno.note.as_deref().filter(|n| !n.trim().is_empty());
+        if text.is_none() && note.is_none() {
+            continue; // Bookmarks mean nothing to readers of the page
+        }
+        if anno.chapter.is_some() && anno.chapter.as_deref() != chapter {
+            chapter = anno.chapter.as_deref();
+            out.push_str(&format!(
+                "<h2>{}</h2>\n",
+                escape_html(chapter.unwrap_or_default())
+            ));
+        }
+        if let Some(text) = text {
+            out.push_str(&format!(
+                "<blockquote>{}</blockquote>\n",
+                escape_html(text.trim()).replace('\n', "<br>\n")
+            ));
+        }
+        if let Some(note) = note {
+            out.push_str(&format!(
+                "<p class=\"note\">{}</p>\n",
+                escape_html(note.trim()).replace('\n', "<br>\n")
+            ));
+        }
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+const ANKI_HEADER: &str = "#separator:tab\n#html:true\n#notetype:Basic\n\
+                           #guid column:1\n#deck column:2\n#tags column:5\n";
+
+/// Highlights as an Anki text import: one Basic card per highlight, with
+/// the highlight on the front and its note and source on the back. Books
+/// are given as `(title, annotations)` and each gets its own deck.
+///
+/// Cards are identified by annotation id, so importing a newer export
+/// updates the cards from an older one instead of adding them again.
+pub fn annotations_anki<'a>(
+    books: impl IntoIterator<Item = (&'a str, &'a [Annotation])>,
+) -> String {
+    let mut out = String::from(ANKI_HEADER);
+    for (title, annotations) in books {
+        let deck = format!("KOReader::{}", title.replace(['\t', '\n', '\r'], " "));
+        for anno in reading_order(annotations) {
+            let Some(text) = anno.text.as_deref().filter(|t| !t.trim().is_empty()) else {
+                continue;
+            };
+            let mut back = String::new();
+            if let Some(note) = anno.note.as_deref().filter(|n| !n.trim().is_empty()) {
+                back.push_str(&anki_field(note));
+                back.push_str("<br><br>");
+            }
+            let source = match &anno.chapter {
+                Some(chapter) => format!("{} · {}", title, chapter),
+                None => title.to_string(),
+            };
+            back.push_str(&format!("<small>{}</small>", anki_field(&source)));
+            let tags: Vec<String> = anno
+                .tags
+                .iter()
+                .map(|tag| tag.split_whitespace().collect::<Vec<_>>().join("_"))
+                .collect();
+
+            let guid = anno.id.as_deref().unwrap_or(&anno.datetime);
+            let fields = [guid, &deck, &anki_field(text), &back, &tags.join(" ")]
+                .map(|field| field.replace(['\t', '\n', '\r'], " "));
+            out.push_str(&fields.join("\t"));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Text as the HTML of an Anki field, on one line.
+fn anki_field(text: &str) -> String {
+    escape_html(text.trim())
+        .replace("\r\n", "\n")
+        .replace('\n', "<br>")
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Markdown for Obsidian: YAML frontmatter with the book's metadata, and a
+/// block ID per highlight so notes elsewhere in the vault can link to it.
+pub fn annotations_obsidian(
+    document: &str,
+    metadata: &DocumentMetadata,
+    annotations: &[Annotation],
+) -> String {
+    let title = metadata.title.as_deref().unwrap_or(document);
+    // JSON strings are valid YAML double-quoted scalars
+    let quote = |value: &str| serde_json::to_string(value).unwrap_or_default();
+
+    let mut out = String::from("---\n");
+    out.push_str(&format!("title: {}\n", quote(title)));
+    if let Some(author) = &metadata.author {
+        out.push_str(&format!("author: {}\n", quote(author)));
+    }
+    if let Some(isbn) = &metadata.isbn {
+        out.push_str(&format!("isbn: {}\n", quote(isbn)));
+    }
+    out.push_str(&format!("kosync_document: {}\n", quote(document)));
+    out.push_str("tags:\n  - highlights\n---\n\n");
+    out.push_str(&format!("# {}\n", title));
+    push_highlights(&mut out, metadata, annotations, true);
+    out
+}
+
+/// A Joplin export (JEX) archive holding a "KOReader" notebook with one
+/// note for the document.
+///
+/// Ids are derived from the document hash, so importing a newer export
+/// updates the existing note rather than adding a copy.
+pub fn annotations_jex(
+    document: &str,
+    metadata: &DocumentMetadata,
+    annotations: &[Annotation],
+    updated_at: i64,
+) -> Vec<u8> {
+    let folder_id = format!("{:x}", md5::compute("kosync:joplin:folder"));
+    let note_id = format!("{:x}", md5::compute(format!("kosync:joplin:{}", document)));
+    let time = iso_timestamp(updated_at);
+
+    let folder = format!(
+        "KOReader\n\nid: {}\ncreated_time: {time}\nupdated_time: {time}\n\
+         user_created_time: {time}\nuser_updated_time: {time}\nparent_id: \ntype_: 2",
+        folder_id
+    );
+
+    let mut body = String::new();
+    push_highlights(&mut body, metadata, annotations, false);
+    let note = format!(
+        "{}\n\n{}\n\nid: {}\nparent_id: {}\ncreated_time: {time}\nupdated_time: {time}\n\
+         user_created_time: {time}\nuser_updated_time: {time}\nauthor: {}\n\
+         source_application: kosync\nmarkup_language: 1\ntype_: 1",
+        metadata.title.as_deref().unwrap_or(document),
+        body.trim(),
+        note_id,
+        folder_id,
+        metadata.author.as_deref().unwrap_or_default(),
+    );
+
+    let mut archive = tar::Builder::new(Vec::new());
+    for (id, content) in [(folder_id, folder), (note_id, note)] {
+        let mut header = tar::Header::new_ustar();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(updated_at.max(0) as u64);
+        header.set_cksum();
+        archive
+            .append_data(&mut header, format!("{}.md", id), content.as_bytes())
+            .expect("writing to memory cannot fail");
+    }
+    archive.into_inner().expect("writing to memory cannot fail")
+}
+
+/// A highlight or note as an entry in the highlights feed.
+pub struct FeedEntry<'a> {
+    pub document: &'a str,
+    pub title: &'a str,
+    pub annotation: &'a Annotation,
+}
+
+/// An Atom feed of highlights and notes, entries given newest first.
+/// `updated` stands in for the newest entry's time when there are none.
+pub fn annotations_atom(feed_id: &str, entries: &[FeedEntry], updated: i64) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    out.push_str("<title>Highlights and notes</title>\n");
+    out.push_str(&format!(
+        "<id>urn:kosync:feed:{}</id>\n",
+        escape_html(feed_id)
+    ));
+    let updated = entries
+        .first()
+        .map(|e| atom_datetime(e.annotation))
+        .unwrap_or_else(|| iso_timestamp(updated));
+    out.push_str(&format!("<updated>{}</updated>\n", updated));
+    out.push_str("<author><name>KOReader</name></author>\n");
+
+    for entry in entries {
+        let anno = entry.annotation;
+        let id = anno.id.as_deref().unwrap_or(&anno.datetime);
+        let title = match &anno.chapter {
+            Some(chapter) => format!("{} · {}", entry.title, chapter),
+            None => entry.title.to_string(),
+        };
+        let mut content = String::new();
+        if let Some(text) = anno.text.as_deref().filter(|t| !t.trim().is_empty()) {
+            content.push_str(&format!(
+                "<blockquote>{}</blockquote>",
+                escape_html(text.trim()).replace('\n', "<br>")
+            ));
+        }
+        if let Some(note) = anno.note.as_deref().filter(|n| !n.trim().is_empty()) {
+            content.push_str(&format!(
+                "<p>{}</p>",
+                escape_html(note.trim()).replace('\n', "<br>")
+            ));
+        }
+        out.push_str("<entry>\n");
+        out.push_str(&format!("<title>{}</title>\n", escape_html(&title)));
+        out.push_str(&format!(
+            "<id>urn:kosync:annotation:{}:{}</id>\n",
+            escape_html(entry.document),
+            escape_html(id)
+        ));
+        out.push_str(&format!("<updated>{}</updated>\n", atom_datetime(anno)));
+        out.push_str(&format!(
+            "<content type=\"html\">{}</content>\n",
+            escape_html(&content)
+        ));
+        out.push_str("</entry>\n");
+    }
+    out.push_str("</feed>\n");
+    out
+}
+
+/// KOReader's `YYYY-MM-DD HH:MM:SS` as RFC 3339. The device's time zone
+/// isn't recorded, so it is taken as UTC.
+fn atom_datetime(anno: &Annotation) -> String {
+    let datetime = anno.datetime_updated.as_deref().unwrap_or(&anno.datetime);
+    let date = datetime
+        .get(..10)
+        .filter(|date| streaks::parse_date(date).is_some())
+        .unwrap_or("1970-01-01");
+    let time = datetime
+        .get(11..19)
+        .filter(|time| {
+            time.bytes().enumerate().all(|(i, b)| {
+                if i % 3 == 2 {
+                    b == b':'
+                } else {
+                    b.is_ascii_digit()
+                }
+            })
+        })
+        .unwrap_or("00:00:00");
+    format!("{}T{}Z", date, time)
+}
+
+/// `YYYY-MM-DDTHH:MM:SS.000Z`, as Joplin writes timestamps.
+fn iso_timestamp(timestamp: i64) -> String {
+    let seconds = timestamp.rem_euclid(86400);
+    format!(
+        "{}T{:02}:{:02}:{:02}.000Z",
+        streaks::local_date(timestamp, 0),
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// The author line and highlights, with a heading per chapter.
+fn push_highlights(
+    out: &mut String,
+    metadata: &DocumentMetadata,
+    annotations: &[Annotation],
+    block_ids: bool,
+) {
+    if let Some(author) = &metadata.author {
+        out.push_str(&format!("\n*{}*\n", author));
+    }
+
+    let mut chapter: Option<&str> = None;
+    for anno in reading_order(annotations) {
+        if anno.chapter.is_some() && anno.chapter.as_deref() != chapter {
+            chapter = anno.chapter.as_deref();
+            out.push_str(&format!("\n## {}\n", chapter.unwrap_or_default()));
+        }
+        out.push('\n');
+        let block_id = anno.id.as_deref().filter(|_| block_ids);
+        push_annotation_markdown(out, anno, block_id);
+    }
+}
+
+fn push_annotation_markdown(out: &mut String, anno: &Annotation, block_id: Option<&str>) {
+    match anno.text.as_deref().filter(|t| !t.trim().is_empty()) {
+        Some(text) => {
+            for line in text.trim().lines() {
+                out.push('>');
+                if !line.is_empty() {
+                    out.push(' ');
+                    out.push_str(line);
+                }
+                out.push('\n');
+            }
+        }
+        None => match anno.pageno {
+            Some(page) => out.push_str(&format!("*Bookmark, page {}*\n", page)),
+            None => out.push_str("*Bookmark*\n"),
+        },
+    }
+    if let Some(id) = block_id {
+        // Obsidian block IDs go at the end of the block's last line
+        out.pop();
+        out.push_str(&format!(" ^{}\n", id));
+    }
+    if let Some(note) = anno.note.as_deref().filter(|n| !n.trim().is_empty()) {
+        out.push('\n');
+        out.push_str(note.trim());
+        out.push('\n');
+    }
+    if !anno.tags.is_empty() {
+        // Hashtags end at whitespace
+        let tags: Vec<String> = anno
+            .tags
+            .iter()
+            .map(|tag| format!("#{}", tag.split_whitespace().collect::<Vec<_>>().join("-")))
+            .collect();
+        out.push('\n');
+        out.push_str(&tags.join(" "));
+        out.push('\n');
+    }
+}
+
+fn write_record<'a>(out: &mut String, fields: impl IntoIterator<Item = &'a str>) {
+    for (i, field) in fields.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        push_field(out, field);
+    }
+    out.push_str("\r\n");
+}
+
+/// Quote a field if it contains a separator, quote or line break.
+fn push_field(out: &mut String, field: &str) {
+    if field.contains([',', '"', '\r', '\n']) {
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(field);
+    }
+}
@@ -0,0 +1,102 @@
+//! Leader/follower replication over HTTP.
+//!
+//! A follower (`KOSYNC_LEADER_URL` set) polls its leader's
+//! `GET /admin/journal` for the changes after the last one it has, and
+//! applies them with `Database::apply_journal`: each change comes with the
+//! value its key holds on the leader, so applying one is writing or
+//! removing that key. Followers serve reads and refuse writes, and keep
+//! the leader's journal as their own, sequence numbers included, so one
+//! can be promoted by restarting it without `KOSYNC_LEADER_URL`.
+//!
+//! A new follower starts from a backup of the leader (see
+//! `Database::backup`), which carries the journal's sequence number, or
+//! from an empty database while the leader still has its whole journal.
+
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::error::{AppError, Result};
+use crate::models::JournalResponse;
+use crate::AppState;
+
+/// Changes asked for per request.
+const BATCH_SIZE: usize = 1000;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The leader a follower pulls changes from.
+pub struct Leader {
+    url: String,
+    username: String,
+    key: String,
+    client: reqwest::Client,
+}
+
+impl Leader {
+    /// The leader configured with `KOSYNC_LEADER_*`, if this server is a
+    /// follower.
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let Some(url) = &config.leader_url else {
+            return Ok(None);
+        };
+        let (Some(username), Some(key)) = (&config.leader_username, &config.leader_key) else {
+            return Err(AppError::InvalidRequest(
+                "KOSYNC_LEADER_URL needs KOSYNC_LEADER_USER and KOSYNC_LEADER_KEY".into(),
+            ));
+        };
+        Ok(Some(Self {
+            url: url.trim_end_matches('/').to_string(),
+            username: username.clone(),
+            key: key.clone(),
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("failed to build leader HTTP client"),
+        }))
+    }
+
+    /// Apply every change the leader has that this server doesn't.
+    /// Returns how many were applied.
+    pub async fn pull(&self, state: &AppState) -> Result<usize> {
+        let mut applied = 0;
+        loop {
+            let after = state.with_db(|db| db.journal_sequence())?;
+            let response = self.fetch(after).await?;
+            let Some(last) = response.changes.last() else {
+                if response.sequence > after {
+                    return Err(AppError::Upstream(format!(
+                        "the leader no longer has the changes after {}; \
+                         start again from a backup of it",
+                        after
+                    )));
+                }
+                return Ok(applied);
+            };
+            let caught_up = last.entry.sequence >= response.sequence;
+            state.with_db(|db| db.apply_journal(&response.changes))?;
+            applied += response.changes.len();
+            if caught_up {
+                return Ok(applied);
+            }
+        }
+    }
+
+    async fn fetch(&self, after: u64) -> Result<JournalResponse> {
+        let leader_error = |e: reqwest::Error| AppError::Upstream(format!("leader: {}", e));
+        let response = self
+            .client
+            .get(format!("{}/admin/journal", self.url))
+            .query(&[("after", after), ("limit", BATCH_SIZE as u64)])
+            .header("x-auth-user", &self.username)
+            .header("x-auth-key", &self.key)
+            .send()
+            .await
+            .map_err(leader_error)?;
+        if !response.status().is_success() {
+            return Err(AppError::Upstream(format!(
+                "leader: HTTP {}",
+                response.status()
+            )));
+        }
+        response.json().await.map_err(leader_error)
+    }
+}
@@ -1,12 +1,42 @@
 use axum::{
-    extract::{Path, State},
-    http::{HeaderMap, StatusCode},
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{
+        header::{
+            CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MATCH, IF_MODIFIED_SINCE,
+            IF_NONE_MATCH, LAST_MODIFIED,
+        },
+        HeaderMap, StatusCode,
+    },
+    response::{Html, IntoResponse, Response},
     Json,
 };
+use futures_util::stream;
 use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
 
+use crate::backup;
+use crate::calibre_annotations;
+use crate::calibre_web;
+use crate::config::{Config, PercentageMode};
+use crate::db::ProgressWrite;
 use crate::error::{AppError, Result};
+use crate::export;
+use crate::hardcover;
+use crate::kindle_clippings;
+use crate::koreader_metadata;
+use crate::merge::{self, MergeOptions};
+use crate::metrics;
 use crate::models::*;
+use crate::outbound;
+use crate::position;
+use crate::quota;
+use crate::readwise;
+use crate::search;
+use crate::streaks::{self, Activity};
+use crate::style;
 use crate::AppState;
 
 // === Auth helpers ===
@@ -27,15 +57,78 @@ fn extract_auth(headers: &HeaderMap) -> Result<(&str, &str)> {
     Ok((user, key))
 }
 
-fn authorize(state: &AppState, headers: &HeaderMap) -> Result<String> {
+pub(crate) fn authorize(state: &AppState, headers: &HeaderMap) -> Result<String> {
     let (user, key) = extract_auth(headers)?;
-    if state.db.verify_user(user, key)? {
+    if state.config.is_demo_user(user) || state.with_db(|db| db.verify_user(user, key))? {
+        metrics::authenticated(user);
         Ok(user.to_string())
     } else {
         Err(AppError::Unauthorized)
     }
 }
 
+fn authorize_admin(state: &AppState, headers: &HeaderMap) -> Result<String> {
+    let username = authorize(state, headers)?;
+    if state.config.is_admin(&username) {
+        Ok(username)
+    } else {
+        Err(AppError::Forbidden)
+    }
+}
+
+// === Conditional request helpers ===
+
+fn unix_to_system_time(timestamp: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64)
+}
+
+/// Evaluate `If-None-Match` / `If-Modified-Since` against the current
+/// representation. `If-None-Match` takes precedence when both are sent.
+fn is_not_modified(headers: &HeaderMap, etag: &str, last_modified: SystemTime) -> bool {
+    if let Some(value) = headers.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        return value
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+    headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+        .is_some_and(|since| last_modified <= since)
+}
+
+/// The ETag of a document's annotations: their version.
+fn annotations_etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// The `base_version` an `If-Match` header asks for, reconciled with the
+/// one in the body. `If-Match: *` sets no condition.
+fn if_match_version(headers: &HeaderMap, base_version: Option<u64>) -> Result<Option<u64>> {
+    let Some(value) = headers.get(IF_MATCH) else {
+        return Ok(base_version);
+    };
+    let value = value
+        .to_str()
+        .map_err(|_| AppError::InvalidRequest("invalid If-Match".into()))?
+        .trim();
+    if value == "*" {
+        return Ok(base_version);
+    }
+    let version = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or_else(|| AppError::InvalidRequest("If-Match must be one annotation ETag".into()))?;
+    if base_version.is_some_and(|base| base != version) {
+        return Err(AppError::InvalidRequest(
+            "If-Match and base_version disagree".into(),
+        ));
+    }
+    Ok(Some(version))
+}
+
 // === User endpoints ===
 
 pub async fn create_user(
@@ -48,8 +141,11 @@ pub async fn create_user(
     if req.password.is_empty() {
         return Err(AppError::InvalidRequest("invalid password".into()));
     }
+    if state.config.is_demo_user(&req.username) {
+        return Err(AppError::UserExists);
+    }
 
-    if state.db.create_user(&req.username, &req.password)? {
+    if state.with_db(|db| db.create_user(&req.username, &req.password))? {
         Ok((
             StatusCode::CREATED,
             Json(CreateUserResponse {
@@ -69,90 +165,2704 @@ pub async fn auth_user(
     Ok(Json(AuthResponse { authorized: "OK" }))
 }
 
+pub async fn get_settings(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<UserSettings>> {
+    let username = authorize(&state, &headers)?;
+    Ok(Json(state.with_db(|db| db.get_user_settings(&username))?))
+}
+
+pub async fn update_settings(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(changes): Json<UserSettings>,
+) -> Result<Json<UserSettings>> {
+    let username = authorize(&state, &headers)?;
+    Ok(Json(state.with_db(|db| {
+        db.update_user_settings(&username, &changes)
+    })?))
+}
+
 // === Progress endpoints (legacy KOSync) ===
 
 pub async fn get_progress(
     State(state): State<AppState>,
     headers: HeaderMap,
     Path(document): Path<String>,
-) -> Result<Json<Progress>> {
+    Query(query): Query<ProgressQuery>,
+) -> Result<Response> {
     let username = authorize(&state, &headers)?;
 
     if document.is_empty() || document.contains(':') {
         return Err(AppError::DocumentMissing);
     }
 
-    let progress = state.db.get_progress(&username, &document)?;
-    Ok(Json(progress))
+    let progress = match &query.device_id {
+        Some(device_id) => {
+            state.with_db(|db| db.get_device_progress(&username, &document, device_id))?
+        }
+        None => state.with_db(|db| db.get_progress(&username, &document))?,
+    };
+    let Some(timestamp) = progress.timestamp else {
+        if query.not_found.unwrap_or(state.config.missing_progress_404) {
+            return Err(AppError::ProgressNotFound);
+        }
+        return Ok(Json(progress).into_response());
+    };
+
+    // The tag covers the body as well as the timestamp, so two updates
+    // within the same second still produce different tags.
+    let body = serde_json::to_vec(&progress)?;
+    let etag = format!("\"{}-{:x}\"", timestamp, md5::compute(&body));
+    let last_modified = unix_to_system_time(timestamp);
+    let validators = [
+        (ETAG, etag.clone()),
+        (LAST_MODIFIED, httpdate::fmt_http_date(last_modified)),
+    ];
+
+    if is_not_modified(&headers, &etag, last_modified) {
+        return Ok((StatusCode::NOT_MODIFIED, validators).into_response());
+    }
+    Ok((validators, Json(progress)).into_response())
+}
+
+pub async fn list_progress(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ProgressListQuery>,
+) -> Result<Json<ProgressListResponse>> {
+    let username = authorize(&state, &headers)?;
+    let documents = query.documents.map(|documents| {
+        documents
+            .split(',')
+            .map(str::trim)
+            .filter(|d| !d.is_empty())
+            .map(String::from)
+            .collect()
+    });
+    progress_listing(&state, &username, documents).map(Json)
+}
+
+/// Same as `GET /syncs/progress?documents=`, for lists too long for a URL.
+pub async fn query_progress(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<ProgressQueryRequest>,
+) -> Result<Json<ProgressListResponse>> {
+    let username = authorize(&state, &headers)?;
+    progress_listing(&state, &username, Some(req.documents)).map(Json)
+}
+
+/// Maximum number of documents in one multi-document progress query.
+const MAX_PROGRESS_QUERY: usize = 1000;
+
+fn progress_listing(
+    state: &AppState,
+    username: &str,
+    documents: Option<Vec<String>>,
+) -> Result<ProgressListResponse> {
+    let progress = match documents {
+        Some(documents) => {
+            if documents.len() > MAX_PROGRESS_QUERY {
+                return Err(AppError::InvalidRequest(format!(
+                    "query exceeds {} documents",
+                    MAX_PROGRESS_QUERY
+                )));
+            }
+            if documents.iter().any(|d| d.is_empty() || d.contains(':')) {
+                return Err(AppError::DocumentMissing);
+            }
+            state.with_db(|db| db.get_progress_many(username, &documents))?
+        }
+        None => state.with_db(|db| db.list_progress(username))?,
+    };
+
+    let mut metadata = state.with_db(|db| db.list_metadata(username))?;
+    let documents = progress
+        .into_iter()
+        .map(|progress| ProgressListEntry {
+            metadata: progress
+                .document
+                .as_ref()
+                .and_then(|document| metadata.remove(document)),
+            progress,
+        })
+        .collect();
+    Ok(ProgressListResponse { documents })
+}
+
+const DEFAULT_CONTINUE_LIMIT: usize = 10;
+const MAX_CONTINUE_LIMIT: usize = 100;
+
+/// The most recently read documents that aren't finished, newest first.
+pub async fn continue_reading(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ContinueReadingQuery>,
+) -> Result<Json<ProgressListResponse>> {
+    let username = authorize(&state, &headers)?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_CONTINUE_LIMIT)
+        .min(MAX_CONTINUE_LIMIT);
+
+    let mut progress: Vec<Progress> = state
+        .with_db(|db| db.list_progress(&username))?
+        .into_iter()
+        .filter(|p| p.percentage.unwrap_or(0.0) < state.config.finished_threshold)
+        .collect();
+    progress.sort_by_key(|p| std::cmp::Reverse(p.timestamp));
+    progress.truncate(limit);
+
+    let mut metadata = state.with_db(|db| db.list_metadata(&username))?;
+    let documents = progress
+        .into_iter()
+        .map(|progress| ProgressListEntry {
+            metadata: progress
+                .document
+                .as_ref()
+                .and_then(|document| metadata.remove(document)),
+            progress,
+        })
+        .collect();
+    Ok(Json(ProgressListResponse { documents }))
+}
+
+pub async fn export_progress(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response> {
+    let username = authorize(&state, &headers)?;
+    let mut metadata = state.with_db(|db| db.list_metadata(&username))?;
+    let rows: Vec<ProgressExportRow> = state
+        .with_db(|db| db.list_progress(&username))?
+        .into_iter()
+        .map(|progress| {
+            let document = progress.document.unwrap_or_default();
+            let metadata = metadata.remove(&document).unwrap_or_default();
+            ProgressExportRow {
+                document,
+                title: metadata.title,
+                author: metadata.author,
+                progress: progress.progress,
+                percentage: progress.percentage,
+                device: progress.device,
+                timestamp: progress.timestamp,
+            }
+        })
+        .collect();
+
+    Ok(match query.format {
+        ExportFormat::Json => (
+            [(
+                CONTENT_DISPOSITION,
+                "attachment; filename=\"progress.json\"",
+            )],
+            Json(rows),
+        )
+            .into_response(),
+        ExportFormat::Csv => (
+            [
+                (CONTENT_TYPE, "text/csv; charset=utf-8"),
+                (CONTENT_DISPOSITION, "attachment; filename=\"progress.csv\""),
+            ],
+            export::progress_csv(&rows),
+        )
+            .into_response(),
+    })
 }
 
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
 pub async fn update_progress(
     State(state): State<AppState>,
     headers: HeaderMap,
-    Json(req): Json<UpdateProgressRequest>,
+    Json(mut req): Json<UpdateProgressRequest>,
 ) -> Result<Json<UpdateProgressResponse>> {
     let username = authorize(&state, &headers)?;
+    validate_progress(&state.config, &mut req)?;
+
+    let idempotency_key = headers
+        .get("idempotency-key")
+        .map(|v| v.to_str().unwrap_or_default())
+        .map(|key| {
+            if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+                Err(AppError::InvalidRequest("invalid Idempotency-Key".into()))
+            } else {
+                Ok(key)
+            }
+        })
+        .transpose()?;
+
+    let written = match idempotency_key {
+        Some(key) => {
+            let (written, replayed) =
+                state.with_db(|db| db.set_progress_idempotent(&username, &req, key))?;
+            if !replayed {
+                publish_progress(&state, &username, &req, &written);
+            }
+            written
+        }
+        None => {
+            let written = state.with_db(|db| db.set_progress(&username, &req))?;
+            publish_progress(&state, &username, &req, &written);
+            written
+        }
+    };
+
+    Ok(Json(UpdateProgressResponse {
+        document: req.document,
+        timestamp: written.timestamp,
+        version: written.version,
+    }))
+}
+
+fn publish_progress(
+    state: &AppState,
+    username: &str,
+    update: &UpdateProgressRequest,
+    written: &ProgressWrite,
+) {
+    state.events.publish(
+        username,
+        SyncEvent::progress(update, &written.document, written.timestamp),
+    );
+    if written.finished {
+        state.events.publish(
+            username,
+            SyncEvent::Finished {
+                document: written.document.clone(),
+                percentage: update.percentage,
+                timestamp: written.timestamp,
+            },
+        );
+    }
+}
+
+/// Maximum number of entries accepted by the batch progress endpoint.
+const MAX_PROGRESS_BATCH: usize = 1000;
+
+pub async fn update_progress_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(mut updates): Json<Vec<UpdateProgressRequest>>,
+) -> Result<Json<BatchProgressResponse>> {
+    let username = authorize(&state, &headers)?;
+
+    if updates.len() > MAX_PROGRESS_BATCH {
+        return Err(AppError::InvalidRequest(format!(
+            "batch exceeds {} entries",
+            MAX_PROGRESS_BATCH
+        )));
+    }
+
+    let mut checked: Vec<Result<i64>> = updates
+        .iter_mut()
+        .map(|update| validate_progress(&state.config, update).map(|()| 0))
+        .collect();
+    let valid: Vec<&UpdateProgressRequest> = updates
+        .iter()
+        .zip(&checked)
+        .filter(|(_, check)| check.is_ok())
+        .map(|(update, _)| update)
+        .collect();
+
+    if !valid.is_empty() {
+        let stored = state.with_db(|db| db.set_progress_batch(&username, &valid))?;
+        for (update, result) in valid.iter().zip(&stored) {
+            if let Ok(written) = result {
+                publish_progress(&state, &username, update, written);
+            }
+        }
+        let mut stored = stored.into_iter();
+        for check in checked.iter_mut().filter(|check| check.is_ok()) {
+            *check = stored.next().unwrap().map(|written| written.timestamp);
+        }
+    }
+
+    let results = updates
+        .into_iter()
+        .zip(checked)
+        .map(|(update, result)| match result {
+            Ok(timestamp) => BatchProgressResult {
+                document: update.document,
+                timestamp: Some(timestamp),
+                error: None,
+            },
+            Err(e) => BatchProgressResult {
+                document: update.document,
+                timestamp: None,
+                error: Some(e.to_error_response()),
+            },
+        })
+        .collect();
+
+    Ok(Json(BatchProgressResponse { results }))
+}
 
+/// Check a progress update, clamping its percentage into `[0, 1]` when the
+/// server runs in lenient mode.
+fn validate_progress(config: &Config, req: &mut UpdateProgressRequest) -> Result<()> {
     if req.document.is_empty() || req.document.contains(':') {
         return Err(AppError::DocumentMissing);
     }
+    if req
+        .alt_document
+        .as_ref()
+        .is_some_and(|alt| alt.is_empty() || alt.contains(':'))
+    {
+        return Err(AppError::InvalidRequest("invalid alt_document".into()));
+    }
     if req.progress.is_empty() || req.device.is_empty() {
         return Err(AppError::InvalidRequest("missing required fields".into()));
     }
+    req.percentage = check_percentage(config.percentage_mode, req.percentage)?;
+    Ok(())
+}
 
-    let timestamp = state.db.set_progress(
-        &username,
-        &req.document,
-        &req.progress,
-        req.percentage,
-        &req.device,
-        req.device_id.as_deref(),
-    )?;
+fn check_percentage(mode: PercentageMode, percentage: f64) -> Result<f64> {
+    if !percentage.is_finite() {
+        return Err(AppError::InvalidPercentage(percentage));
+    }
+    match mode {
+        PercentageMode::Strict if !(0.0..=1.0).contains(&percentage) => {
+            Err(AppError::InvalidPercentage(percentage))
+        }
+        PercentageMode::Strict => Ok(percentage),
+        PercentageMode::Lenient => Ok(percentage.clamp(0.0, 1.0)),
+    }
+}
 
-    Ok(Json(UpdateProgressResponse {
-        document: req.document,
-        timestamp,
-    }))
+pub async fn delete_progress(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<Json<DeleteProgressResponse>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let deleted = state.with_db(|db| db.delete_progress(&username, &document))?;
+    if deleted {
+        state.events.publish(
+            &username,
+            SyncEvent::ProgressDeleted {
+                document: document.clone(),
+            },
+        );
+    }
+    Ok(Json(DeleteProgressResponse { document, deleted }))
 }
 
-// === Annotations endpoints (extended API) ===
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+const MAX_HISTORY_LIMIT: usize = 1000;
 
-pub async fn get_annotations(
+pub async fn get_progress_history(
     State(state): State<AppState>,
     headers: HeaderMap,
     Path(document): Path<String>,
-) -> Result<Json<DocumentAnnotations>> {
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<ProgressHistoryResponse>> {
     let username = authorize(&state, &headers)?;
 
     if document.is_empty() || document.contains(':') {
         return Err(AppError::DocumentMissing);
     }
 
-    let annotations = state.db.get_annotations(&username, &document)?;
-    Ok(Json(annotations))
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .min(MAX_HISTORY_LIMIT);
+    let history = state.with_db(|db| db.get_progress_history(&username, &document, limit))?;
+    Ok(Json(ProgressHistoryResponse { document, history }))
 }
 
-pub async fn update_annotations(
+// === Document metadata ===
+
+/// Upper bound on any single metadata field, in bytes.
+const MAX_METADATA_FIELD_LEN: usize = 1024;
+
+/// Star ratings by document, for annotating finished-book listings.
+fn ratings(state: &AppState, username: &str) -> Result<HashMap<String, u8>> {
+    Ok(state
+        .with_db(|db| db.list_reviews(username))?
+        .into_iter()
+        .filter_map(|review| Some((review.document, review.rating?)))
+        .collect())
+}
+
+/// Maximum size of a review, in bytes.
+const MAX_REVIEW_LEN: usize = 64 * 1024;
+
+pub async fn get_review(
     State(state): State<AppState>,
     headers: HeaderMap,
     Path(document): Path<String>,
-    Json(req): Json<UpdateAnnotationsRequest>,
-) -> Result<Json<UpdateAnnotationsResponse>> {
+) -> Result<Json<Option<Review>>> {
     let username = authorize(&state, &headers)?;
 
     if document.is_empty() || document.contains(':') {
         return Err(AppError::DocumentMissing);
     }
 
-    let (version, timestamp) = state.db.update_annotations(
-        &username,
-        &document,
-        req.annotations,
-        req.deleted,
-        req.base_version,
-    )?;
+    Ok(Json(
+        state.with_db(|db| db.get_review(&username, &document))?,
+    ))
+}
+
+pub async fn update_review(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+    Json(req): Json<UpdateReviewRequest>,
+) -> Result<Json<Review>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+    if req.rating.is_some_and(|r| !(1..=5).contains(&r)) {
+        return Err(AppError::InvalidRequest("rating must be 1-5".into()));
+    }
+    let review = req.review.filter(|text| !text.trim().is_empty());
+    if review
+        .as_ref()
+        .is_some_and(|text| text.len() > MAX_REVIEW_LEN)
+    {
+        return Err(AppError::InvalidRequest("review too long".into()));
+    }
+    if req.rating.is_none() && review.is_none() {
+        return Err(AppError::InvalidRequest(
+            "rating or review is required".into(),
+        ));
+    }
+
+    let review = state.with_db(|db| db.set_review(&username, &document, req.rating, review))?;
+    Ok(Json(review))
+}
+
+pub async fn delete_review(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    state.with_db(|db| db.delete_review(&username, &document))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_reviews(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<ReviewListResponse>> {
+    let username = authorize(&state, &headers)?;
+    let mut metadata = state.with_db(|db| db.list_metadata(&username))?;
+
+    let reviews = state
+        .with_db(|db| db.list_reviews(&username))?
+        .into_iter()
+        .map(|mut review| {
+            review.metadata = metadata.remove(&review.document);
+            review
+        })
+        .collect();
+    Ok(Json(ReviewListResponse { reviews }))
+}
+
+pub async fn export_reviews(State(state): State<AppState>, headers: HeaderMap) -> Result<Response> {
+    let username = authorize(&state, &headers)?;
+    let mut metadata = state.with_db(|db| db.list_metadata(&username))?;
+
+    let mut reviews = state.with_db(|db| db.list_reviews(&username))?;
+    for review in &mut reviews {
+        review.metadata = metadata.remove(&review.document);
+    }
+
+    Ok((
+        [
+            (CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+            (
+                CONTENT_DISPOSITION,
+                "attachment; filename=\"reviews.csv\"".to_string(),
+            ),
+        ],
+        export::reviews_csv(&reviews),
+    )
+        .into_response())
+}
+
+pub async fn get_status(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<Json<Option<DocumentStatus>>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    Ok(Json(
+        state.with_db(|db| db.get_status(&username, &document))?,
+    ))
+}
+
+pub async fn update_status(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+    Json(req): Json<UpdateStatusRequest>,
+) -> Result<Json<DocumentStatus>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let status = state.with_db(|db| db.set_status(&username, &document, Some(req.status)))?;
+    Ok(Json(status.expect("status was set")))
+}
+
+pub async fn delete_status(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    state.with_db(|db| db.set_status(&username, &document, None))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_status(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<StatusQuery>,
+) -> Result<Json<StatusListResponse>> {
+    let username = authorize(&state, &headers)?;
+    let mut metadata = state.with_db(|db| db.list_metadata(&username))?;
+
+    let documents = state
+        .with_db(|db| db.list_status(&username))?
+        .into_iter()
+        .filter(|record| query.status.is_none_or(|status| status == record.status))
+        .map(|mut record| {
+            record.metadata = metadata.remove(&record.document);
+            record
+        })
+        .collect();
+    Ok(Json(StatusListResponse { documents }))
+}
+
+const MAX_TAGS: usize = 100;
+const MAX_TAG_LEN: usize = 64;
+
+pub async fn get_tags(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<Json<DocumentTags>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    Ok(Json(state.with_db(|db| db.get_tags(&username, &document))?))
+}
+
+pub async fn update_tags(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+    Json(req): Json<UpdateTagsRequest>,
+) -> Result<Json<DocumentTags>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let tags = normalize_tags(&req.tags)?;
+    Ok(Json(
+        state.with_db(|db| db.set_tags(&username, &document, tags))?,
+    ))
+}
+
+/// Trimmed, sorted and deduplicated tags, for documents and annotations
+/// alike.
+fn normalize_tags(tags: &[String]) -> Result<Vec<String>> {
+    let mut tags: Vec<String> = tags
+        .iter()
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    if tags.len() > MAX_TAGS {
+        return Err(AppError::InvalidRequest("too many tags".into()));
+    }
+    // Tags are part of the index key, so they can't contain its separator
+    if tags
+        .iter()
+        .any(|tag| tag.len() > MAX_TAG_LEN || tag.contains(':'))
+    {
+        return Err(AppError::InvalidRequest(
+            "tags must be at most 64 bytes and cannot contain ':'".into(),
+        ));
+    }
+    Ok(tags)
+}
+
+pub async fn list_documents(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<DocumentListQuery>,
+) -> Result<Json<DocumentListResponse>> {
+    let username = authorize(&state, &headers)?;
+    let tag = query.tag.as_deref().map(str::trim);
+    if tag.is_some_and(|tag| tag.contains(':')) {
+        return Ok(Json(DocumentListResponse {
+            documents: Vec::new(),
+        }));
+    }
+    let mut metadata = state.with_db(|db| db.list_metadata(&username))?;
+
+    let documents = state
+        .with_db(|db| db.list_tagged(&username, tag))?
+        .into_iter()
+        .map(|mut document| {
+            document.metadata = metadata.remove(&document.document);
+            document
+        })
+        .collect();
+    Ok(Json(DocumentListResponse { documents }))
+}
+
+/// Maximum size of a document note, in bytes.
+const MAX_NOTE_LEN: usize = 64 * 1024;
+
+pub async fn get_note(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<Json<DocumentNote>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
 
-    Ok(Json(UpdateAnnotationsResponse { version, timestamp }))
+    Ok(Json(state.with_db(|db| db.get_note(&username, &document))?))
+}
+
+pub async fn update_note(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+    Json(req): Json<UpdateNoteRequest>,
+) -> Result<Json<DocumentNote>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+    if req.text.len() > MAX_NOTE_LEN {
+        return Err(AppError::InvalidRequest("note too long".into()));
+    }
+
+    let note =
+        state.with_db(|db| db.set_note(&username, &document, &req.text, req.base_version))?;
+    Ok(Json(note))
+}
+
+pub async fn get_metadata(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<Json<DocumentMetadata>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let metadata = state.with_db(|db| db.get_metadata(&username, &document))?;
+    Ok(Json(metadata.unwrap_or_default()))
+}
+
+pub async fn update_metadata(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+    Json(metadata): Json<DocumentMetadata>,
+) -> Result<Json<DocumentMetadata>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+    if [
+        &metadata.title,
+        &metadata.author,
+        &metadata.series,
+        &metadata.language,
+        &metadata.isbn,
+    ]
+    .into_iter()
+    .flatten()
+    .any(|field| field.len() > MAX_METADATA_FIELD_LEN)
+    {
+        return Err(AppError::InvalidRequest("metadata field too long".into()));
+    }
+
+    let stored = state.with_db(|db| db.set_metadata(&username, &document, &metadata))?;
+    Ok(Json(stored))
+}
+
+// === Document aliases ===
+
+pub async fn create_aliases(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<CreateAliasesRequest>,
+) -> Result<Json<AliasListResponse>> {
+    let username = authorize(&state, &headers)?;
+
+    if std::iter::once(&req.document)
+        .chain(&req.aliases)
+        .any(|d| d.is_empty() || d.contains(':'))
+    {
+        return Err(AppError::DocumentMissing);
+    }
+
+    state.with_db(|db| db.add_aliases(&username, &req.document, &req.aliases))?;
+    let aliases = state.with_db(|db| db.list_aliases(&username))?;
+    Ok(Json(AliasListResponse { aliases }))
+}
+
+pub async fn list_aliases(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<AliasListResponse>> {
+    let username = authorize(&state, &headers)?;
+    let aliases = state.with_db(|db| db.list_aliases(&username))?;
+    Ok(Json(AliasListResponse { aliases }))
+}
+
+pub async fn delete_alias(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(alias): Path<String>,
+) -> Result<Json<AliasListResponse>> {
+    let username = authorize(&state, &headers)?;
+    state.with_db(|db| db.delete_alias(&username, &alias))?;
+    let aliases = state.with_db(|db| db.list_aliases(&username))?;
+    Ok(Json(AliasListResponse { aliases }))
+}
+
+// === Annotations endpoints (extended API) ===
+
+/// Largest page `GET /syncs/annotations/{document}` returns.
+const MAX_ANNOTATION_PAGE: usize = 500;
+
+/// Responses with more annotations than this are streamed one annotation at
+/// a time instead of being serialized into a single buffer.
+const STREAM_ANNOTATIONS_OVER: usize = 1000;
+
+/// Largest page of deletions returned by one fetch.
+const MAX_TOMBSTONE_PAGE: usize = 5000;
+
+pub async fn get_annotations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+    Query(query): Query<AnnotationsQuery>,
+) -> Result<Response> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let mut annotations = state.with_db(|db| db.get_annotations(&username, &document))?;
+    let version = annotations.version;
+    let etag = annotations_etag(version);
+    let last_modified = unix_to_system_time(annotations.updated_at);
+    if let Some(since) = query.since_version {
+        annotations = annotations.changes_since(since);
+    }
+    if let Some(tag) = &query.tag {
+        annotations.annotations.retain(|a| a.tags.contains(tag));
+    }
+    if query.limit.is_some() || query.cursor.is_some() {
+        let limit = query
+            .limit
+            .unwrap_or(MAX_ANNOTATION_PAGE)
+            .clamp(1, MAX_ANNOTATION_PAGE);
+        annotations = annotations.page(query.cursor.as_deref(), limit);
+    } else {
+        annotations.annotations.sort_by(position::reading_order);
+    }
+    if query.deleted_limit.is_some() || query.deleted_cursor.is_some() {
+        let limit = query
+            .deleted_limit
+            .unwrap_or(MAX_TOMBSTONE_PAGE)
+            .clamp(1, MAX_TOMBSTONE_PAGE);
+        annotations = annotations.page_deleted(query.deleted_cursor.as_deref(), limit);
+    }
+    // Only a complete fetch delivers every tombstone. A follower's marks
+    // would hold back nothing: only its leader prunes tombstones.
+    if let Some(device_id) = query.device_id.filter(|_| {
+        annotations.next_cursor.is_none()
+            && annotations.next_deleted_cursor.is_none()
+            && !state.config.is_follower()
+    }) {
+        // Worst case a tombstone is kept longer; not worth failing the read
+        match state
+            .with_db(|db| db.record_annotation_sync(&username, &document, &device_id, version))
+        {
+            Ok(()) | Err(AppError::ReadOnly(_)) => {}
+            Err(e) => tracing::warn!("Failed to record annotation sync: {}", e),
+        }
+    }
+
+    let validators = [
+        (ETAG, etag.clone()),
+        (LAST_MODIFIED, httpdate::fmt_http_date(last_modified)),
+    ];
+    if is_not_modified(&headers, &etag, last_modified) {
+        return Ok((StatusCode::NOT_MODIFIED, validators).into_response());
+    }
+    Ok((validators, annotations_response(annotations)?).into_response())
+}
+
+/// The annotations as JSON, sent with chunked encoding when there are many
+/// of them. A document's annotations are stored, cached and paged as one
+/// record, so the list itself is in memory either way; streaming only
+/// spares a second, serialized copy of it.
+fn annotations_response(mut annotations: DocumentAnnotations) -> Result<Response> {
+    if annotations.annotations.len() <= STREAM_ANNOTATIONS_OVER {
+        return Ok(Json(annotations).into_response());
+    }
+
+    // Serialize everything but the list, and send the list where it goes
+    let items = std::mem::take(&mut annotations.annotations);
+    let envelope = serde_json::to_string(&annotations)?;
+    let Some((head, tail)) = envelope.split_once("\"annotations\":[]") else {
+        annotations.annotations = items;
+        return Ok(Json(annotations).into_response());
+    };
+    let head = Bytes::from(format!("{}\"annotations\":[", head));
+    let tail = Bytes::from(format!("]{}", tail));
+
+    let items = items.into_iter().enumerate().map(|(i, annotation)| {
+        let mut chunk = Vec::new();
+        if i > 0 {
+            chunk.push(b',');
+        }
+        serde_json::to_writer(&mut chunk, &annotation)?;
+        Ok(Bytes::from(chunk))
+    });
+    // Each annotation is serialized only when the client is ready for it
+    let chunks = std::iter::once(Ok::<_, serde_json::Error>(head))
+        .chain(items)
+        .chain(std::iter::once(Ok(tail)));
+    let body = Body::from_stream(stream::iter(chunks));
+    Ok(([(CONTENT_TYPE, "application/json")], body).into_response())
+}
+
+pub async fn get_annotations_version(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<Json<AnnotationsStamp>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    Ok(Json(state.with_db(|db| {
+        db.annotations_version(&username, &document)
+    })?))
+}
+
+pub async fn update_annotations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+    Json(req): Json<UpdateAnnotationsRequest>,
+) -> Result<Response> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let base_version = if_match_version(&headers, req.base_version)?;
+    let mut annotations = req.annotations;
+    for anno in &mut annotations {
+        anno.tags = normalize_tags(&anno.tags)?;
+    }
+    let response = store_annotations(
+        &state,
+        &username,
+        &document,
+        annotations,
+        req.deleted,
+        base_version,
+        MergeOptions {
+            strategy: req.strategy,
+            dedup: req.dedup,
+            device_id: req.device_id,
+        },
+    )?;
+    let etag = annotations_etag(response.version);
+    Ok(([(ETAG, etag)], Json(response)).into_response())
+}
+
+/// Merge an upload without storing it, to show the user what syncing
+/// would change.
+pub async fn preview_annotations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+    Json(req): Json<UpdateAnnotationsRequest>,
+) -> Result<Json<AnnotationPreviewResponse>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let mut annotations = req.annotations;
+    for anno in &mut annotations {
+        anno.tags = normalize_tags(&anno.tags)?;
+        style::normalize(anno);
+    }
+    validate_annotations(&state.config, &annotations, &req.deleted)?;
+    let base_version = if_match_version(&headers, req.base_version)?;
+    let (result, write) = state.with_db(|db| {
+        db.preview_annotations(
+            &username,
+            &document,
+            annotations,
+            req.deleted,
+            base_version,
+            MergeOptions {
+                strategy: req.strategy,
+                dedup: req.dedup,
+                device_id: req.device_id,
+            },
+        )
+    })?;
+    Ok(Json(AnnotationPreviewResponse {
+        version: result.version,
+        annotations: result.annotations,
+        deleted: result.deleted,
+        ids: write.ids,
+        conflicts: write.conflicts,
+        merged: write.duplicates,
+    }))
+}
+
+pub async fn clear_annotations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+    Query(query): Query<ClearAnnotationsQuery>,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let (version, timestamp) =
+        state.with_db(|db| db.clear_annotations(&username, &document, query.keep_tombstones))?;
+    // Forgotten deletions can't be sent as a patch
+    let patch = query.keep_tombstones;
+    notify_annotations(&state, &username, &document, version, timestamp, patch)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Problems reported in one validation error before the rest are counted.
+const MAX_REPORTED_PROBLEMS: usize = 20;
+
+/// Check an upload against the configured limits, naming every offending
+/// annotation by its index in the upload.
+fn validate_annotations(
+    config: &Config,
+    annotations: &[Annotation],
+    deleted: &[String],
+) -> Result<()> {
+    if annotations.len() + deleted.len() > config.max_annotations_per_request {
+        return Err(AppError::InvalidRequest(format!(
+            "upload exceeds {} annotations and deletions",
+            config.max_annotations_per_request
+        )));
+    }
+
+    let mut problems = Vec::new();
+    for (i, anno) in annotations.iter().enumerate() {
+        let mut problem = |field: &str, issue: String| {
+            problems.push(format!("annotations[{}].{} {}", i, field, issue));
+        };
+        let too_long = |limit: usize| format!("exceeds {} bytes", limit);
+
+        for (field, value) in [("text", &anno.text), ("note", &anno.note)] {
+            if value
+                .as_ref()
+                .is_some_and(|v| v.len() > config.max_annotation_text_bytes)
+            {
+                problem(field, too_long(config.max_annotation_text_bytes));
+            }
+        }
+        for (field, value) in [
+            ("id", anno.id.as_deref()),
+            ("datetime", Some(anno.datetime.as_str())),
+            ("datetime_updated", anno.datetime_updated.as_deref()),
+            ("drawer", anno.drawer.as_deref()),
+            ("color", anno.color.as_deref()),
+            ("chapter", anno.chapter.as_deref()),
+        ] {
+            match value {
+                Some(v) if v.len() > config.max_annotation_field_bytes => {
+                    problem(field, too_long(config.max_annotation_field_bytes))
+                }
+                Some(v) if v.chars().any(char::is_control) => {
+                    problem(field, "contains control characters".into())
+                }
+                _ => {}
+            }
+        }
+        if anno.datetime.trim().is_empty() {
+            problem("datetime", "is empty".into());
+        }
+        if anno
+            .drawer
+            .as_deref()
+            .is_some_and(|d| !style::DRAWERS.contains(&d))
+        {
+            problem(
+                "drawer",
+                format!("must be one of {}", style::DRAWERS.join(", ")),
+            );
+        }
+        if anno
+            .color
+            .as_deref()
+            .is_some_and(|c| style::color(c).as_deref() != Some(c))
+        {
+            problem("color", "must be a color name or #rrggbb".into());
+        }
+
+        match &anno.page {
+            serde_json::Value::String(page) if page.is_empty() => {
+                problem("page", "is empty".into())
+            }
+            serde_json::Value::String(_) => {}
+            serde_json::Value::Number(page) if page.as_u64().is_some() => {}
+            _ => problem("page", "must be an xpointer or a page number".into()),
+        }
+        for (field, value) in [("pos0", &anno.pos0), ("pos1", &anno.pos1)] {
+            match value {
+                None | Some(serde_json::Value::String(_) | serde_json::Value::Number(_)) => {}
+                // Paged documents position highlights by page and coordinates
+                Some(serde_json::Value::Object(pos))
+                    if pos.values().all(|v| v.is_number() || v.is_string()) => {}
+                Some(_) => problem(
+                    field,
+                    "must be an xpointer or a flat position object".into(),
+                ),
+            }
+        }
+        for (field, value) in [
+            ("page", Some(&anno.page)),
+            ("pos0", anno.pos0.as_ref()),
+            ("pos1", anno.pos1.as_ref()),
+        ] {
+            let len = value.map_or(0, |v| match v {
+                serde_json::Value::String(s) => s.len(),
+                v => v.to_string().len(),
+            });
+            if len > config.max_annotation_field_bytes {
+                problem(field, too_long(config.max_annotation_field_bytes));
+            }
+        }
+        if anno.attachments.iter().any(|id| !valid_attachment_id(id)) {
+            problem("attachments", "contains an invalid attachment id".into());
+        }
+    }
+    if deleted
+        .iter()
+        .any(|id| id.is_empty() || id.len() > config.max_annotation_field_bytes)
+    {
+        problems.push("deleted contains an invalid id".into());
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let extra = problems.len().saturating_sub(MAX_REPORTED_PROBLEMS);
+    problems.truncate(MAX_REPORTED_PROBLEMS);
+    let mut message = format!("invalid annotations: {}", problems.join("; "));
+    if extra > 0 {
+        message.push_str(&format!("; and {} more", extra));
+    }
+    Err(AppError::InvalidRequest(message))
+}
+
+/// Merge annotations and tell everyone who sees them.
+fn store_annotations(
+    state: &AppState,
+    username: &str,
+    document: &str,
+    mut annotations: Vec<Annotation>,
+    deleted: Vec<String>,
+    base_version: Option<u64>,
+    options: MergeOptions,
+) -> Result<UpdateAnnotationsResponse> {
+    annotations.iter_mut().for_each(style::normalize);
+    validate_annotations(&state.config, &annotations, &deleted)?;
+    let write = state.with_db(|db| {
+        db.update_annotations(
+            username,
+            document,
+            annotations,
+            deleted,
+            base_version,
+            options,
+        )
+    })?;
+    notify_annotations(
+        state,
+        username,
+        document,
+        write.version,
+        write.timestamp,
+        true,
+    )?;
+
+    Ok(UpdateAnnotationsResponse {
+        version: write.version,
+        timestamp: write.timestamp,
+        ids: write.ids,
+        conflicts: write.conflicts,
+        merged: write.duplicates,
+    })
+}
+
+/// Most annotations and deletions pushed in one event; bigger changes are
+/// announced without them, for clients to fetch.
+const MAX_PUSHED_CHANGES: usize = 100;
+
+/// Tell everyone who sees a document's annotations about a new version,
+/// with what it changed when `patch` is set and that is small enough.
+fn notify_annotations(
+    state: &AppState,
+    username: &str,
+    document: &str,
+    version: u64,
+    timestamp: i64,
+    patch: bool,
+) -> Result<()> {
+    let patch = if patch {
+        annotation_patch(state, username, document, version)?
+    } else {
+        None
+    };
+    for member in state.with_db(|db| db.annotation_audience(username, document))? {
+        state.events.publish(
+            &member,
+            SyncEvent::Annotations {
+                document: document.to_string(),
+                version,
+                timestamp,
+                patch: patch.clone(),
+            },
+        );
+    }
+    Ok(())
+}
+
+fn annotation_patch(
+    state: &AppState,
+    username: &str,
+    document: &str,
+    version: u64,
+) -> Result<Option<AnnotationPatch>> {
+    let current = state.with_db(|db| db.get_annotations(username, document))?;
+    // Another write got in first; clients that see the gap refetch
+    if current.version != version {
+        return Ok(None);
+    }
+    let base_version = version.saturating_sub(1);
+    let changes = current.changes_since(base_version);
+    if changes.annotations.len() + changes.deleted.len() > MAX_PUSHED_CHANGES {
+        return Ok(None);
+    }
+    Ok(Some(AnnotationPatch {
+        base_version,
+        annotations: changes.annotations,
+        deleted: changes.deleted,
+    }))
+}
+
+pub async fn list_annotation_versions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<Json<AnnotationVersionsResponse>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let versions = state.with_db(|db| db.annotation_versions(&username, &document))?;
+    Ok(Json(AnnotationVersionsResponse { versions }))
+}
+
+/// What changed in a document's annotations between two kept versions.
+pub async fn diff_annotations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+    Query(query): Query<AnnotationDiffQuery>,
+) -> Result<Json<AnnotationDiffResponse>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+    if query.from > query.to {
+        return Err(AppError::InvalidRequest("from must not be after to".into()));
+    }
+
+    let (before, after) = state
+        .with_db(|db| db.annotation_version_pair(&username, &document, query.from, query.to))?;
+    // Annotations from before server-assigned ids are known by datetime
+    let key = |a: &Annotation| a.id.clone().unwrap_or_else(|| a.datetime.clone());
+    let mut before: HashMap<String, Annotation> = before
+        .annotations
+        .into_iter()
+        .map(|a| (key(&a), a))
+        .collect();
+
+    let mut after = after.annotations;
+    after.sort_by(position::reading_order);
+    let mut added = Vec::new();
+    let mut modified = Vec::new();
+    for anno in after {
+        let id = key(&anno);
+        match before.remove(&id) {
+            None => added.push(anno),
+            Some(old) => {
+                let fields = merge::changed_fields(&old, &anno);
+                if !fields.is_empty() {
+                    modified.push(AnnotationChange {
+                        id,
+                        fields,
+                        before: old,
+                        after: anno,
+                    });
+                }
+            }
+        }
+    }
+    let mut removed: Vec<Annotation> = before.into_values().collect();
+    removed.sort_by(position::reading_order);
+
+    Ok(Json(AnnotationDiffResponse {
+        from: query.from,
+        to: query.to,
+        added,
+        removed,
+        modified,
+    }))
+}
+
+pub async fn revert_annotations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((document, target)): Path<(String, u64)>,
+) -> Result<Json<UpdateAnnotationsResponse>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let (version, timestamp, ids) =
+        state.with_db(|db| db.revert_annotations(&username, &document, target))?;
+    notify_annotations(&state, &username, &document, version, timestamp, true)?;
+    Ok(Json(UpdateAnnotationsResponse {
+        version,
+        timestamp,
+        ids,
+        conflicts: Vec::new(),
+        merged: BTreeMap::new(),
+    }))
+}
+
+pub async fn import_annotations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+    Query(query): Query<AnnotationImportQuery>,
+    body: String,
+) -> Result<Json<UpdateAnnotationsResponse>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let sidecar = match query.format {
+        AnnotationImportFormat::Calibre => {
+            let body = serde_json::from_str(&body).map_err(|e| {
+                AppError::InvalidRequest(format!("not a Calibre annotation file: {}", e))
+            })?;
+            let (annotations, deleted) = calibre_annotations::import(body);
+            let response = store_annotations(
+                &state,
+                &username,
+                &document,
+                annotations,
+                deleted,
+                None,
+                MergeOptions::default(),
+            )?;
+            return Ok(Json(response));
+        }
+        AnnotationImportFormat::Koreader => koreader_metadata::import(&body).map_err(|e| {
+            AppError::InvalidRequest(format!("not a KOReader metadata file: {}", e))
+        })?,
+    };
+    let Some(progress) = sidecar.progress else {
+        let response = store_annotations(
+            &state,
+            &username,
+            &document,
+            sidecar.annotations,
+            Vec::new(),
+            None,
+            MergeOptions::default(),
+        )?;
+        return Ok(Json(response));
+    };
+
+    // Check everything before storing anything, so a rejected file changes
+    // neither the annotations nor the position
+    let mut annotations = sidecar.annotations;
+    annotations.iter_mut().for_each(style::normalize);
+    validate_annotations(&state.config, &annotations, &[])?;
+    let progress = seed_progress(&state.config, &document, progress, sidecar.percentage)?;
+    let (write, seeded) = state.with_db(|db| {
+        db.import_annotations_with_progress(&username, &document, annotations, &progress)
+    })?;
+    if let Some(written) = seeded {
+        publish_progress(&state, &username, &progress, &written);
+    }
+    notify_annotations(
+        &state,
+        &username,
+        &document,
+        write.version,
+        write.timestamp,
+        true,
+    )?;
+    Ok(Json(UpdateAnnotationsResponse {
+        version: write.version,
+        timestamp: write.timestamp,
+        ids: write.ids,
+        conflicts: write.conflicts,
+        merged: write.duplicates,
+    }))
+}
+
+pub async fn import_kindle_clippings(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<KindleImportRequest>,
+) -> Result<Json<KindleImportResponse>> {
+    let username = authorize(&state, &headers)?;
+
+    if req
+        .mapping
+        .values()
+        .any(|document| document.is_empty() || document.contains(':'))
+    {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let metadata = state.with_db(|db| db.list_metadata(&username))?;
+    let import = kindle_clippings::import(&req.clippings, &metadata, &req.mapping);
+    // Check every book before storing any, so a rejected file imports nothing
+    for annotations in import.documents.values() {
+        validate_annotations(&state.config, annotations, &[])?;
+    }
+
+    let mut imported = BTreeMap::new();
+    for (document, annotations) in import.documents {
+        imported.insert(document.clone(), annotations.len());
+        store_annotations(
+            &state,
+            &username,
+            &document,
+            annotations,
+            Vec::new(),
+            None,
+            MergeOptions::default(),
+        )?;
+    }
+    Ok(Json(KindleImportResponse {
+        imported,
+        unmatched: import.unmatched,
+    }))
+}
+
+/// The update storing an imported reading position, unless the document
+/// already has one.
+fn seed_progress(
+    config: &Config,
+    document: &str,
+    progress: String,
+    percentage: Option<f64>,
+) -> Result<UpdateProgressRequest> {
+    let mut req = UpdateProgressRequest {
+        document: document.to_string(),
+        progress,
+        percentage: percentage.unwrap_or_default(),
+        device: "KOReader import".to_string(),
+        device_id: None,
+        alt_document: None,
+        client_timestamp: None,
+        // Only written over no progress at all
+        base_version: Some(0),
+        force: true,
+    };
+    validate_progress(config, &mut req)?;
+    Ok(req)
+}
+
+fn valid_attachment_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+pub async fn put_attachment(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((document, id)): Path<(String, String)>,
+    body: Bytes,
+) -> Result<Json<Attachment>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+    if !valid_attachment_id(&id) {
+        return Err(AppError::InvalidRequest("invalid attachment id".into()));
+    }
+    if body.is_empty() || body.len() > state.config.max_attachment_size {
+        return Err(AppError::InvalidRequest(format!(
+            "attachments must be 1 to {} bytes",
+            state.config.max_attachment_size
+        )));
+    }
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/octet-stream");
+    let attachment =
+        state.with_db(|db| db.put_attachment(&username, &document, &id, content_type, &body))?;
+    Ok(Json(attachment))
+}
+
+pub async fn get_attachment(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((document, id)): Path<(String, String)>,
+) -> Result<Response> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+    if !valid_attachment_id(&id) {
+        return Err(AppError::InvalidRequest("invalid attachment id".into()));
+    }
+
+    let Some((attachment, data)) =
+        state.with_db(|db| db.get_attachment(&username, &document, &id))?
+    else {
+        return Err(AppError::AttachmentNotFound);
+    };
+    Ok((
+        [
+            (CONTENT_TYPE, attachment.content_type),
+            (ETAG, format!("\"{}\"", attachment.sha256)),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+pub async fn list_attachments(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<Json<AttachmentListResponse>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let attachments = state.with_db(|db| db.list_attachments(&username, &document))?;
+    Ok(Json(AttachmentListResponse { attachments }))
+}
+
+pub async fn delete_attachment(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((document, id)): Path<(String, String)>,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+    if !valid_attachment_id(&id) {
+        return Err(AppError::InvalidRequest("invalid attachment id".into()));
+    }
+
+    if state.with_db(|db| db.delete_attachment(&username, &document, &id))? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::AttachmentNotFound)
+    }
+}
+
+/// Default and largest number of results from a library-wide search.
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+const MAX_SEARCH_LIMIT: usize = 200;
+
+pub async fn get_notebook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<NotebookResponse>> {
+    let username = authorize(&state, &headers)?;
+
+    let mut metadata = state.with_db(|db| db.list_metadata(&username))?;
+    let mut books = Vec::new();
+    for document in state.with_db(|db| db.annotated_documents(&username))? {
+        let mut notes = state
+            .with_db(|db| db.get_annotations(&username, &document))?
+            .annotations;
+        notes.retain(|a| a.note.as_ref().is_some_and(|n| !n.trim().is_empty()));
+        if notes.is_empty() {
+            continue;
+        }
+        notes.sort_by(|a, b| a.datetime.cmp(&b.datetime));
+        let meta = metadata.remove(&document).unwrap_or_default();
+        books.push(NotebookBook {
+            document,
+            title: meta.title,
+            author: meta.author,
+            notes,
+        });
+    }
+    books.sort_by(|a, b| {
+        let latest = |book: &NotebookBook| book.notes.last().map(|n| n.datetime.clone());
+        latest(b).cmp(&latest(a))
+    });
+    Ok(Json(NotebookResponse { books }))
+}
+
+const DEFAULT_REVIEW_COUNT: usize = 5;
+const MAX_REVIEW_COUNT: usize = 50;
+
+/// A few random highlights from the whole library, for daily review.
+/// Serving a highlight counts as reviewing it.
+pub async fn random_highlights(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<RandomHighlightsQuery>,
+) -> Result<Json<RandomHighlightsResponse>> {
+    let username = authorize(&state, &headers)?;
+    let count = query
+        .count
+        .unwrap_or(DEFAULT_REVIEW_COUNT)
+        .clamp(1, MAX_REVIEW_COUNT);
+    let now = crate::db::now();
+    let today = now.div_euclid(86_400);
+
+    let mut candidates = Vec::new();
+    for document in state.with_db(|db| db.annotated_documents(&username))? {
+        let reviews = state.with_db(|db| db.highlight_reviews(&username, &document))?;
+        for anno in state
+            .with_db(|db| db.get_annotations(&username, &document))?
+            .annotations
+        {
+            if anno.text.as_deref().is_none_or(|t| t.trim().is_empty()) {
+                continue;
+            }
+            let review = anno
+                .id
+                .as_ref()
+                .and_then(|id| reviews.get(id))
+                .cloned()
+                .unwrap_or_default();
+            candidates.push((document.clone(), anno, review));
+        }
+    }
+
+    // Efraimidis-Spirakis: the `count` largest of u^(1/weight) are a
+    // weighted sample without replacement
+    let mut keyed: Vec<(f64, usize)> = candidates
+        .iter()
+        .enumerate()
+        .map(|(i, (_, anno, review))| {
+            let weight = if query.weighted {
+                let seen = match review.count {
+                    0 => streaks::parse_date(&anno.datetime),
+                    _ => Some(review.last_reviewed.div_euclid(86_400)),
+                };
+                // Undated highlights count as a year old
+                let days = seen.map_or(365, |day| (today - day).max(0));
+                (days + 1) as f64 / f64::from(review.count + 1)
+            } else {
+                1.0
+            };
+            (fastrand::f64().powf(1.0 / weight), i)
+        })
+        .collect();
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    keyed.truncate(count);
+
+    let mut chosen: Vec<usize> = keyed.into_iter().map(|(_, i)| i).collect();
+    chosen.sort_unstable();
+    let metadata = state.with_db(|db| db.list_metadata(&username))?;
+    let mut highlights = Vec::new();
+    for (i, (document, annotation, review)) in candidates.into_iter().enumerate() {
+        if chosen.binary_search(&i).is_err() {
+            continue;
+        }
+        let meta = metadata.get(&document).cloned().unwrap_or_default();
+        highlights.push(ReviewHighlight {
+            document,
+            title: meta.title,
+            author: meta.author,
+            annotation,
+            review_count: review.count,
+        });
+    }
+    fastrand::shuffle(&mut highlights);
+
+    let reviewed: Vec<(String, String)> = highlights
+        .iter()
+        .filter_map(|h| Some((h.document.clone(), h.annotation.id.clone()?)))
+        .collect();
+    // Review counts only weight later draws; a follower or a full disk
+    // still serves highlights
+    if !reviewed.is_empty() {
+        match state.with_db(|db| db.record_highlight_reviews(&username, &reviewed, now)) {
+            Ok(()) | Err(AppError::ReadOnly(_)) => {}
+            Err(e) => tracing::warn!("Failed to record highlight reviews: {}", e),
+        }
+    }
+    Ok(Json(RandomHighlightsResponse { highlights }))
+}
+
+pub async fn search_annotations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<LibrarySearchQuery>,
+) -> Result<Json<LibrarySearchResponse>> {
+    let username = authorize(&state, &headers)?;
+    let terms = search::query_terms(&query.q);
+    if terms.is_empty() {
+        return Err(AppError::InvalidRequest("empty search query".into()));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+
+    let mut metadata = state.with_db(|db| db.list_metadata(&username))?;
+    let mut results = Vec::new();
+    let mut truncated = false;
+    for (document, ids) in state.with_db(|db| db.search_annotations(&username, None, &terms))? {
+        let title = metadata.remove(&document).and_then(|m| m.title);
+        let mut annotations = state
+            .with_db(|db| db.get_annotations(&username, &document))?
+            .annotations;
+        annotations.retain(|a| a.id.as_ref().is_some_and(|id| ids.contains(id)));
+        annotations.sort_by(position::reading_order);
+
+        for annotation in annotations {
+            if results.len() == limit {
+                truncated = true;
+                break;
+            }
+            let snippet = [&annotation.text, &annotation.note]
+                .into_iter()
+                .flatten()
+                .find_map(|field| search::snippet(field, &terms))
+                .unwrap_or_default();
+            results.push(AnnotationSearchHit {
+                document: document.clone(),
+                title: title.clone(),
+                snippet,
+                annotation,
+            });
+        }
+        if truncated {
+            break;
+        }
+    }
+    Ok(Json(LibrarySearchResponse { results, truncated }))
+}
+
+pub async fn search_document_annotations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<AnnotationSearchResponse>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+    let terms = search::query_terms(&query.q);
+    if terms.is_empty() {
+        return Err(AppError::InvalidRequest("empty search query".into()));
+    }
+
+    let matches = state.with_db(|db| db.search_annotations(&username, Some(&document), &terms))?;
+    let ids = matches.into_values().next().unwrap_or_default();
+    let mut annotations = Vec::new();
+    if !ids.is_empty() {
+        annotations = state
+            .with_db(|db| db.get_annotations(&username, &document))?
+            .annotations;
+        annotations.retain(|a| a.id.as_ref().is_some_and(|id| ids.contains(id)));
+        annotations.sort_by(position::reading_order);
+    }
+    Ok(Json(AnnotationSearchResponse { annotations }))
+}
+
+pub async fn export_annotations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+    Query(query): Query<AnnotationExportQuery>,
+) -> Result<Response> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let mut annotations = state.with_db(|db| db.get_annotations(&username, &document))?;
+    if let Some(tag) = &query.tag {
+        annotations.annotations.retain(|a| a.tags.contains(tag));
+    }
+    let metadata = state
+        .with_db(|db| db.get_metadata(&username, &document))?
+        .unwrap_or_default();
+
+    let (body, content_type, extension) = match query.format {
+        AnnotationExportFormat::Markdown => (
+            export::annotations_markdown(&document, &metadata, &annotations.annotations)
+                .into_bytes(),
+            "text/markdown; charset=utf-8",
+            "md",
+        ),
+        AnnotationExportFormat::Obsidian => (
+            export::annotations_obsidian(&document, &metadata, &annotations.annotations)
+                .into_bytes(),
+            "text/markdown; charset=utf-8",
+            "md",
+        ),
+        AnnotationExportFormat::Calibre => (
+            serde_json::to_vec_pretty(&calibre_annotations::export(&annotations.annotations))?,
+            "application/json",
+            "calibre.json",
+        ),
+        AnnotationExportFormat::Jex => (
+            export::annotations_jex(
+                &document,
+                &metadata,
+                &annotations.annotations,
+                annotations.updated_at,
+            ),
+            "application/x-tar",
+            "jex",
+        ),
+        AnnotationExportFormat::Anki => (
+            export::annotations_anki([(
+                metadata.title.as_deref().unwrap_or(&document),
+                annotations.annotations.as_slice(),
+            )])
+            .into_bytes(),
+            "text/tab-separated-values; charset=utf-8",
+            "anki.txt",
+        ),
+    };
+    // The path segment is user input; keep quotes and control characters
+    // out of the header
+    let name: String = document
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    Ok((
+        [
+            (CONTENT_TYPE, content_type.to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}.{}\"", name, extension),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Flashcards from every annotated book, a deck per book.
+pub async fn export_anki(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AnkiExportQuery>,
+) -> Result<Response> {
+    let username = authorize(&state, &headers)?;
+
+    let mut metadata = state.with_db(|db| db.list_metadata(&username))?;
+    let mut books = Vec::new();
+    for document in state.with_db(|db| db.annotated_documents(&username))? {
+        let mut annotations = state
+            .with_db(|db| db.get_annotations(&username, &document))?
+            .annotations;
+        if let Some(tag) = &query.tag {
+            annotations.retain(|a| a.tags.contains(tag));
+        }
+        if annotations.is_empty() {
+            continue;
+        }
+        let title = metadata
+            .remove(&document)
+            .and_then(|m| m.title)
+            .unwrap_or(document);
+        books.push((title, annotations));
+    }
+    let body = export::annotations_anki(
+        books
+            .iter()
+            .map(|(title, annotations)| (title.as_str(), annotations.as_slice())),
+    );
+    Ok((
+        [
+            (CONTENT_TYPE, "text/tab-separated-values; charset=utf-8"),
+            (
+                CONTENT_DISPOSITION,
+                "attachment; filename=\"highlights.anki.txt\"",
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+// === Shared documents ===
+
+pub async fn publish_annotations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<(StatusCode, Json<PublicShareResponse>)> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let share = state.with_db(|db| db.create_public_share(&username, &document))?;
+    Ok((
+        StatusCode::CREATED,
+        Json(PublicShareResponse {
+            url: format!("/shared/{}", share.token),
+            token: share.token,
+            document: share.document,
+            created_at: share.created_at,
+        }),
+    ))
+}
+
+pub async fn unpublish_annotations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    if state.with_db(|db| db.delete_public_share(&username, &document))? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::InvalidRequest("unknown public page".into()))
+    }
+}
+
+/// The public page of a document's highlights; no authentication needed.
+pub async fn shared_annotations_page(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Response> {
+    let Some(share) = state.with_db(|db| db.public_share(&token))? else {
+        return Ok((StatusCode::NOT_FOUND, Html("<h1>Not found</h1>")).into_response());
+    };
+
+    let annotations = state.with_db(|db| db.get_annotations(&share.username, &share.document))?;
+    let metadata = state
+        .with_db(|db| db.get_metadata(&share.username, &share.document))?
+        .unwrap_or_default();
+    Ok(Html(export::annotations_html(
+        &share.document,
+        &metadata,
+        &annotations.annotations,
+    ))
+    .into_response())
+}
+
+/// Entries in the highlights feed.
+const FEED_ENTRIES: usize = 50;
+
+pub async fn create_feed(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<FeedQuery>,
+) -> Result<Json<FeedResponse>> {
+    let username = authorize(&state, &headers)?;
+    let token = state.with_db(|db| db.feed_token(&username, query.rotate))?;
+    Ok(Json(FeedResponse {
+        url: format!("/feeds/{}", token),
+        token,
+    }))
+}
+
+pub async fn delete_feed(State(state): State<AppState>, headers: HeaderMap) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+    if state.with_db(|db| db.delete_feed_token(&username))? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::InvalidRequest("no feed to delete".into()))
+    }
+}
+
+/// The newest highlights and notes across a user's library as Atom, for
+/// feed readers; the token in the URL stands in for authentication.
+pub async fn annotations_feed(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Response> {
+    let Some(username) = state.with_db(|db| db.feed_user(&token))? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let mut annotations = Vec::new();
+    for document in state.with_db(|db| db.annotated_documents(&username))? {
+        for anno in state
+            .with_db(|db| db.get_annotations(&username, &document))?
+            .annotations
+        {
+            let written = |v: &Option<String>| v.as_deref().is_some_and(|v| !v.trim().is_empty());
+            if written(&anno.text) || written(&anno.note) {
+                annotations.push((document.clone(), anno));
+            }
+        }
+    }
+    let changed = |anno: &Annotation| {
+        anno.datetime_updated
+            .clone()
+            .unwrap_or_else(|| anno.datetime.clone())
+    };
+    annotations.sort_by_cached_key(|(_, anno)| std::cmp::Reverse(changed(anno)));
+    annotations.truncate(FEED_ENTRIES);
+
+    let metadata = state.with_db(|db| db.list_metadata(&username))?;
+    let entries: Vec<export::FeedEntry> = annotations
+        .iter()
+        .map(|(document, annotation)| export::FeedEntry {
+            document,
+            title: metadata
+                .get(document)
+                .and_then(|m| m.title.as_deref())
+                .unwrap_or(document),
+            annotation,
+        })
+        .collect();
+    let body = export::annotations_atom(&username, &entries, crate::db::now());
+    Ok((
+        [(CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+        body,
+    )
+        .into_response())
+}
+
+pub async fn create_share(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<CreateShareRequest>,
+) -> Result<(StatusCode, Json<ShareGroup>)> {
+    let username = authorize(&state, &headers)?;
+
+    if req.document.is_empty() || req.document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+    if req.members.iter().all(|m| *m == username) {
+        return Err(AppError::InvalidRequest("no members to share with".into()));
+    }
+
+    let group = state.with_db(|db| db.create_share(&username, &req.document, &req.members))?;
+    Ok((StatusCode::CREATED, Json(group)))
+}
+
+pub async fn list_shares(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<ShareListResponse>> {
+    let username = authorize(&state, &headers)?;
+    let shares = state.with_db(|db| db.list_shares(&username))?;
+    Ok(Json(ShareListResponse { shares }))
+}
+
+pub async fn join_share(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<ShareGroup>> {
+    let username = authorize(&state, &headers)?;
+    Ok(Json(state.with_db(|db| db.join_share(&username, &id))?))
+}
+
+pub async fn leave_share(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+    state.with_db(|db| db.leave_share(&username, &id))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// === Reading statistics (KOReader statistics plugin) ===
+
+pub async fn get_statistics(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<StatisticsQuery>,
+) -> Result<Json<Statistics>> {
+    let username = authorize(&state, &headers)?;
+    let statistics = state.with_db(|db| db.get_statistics(&username, query.since))?;
+    Ok(Json(statistics))
+}
+
+pub async fn update_statistics(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(upload): Json<StatisticsUpload>,
+) -> Result<Json<StatisticsMergeResult>> {
+    let username = authorize(&state, &headers)?;
+
+    if upload
+        .books
+        .iter()
+        .map(|b| &b.md5)
+        .chain(upload.page_stats.iter().map(|p| &p.md5))
+        .any(|md5| md5.is_empty() || md5.contains(':'))
+    {
+        return Err(AppError::DocumentMissing);
+    }
+
+    let result = state.with_db(|db| db.merge_statistics(&username, &upload))?;
+    Ok(Json(result))
+}
+
+// === Reading sessions ===
+
+pub async fn create_session(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(session): Json<ReadingSession>,
+) -> Result<(StatusCode, Json<ReadingSession>)> {
+    let username = authorize(&state, &headers)?;
+
+    if session.document.is_empty() || session.document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+    if session.start < 0 || session.end < session.start {
+        return Err(AppError::InvalidRequest(
+            "invalid session time range".into(),
+        ));
+    }
+
+    state.with_db(|db| db.add_session(&username, &session))?;
+    Ok((StatusCode::CREATED, Json(session)))
+}
+
+pub async fn list_sessions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<SessionsQuery>,
+) -> Result<Json<SessionsResponse>> {
+    let username = authorize(&state, &headers)?;
+    let sessions = state.with_db(|db| {
+        db.list_sessions(&username, query.from, query.to, query.document.as_deref())
+    })?;
+    Ok(Json(SessionsResponse { sessions }))
+}
+
+// === Trash ===
+
+pub async fn list_trash(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<TrashListResponse>> {
+    let username = authorize(&state, &headers)?;
+    let items = state.with_db(|db| db.list_trash(&username))?;
+    Ok(Json(TrashListResponse { items }))
+}
+
+pub async fn restore_trash(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<RestoreResponse>> {
+    let username = authorize(&state, &headers)?;
+    let restored = state.with_db(|db| db.restore_trash(&username, &id))?;
+    match restored.kind {
+        TrashKind::Progress => {
+            let progress = state.with_db(|db| db.get_progress(&username, &restored.document))?;
+            state.events.publish(
+                &username,
+                SyncEvent::Progress {
+                    document: restored.document.clone(),
+                    progress: progress.progress.unwrap_or_default(),
+                    percentage: progress.percentage.unwrap_or_default(),
+                    device: progress.device.unwrap_or_default(),
+                    device_id: progress.device_id,
+                    timestamp: restored.timestamp,
+                },
+            );
+        }
+        TrashKind::Annotations => notify_annotations(
+            &state,
+            &username,
+            &restored.document,
+            restored.version,
+            restored.timestamp,
+            true,
+        )?,
+    }
+    Ok(Json(restored))
+}
+
+// === Devices ===
+
+pub async fn list_devices(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<DeviceListResponse>> {
+    let username = authorize(&state, &headers)?;
+    let devices = state.with_db(|db| db.list_devices(&username))?;
+    Ok(Json(DeviceListResponse { devices }))
+}
+
+pub async fn delete_device(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(device_id): Path<String>,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+    if state.with_db(|db| db.delete_device(&username, &device_id))? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::InvalidRequest("unknown device".into()))
+    }
+}
+
+// === Finished books ===
+
+pub async fn list_finished(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<FinishedQuery>,
+) -> Result<Json<FinishedResponse>> {
+    let username = authorize(&state, &headers)?;
+    let offset = state
+        .with_db(|db| db.get_user_settings(&username))?
+        .utc_offset_minutes
+        .unwrap_or(0);
+    let mut metadata = state.with_db(|db| db.list_metadata(&username))?;
+    let mut ratings = ratings(&state, &username)?;
+
+    let mut years: BTreeMap<i64, Vec<FinishedBook>> = BTreeMap::new();
+    for mut book in state.with_db(|db| db.list_finished(&username))? {
+        let year = streaks::local_year(book.finished_at, offset);
+        if query.year.is_some_and(|y| y != year) {
+            continue;
+        }
+        book.metadata = metadata.remove(&book.document);
+        book.rating = ratings.remove(&book.document);
+        years.entry(year).or_default().push(book);
+    }
+
+    let years = years
+        .into_iter()
+        .rev()
+        .map(|(year, books)| FinishedYear {
+            year,
+            count: books.len(),
+            books,
+        })
+        .collect();
+    Ok(Json(FinishedResponse { years }))
+}
+
+pub async fn export_finished(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ReadingLogQuery>,
+) -> Result<Response> {
+    let username = authorize(&state, &headers)?;
+    let offset = state
+        .with_db(|db| db.get_user_settings(&username))?
+        .utc_offset_minutes
+        .unwrap_or(0);
+    let mut metadata = state.with_db(|db| db.list_metadata(&username))?;
+    let mut ratings = ratings(&state, &username)?;
+
+    let rows: Vec<export::ReadingLogRow> = state
+        .with_db(|db| db.list_finished(&username))?
+        .into_iter()
+        .map(|book| {
+            let metadata = metadata.remove(&book.document).unwrap_or_default();
+            let rating = ratings.remove(&book.document);
+            export::ReadingLogRow {
+                // Without a title the hash is the only identifier there is
+                title: metadata.title.unwrap_or(book.document),
+                author: metadata.author.unwrap_or_default(),
+                isbn: metadata.isbn.unwrap_or_default(),
+                date_read: streaks::local_date(book.finished_at, offset).replace('-', "/"),
+                rating,
+            }
+        })
+        .collect();
+
+    let (body, filename) = match query.format {
+        ReadingLogFormat::Goodreads => (export::goodreads_csv(&rows), "goodreads.csv"),
+        ReadingLogFormat::Storygraph => (export::storygraph_csv(&rows), "storygraph.csv"),
+    };
+    Ok((
+        [
+            (CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+// === Goals and streaks ===
+
+pub async fn get_streak(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<StreakResponse>> {
+    let username = authorize(&state, &headers)?;
+    let settings = state.with_db(|db| db.get_user_settings(&username))?;
+
+    let mut activity = Activity::new(settings.utc_offset_minutes.unwrap_or(0));
+    for session in state.with_db(|db| db.list_sessions(&username, None, None, None))? {
+        activity.add_session(&session);
+    }
+    for stat in state
+        .with_db(|db| db.get_statistics(&username, None))?
+        .page_stats
+    {
+        activity.add_page_stat(&stat);
+    }
+    for progress in state.with_db(|db| db.list_progress(&username))? {
+        if let Some(timestamp) = progress.timestamp {
+            activity.add_progress(timestamp);
+        }
+    }
+
+    let goal = ReadingGoal {
+        minutes: settings.daily_goal_minutes,
+        pages: settings.daily_goal_pages,
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    Ok(Json(activity.streak(goal, now)))
+}
+
+// === Integrations ===
+
+pub async fn set_hardcover(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(integration): Json<HardcoverIntegration>,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+    // The demo account is shared, and so would be its token
+    if state.config.is_demo_user(&username) {
+        return Err(AppError::Forbidden);
+    }
+    if integration.token.trim().is_empty() {
+        return Err(AppError::InvalidRequest("empty token".into()));
+    }
+    state.with_db(|db| db.set_integration(&username, hardcover::NAME, &integration))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_hardcover(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+    state.with_db(|db| db.delete_integration(&username, hardcover::NAME))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn set_readwise(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(integration): Json<ReadwiseIntegration>,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+    // The demo account is shared, and so would be its token
+    if state.config.is_demo_user(&username) {
+        return Err(AppError::Forbidden);
+    }
+    if integration.token.trim().is_empty() {
+        return Err(AppError::InvalidRequest("empty token".into()));
+    }
+    state.with_db(|db| db.set_integration(&username, readwise::NAME, &integration))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_readwise(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+    state.with_db(|db| db.delete_integration(&username, readwise::NAME))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn set_calibre_web(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(integration): Json<CalibreWebIntegration>,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+    // The demo account is shared, and so would be its login
+    if state.config.is_demo_user(&username) {
+        return Err(AppError::Forbidden);
+    }
+    outbound::check_url(&integration.url, state.config.private_urls)
+        .map_err(|e| AppError::InvalidRequest(format!("calibre-web {}", e)))?;
+    state.with_db(|db| db.set_integration(&username, calibre_web::NAME, &integration))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_calibre_web(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+    state.with_db(|db| db.delete_integration(&username, calibre_web::NAME))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_calibre_books(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<CalibreBookListResponse>> {
+    let username = authorize(&state, &headers)?;
+    let books = state.with_db(|db| db.list_calibre_books(&username))?;
+    Ok(Json(CalibreBookListResponse { books }))
+}
+
+pub async fn set_calibre_book(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+    Json(book): Json<CalibreBook>,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+    if book.format.is_empty() || !book.format.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(AppError::InvalidRequest("invalid book format".into()));
+    }
+
+    state.with_db(|db| db.set_calibre_book(&username, &document, &book))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_calibre_book(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+    if state.with_db(|db| db.delete_calibre_book(&username, &document))? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::DocumentMissing)
+    }
+}
+
+/// Replace the stored position with calibre-web's bookmark.
+pub async fn pull_calibre_progress(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<Json<Progress>> {
+    let username = authorize(&state, &headers)?;
+
+    if document.is_empty() || document.contains(':') {
+        return Err(AppError::DocumentMissing);
+    }
+
+    if let Some((update, written)) = calibre_web::pull(&state, &username, &document).await? {
+        publish_progress(&state, &username, &update, &written);
+    }
+    Ok(Json(
+        state.with_db(|db| db.get_progress(&username, &document))?,
+    ))
+}
+
+// === Webhooks ===
+
+const MAX_WEBHOOKS: usize = 20;
+
+pub async fn create_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<CreateWebhookRequest>,
+) -> Result<(StatusCode, Json<Webhook>)> {
+    let username = authorize(&state, &headers)?;
+    // Every demo visitor would receive the others' events
+    if state.config.is_demo_user(&username) {
+        return Err(AppError::Forbidden);
+    }
+
+    outbound::check_url(&req.url, state.config.private_urls)
+        .map_err(|e| AppError::InvalidRequest(format!("webhook {}", e)))?;
+    if req.secret.as_ref().is_some_and(|s| s.is_empty()) {
+        return Err(AppError::InvalidRequest("empty webhook secret".into()));
+    }
+    if state.with_db(|db| db.list_webhooks(&username))?.len() >= MAX_WEBHOOKS {
+        return Err(AppError::InvalidRequest("too many webhooks".into()));
+    }
+
+    let webhook = Webhook {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        url: req.url,
+        secret: req
+            .secret
+            .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string()),
+        events: req.events,
+        created_at: crate::db::now(),
+    };
+    state.with_db(|db| db.add_webhook(&username, &webhook))?;
+
+    // The secret is only ever returned here
+    Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+pub async fn list_webhooks(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<WebhookListResponse>> {
+    let username = authorize(&state, &headers)?;
+    let webhooks = state
+        .with_db(|db| db.list_webhooks(&username))?
+        .into_iter()
+        .map(WebhookInfo::from)
+        .collect();
+    Ok(Json(WebhookListResponse { webhooks }))
+}
+
+pub async fn delete_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<StatusCode> {
+    let username = authorize(&state, &headers)?;
+    if state.with_db(|db| db.delete_webhook(&username, &id))? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::InvalidRequest("unknown webhook".into()))
+    }
+}
+
+// === Usage metrics ===
+
+pub async fn get_usage(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<UsageResponse>> {
+    let username = authorize(&state, &headers)?;
+    Ok(Json(UsageResponse {
+        window_secs: state.usage.window().as_secs(),
+        endpoints: state.usage.user_usage(&username),
+    }))
+}
+
+pub async fn get_all_usage(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<AdminUsageResponse>> {
+    authorize_admin(&state, &headers)?;
+    Ok(Json(AdminUsageResponse {
+        window_secs: state.usage.window().as_secs(),
+        users: state.usage.all_usage(),
+    }))
+}
+
+// === Backups ===
+
+pub async fn create_backup(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<BackupResponse>> {
+    authorize_admin(&state, &headers)?;
+    let dir = state
+        .config
+        .backup_dir
+        .as_deref()
+        .ok_or_else(|| AppError::InvalidRequest("KOSYNC_BACKUP_DIR is not set".into()))?;
+    let (path, size) = state.with_db(|db| backup::create_in(db, dir))?;
+    tracing::info!("Wrote backup {} ({} bytes)", path.display(), size);
+    Ok(Json(BackupResponse {
+        file: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        size,
+    }))
+}
+
+/// Bytes read from a snapshot per chunk of the response.
+const SNAPSHOT_CHUNK: usize = 64 * 1024;
+
+/// A consistent copy of the whole database as a redb file, for backups
+/// taken from another machine. Writes go on while it is taken and sent.
+pub async fn get_db_snapshot(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    authorize_admin(&state, &headers)?;
+    let (file, size) = state.with_db(backup::snapshot)?;
+    tracing::info!("Sending a snapshot of the database ({} bytes)", size);
+    let chunks = stream::unfold(tokio::fs::File::from_std(file), |mut file| async move {
+        let mut chunk = vec![0; SNAPSHOT_CHUNK];
+        match file.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(read) => {
+                chunk.truncate(read);
+                Some((Ok(Bytes::from(chunk)), file))
+            }
+            Err(e) => Some((Err(e), file)),
+        }
+    });
+    Ok((
+        [
+            (CONTENT_TYPE, "application/octet-stream".to_string()),
+            (CONTENT_LENGTH, size.to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"{}\"",
+                    backup::file_name(crate::db::now())
+                ),
+            ),
+        ],
+        Body::from_stream(chunks),
+    )
+        .into_response())
+}
+
+/// The database file's size against `KOSYNC_DISK_BUDGET_MB`.
+pub async fn get_disk_usage(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<DiskUsage>> {
+    authorize_admin(&state, &headers)?;
+    let size = state.with_db(|db| db.file_size())?;
+    Ok(Json(
+        state.with_db(|db| quota::usage(db, &state.config, size)),
+    ))
+}
+
+const DEFAULT_JOURNAL_LIMIT: usize = 1000;
+const MAX_JOURNAL_LIMIT: usize = 10_000;
+
+/// Changes after `after`, with the values they left, for followers.
+pub async fn get_journal(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<JournalQuery>,
+) -> Result<Json<JournalResponse>> {
+    authorize_admin(&state, &headers)?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_JOURNAL_LIMIT)
+        .clamp(1, MAX_JOURNAL_LIMIT);
+    Ok(Json(
+        state.with_db(|db| db.journal_changes(query.after, limit))?,
+    ))
 }
 
 // === Health check ===
@@ -0,0 +1,299 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::events::UserEvent;
+use crate::models::{DocumentMetadata, HardcoverIntegration, SyncEvent};
+use crate::AppState;
+
+/// Key the user's token is stored under in the integrations table.
+pub const NAME: &str = "hardcover";
+
+/// Minimum time between progress pushes for one document. Finishing a
+/// book is always pushed.
+const PROGRESS_PUSH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Hardcover's `user_book_statuses`
+const STATUS_READING: i64 = 2;
+const STATUS_READ: i64 = 3;
+
+const FIND_EDITION: &str = "query FindEdition($isbn: String!) {
+  editions(where: {_or: [{isbn_13: {_eq: $isbn}}, {isbn_10: {_eq: $isbn}}]}, limit: 1) {
+    id book_id pages
+  }
+}";
+const FIND_BOOK: &str = "query FindBook($title: String!) {
+  books(where: {title: {_eq: $title}}, order_by: {users_count: desc}, limit: 1) {
+    id pages
+  }
+}";
+const FIND_USER_BOOK: &str = "query FindUserBook($book_id: Int!) {
+  me {
+    user_books(where: {book_id: {_eq: $book_id}}, limit: 1) {
+      id
+      user_book_reads(order_by: {id: desc}, limit: 1) { id }
+    }
+  }
+}";
+const INSERT_USER_BOOK: &str = "mutation InsertUserBook($object: UserBookCreateInput!) {
+  insert_user_book(object: $object) { id error }
+}";
+const UPDATE_USER_BOOK: &str = "mutation UpdateUserBook($id: Int!, $object: UserBookUpdateInput!) {
+  update_user_book(id: $id, object: $object) { id error }
+}";
+const INSERT_READ: &str = "mutation InsertRead($user_book_id: Int!, $read: DatesReadInput!) {
+  insert_user_book_read(user_book_id: $user_book_id, user_book_read: $read) { id error }
+}";
+const UPDATE_READ: &str = "mutation UpdateRead($id: Int!, $read: DatesReadInput!) {
+  update_user_book_read(id: $id, object: $read) { id error }
+}";
+
+/// Push progress and finished events for users who configured a Hardcover
+/// token. Books are matched by the ISBN in the document's metadata, or by
+/// exact title when no ISBN is stored.
+pub fn spawn_sync(state: AppState) {
+    let mut events = state.events.subscribe();
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("failed to build Hardcover HTTP client");
+
+    tokio::spawn(async move {
+        let mut last_push: HashMap<(String, String), Instant> = HashMap::new();
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Hardcover sync skipped {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            // At or past the threshold a progress update is followed by its
+            // own finished event, so only that one is pushed
+            let (document, percentage, finished) = match &event.event {
+                SyncEvent::Progress {
+                    document,
+                    percentage,
+                    ..
+                } if *percentage < state.config.finished_threshold => {
+                    (document, *percentage, false)
+                }
+                SyncEvent::Finished {
+                    document,
+                    percentage,
+                    ..
+                } => (document, *percentage, true),
+                _ => continue,
+            };
+
+            let integration: HardcoverIntegration =
+                match state.with_db(|db| db.get_integration(&event.username, NAME)) {
+                    Ok(Some(integration)) => integration,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        tracing::error!("Failed to load Hardcover token: {}", e);
+                        continue;
+                    }
+                };
+
+            let key = (event.username.clone(), document.clone());
+            if !finished
+                && last_push
+                    .get(&key)
+                    .is_some_and(|t| t.elapsed() < PROGRESS_PUSH_INTERVAL)
+            {
+                continue;
+            }
+
+            let metadata = match state.with_db(|db| db.get_metadata(&event.username, document)) {
+                Ok(Some(metadata)) if metadata.isbn.is_some() || metadata.title.is_some() => {
+                    metadata
+                }
+                Ok(_) => {
+                    tracing::debug!(
+                        "No title or ISBN for {}; not pushing to Hardcover",
+                        document
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to load metadata for {}: {}", document, e);
+                    continue;
+                }
+            };
+
+            last_push.insert(key, Instant::now());
+            tokio::spawn(push(
+                client.clone(),
+                state.config.hardcover_url.clone(),
+                integration.token,
+                event.clone(),
+                metadata,
+                percentage,
+                finished,
+            ));
+        }
+    });
+}
+
+async fn push(
+    client: reqwest::Client,
+    url: String,
+    token: String,
+    event: UserEvent,
+    metadata: DocumentMetadata,
+    percentage: f64,
+    finished: bool,
+) {
+    let api = Api {
+        client: &client,
+        url: &url,
+        token: &token,
+    };
+    if let Err(e) = api.push(&metadata, percentage, finished).await {
+        tracing::warn!("Hardcover push for {} failed: {}", event.username, e);
+    }
+}
+
+struct Api<'a> {
+    client: &'a reqwest::Client,
+    url: &'a str,
+    token: &'a str,
+}
+
+impl Api<'_> {
+    async fn push(
+        &self,
+        metadata: &DocumentMetadata,
+        percentage: f64,
+        finished: bool,
+    ) -> Result<(), String> {
+        let Some(book) = self.find_book(metadata).await? else {
+            tracing::debug!("No Hardcover match for {:?}", metadata.title);
+            return Ok(());
+        };
+        let status = if finished {
+            STATUS_READ
+        } else {
+            STATUS_READING
+        };
+
+        let data = self
+            .query(FIND_USER_BOOK, json!({ "book_id": book.book_id }))
+            .await?;
+        let existing = &data["me"][0]["user_books"][0];
+        let user_book_id = match existing["id"].as_i64() {
+            Some(id) => {
+                self.query(
+                    UPDATE_USER_BOOK,
+                    json!({ "id": id, "object": { "status_id": status } }),
+                )
+                .await?;
+                id
+            }
+            None => {
+                let mut object = json!({ "book_id": book.book_id, "status_id": status });
+                if let Some(edition_id) = book.edition_id {
+                    object["edition_id"] = json!(edition_id);
+                }
+                let data = self
+                    .query(INSERT_USER_BOOK, json!({ "object": object }))
+                    .await?;
+                data["insert_user_book"]["id"]
+                    .as_i64()
+                    .ok_or("insert_user_book returned no id")?
+            }
+        };
+
+        // Page progress needs a page count to convert the percentage
+        let Some(pages) = book.pages else {
+            return Ok(());
+        };
+        let mut read = json!({
+            "progress_pages": (percentage.clamp(0.0, 1.0) * pages as f64).round() as i64,
+        });
+        if let Some(edition_id) = book.edition_id {
+            read["edition_id"] = json!(edition_id);
+        }
+        if finished {
+            read["finished_at"] = json!(today());
+        }
+        match existing["user_book_reads"][0]["id"].as_i64() {
+            Some(id) => {
+                self.query(UPDATE_READ, json!({ "id": id, "read": read }))
+                    .await?
+            }
+            None => {
+                self.query(
+                    INSERT_READ,
+                    json!({ "user_book_id": user_book_id, "read": read }),
+                )
+                .await?
+            }
+        };
+        Ok(())
+    }
+
+    async fn find_book(&self, metadata: &DocumentMetadata) -> Result<Option<BookMatch>, String> {
+        if let Some(isbn) = &metadata.isbn {
+            let isbn: String = isbn.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+            let data = self.query(FIND_EDITION, json!({ "isbn": isbn })).await?;
+            let edition = &data["editions"][0];
+            if let Some(book_id) = edition["book_id"].as_i64() {
+                return Ok(Some(BookMatch {
+                    book_id,
+                    edition_id: edition["id"].as_i64(),
+                    pages: edition["pages"].as_i64().filter(|&p| p > 0),
+                }));
+            }
+        }
+        if let Some(title) = &metadata.title {
+            let data = self.query(FIND_BOOK, json!({ "title": title })).await?;
+            let book = &data["books"][0];
+            if let Some(book_id) = book["id"].as_i64() {
+                return Ok(Some(BookMatch {
+                    book_id,
+                    edition_id: None,
+                    pages: book["pages"].as_i64().filter(|&p| p > 0),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Run one GraphQL operation and return its `data`.
+    async fn query(&self, query: &str, variables: Value) -> Result<Value, String> {
+        let response = self
+            .client
+            .post(self.url)
+            .bearer_auth(self.token)
+            .json(&json!({ "query": query, "variables": variables }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("HTTP {}", status));
+        }
+        let mut body: Value = response.json().await.map_err(|e| e.to_string())?;
+        if let Some(errors) = body.get("errors") {
+            return Err(errors.to_string());
+        }
+        Ok(body["data"].take())
+    }
+}
+
+struct BookMatch {
+    book_id: i64,
+    edition_id: Option<i64>,
+    pages: Option<i64>,
+}
+
+/// Today's UTC date as `YYYY-MM-DD`.
+fn today() -> String {
+    crate::streaks::format_date(crate::db::now().div_euclid(24 * 60 * 60))
+}
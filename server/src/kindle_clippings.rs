@@ -0,0 +1,310 @@
+//! Import of the `My Clippings.txt` file Kindles keep of every highlight,
+//! note and bookmark.
+//!
+//! Clippings name their book by title and author only, and locate themselves
+//! by Kindle "location" numbers that have no counterpart in KOReader.
+//! Imported annotations keep that location in `kindle` and get a synthetic
+//! `page` derived from it, so importing the same file twice matches them up
+//! again instead of duplicating them.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::Value;
+
+use crate::models::{Annotation, DocumentMetadata, KindleLocation};
+use crate::streaks;
+
+const SEPARATOR: &str = "==========";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Highlight,
+    Note,
+    Bookmark,
+}
+
+#[derive(Debug)]
+struct Clipping {
+    /// The book line as written, `Title (Author)`.
+    book: String,
+    title: String,
+    kind: Kind,
+    location: KindleLocation,
+    datetime: Option<String>,
+    content: String,
+}
+
+/// Annotations per document, and the books no document matched.
+#[derive(Debug, Default)]
+pub struct Import {
+    pub documents: BTreeMap<String, Vec<Annotation>>,
+    pub unmatched: Vec<String>,
+}
+
+/// Turn a clippings file into annotations for the user's documents.
+///
+/// `mapping` sends a book (its title, or the full `Title (Author)` line) to
+/// a document; books not in it are matched against the titles in
+/// `metadata`, ignoring case and punctuation.
+pub fn import(
+    text: &str,
+    metadata: &BTreeMap<String, DocumentMetadata>,
+    mapping: &HashMap<String, String>,
+) -> Import {
+    let titles: HashMap<String, &String> = metadata
+        .iter()
+        .filter_map(|(document, meta)| Some((normalize(meta.title.as_deref()?), document)))
+        .collect();
+
+    let mut books: BTreeMap<String, (String, Vec<Clipping>)> = BTreeMap::new();
+    for clipping in parse(text) {
+        books
+            .entry(clipping.book.clone())
+            .or_insert_with(|| (clipping.title.clone(), Vec::new()))
+            .1
+            .push(clipping);
+    }
+
+    let mut result = Import::default();
+    for (book, (title, clippings)) in books {
+        let document = mapping
+            .get(&book)
+            .or_else(|| mapping.get(&title))
+            .or_else(|| titles.get(&normalize(&title)).copied());
+        match document {
+            Some(document) => result
+                .documents
+                .entry(document.clone())
+                .or_default()
+                .extend(to_annotations(clippings)),
+            None => result.unmatched.push(book),
+        }
+    }
+    result
+}
+
+/// A book's clippings as annotations, with each note attached to the
+/// highlight it was written on.
+fn to_annotations(clippings: Vec<Clipping>) -> Vec<Annotation> {
+    let mut annotations: Vec<Annotation> = Vec::new();
+    let mut notes = Vec::new();
+    for clipping in clippings {
+        match clipping.kind {
+            Kind::Note => notes.push(clipping),
+            // Re-highlighting a passage on a Kindle appends a new clipping
+            // instead of changing the old one
+            Kind::Highlight => match annotations
+                .iter_mut()
+                .find(|a| a.kindle.as_ref() == Some(&clipping.location))
+            {
+                Some(existing) => existing.text = Some(clipping.content),
+                None => annotations.push(annotation(clipping)),
+            },
+            Kind::Bookmark => annotations.push(annotation(clipping)),
+        }
+    }
+
+    for note in notes {
+        // Kindles place a note at the last location of its highlight
+        let highlight = annotations.iter_mut().find(|a| {
+            a.text.is_some()
+                && a.kindle.as_ref().is_some_and(|k| {
+                    (k.start..=k.end.unwrap_or(k.start)).contains(&note.location.start)
+                })
+        });
+        match highlight {
+            Some(highlight) => highlight.note = Some(note.content),
+            None => annotations.push(annotation(note)),
+        }
+    }
+    annotations
+}
+
+fn annotation(clipping: Clipping) -> Annotation {
+    let (text, note, drawer) = match clipping.kind {
+        Kind::Highlight => (Some(clipping.content), None, Some("lighten".to_string())),
+        Kind::Note => (None, Some(clipping.content), None),
+        Kind::Bookmark => (None, None, None),
+    };
+    let location = clipping.location;
+    let page = match location.end {
+        Some(end) => format!("kindle:{}-{}", location.start, end),
+        None => format!("kindle:{}", location.start),
+    };
+    Annotation {
+        id: None,
+        datetime: clipping.datetime.unwrap_or_else(now_datetime),
+        datetime_updated: None,
+        drawer,
+        color: None,
+        text,
+        text_edited: None,
+        note,
+        chapter: None,
+        pageno: location.page.and_then(|p| i32::try_from(p).ok()),
+        page: Value::String(page),
+        pos0: None,
+        pos1: None,
+        version: None,
+        tags: Vec::new(),
+        attachments: Vec::new(),
+        clocks: Default::default(),
+        calibre: None,
+        kindle: Some(location),
+        created_by: None,
+        modified_by: None,
+    }
+}
+
+fn parse(text: &str) -> Vec<Clipping> {
+    let text = text.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+    text.split(SEPARATOR)
+        .filter_map(|entry| {
+            let mut lines = entry.trim_matches('\n').lines();
+            let book = lines.next()?.trim_start_matches('\u{feff}').trim();
+            let header = lines.next()?;
+            let content = lines.collect::<Vec<_>>().join("\n").trim().to_string();
+            parse_clipping(book, header, content)
+        })
+        .collect()
+}
+
+fn parse_clipping(book: &str, header: &str, content: String) -> Option<Clipping> {
+    let header = header.trim().trim_start_matches('-').trim();
+    let lower = header.to_ascii_lowercase();
+    let kind = if lower.contains("highlight") {
+        Kind::Highlight
+    } else if lower.contains("note") {
+        Kind::Note
+    } else if lower.contains("bookmark") {
+        Kind::Bookmark
+    } else {
+        return None;
+    };
+    if content.is_empty() && kind != Kind::Bookmark {
+        return None;
+    }
+
+    let mut location = None;
+    let mut page = None;
+    let mut datetime = None;
+    for part in header.split('|').map(str::trim) {
+        let lower = part.to_ascii_lowercase();
+        if let Some(rest) = lower.strip_prefix("added on ") {
+            datetime = parse_date(rest);
+        } else if let Some(at) = lower.find("location ").map(|i| i + 9).or_else(|| {
+            // Older Kindles write "Loc. 1234-36"
+            lower.find("loc. ").map(|i| i + 5)
+        }) {
+            location = parse_range(&lower[at..]);
+        } else if let Some(at) = lower.find("page ") {
+            page = parse_range(&lower[at + 5..]).map(|(start, _)| start);
+        }
+    }
+    let (start, end) = location?;
+
+    let book = book.to_string();
+    let title = match book.rfind(" (") {
+        Some(i) if book.ends_with(')') => book[..i].trim().to_string(),
+        _ => book.clone(),
+    };
+    Some(Clipping {
+        book,
+        title,
+        kind,
+        location: KindleLocation { start, end, page },
+        datetime,
+        content,
+    })
+}
+
+/// `1234-1240`, or `1234-40` with the leading digits of the end left off.
+fn parse_range(text: &str) -> Option<(u32, Option<u32>)> {
+    let text: String = text
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '-')
+        .collect();
+    let (start, end) = match text.split_once('-') {
+        Some((start, end)) => (start, Some(end)),
+        None => (text.as_str(), None),
+    };
+    let start: u32 = start.parse().ok()?;
+    let end = end.and_then(|end| {
+        let value: u32 = end.parse().ok()?;
+        if end.len() < start.to_string().len() {
+            let scale = 10u32.pow(end.len() as u32);
+            let end = start - start % scale + value;
+            // "1298-02" runs on to 1302
+            Some(if end < start { end + scale } else { end })
+        } else {
+            Some(value)
+        }
+    });
+    Some((start, end.filter(|&end| end > start)))
+}
+
+/// `Monday, January 1, 2024 10:00:00 AM` as KOReader's `2024-01-01 10:00:00`.
+/// Other languages' date formats aren't recognized.
+fn parse_date(text: &str) -> Option<String> {
+    const MONTHS: [&str; 12] = [
+        "january",
+        "february",
+        "march",
+        "april",
+        "may",
+        "june",
+        "july",
+        "august",
+        "september",
+        "october",
+        "november",
+        "december",
+    ];
+    let text = text.split_once(", ").map_or(text, |(_, rest)| rest);
+    let words: Vec<&str> = text
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|w| !w.is_empty())
+        .collect();
+    let (month, day, year, time) = match words[..] {
+        [month, day, year, time, ..] => (month, day, year, time),
+        _ => return None,
+    };
+    let month = MONTHS.iter().position(|m| *m == month)? + 1;
+    let day: u32 = day.parse().ok()?;
+    let year: u32 = year.parse().ok()?;
+
+    let mut clock = time.split(':').map(|part| part.parse::<u32>().ok());
+    let mut hour = clock.next()??;
+    let minute = clock.next()??;
+    let second = clock.next().flatten().unwrap_or(0);
+    match words.get(4).copied() {
+        Some("pm") if hour < 12 => hour += 12,
+        Some("am") if hour == 12 => hour = 0,
+        _ => {}
+    }
+    Some(format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year, month, day, hour, minute, second
+    ))
+}
+
+fn now_datetime() -> String {
+    let now = crate::db::now();
+    let seconds = now.rem_euclid(86_400);
+    format!(
+        "{} {:02}:{:02}:{:02}",
+        streaks::format_date(now.div_euclid(86_400)),
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Letters and digits only, lowercased, for comparing titles.
+fn normalize(title: &str) -> String {
+    title
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
@@ -0,0 +1,463 @@
+//! Import from KOReader's `*.sdr/metadata.<ext>.lua` sidecar files, where
+//! KOReader keeps a book's highlights, bookmarks and reading position when
+//! nothing syncs them.
+//!
+//! The sidecar is a Lua table literal written by KOReader's `dump()`. Only
+//! that subset of Lua is understood: nested tables, strings, numbers,
+//! booleans and `nil`. Both the current `annotations` list and the older
+//! `highlight` and `bookmarks` tables are read.
+
+use serde_json::{Map, Value};
+
+use crate::models::Annotation;
+
+/// What a sidecar holds that the server can store.
+#[derive(Debug, Default)]
+pub struct Sidecar {
+    pub annotations: Vec<Annotation>,
+    /// Last position: an xpointer, or a page number for paged documents.
+    pub progress: Option<String>,
+    pub percentage: Option<f64>,
+}
+
+/// Parse a sidecar file.
+pub fn import(source: &str) -> Result<Sidecar, String> {
+    let settings = parse(source)?;
+    if !settings.is_object() && !settings.is_array() {
+        return Err("expected a table".into());
+    }
+
+    let annotations = match settings.get("annotations") {
+        Some(annotations) => entries(annotations)
+            .into_iter()
+            .filter_map(|entry| to_annotation(entry, None))
+            .collect(),
+        None => legacy_annotations(&settings),
+    };
+
+    let progress = match settings.get("last_xpointer") {
+        Some(Value::String(xpointer)) => Some(xpointer.clone()),
+        _ => settings
+            .get("last_page")
+            .and_then(Value::as_i64)
+            .map(|page| page.to_string()),
+    };
+
+    Ok(Sidecar {
+        annotations,
+        progress,
+        percentage: settings.get("percent_finished").and_then(Value::as_f64),
+    })
+}
+
+/// Annotations from the `highlight` and `bookmarks` tables used before
+/// KOReader 2024.04. Highlights are listed by page, and their notes live on
+/// the matching bookmark.
+fn legacy_annotations(settings: &Value) -> Vec<Annotation> {
+    let bookmarks: Vec<&Value> = settings.get("bookmarks").map(entries).unwrap_or_default();
+    let note_for = |datetime: &str| {
+        bookmarks
+            .iter()
+            .find(|bm| bm.get("datetime").and_then(Value::as_str) == Some(datetime))
+            .and_then(|bm| user_note(bm))
+    };
+
+    let mut annotations = Vec::new();
+    let mut highlighted = Vec::new();
+    let pages: Vec<(Value, &Value)> = match settings.get("highlight") {
+        Some(Value::Array(pages)) => (1..).map(Value::from).zip(pages).collect(),
+        Some(Value::Object(pages)) => pages
+            .iter()
+            .map(|(page, entries)| {
+                (
+                    page.parse::<i64>().map_or(Value::Null, Value::from),
+                    entries,
+                )
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    for (pageno, page) in pages {
+        for entry in entries(page) {
+            let Some(datetime) = entry.get("datetime").and_then(Value::as_str) else {
+                continue;
+            };
+            // Highlights are keyed by page number; in reflowable documents
+            // the xpointer of their start is the better position
+            let mut entry = entry.clone();
+            if entry.get("page").is_none() {
+                let page = match entry.get("pos0") {
+                    Some(Value::String(pos0)) => Value::String(pos0.clone()),
+                    _ => pageno.clone(),
+                };
+                entry["page"] = page;
+            }
+            if let Some(annotation) = to_annotation(&entry, note_for(datetime)) {
+                highlighted.push(datetime.to_string());
+                annotations.push(annotation);
+            }
+        }
+    }
+
+    for bookmark in bookmarks.iter().copied() {
+        let datetime = bookmark.get("datetime").and_then(Value::as_str);
+        if datetime.is_some_and(|dt| highlighted.iter().any(|h| h == dt))
+            || bookmark.get("highlighted") == Some(&Value::Bool(true))
+        {
+            continue;
+        }
+        if let Some(mut annotation) = to_annotation(bookmark, user_note(bookmark)) {
+            // A legacy bookmark's `text` is its note, taken above
+            annotation.text = None;
+            annotations.push(annotation);
+        }
+    }
+    annotations
+}
+
+/// A legacy bookmark's `text`, unless it is the "Page 12 @ <date>" label
+/// KOReader fills in when the user wrote nothing.
+fn user_note(bookmark: &Value) -> Option<String> {
+    let text = bookmark.get("text").and_then(Value::as_str)?;
+    let generated = text.starts_with("Page ") && text.contains(" @ ");
+    (!generated && !text.trim().is_empty()).then(|| text.to_string())
+}
+
+fn to_annotation(entry: &Value, legacy_note: Option<String>) -> Option<Annotation> {
+    let string = |field: &str| entry.get(field).and_then(Value::as_str).map(str::to_string);
+    let datetime = string("datetime")?;
+    let page = entry
+        .get("page")
+        .filter(|p| p.is_string() || p.is_number())?;
+
+    Some(Annotation {
+        id: None,
+        datetime,
+        datetime_updated: string("datetime_updated"),
+        drawer: string("drawer"),
+        color: string("color"),
+        text: string("text"),
+        text_edited: entry
+            .get("text_edited")
+            .or_else(|| entry.get("edited"))
+            .and_then(Value::as_bool),
+        note: legacy_note.or_else(|| string("note")),
+        chapter: string("chapter"),
+        pageno: entry
+            .get("pageno")
+            .and_then(Value::as_i64)
+            .and_then(|p| i32::try_from(p).ok()),
+        page: page.clone(),
+        pos0: entry.get("pos0").cloned(),
+        pos1: entry.get("pos1").cloned(),
+        version: None,
+        tags: Vec::new(),
+        attachments: Vec::new(),
+        clocks: Default::default(),
+        calibre: None,
+        kindle: None,
+        created_by: None,
+        modified_by: None,
+    })
+}
+
+/// The values of a Lua table, which may have become an array or an object.
+fn entries(value: &Value) -> Vec<&Value> {
+    match value {
+        Value::Array(items) => items.iter().collect(),
+        Value::Object(map) => map.values().collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Parse a Lua table literal, optionally preceded by `return`, into JSON.
+/// Tables keyed `1..n` become arrays; any other table becomes an object with
+/// its keys as strings.
+pub fn parse(source: &str) -> Result<Value, String> {
+    let mut parser = Parser {
+        src: source.as_bytes(),
+        pos: 0,
+    };
+    parser.skip_space();
+    if parser.src[parser.pos..].starts_with(b"return")
+        && !parser
+            .src
+            .get(parser.pos + 6)
+            .is_some_and(|c| c.is_ascii_alphanumeric() || *c == b'_')
+    {
+        parser.pos += 6;
+    }
+    let value = parser.value(0)?;
+    parser.skip_space();
+    if parser.pos < parser.src.len() {
+        return Err(parser.error("unexpected trailing input"));
+    }
+    Ok(value)
+}
+
+/// Deepest table nesting accepted; sidecars stay well below this.
+const MAX_DEPTH: usize = 64;
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("{} at byte {}", message, self.pos)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn skip_space(&mut self) {
+        loop {
+            while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+                self.pos += 1;
+            }
+            if !self.src[self.pos..].starts_with(b"--") {
+                return;
+            }
+            self.pos += 2;
+            if self.src[self.pos..].starts_with(b"[[") {
+                match find(&self.src[self.pos..], b"]]") {
+                    Some(end) => self.pos += end + 2,
+                    None => self.pos = self.src.len(),
+                }
+            } else {
+                while self.peek().is_some_and(|c| c != b'\n') {
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        self.skip_space();
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("expected '{}'", c as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        self.skip_space();
+        match self.peek() {
+            Some(b'{') => self.table(depth + 1),
+            Some(b'"' | b'\'') => self.string().map(Value::String),
+            Some(c) if c == b'-' || c == b'.' || c.is_ascii_digit() => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => match self.identifier().as_str() {
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                "nil" => Ok(Value::Null),
+                _ => Err(self.error("unsupported expression")),
+            },
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn table(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("tables nested too deeply"));
+        }
+        self.expect(b'{')?;
+        let mut fields: Vec<(Value, Value)> = Vec::new();
+        let mut next_index = 1;
+        loop {
+            self.skip_space();
+            match self.peek() {
+                Some(b'}') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(b'[') => {
+                    self.pos += 1;
+                    let key = self.value(depth)?;
+                    self.expect(b']')?;
+                    self.expect(b'=')?;
+                    fields.push((key, self.value(depth)?));
+                }
+                Some(c) if c.is_ascii_alphabetic() || c == b'_' => {
+                    let start = self.pos;
+                    let name = self.identifier();
+                    self.skip_space();
+                    if self.peek() == Some(b'=') {
+                        self.pos += 1;
+                        fields.push((Value::String(name), self.value(depth)?));
+                    } else {
+                        self.pos = start;
+                        fields.push((next_index.into(), self.value(depth)?));
+                        next_index += 1;
+                    }
+                }
+                Some(_) => {
+                    fields.push((next_index.into(), self.value(depth)?));
+                    next_index += 1;
+                }
+                None => return Err(self.error("unterminated table")),
+            }
+            self.skip_space();
+            match self.peek() {
+                Some(b',' | b';') => self.pos += 1,
+                Some(b'}') => {}
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+
+        // `nil` values leave the key unset, as in Lua
+        fields.retain(|(_, value)| !value.is_null());
+        let is_array =
+            !fields.is_empty() && fields.iter().all(|(key, _)| key.as_u64().is_some()) && {
+                let mut indices: Vec<u64> =
+                    fields.iter().filter_map(|(key, _)| key.as_u64()).collect();
+                indices.sort_unstable();
+                indices.iter().copied().eq(1..=fields.len() as u64)
+            };
+        if is_array {
+            fields.sort_by_key(|(key, _)| key.as_u64());
+            return Ok(Value::Array(fields.into_iter().map(|(_, v)| v).collect()));
+        }
+
+        let mut map = Map::new();
+        for (key, value) in fields {
+            let key = match key {
+                Value::String(key) => key,
+                Value::Null => return Err(self.error("nil table key")),
+                other => other.to_string(),
+            };
+            map.insert(key, value);
+        }
+        Ok(Value::Object(map))
+    }
+
+    fn identifier(&mut self) -> String {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == b'_')
+        {
+            self.pos += 1;
+        }
+        String::from_utf8_lossy(&self.src[start..self.pos]).into_owned()
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, b'.' | b'+' | b'-'))
+        {
+            // A sign only belongs to the number right after an exponent
+            if matches!(self.peek(), Some(b'+' | b'-'))
+                && !matches!(self.src[self.pos - 1], b'e' | b'E')
+            {
+                break;
+            }
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.src[start..self.pos]).unwrap_or_default();
+        if let Ok(n) = text.parse::<i64>() {
+            return Ok(n.into());
+        }
+        text.parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number)
+            .ok_or_else(|| self.error("invalid number"))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let quote = self.src[self.pos];
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+            match c {
+                c if c == quote => break,
+                b'\\' => self.escape(&mut out)?,
+                c => out.push(c),
+            }
+        }
+        Ok(String::from_utf8_lossy(&out).into_owned())
+    }
+
+    fn escape(&mut self, out: &mut Vec<u8>) -> Result<(), String> {
+        let Some(c) = self.peek() else {
+            return Err(self.error("unterminated string"));
+        };
+        self.pos += 1;
+        match c {
+            b'n' | b'\n' => out.push(b'\n'),
+            b'r' => out.push(b'\r'),
+            b't' => out.push(b'\t'),
+            b'a' => out.push(0x07),
+            b'b' => out.push(0x08),
+            b'f' => out.push(0x0c),
+            b'v' => out.push(0x0b),
+            b'\r' => {
+                out.push(b'\n');
+                if self.peek() == Some(b'\n') {
+                    self.pos += 1;
+                }
+            }
+            b'z' => {
+                while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+                    self.pos += 1;
+                }
+            }
+            b'x' => {
+                let hex = self.src.get(self.pos..self.pos + 2).unwrap_or_default();
+                let byte = std::str::from_utf8(hex)
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| self.error("invalid \\x escape"))?;
+                self.pos += 2;
+                out.push(byte);
+            }
+            b'u' => {
+                self.expect(b'{')?;
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+                    self.pos += 1;
+                }
+                let ch = std::str::from_utf8(&self.src[start..self.pos])
+                    .ok()
+                    .and_then(|h| u32::from_str_radix(h, 16).ok())
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| self.error("invalid \\u escape"))?;
+                self.expect(b'}')?;
+                out.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes());
+            }
+            c if c.is_ascii_digit() => {
+                let mut value = u32::from(c - b'0');
+                for _ in 0..2 {
+                    match self.peek() {
+                        Some(d) if d.is_ascii_digit() => {
+                            value = value * 10 + u32::from(d - b'0');
+                            self.pos += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                let byte = u8::try_from(value).map_err(|_| self.error("invalid escape"))?;
+                out.push(byte);
+            }
+            // \\, \", \' and anything else stand for themselves
+            c => out.push(c),
+        }
+        Ok(())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
@@ -0,0 +1,272 @@
+//! Import from the original koreader-sync-server, which keeps everything in
+//! Redis under two kinds of keys:
+//!
+//! - `user:<username>:key`: the account's key, the MD5 of its password,
+//!   which this server stores as it is.
+//! - `user:<username>:document:<document>`: a hash of `progress`,
+//!   `percentage`, `device`, `device_id` and `timestamp`.
+//!
+//! Only a handful of commands are needed to read them, so this speaks the
+//! Redis protocol (RESP2) itself over a plain TCP connection.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+use crate::db::Database;
+use crate::error::{AppError, Result};
+use crate::models::Progress;
+
+/// What an import did.
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    /// Accounts created.
+    pub users: usize,
+    /// Accounts that already existed here with the same key; their progress
+    /// is imported too.
+    pub existing_users: usize,
+    /// Progress records stored.
+    pub documents: usize,
+    /// Keys that weren't imported, and why.
+    pub skipped: Vec<String>,
+}
+
+/// Copy the accounts and progress from the Redis server at `url`
+/// (`redis://[[user]:password@]host[:port][/db]`) into `db`.
+///
+/// Accounts whose name is already taken here with a different key are
+/// left alone, progress included. Progress replaces what is stored only
+/// when it is newer.
+pub fn import(db: &Database, url: &str) -> Result<ImportSummary> {
+    let mut redis = Connection::open(url)?;
+    let mut summary = ImportSummary::default();
+
+    let mut users = BTreeSet::new();
+    for key in redis.scan("user:*:key")? {
+        let Some(username) = key
+            .strip_prefix("user:")
+            .and_then(|rest| rest.strip_suffix(":key"))
+        else {
+            continue;
+        };
+        if username.is_empty() || username.contains(':') {
+            summary.skipped.push(format!("{}: invalid username", key));
+            continue;
+        }
+        let Some(userkey) = redis.get(&key)? else {
+            continue;
+        };
+        if db.create_user(username, &userkey)? {
+            summary.users += 1;
+        } else if db.verify_user(username, &userkey)? {
+            summary.existing_users += 1;
+        } else {
+            summary
+                .skipped
+                .push(format!("{}: user exists with another key", key));
+            continue;
+        }
+        users.insert(username.to_string());
+    }
+
+    let mut progress: BTreeMap<String, Vec<Progress>> = BTreeMap::new();
+    for key in redis.scan("user:*:document:*")? {
+        let Some((username, document)) = key
+            .strip_prefix("user:")
+            .and_then(|rest| rest.split_once(":document:"))
+        else {
+            continue;
+        };
+        if !users.contains(username) {
+            summary.skipped.push(format!("{}: user not imported", key));
+            continue;
+        }
+        if document.is_empty() || document.contains(':') {
+            summary.skipped.push(format!("{}: invalid document", key));
+            continue;
+        }
+        let fields = redis.hgetall(&key)?;
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value.clone())
+        };
+        progress
+            .entry(username.to_string())
+            .or_default()
+            .push(Progress {
+                document: Some(document.to_string()),
+                progress: field("progress"),
+                percentage: field("percentage").and_then(|p| p.parse().ok()),
+                device: field("device"),
+                device_id: field("device_id"),
+                timestamp: field("timestamp").and_then(|t| t.parse().ok()),
+                ..Progress::default()
+            });
+    }
+    for (username, records) in progress {
+        summary.documents += db.import_progress(&username, records)?;
+    }
+
+    Ok(summary)
+}
+
+fn redis_error(message: impl std::fmt::Display) -> AppError {
+    AppError::Upstream(format!("Redis: {}", message))
+}
+
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+impl Reply {
+    fn into_string(self) -> Result<Option<String>> {
+        match self {
+            Reply::Status(s) => Ok(Some(s)),
+            Reply::Integer(n) => Ok(Some(n.to_string())),
+            Reply::Bulk(None) => Ok(None),
+            Reply::Bulk(Some(bytes)) => String::from_utf8(bytes)
+                .map(Some)
+                .map_err(|_| redis_error("value is not UTF-8")),
+            Reply::Array(_) => Err(redis_error("expected a string, got an array")),
+        }
+    }
+
+    fn into_array(self) -> Result<Vec<Reply>> {
+        match self {
+            Reply::Array(items) => Ok(items.unwrap_or_default()),
+            _ => Err(redis_error("expected an array")),
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn open(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("redis://")
+            .ok_or_else(|| redis_error("URL must start with redis://"))?;
+        let (rest, db) = match rest.split_once('/') {
+            Some((rest, db)) if !db.is_empty() => (rest, Some(db)),
+            Some((rest, _)) => (rest, None),
+            None => (rest, None),
+        };
+        let (credentials, address) = match rest.rsplit_once('@') {
+            Some((credentials, address)) => (Some(credentials), address),
+            None => (None, rest),
+        };
+        let address = if address.contains(':') {
+            address.to_string()
+        } else {
+            format!("{}:6379", address)
+        };
+
+        let writer = TcpStream::connect(&address)?;
+        let mut connection = Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        };
+        if let Some(credentials) = credentials {
+            let auth = match credentials.split_once(':') {
+                Some(("", password)) => vec!["AUTH", password],
+                Some((user, password)) => vec!["AUTH", user, password],
+                None => vec!["AUTH", credentials],
+            };
+            connection.command(&auth)?;
+        }
+        if let Some(db) = db {
+            connection.command(&["SELECT", db])?;
+        }
+        Ok(connection)
+    }
+
+    fn command(&mut self, args: &[&str]) -> Result<Reply> {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(request.as_bytes())?;
+        self.read_reply()
+    }
+
+    fn read_reply(&mut self) -> Result<Reply> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(redis_error("connection closed"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (kind, value) = line.split_at_checked(1).unwrap_or(("", ""));
+        let length = || {
+            value
+                .parse::<i64>()
+                .map_err(|_| redis_error(format!("bad reply: {}", line)))
+        };
+        match kind {
+            "+" => Ok(Reply::Status(value.to_string())),
+            "-" => Err(redis_error(value)),
+            ":" => Ok(Reply::Integer(length()?)),
+            "$" => match usize::try_from(length()?) {
+                Ok(len) => {
+                    let mut bytes = vec![0; len + 2];
+                    self.reader.read_exact(&mut bytes)?;
+                    bytes.truncate(len);
+                    Ok(Reply::Bulk(Some(bytes)))
+                }
+                Err(_) => Ok(Reply::Bulk(None)),
+            },
+            "*" => match usize::try_from(length()?) {
+                Ok(len) => {
+                    let items = (0..len).map(|_| self.read_reply()).collect::<Result<_>>()?;
+                    Ok(Reply::Array(Some(items)))
+                }
+                Err(_) => Ok(Reply::Array(None)),
+            },
+            _ => Err(redis_error(format!("bad reply: {}", line))),
+        }
+    }
+
+    /// Every key matching `pattern`, in order.
+    fn scan(&mut self, pattern: &str) -> Result<BTreeSet<String>> {
+        let mut keys = BTreeSet::new();
+        let mut cursor = "0".to_string();
+        loop {
+            let mut reply = self
+                .command(&["SCAN", &cursor, "MATCH", pattern, "COUNT", "1000"])?
+                .into_array()?
+                .into_iter();
+            let (Some(next), Some(batch)) = (reply.next(), reply.next()) else {
+                return Err(redis_error("bad SCAN reply"));
+            };
+            for key in batch.into_array()? {
+                keys.extend(key.into_string()?);
+            }
+            cursor = next.into_string()?.unwrap_or_default();
+            if cursor == "0" {
+                return Ok(keys);
+            }
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.command(&["GET", key])?.into_string()
+    }
+
+    fn hgetall(&mut self, key: &str) -> Result<Vec<(String, String)>> {
+        let mut items = self.command(&["HGETALL", key])?.into_array()?.into_iter();
+        let mut fields = Vec::new();
+        while let (Some(field), Some(value)) = (items.next(), items.next()) {
+            if let (Some(field), Some(value)) = (field.into_string()?, value.into_string()?) {
+                fields.push((field, value));
+            }
+        }
+        Ok(fields)
+    }
+}
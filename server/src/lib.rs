@@ -1,20 +1,84 @@
+pub mod backup;
+pub mod cache;
+pub mod calibre_annotations;
+pub mod calibre_web;
+pub mod config;
 pub mod db;
+pub mod dump;
 pub mod error;
+pub mod events;
+pub mod export;
+pub mod follower;
 pub mod handlers;
+pub mod hardcover;
+pub mod kindle_clippings;
+pub mod koreader_metadata;
+pub mod legacy_redis;
+pub mod merge;
+pub mod metrics;
+pub mod migrations;
 pub mod models;
+pub mod outbound;
+pub mod position;
+pub mod quota;
+pub mod readwise;
+pub mod replication;
+pub mod s3;
+pub mod search;
+pub mod streaks;
+pub mod style;
+pub mod tasks;
+pub mod webhooks;
+pub mod write_queue;
+pub mod ws;
 
 use axum::{
-    routing::{get, post, put},
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{delete, get, post, put},
     Router,
 };
 use std::sync::Arc;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 
+pub use config::Config;
 pub use db::Database;
 
+use events::EventHub;
+use metrics::UsageTracker;
+
 #[derive(Clone)]
 pub struct AppState {
     pub db: Arc<Database>,
+    pub config: Arc<Config>,
+    pub usage: Arc<UsageTracker>,
+    pub events: Arc<EventHub>,
+}
+
+impl AppState {
+    pub fn new(mut db: Database, config: Config) -> Self {
+        let config = Arc::new(config);
+        db.set_config(config.clone());
+        Self {
+            db: Arc::new(db),
+            usage: Arc::new(UsageTracker::new(config.usage_window)),
+            events: Arc::default(),
+            config,
+        }
+    }
+
+    /// Run `f` against the database. redb calls block, on an fsync for every
+    /// commit, so on the multi-threaded runtime the worker first hands its
+    /// other tasks to another thread instead of stalling them. The
+    /// single-threaded runtime (the tests') has nowhere to hand them.
+    pub fn with_db<T>(&self, f: impl FnOnce(&Database) -> T) -> T {
+        match tokio::runtime::Handle::try_current().map(|h| h.runtime_flavor()) {
+            Ok(tokio::runtime::RuntimeFlavor::MultiThread) => {
+                tokio::task::block_in_place(|| f(&self.db))
+            }
+            _ => f(&self.db),
+        }
+    }
 }
 
 pub fn create_router(state: AppState) -> Router {
@@ -22,13 +86,235 @@ pub fn create_router(state: AppState) -> Router {
         // Legacy KOSync API (v1)
         .route("/users/create", post(handlers::create_user))
         .route("/users/auth", get(handlers::auth_user))
+        .route("/users/settings", get(handlers::get_settings))
+        .route("/users/settings", put(handlers::update_settings))
         .route("/syncs/progress", put(handlers::update_progress))
+        .route("/syncs/progress", get(handlers::list_progress))
+        .route("/syncs/progress/export", get(handlers::export_progress))
+        .route("/syncs/progress/query", post(handlers::query_progress))
+        .route("/syncs/continue", get(handlers::continue_reading))
+        .route(
+            "/syncs/progress/batch",
+            put(handlers::update_progress_batch),
+        )
         .route("/syncs/progress/{document}", get(handlers::get_progress))
+        .route(
+            "/syncs/progress/{document}",
+            delete(handlers::delete_progress),
+        )
+        .route(
+            "/syncs/progress/{document}/history",
+            get(handlers::get_progress_history),
+        )
+        // Extended API (v2) - document metadata
+        .route(
+            "/syncs/documents/{document}/metadata",
+            get(handlers::get_metadata),
+        )
+        .route(
+            "/syncs/documents/{document}/metadata",
+            put(handlers::update_metadata),
+        )
+        .route(
+            "/syncs/documents/{document}/status",
+            get(handlers::get_status),
+        )
+        .route(
+            "/syncs/documents/{document}/status",
+            put(handlers::update_status),
+        )
+        .route(
+            "/syncs/documents/{document}/status",
+            delete(handlers::delete_status),
+        )
+        .route("/syncs/status", get(handlers::list_status))
+        .route(
+            "/syncs/documents/{document}/review",
+            get(handlers::get_review),
+        )
+        .route(
+            "/syncs/documents/{document}/review",
+            put(handlers::update_review),
+        )
+        .route(
+            "/syncs/documents/{document}/review",
+            delete(handlers::delete_review),
+        )
+        .route("/syncs/documents", get(handlers::list_documents))
+        .route("/syncs/documents/{document}/tags", get(handlers::get_tags))
+        .route(
+            "/syncs/documents/{document}/tags",
+            put(handlers::update_tags),
+        )
+        .route("/syncs/reviews", get(handlers::list_reviews))
+        .route("/syncs/reviews/export", get(handlers::export_reviews))
+        .route("/syncs/documents/{document}/note", get(handlers::get_note))
+        .route(
+            "/syncs/documents/{document}/note",
+            put(handlers::update_note),
+        )
+        // Extended API (v2) - document aliases
+        .route("/syncs/aliases", post(handlers::create_aliases))
+        .route("/syncs/aliases", get(handlers::list_aliases))
+        .route("/syncs/aliases/{alias}", delete(handlers::delete_alias))
         // Extended API (v2) - annotations
-        .route("/syncs/annotations/{document}", get(handlers::get_annotations))
-        .route("/syncs/annotations/{document}", put(handlers::update_annotations))
+        .route(
+            "/syncs/annotations/search",
+            get(handlers::search_annotations),
+        )
+        .route("/syncs/annotations/anki", get(handlers::export_anki))
+        .route(
+            "/syncs/annotations/kindle",
+            post(handlers::import_kindle_clippings),
+        )
+        .route(
+            "/syncs/annotations/{document}",
+            get(handlers::get_annotations),
+        )
+        .route(
+            "/syncs/annotations/{document}",
+            put(handlers::update_annotations),
+        )
+        .route(
+            "/syncs/annotations/{document}",
+            delete(handlers::clear_annotations),
+        )
+        .route(
+            "/syncs/annotations/{document}/preview",
+            post(handlers::preview_annotations),
+        )
+        .route(
+            "/syncs/annotations/{document}/export",
+            get(handlers::export_annotations),
+        )
+        .route(
+            "/syncs/annotations/{document}/import",
+            post(handlers::import_annotations),
+        )
+        .route(
+            "/syncs/annotations/{document}/search",
+            get(handlers::search_document_annotations),
+        )
+        .route(
+            "/syncs/annotations/{document}/attachments",
+            get(handlers::list_attachments),
+        )
+        .route(
+            "/syncs/annotations/{document}/attachments/{id}",
+            put(handlers::put_attachment)
+                .layer(DefaultBodyLimit::max(state.config.max_attachment_size)),
+        )
+        .route(
+            "/syncs/annotations/{document}/attachments/{id}",
+            get(handlers::get_attachment),
+        )
+        .route(
+            "/syncs/annotations/{document}/attachments/{id}",
+            delete(handlers::delete_attachment),
+        )
+        .route(
+            "/syncs/annotations/{document}/version",
+            get(handlers::get_annotations_version),
+        )
+        .route(
+            "/syncs/annotations/{document}/versions",
+            get(handlers::list_annotation_versions),
+        )
+        .route(
+            "/syncs/annotations/{document}/diff",
+            get(handlers::diff_annotations),
+        )
+        .route(
+            "/syncs/annotations/{document}/revert/{version}",
+            post(handlers::revert_annotations),
+        )
+        .route(
+            "/syncs/annotations/{document}/public",
+            post(handlers::publish_annotations),
+        )
+        .route(
+            "/syncs/annotations/{document}/public",
+            delete(handlers::unpublish_annotations),
+        )
+        .route("/shared/{token}", get(handlers::shared_annotations_page))
+        .route("/syncs/feed", post(handlers::create_feed))
+        .route("/syncs/feed", delete(handlers::delete_feed))
+        .route("/feeds/{token}", get(handlers::annotations_feed))
+        .route("/syncs/notes", get(handlers::get_notebook))
+        .route("/syncs/highlights/random", get(handlers::random_highlights))
+        // Shared documents
+        .route("/syncs/shares", post(handlers::create_share))
+        .route("/syncs/shares", get(handlers::list_shares))
+        .route("/syncs/shares/{id}/join", post(handlers::join_share))
+        .route("/syncs/shares/{id}", delete(handlers::leave_share))
+        // Extended API (v2) - reading statistics
+        .route("/syncs/statistics", get(handlers::get_statistics))
+        .route("/syncs/statistics", put(handlers::update_statistics))
+        // Extended API (v2) - reading sessions
+        .route("/syncs/sessions", post(handlers::create_session))
+        .route("/syncs/sessions", get(handlers::list_sessions))
+        // Extended API (v2) - change notifications
+        .route("/syncs/ws", get(ws::sync_socket))
+        // Devices
+        .route("/devices", get(handlers::list_devices))
+        .route("/devices/{id}", delete(handlers::delete_device))
+        // Trash
+        .route("/trash", get(handlers::list_trash))
+        .route("/trash/{id}/restore", post(handlers::restore_trash))
+        // Finished books
+        .route("/syncs/finished", get(handlers::list_finished))
+        .route("/syncs/finished/export", get(handlers::export_finished))
+        // Goals and streaks
+        .route("/stats/streak", get(handlers::get_streak))
+        // Integrations
+        .route(
+            "/users/integrations/hardcover",
+            put(handlers::set_hardcover),
+        )
+        .route(
+            "/users/integrations/hardcover",
+            delete(handlers::delete_hardcover),
+        )
+        .route("/users/integrations/readwise", put(handlers::set_readwise))
+        .route(
+            "/users/integrations/readwise",
+            delete(handlers::delete_readwise),
+        )
+        .route(
+            "/users/integrations/calibre-web",
+            put(handlers::set_calibre_web),
+        )
+        .route(
+            "/users/integrations/calibre-web",
+            delete(handlers::delete_calibre_web),
+        )
+        .route("/syncs/calibre", get(handlers::list_calibre_books))
+        .route("/syncs/calibre/{document}", put(handlers::set_calibre_book))
+        .route(
+            "/syncs/calibre/{document}",
+            delete(handlers::delete_calibre_book),
+        )
+        .route(
+            "/syncs/calibre/{document}/pull",
+            post(handlers::pull_calibre_progress),
+        )
+        // Webhooks
+        .route("/users/webhooks", post(handlers::create_webhook))
+        .route("/users/webhooks", get(handlers::list_webhooks))
+        .route("/users/webhooks/{id}", delete(handlers::delete_webhook))
+        // Usage metrics
+        .route("/users/usage", get(handlers::get_usage))
+        .route("/admin/usage", get(handlers::get_all_usage))
+        .route("/admin/backup", post(handlers::create_backup))
+        .route("/admin/journal", get(handlers::get_journal))
+        .route("/admin/disk", get(handlers::get_disk_usage))
+        .route("/admin/db/snapshot", get(handlers::get_db_snapshot))
         // Health check
         .route("/healthcheck", get(handlers::healthcheck))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track_usage,
+        ))
         .layer(CorsLayer::permissive())
         .layer(TraceLayer::new_for_http())
         .with_state(state)
@@ -1,19 +1,108 @@
-use kosync_server::{create_router, AppState, Database};
-use std::sync::Arc;
+use std::path::Path;
+
+use kosync_server::config::{Durability, DEMO_USER};
+use kosync_server::error::AppError;
+use kosync_server::{
+    backup, create_router, dump, follower, legacy_redis, replication, tasks, AppState, Config,
+    Database,
+};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+const USAGE: &str = "usage: kosync-server [serve | backup <path> | compact | fsck [--quarantine] \
+     | export --all | import <dump.jsonl | -> | import-redis <redis://host[:port][/db]> \
+     | restore-replica <path> | user purge <username> [--keep-account] \
+     | gc-orphans [--dry-run]]";
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new(
             std::env::var("RUST_LOG").unwrap_or_else(|_| "info,tower_http=debug".into()),
         ))
-        .with(tracing_subscriber::fmt::layer())
+        // Keeps stdout for `export`
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] | ["serve"] => serve().await,
+        ["backup", path] => backup(Path::new(path)),
+        ["compact"] => compact(),
+        ["fsck"] => fsck(false),
+        ["fsck", "--quarantine"] => fsck(true),
+        ["export", "--all"] => export_all(),
+        ["import", path] => import(path),
+        ["import-redis", url] => import_redis(url),
+        ["restore-replica", path] => restore_replica(Path::new(path)),
+        ["user", "purge", username] => purge_user(username, false),
+        ["user", "purge", username, "--keep-account"] => purge_user(username, true),
+        ["gc-orphans"] => gc_orphans(false),
+        ["gc-orphans", "--dry-run"] => gc_orphans(true),
+        _ => anyhow::bail!(USAGE),
+    }
+}
+
+fn db_path() -> anyhow::Result<String> {
     let db_path = std::env::var("KOSYNC_DB_PATH").unwrap_or_else(|_| "kosync.db".into());
-    let db = Database::open(&db_path)?;
-    let state = AppState { db: Arc::new(db) };
+    // Otherwise redb would try to create a file by that name
+    if db_path.starts_with("postgres://") || db_path.starts_with("postgresql://") {
+        anyhow::bail!(
+            "PostgreSQL storage is not supported; KOSYNC_DB_PATH must be a file path. \
+             To run several servers, see KOSYNC_LEADER_URL"
+        );
+    }
+    Ok(db_path)
+}
+
+fn open_database() -> anyhow::Result<Database> {
+    let db_path = db_path()?;
+    if db_path == ":memory:" {
+        tracing::warn!("Using an in-memory database; everything is lost on exit");
+        return Ok(Database::open_in_memory()?);
+    }
+    Database::open(&db_path).map_err(|e| open_error(e, &db_path))
+}
+
+/// redb's error for a locked file doesn't say which file.
+fn open_error(e: AppError, db_path: &str) -> anyhow::Error {
+    match e {
+        AppError::DatabaseError(redb::DatabaseError::DatabaseAlreadyOpen) => {
+            anyhow::anyhow!("{} is already open in another process", db_path)
+        }
+        e => e.into(),
+    }
+}
+
+async fn serve() -> anyhow::Result<()> {
+    let config = Config::from_env();
+    if config.compact_on_startup && db_path()? != ":memory:" {
+        compact()?;
+    }
+    let eventual = config.durability == Durability::Eventual;
+    // Fail now rather than start a follower that never follows
+    follower::Leader::from_config(&config)?;
+    let db = open_database()?;
+    if config.demo_mode && db.user_exists(DEMO_USER)? {
+        anyhow::bail!(
+            "KOSYNC_DEMO_MODE would take over and periodically wipe the registered '{}' account; \
+             rename or remove it first",
+            DEMO_USER
+        );
+    }
+    let state = AppState::new(db, config);
+    tasks::spawn_all(&state);
+    if eventual {
+        // Otherwise stopping the server loses the commits since the last flush
+        let state = state.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            match state.with_db(|db| db.flush()) {
+                Ok(()) => tracing::info!("Flushed commits to disk, stopping"),
+                Err(e) => tracing::error!("Failed to flush commits to disk: {}", e),
+            }
+            std::process::exit(0);
+        });
+    }
 
     let app = create_router(state);
 
@@ -26,3 +115,181 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Ctrl-C, or SIGTERM from `docker stop` and service managers.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Copy the database to `path`. redb locks the file while a server has it
+/// open, so this is for stopped servers; a running one is backed up
+/// through `POST /admin/backup`.
+fn backup(path: &Path) -> anyhow::Result<()> {
+    let size = open_database()?.backup(path)?;
+    tracing::info!("Wrote backup {} ({} bytes)", path.display(), size);
+    Ok(())
+}
+
+/// Rewrite the database into a fresh file to reclaim the space of deleted
+/// data. Like `backup`, this needs the server stopped.
+fn compact() -> anyhow::Result<()> {
+    let db_path = db_path()?;
+    if db_path == ":memory:" {
+        anyhow::bail!("An in-memory database has no file to compact");
+    }
+    let (before, after) =
+        backup::compact(Path::new(&db_path)).map_err(|e| open_error(e, &db_path))?;
+    tracing::info!("Compacted {} from {} to {} bytes", db_path, before, after);
+    Ok(())
+}
+
+/// Check the database for damage and entries the server can't read. Like
+/// `backup`, this needs the server stopped.
+fn fsck(quarantine: bool) -> anyhow::Result<()> {
+    let report = open_database()?.fsck(quarantine)?;
+    if report.repaired {
+        tracing::warn!("The database file was damaged and has been repaired");
+    }
+    for problem in &report.problems {
+        tracing::warn!("{} {}: {}", problem.table, problem.key, problem.reason);
+    }
+    tracing::info!(
+        "Checked {} entries, found {} problems",
+        report.checked,
+        report.problems.len()
+    );
+    if report.quarantined {
+        tracing::info!("Moved them to the quarantine table");
+    } else if !report.problems.is_empty() {
+        anyhow::bail!("Found problems; `fsck --quarantine` moves them aside");
+    }
+    Ok(())
+}
+
+/// Rebuild the newest snapshot in `KOSYNC_REPLICA_DIR` as a database file
+/// at `path`.
+fn restore_replica(path: &Path) -> anyhow::Result<()> {
+    let Some(dir) = Config::from_env().replica_dir else {
+        anyhow::bail!("KOSYNC_REPLICA_DIR is not set");
+    };
+    let (snapshot, size) = replication::restore(&dir, path)?;
+    tracing::info!(
+        "Restored snapshot {} to {} ({} bytes)",
+        snapshot,
+        path.display(),
+        size
+    );
+    Ok(())
+}
+
+/// Write a JSON-lines dump of every account, progress record and
+/// annotation to stdout. Like `backup`, this needs the server stopped.
+fn export_all() -> anyhow::Result<()> {
+    let stdout = std::io::stdout().lock();
+    let summary = dump::export(&open_database()?, std::io::BufWriter::new(stdout))?;
+    tracing::info!(
+        "Exported {} users, {} progress records and {} documents' annotations",
+        summary.users,
+        summary.progress,
+        summary.annotations
+    );
+    Ok(())
+}
+
+/// Load a dump written by `export --all`, from `path` or `-` for stdin.
+fn import(path: &str) -> anyhow::Result<()> {
+    let db = open_database()?;
+    let summary = if path == "-" {
+        dump::import(&db, std::io::stdin().lock())?
+    } else {
+        dump::import(&db, std::io::BufReader::new(std::fs::File::open(path)?))?
+    };
+    for skipped in &summary.skipped {
+        tracing::warn!("Skipped {}", skipped);
+    }
+    tracing::info!(
+        "Imported {} new and {} existing users, {} progress records and {} documents' \
+         annotations; skipped {} lines",
+        summary.users,
+        summary.existing_users,
+        summary.progress,
+        summary.annotations,
+        summary.skipped.len()
+    );
+    Ok(())
+}
+
+/// Remove everything stored for a user, for deletion requests and
+/// abandoned accounts. Like `backup`, this needs the server stopped.
+fn purge_user(username: &str, keep_account: bool) -> anyhow::Result<()> {
+    let existed = open_database()?.purge_user(username, keep_account)?;
+    match (existed, keep_account) {
+        (false, _) => tracing::warn!(
+            "There is no user {}; removed any data left under that name",
+            username
+        ),
+        (true, true) => tracing::info!("Removed all data of {}, keeping the account", username),
+        (true, false) => tracing::info!("Removed {} and all their data", username),
+    }
+    Ok(())
+}
+
+/// Remove progress and annotations left under usernames without an
+/// account, or with `dry_run`, only list them. Like `backup`, this needs
+/// the server stopped.
+fn gc_orphans(dry_run: bool) -> anyhow::Result<()> {
+    // The demo account never has one
+    let keep: &[&str] = if Config::from_env().demo_mode {
+        &[DEMO_USER]
+    } else {
+        &[]
+    };
+    let orphans = open_database()?.collect_orphans(dry_run, keep)?;
+    for orphan in &orphans {
+        tracing::info!(
+            "{}: {} progress records, {} documents' annotations",
+            orphan.username,
+            orphan.progress,
+            orphan.annotations
+        );
+    }
+    match (orphans.len(), dry_run) {
+        (0, _) => tracing::info!("Found no records without an account"),
+        (count, true) => tracing::info!(
+            "Found records of {} usernames without an account; run without --dry-run to remove them",
+            count
+        ),
+        (count, false) => tracing::info!(
+            "Removed everything stored under {} usernames without an account",
+            count
+        ),
+    }
+    Ok(())
+}
+
+/// Import accounts and progress from the original Lua server's Redis.
+fn import_redis(url: &str) -> anyhow::Result<()> {
+    let summary = legacy_redis::import(&open_database()?, url)?;
+    for skipped in &summary.skipped {
+        tracing::warn!("Skipped {}", skipped);
+    }
+    tracing::info!(
+        "Imported {} new and {} existing users with {} progress records; skipped {} keys",
+        summary.users,
+        summary.existing_users,
+        summary.documents,
+        summary.skipped.len()
+    );
+    Ok(())
+}
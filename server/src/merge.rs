@@ -0,0 +1,499 @@
+//! Annotation merging.
+//!
+//! A document's annotations form an observed-remove set keyed by id: each
+//! highlight gets a fresh id when the server first sees it, and a deletion
+//! names the id it removes, so it never takes out a highlight made
+//! concurrently elsewhere. Within one annotation every editable field is a
+//! last-writer-wins register with its own clock, so when one device edits
+//! the note and another the color, both edits survive.
+//!
+//! An upload that changes an annotation the server also changed since the
+//! client last fetched it is a conflict, resolved by a `MergeStrategy`.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::config::MergeStrategyKind;
+use crate::error::{AppError, Result};
+use crate::models::{Annotation, AnnotationConflict, ConflictOutcome, FieldClock};
+use crate::position;
+
+/// Where merged-in annotations come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Incoming {
+    /// A client upload. Each annotation's `version` is the one the client
+    /// last fetched, and any clocks it sends are ignored.
+    Upload,
+    /// Another stored record, whose clocks are authoritative.
+    Replica,
+}
+
+/// The independently merged parts of an annotation.
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    Drawer,
+    Color,
+    Note,
+    Text,
+    Chapter,
+    Position,
+    Tags,
+    Attachments,
+}
+
+impl Field {
+    const ALL: [Field; 8] = [
+        Field::Drawer,
+        Field::Color,
+        Field::Note,
+        Field::Text,
+        Field::Chapter,
+        Field::Position,
+        Field::Tags,
+        Field::Attachments,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Field::Drawer => "drawer",
+            Field::Color => "color",
+            Field::Note => "note",
+            Field::Text => "text",
+            Field::Chapter => "chapter",
+            Field::Position => "position",
+            Field::Tags => "tags",
+            Field::Attachments => "attachments",
+        }
+    }
+
+    fn same(self, a: &Annotation, b: &Annotation) -> bool {
+        match self {
+            Field::Drawer => a.drawer == b.drawer,
+            Field::Color => a.color == b.color,
+            Field::Note => a.note == b.note,
+            Field::Text => a.text == b.text && a.text_edited == b.text_edited,
+            Field::Chapter => a.chapter == b.chapter,
+            Field::Position => {
+                a.page == b.page
+                    && a.pos0 == b.pos0
+                    && a.pos1 == b.pos1
+                    && a.pageno == b.pageno
+                    && a.calibre == b.calibre
+                    && a.kindle == b.kindle
+            }
+            Field::Tags => a.tags == b.tags,
+            Field::Attachments => a.attachments == b.attachments,
+        }
+    }
+
+    fn copy(self, to: &mut Annotation, from: &Annotation) {
+        match self {
+            Field::Drawer => to.drawer = from.drawer.clone(),
+            Field::Color => to.color = from.color.clone(),
+            Field::Note => to.note = from.note.clone(),
+            Field::Text => {
+                to.text = from.text.clone();
+                to.text_edited = from.text_edited;
+            }
+            Field::Chapter => to.chapter = from.chapter.clone(),
+            Field::Position => {
+                to.page = from.page.clone();
+                to.pos0 = from.pos0.clone();
+                to.pos1 = from.pos1.clone();
+                to.pageno = from.pageno;
+                to.calibre = from.calibre.clone();
+                to.kindle = from.kindle.clone();
+            }
+            Field::Tags => to.tags = from.tags.clone(),
+            Field::Attachments => to.attachments = from.attachments.clone(),
+        }
+    }
+}
+
+/// Names of the fields that differ between two copies of an annotation.
+pub fn changed_fields(a: &Annotation, b: &Annotation) -> Vec<&'static str> {
+    Field::ALL
+        .into_iter()
+        .filter(|field| !field.same(a, b))
+        .map(Field::name)
+        .collect()
+}
+
+fn effective_time(a: &Annotation) -> &str {
+    a.datetime_updated.as_deref().unwrap_or(&a.datetime)
+}
+
+/// A field's clock, or the annotation's own if the field has not been
+/// edited since clocks were tracked.
+fn clock(a: &Annotation, field: Field) -> FieldClock {
+    a.clocks
+        .get(field.name())
+        .cloned()
+        .unwrap_or_else(|| FieldClock {
+            at: effective_time(a).to_string(),
+            version: a.version.unwrap_or(0),
+        })
+}
+
+/// Give every annotation an id. Stored annotations from before ids get a new
+/// one; uploaded annotations without one take the id of the stored
+/// annotation at the same position, if any.
+pub(crate) fn assign_annotation_ids(server: &mut [Annotation], client: &mut [Annotation]) {
+    let mut known: HashMap<String, String> = HashMap::new();
+    for anno in server.iter_mut().chain(client.iter_mut()) {
+        // Same highlight or bookmark, whichever device made it
+        let position = position::canonical_key(anno);
+        match &anno.id {
+            Some(id) => {
+                known.entry(position).or_insert_with(|| id.clone());
+            }
+            None => {
+                let id = known
+                    .entry(position)
+                    .or_insert_with(|| uuid::Uuid::new_v4().simple().to_string());
+                anno.id = Some(id.clone());
+            }
+        }
+    }
+}
+
+/// Names of the fields in which `incoming` differs from `stored`.
+fn differing(stored: &Annotation, incoming: &Annotation) -> Vec<String> {
+    Field::ALL
+        .iter()
+        .filter(|f| !f.same(stored, incoming))
+        .map(|f| f.name().to_string())
+        .collect()
+}
+
+/// Merge `incoming` into `stored` field by field, stamping `stored` with
+/// `version` if anything changed. Returns the fields whose incoming values
+/// lost.
+fn merge_annotation(
+    stored: &mut Annotation,
+    incoming: &Annotation,
+    version: u64,
+    kind: Incoming,
+) -> Vec<String> {
+    // An upload based on a known version edited exactly the fields that
+    // differ from what it fetched; anything else is resolved by time
+    let base = match kind {
+        Incoming::Upload => incoming.version,
+        Incoming::Replica => None,
+    };
+
+    let mut clocks = BTreeMap::new();
+    let mut changed = false;
+    let mut rejected = Vec::new();
+    for field in Field::ALL {
+        let ours = clock(stored, field);
+        let theirs = match kind {
+            Incoming::Upload => FieldClock {
+                at: effective_time(incoming).to_string(),
+                version,
+            },
+            Incoming::Replica => clock(incoming, field),
+        };
+        if field.same(stored, incoming) {
+            clocks.insert(field.name().to_string(), ours);
+            continue;
+        }
+        let wins = match base {
+            Some(base) => ours.version <= base,
+            None => theirs.at > ours.at,
+        };
+        if wins {
+            field.copy(stored, incoming);
+            clocks.insert(
+                field.name().to_string(),
+                FieldClock {
+                    at: theirs.at,
+                    version,
+                },
+            );
+            changed = true;
+        } else {
+            clocks.insert(field.name().to_string(), ours);
+            rejected.push(field.name().to_string());
+        }
+    }
+
+    if changed {
+        let latest = clocks.values().map(|c| c.at.as_str()).max();
+        if let Some(latest) = latest.filter(|at| *at > stored.datetime.as_str()) {
+            stored.datetime_updated = Some(latest.to_string());
+        }
+        stored.clocks = clocks;
+        stored.version = Some(version);
+    }
+    rejected
+}
+
+/// Replace every field of `stored` with those of `incoming`.
+fn overwrite(stored: &mut Annotation, incoming: &Annotation, version: u64) {
+    let at = effective_time(incoming).to_string();
+    let mut clocks = BTreeMap::new();
+    let mut changed = false;
+    for field in Field::ALL {
+        if field.same(stored, incoming) {
+            clocks.insert(field.name().to_string(), clock(stored, field));
+        } else {
+            field.copy(stored, incoming);
+            let clock = FieldClock {
+                at: at.clone(),
+                version,
+            };
+            clocks.insert(field.name().to_string(), clock);
+            changed = true;
+        }
+    }
+    if changed {
+        stored.datetime_updated = incoming.datetime_updated.clone();
+        stored.clocks = clocks;
+        stored.version = Some(version);
+    }
+}
+
+/// What a strategy did with a conflicting upload.
+#[derive(Debug)]
+pub enum Resolution {
+    /// The stored annotation was kept or updated in place, ignoring the
+    /// uploaded values of the named fields.
+    Merged(Vec<String>),
+    /// The upload is to be stored as a separate annotation.
+    Added(Box<Annotation>),
+    /// The upload cannot be applied.
+    Rejected,
+}
+
+/// Resolves an uploaded annotation against a stored one with the same id
+/// that changed since the client last fetched it.
+pub trait MergeStrategy {
+    fn resolve(&self, stored: &mut Annotation, incoming: Annotation, version: u64) -> Resolution;
+}
+
+pub struct ServerWins;
+
+impl MergeStrategy for ServerWins {
+    fn resolve(&self, stored: &mut Annotation, incoming: Annotation, _: u64) -> Resolution {
+        Resolution::Merged(differing(stored, &incoming))
+    }
+}
+
+pub struct ClientWins;
+
+impl MergeStrategy for ClientWins {
+    fn resolve(&self, stored: &mut Annotation, incoming: Annotation, version: u64) -> Resolution {
+        overwrite(stored, &incoming, version);
+        Resolution::Merged(Vec::new())
+    }
+}
+
+pub struct NewestWins;
+
+impl MergeStrategy for NewestWins {
+    fn resolve(&self, stored: &mut Annotation, incoming: Annotation, version: u64) -> Resolution {
+        Resolution::Merged(merge_annotation(
+            stored,
+            &incoming,
+            version,
+            Incoming::Upload,
+        ))
+    }
+}
+
+pub struct Union;
+
+impl MergeStrategy for Union {
+    fn resolve(&self, _stored: &mut Annotation, mut incoming: Annotation, _: u64) -> Resolution {
+        incoming.id = Some(uuid::Uuid::new_v4().simple().to_string());
+        Resolution::Added(Box::new(incoming))
+    }
+}
+
+pub struct Manual;
+
+impl MergeStrategy for Manual {
+    fn resolve(&self, _stored: &mut Annotation, _incoming: Annotation, _: u64) -> Resolution {
+        Resolution::Rejected
+    }
+}
+
+impl MergeStrategyKind {
+    pub fn strategy(self) -> &'static dyn MergeStrategy {
+        match self {
+            MergeStrategyKind::ServerWins => &ServerWins,
+            MergeStrategyKind::ClientWins => &ClientWins,
+            MergeStrategyKind::NewestWins => &NewestWins,
+            MergeStrategyKind::Union => &Union,
+            MergeStrategyKind::Manual => &Manual,
+        }
+    }
+}
+
+/// Whether applying `incoming` would overwrite a change the client has not
+/// seen. Without the version the client fetched, any upload older than
+/// the stored annotation counts.
+fn is_conflict(stored: &Annotation, incoming: &Annotation) -> bool {
+    !differing(stored, incoming).is_empty()
+        && match incoming.version {
+            Some(base) => stored.version.unwrap_or(0) > base,
+            None => effective_time(stored) >= effective_time(incoming),
+        }
+}
+
+/// How an upload is merged.
+#[derive(Debug, Clone, Default)]
+pub struct MergeOptions {
+    /// Overrides the server's default conflict resolution.
+    pub strategy: Option<MergeStrategyKind>,
+    /// Collapse annotations with the same position and text into one.
+    pub dedup: bool,
+    /// Device credited in `created_by` and `modified_by` for what the
+    /// upload adds or changes.
+    pub device_id: Option<String>,
+}
+
+/// Outcome of `merge_annotations`.
+pub(crate) struct Merge {
+    pub annotations: Vec<Annotation>,
+    /// In upload order, the id each client annotation ended up under.
+    pub ids: Vec<String>,
+    /// Uploaded annotations that were not applied as sent.
+    pub conflicts: Vec<AnnotationConflict>,
+    /// Ids removed by `dedup`, mapped to the annotation kept in their place.
+    pub duplicates: BTreeMap<String, String>,
+}
+
+impl Merge {
+    /// Collapse annotations at the same position with the same text, as
+    /// importers and buggy clients create, into the earliest of them. A
+    /// note on a dropped copy is kept if the survivor has none.
+    pub fn dedup(&mut self) {
+        let mut annotations = std::mem::take(&mut self.annotations);
+        annotations.sort_by(|a, b| (&a.datetime, &a.id).cmp(&(&b.datetime, &b.id)));
+
+        let mut kept: HashMap<(String, Option<String>), usize> = HashMap::new();
+        for anno in annotations {
+            let key = (position::canonical_key(&anno), anno.text.clone());
+            let Some(&index) = kept.get(&key) else {
+                kept.insert(key, self.annotations.len());
+                self.annotations.push(anno);
+                continue;
+            };
+            let survivor = &mut self.annotations[index];
+            if survivor.note.is_none() && anno.note.is_some() {
+                survivor.note = anno.note;
+            }
+            let (dropped, id) = (
+                anno.id.unwrap_or_default(),
+                survivor.id.clone().unwrap_or_default(),
+            );
+            self.duplicates.insert(dropped, id);
+        }
+
+        for id in &mut self.ids {
+            if let Some(kept) = self.duplicates.get(id) {
+                *id = kept.clone();
+            }
+        }
+        self.annotations.sort_by(|a, b| a.id.cmp(&b.id));
+    }
+}
+
+/// Merge `client` annotations into `server` ones, stamping whatever the
+/// merge adds or changes with `version`. Conflicting uploads are resolved
+/// by `strategy`; stored records merged with `Incoming::Replica` always
+/// merge field by field.
+pub(crate) fn merge_annotations(
+    server: Vec<Annotation>,
+    client: Vec<Annotation>,
+    server_deleted: &[String],
+    client_deleted: &[String],
+    version: u64,
+    kind: Incoming,
+    strategy: &dyn MergeStrategy,
+) -> Result<Merge> {
+    // Deletions name an id, or a datetime from clients that predate ids
+    fn is_deleted(a: &Annotation, deleted: &[String]) -> bool {
+        deleted
+            .iter()
+            .any(|d| Some(d) == a.id.as_ref() || *d == a.datetime)
+    }
+
+    let mut server = server;
+    let mut client = client;
+    assign_annotation_ids(&mut server, &mut client);
+
+    let mut merged: BTreeMap<String, Annotation> = BTreeMap::new();
+
+    // Add server annotations (skip if deleted by client)
+    for anno in server {
+        if !is_deleted(&anno, client_deleted) {
+            merged.insert(anno.id.clone().unwrap_or_default(), anno);
+        }
+    }
+
+    // Merge client annotations
+    let mut ids = Vec::with_capacity(client.len());
+    let mut conflicts = Vec::new();
+    let mut added = Vec::new();
+    for mut anno in client {
+        let key = anno.id.clone().unwrap_or_default();
+        ids.push(key.clone());
+        let conflict = |outcome, fields| AnnotationConflict {
+            id: key.clone(),
+            outcome,
+            fields,
+            stored_as: None,
+        };
+        if is_deleted(&anno, server_deleted) {
+            // Skip if deleted on server
+            conflicts.push(conflict(ConflictOutcome::Deleted, Vec::new()));
+            continue;
+        }
+
+        if kind == Incoming::Upload {
+            anno.clocks.clear();
+        }
+        let rejected = match merged.get_mut(&key) {
+            Some(existing) if kind == Incoming::Upload && is_conflict(existing, &anno) => {
+                match strategy.resolve(existing, anno, version) {
+                    Resolution::Merged(rejected) => rejected,
+                    Resolution::Added(mut anno) => {
+                        anno.version = Some(version);
+                        let id = anno.id.clone().unwrap_or_default();
+                        conflicts.push(AnnotationConflict {
+                            stored_as: Some(id.clone()),
+                            ..conflict(ConflictOutcome::Duplicated, Vec::new())
+                        });
+                        if let Some(last) = ids.last_mut() {
+                            *last = id;
+                        }
+                        added.push(*anno);
+                        continue;
+                    }
+                    Resolution::Rejected => return Err(AppError::VersionConflict),
+                }
+            }
+            Some(existing) => merge_annotation(existing, &anno, version, kind),
+            None => {
+                anno.version = Some(version);
+                merged.insert(key, anno);
+                continue;
+            }
+        };
+        if kind == Incoming::Upload && !rejected.is_empty() {
+            conflicts.push(conflict(ConflictOutcome::Kept, rejected));
+        }
+    }
+    for anno in added {
+        merged.insert(anno.id.clone().unwrap_or_default(), anno);
+    }
+
+    Ok(Merge {
+        annotations: merged.into_values().collect(),
+        ids,
+        conflicts,
+        duplicates: BTreeMap::new(),
+    })
+}
@@ -0,0 +1,237 @@
+use axum::{
+    body::{Body, HttpBody},
+    extract::{MatchedPath, Request, State},
+    http::header::CONTENT_LENGTH,
+    middleware::Next,
+    response::Response,
+};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_util::StreamExt;
+
+use crate::models::{EndpointUsage, UserUsage};
+use crate::AppState;
+
+const BUCKET_SECS: u64 = 60;
+
+tokio::task_local! {
+    /// The user the current request authenticated as, set by `authenticated`.
+    static AUTHENTICATED: RefCell<Option<String>>;
+}
+
+/// Attribute the request being handled to `username`, once its key has been
+/// checked. Outside `track_usage` this does nothing.
+pub(crate) fn authenticated(username: &str) {
+    let _ = AUTHENTICATED.try_with(|user| *user.borrow_mut() = Some(username.to_string()));
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    start: u64,
+    requests: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+/// In-memory per-user, per-endpoint request counters over a rolling window.
+///
+/// Counters are kept in one-minute buckets; buckets older than the window
+/// are dropped lazily whenever a key is touched or read.
+pub struct UsageTracker {
+    window: Duration,
+    buckets: Mutex<HashMap<(String, String), VecDeque<Bucket>>>,
+}
+
+impl UsageTracker {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn record(&self, username: &str, endpoint: &str, bytes_in: u64, bytes_out: u64) {
+        let now = now_secs();
+        let start = now - now % BUCKET_SECS;
+        let cutoff = self.cutoff(now);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let series = buckets
+            .entry((username.to_string(), endpoint.to_string()))
+            .or_default();
+        while series.front().is_some_and(|b| b.start < cutoff) {
+            series.pop_front();
+        }
+        if series.back().is_none_or(|b| b.start != start) {
+            series.push_back(Bucket {
+                start,
+                ..Default::default()
+            });
+        }
+        let bucket = series.back_mut().unwrap();
+        bucket.requests += 1;
+        bucket.bytes_in += bytes_in;
+        bucket.bytes_out += bytes_out;
+    }
+
+    /// Usage for a single user, one entry per endpoint.
+    pub fn user_usage(&self, username: &str) -> Vec<EndpointUsage> {
+        self.snapshot()
+            .remove(username)
+            .map(|endpoints| endpoints.into_values().collect())
+            .unwrap_or_default()
+    }
+
+    /// Usage for every user seen in the window, busiest first.
+    pub fn all_usage(&self) -> Vec<UserUsage> {
+        let mut users: Vec<UserUsage> = self
+            .snapshot()
+            .into_iter()
+            .map(|(username, endpoints)| {
+                let endpoints: Vec<EndpointUsage> = endpoints.into_values().collect();
+                UserUsage {
+                    username,
+                    requests: endpoints.iter().map(|e| e.requests).sum(),
+                    bytes_in: endpoints.iter().map(|e| e.bytes_in).sum(),
+                    bytes_out: endpoints.iter().map(|e| e.bytes_out).sum(),
+                    endpoints,
+                }
+            })
+            .collect();
+        users.sort_by(|a, b| {
+            b.requests
+                .cmp(&a.requests)
+                .then(a.username.cmp(&b.username))
+        });
+        users
+    }
+
+    fn cutoff(&self, now: u64) -> u64 {
+        now.saturating_sub(self.window.as_secs())
+    }
+
+    fn snapshot(&self) -> BTreeMap<String, BTreeMap<String, EndpointUsage>> {
+        let cutoff = self.cutoff(now_secs());
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|_, series| {
+            while series.front().is_some_and(|b| b.start < cutoff) {
+                series.pop_front();
+            }
+            !series.is_empty()
+        });
+
+        let mut result: BTreeMap<String, BTreeMap<String, EndpointUsage>> = BTreeMap::new();
+        for ((username, endpoint), series) in buckets.iter() {
+            let usage = EndpointUsage {
+                endpoint: endpoint.clone(),
+                requests: series.iter().map(|b| b.requests).sum(),
+                bytes_in: series.iter().map(|b| b.bytes_in).sum(),
+                bytes_out: series.iter().map(|b| b.bytes_out).sum(),
+            };
+            result
+                .entry(username.clone())
+                .or_default()
+                .insert(endpoint.clone(), usage);
+        }
+        result
+    }
+}
+
+/// Middleware recording request counts and body sizes for authenticated users.
+///
+/// Only requests whose credentials were checked (see `authenticated`) are
+/// counted, so a client cannot inflate someone else's numbers, or fill the
+/// tracker with made-up names, by sending an `x-auth-user` header.
+pub async fn track_usage(
+    State(state): State<AppState>,
+    matched: Option<MatchedPath>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let endpoint = format!(
+        "{} {}",
+        req.method(),
+        matched.as_ref().map_or("unmatched", |m| m.as_str())
+    );
+    let bytes_in = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+
+    let (response, username) = AUTHENTICATED
+        .scope(RefCell::new(None), async {
+            let response = next.run(req).await;
+            (response, AUTHENTICATED.with(|user| user.take()))
+        })
+        .await;
+
+    let Some(username) = username else {
+        return response;
+    };
+    if let Some(bytes_out) = response.body().size_hint().exact() {
+        state
+            .usage
+            .record(&username, &endpoint, bytes_in, bytes_out);
+        return response;
+    }
+
+    // A streamed body's size is only known once it has been sent
+    let mut sent = SentBytes {
+        usage: state.usage.clone(),
+        username,
+        endpoint,
+        bytes_in,
+        bytes_out: 0,
+    };
+    let (parts, body) = response.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        if let Ok(data) = &chunk {
+            sent.add(data.len());
+        }
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+/// Counts a streamed response body, recording its usage when the body is
+/// dropped: after the last chunk, or when the client goes away.
+struct SentBytes {
+    usage: Arc<UsageTracker>,
+    username: String,
+    endpoint: String,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl SentBytes {
+    fn add(&mut self, bytes: usize) {
+        self.bytes_out += bytes as u64;
+    }
+}
+
+impl Drop for SentBytes {
+    fn drop(&mut self) {
+        self.usage.record(
+            &self.username,
+            &self.endpoint,
+            self.bytes_in,
+            self.bytes_out,
+        );
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
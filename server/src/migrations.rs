@@ -0,0 +1,48 @@
+//! Changes to how data is stored, applied in order when a database is
+//! opened.
+//!
+//! A database records the schema version it was last opened with; one
+//! without a record is at version 0. Each migration brings it up by one
+//! version inside the same write transaction that opens it, so a failed
+//! migration leaves the database as it was. A database from a newer server
+//! is refused: this one can't know what changed.
+//!
+//! Migrations run before any table is opened, and see each table as it
+//! was at their own version, so they name tables by the definitions of
+//! their time rather than today's constants.
+
+use redb::WriteTransaction;
+
+use crate::db::Database;
+use crate::error::Result;
+
+pub struct Migration {
+    /// The schema version the database is at once this has run.
+    pub version: u64,
+    pub description: &'static str,
+    pub apply: fn(&WriteTransaction) -> Result<()>,
+}
+
+/// Every migration, oldest first. Append only; never reorder or remove.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "rewrite highlight styles stored before uploads were normalized",
+        apply: Database::normalize_stored_styles,
+    },
+    Migration {
+        version: 2,
+        description: "key progress and annotations by (user, document)",
+        apply: Database::split_document_keys,
+    },
+    Migration {
+        version: 3,
+        description: "index each user's documents",
+        apply: Database::index_user_documents,
+    },
+];
+
+/// The schema version this server writes.
+pub fn current_version() -> u64 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
@@ -1,4 +1,7 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::config::MergeStrategyKind;
 
 // === Auth ===
 
@@ -18,24 +21,69 @@ pub struct AuthResponse {
     pub authorized: &'static str,
 }
 
+/// Per-user preferences. Unset fields fall back to the server configuration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UserSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub furthest_read_only: Option<bool>,
+    /// Minutes of reading per day that count towards a streak.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub daily_goal_minutes: Option<u32>,
+    /// Pages per day that count towards a streak.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub daily_goal_pages: Option<u32>,
+    /// Offset from UTC used to decide where a reading day starts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utc_offset_minutes: Option<i32>,
+}
+
+impl UserSettings {
+    /// Overlay the fields set in `changes` onto these settings.
+    pub fn merged_with(self, changes: &UserSettings) -> UserSettings {
+        UserSettings {
+            furthest_read_only: changes.furthest_read_only.or(self.furthest_read_only),
+            daily_goal_minutes: changes.daily_goal_minutes.or(self.daily_goal_minutes),
+            daily_goal_pages: changes.daily_goal_pages.or(self.daily_goal_pages),
+            utc_offset_minutes: changes.utc_offset_minutes.or(self.utc_offset_minutes),
+        }
+    }
+}
+
 // === Progress (legacy KOSync) ===
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct UpdateProgressRequest {
     pub document: String,
     pub progress: String,
     pub percentage: f64,
     pub device: String,
     pub device_id: Option<String>,
+    /// The same book's hash under KOReader's other matching method
+    /// (binary partial-MD5 vs. filename). The server links the two so
+    /// either hash finds this record.
+    #[serde(default)]
+    pub alt_document: Option<String>,
+    /// When the client made this change, by its own clock.
+    #[serde(default)]
+    pub client_timestamp: Option<i64>,
+    /// Only store the update if the stored progress is still at this
+    /// version (0 for none yet); otherwise fail with a version conflict.
+    #[serde(default)]
+    pub base_version: Option<u64>,
+    /// Store the update even if furthest-read-only or stale-timestamp
+    /// rejection would refuse it.
+    #[serde(default)]
+    pub force: bool,
 }
 
 #[derive(Debug, Serialize)]
 pub struct UpdateProgressResponse {
     pub document: String,
     pub timestamp: i64,
+    pub version: u64,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Progress {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub document: Option<String>,
@@ -49,12 +97,298 @@ pub struct Progress {
     pub device_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub timestamp: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_timestamp: Option<i64>,
+    /// Incremented on every write, for `base_version`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchProgressResult {
+    pub document: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchProgressResponse {
+    pub results: Vec<BatchProgressResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeleteProgressResponse {
+    pub document: String,
+    pub deleted: bool,
+}
+
+/// What a trashed record was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrashKind {
+    Progress,
+    Annotations,
+}
+
+/// A deleted progress record or set of annotations, kept until the trash
+/// is emptied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashItem {
+    pub id: String,
+    pub kind: TrashKind,
+    pub document: String,
+    pub deleted_at: i64,
+    /// The record as it was: a `Progress` or `DocumentAnnotations`.
+    pub record: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrashListResponse {
+    pub items: Vec<TrashItem>,
+}
+
+/// A trashed record put back.
+#[derive(Debug, Serialize)]
+pub struct RestoreResponse {
+    pub id: String,
+    pub kind: TrashKind,
+    pub document: String,
+    /// The record's version once restored.
+    pub version: u64,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProgressQuery {
+    /// Return this device's own latest position instead of the global one.
+    pub device_id: Option<String>,
+    /// Overrides the server's choice between 404 and `{}` for documents
+    /// without progress.
+    pub not_found: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+/// One document in a progress export, flattened for spreadsheets.
+#[derive(Debug, Serialize)]
+pub struct ProgressExportRow {
+    pub document: String,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub progress: Option<String>,
+    pub percentage: Option<f64>,
+    pub device: Option<String>,
+    pub timestamp: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProgressHistoryResponse {
+    pub document: String,
+    pub history: Vec<Progress>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProgressListEntry {
+    #[serde(flatten)]
+    pub progress: Progress,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<DocumentMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProgressListQuery {
+    /// Comma-separated hashes to restrict the listing to.
+    pub documents: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProgressQueryRequest {
+    pub documents: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ContinueReadingQuery {
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProgressListResponse {
+    pub documents: Vec<ProgressListEntry>,
+}
+
+// === Document metadata ===
+
+/// Human-readable details a client can attach to a document hash.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DocumentMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub series: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub isbn: Option<String>,
+    /// Set by the server on write.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<i64>,
+}
+
+/// A freeform markdown note about a book, kept apart from annotations.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DocumentNote {
+    pub text: String,
+    pub version: u64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateNoteRequest {
+    pub text: String,
+    /// Fail with a version conflict unless the stored note is at this version.
+    #[serde(default)]
+    pub base_version: Option<u64>,
+}
+
+// === Book status ===
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BookStatus {
+    ToRead,
+    Reading,
+    OnHold,
+    Finished,
+    Abandoned,
+}
+
+/// A user-assigned reading status, independent of the stored percentage.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentStatus {
+    pub document: String,
+    pub status: BookStatus,
+    pub updated_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<DocumentMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateStatusRequest {
+    pub status: BookStatus,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatusQuery {
+    pub status: Option<BookStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusListResponse {
+    /// Most recently updated first.
+    pub documents: Vec<DocumentStatus>,
+}
+
+// === Ratings and reviews ===
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Review {
+    pub document: String,
+    /// 1-5 stars.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rating: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub review: Option<String>,
+    pub updated_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<DocumentMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateReviewRequest {
+    pub rating: Option<u8>,
+    pub review: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReviewListResponse {
+    /// Most recently updated first.
+    pub reviews: Vec<Review>,
+}
+
+// === Tags ===
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentTags {
+    pub document: String,
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<DocumentMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateTagsRequest {
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DocumentListQuery {
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DocumentListResponse {
+    pub documents: Vec<DocumentTags>,
+}
+
+// === Document aliases ===
+
+#[derive(Debug, Deserialize)]
+pub struct CreateAliasesRequest {
+    /// The hash whose progress and annotations the aliases should share.
+    pub document: String,
+    pub aliases: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DocumentAlias {
+    pub alias: String,
+    pub document: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AliasListResponse {
+    pub aliases: Vec<DocumentAlias>,
 }
 
 // === Annotations (extended API) ===
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Annotation {
+    /// Server-assigned identity. Uploads without one are matched to a stored
+    /// annotation at the same position, or given a fresh id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     pub datetime: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub datetime_updated: Option<String>,
@@ -77,45 +411,1043 @@ pub struct Annotation {
     pub pos0: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub pos1: Option<serde_json::Value>,
+    /// Document version at which the server last accepted a change to this
+    /// annotation. Assigned by the server; clients should upload it
+    /// unchanged so the server can tell their edits from stale copies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    /// User labels such as "research" or "quotes".
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Ids of attachments (such as ink drawings) uploaded for this
+    /// annotation.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<String>,
+    /// When each field was last changed, by field name. Maintained by the
+    /// server; ignored on upload.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub clocks: BTreeMap<String, FieldClock>,
+    /// Position in the Calibre viewer, for annotations imported from it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calibre: Option<CalibreLocation>,
+    /// Position on a Kindle, for annotations imported from its clippings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kindle: Option<KindleLocation>,
+    /// `device_id` of the device that uploaded the annotation first. Set by
+    /// the server; uploaded values are ignored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    /// `device_id` of the device that last changed the annotation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_by: Option<String>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+/// Last change to one field of an annotation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldClock {
+    /// The `datetime_updated` of the upload that made the change.
+    pub at: String,
+    /// Document version at which the change was accepted.
+    pub version: u64,
+}
+
+/// An EPUB CFI range within one spine item.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CalibreLocation {
+    pub spine_index: u32,
+    pub start_cfi: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_cfi: Option<String>,
+}
+
+/// A range of Kindle locations, with the printed page where known.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KindleLocation {
+    pub start: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct DocumentAnnotations {
     pub version: u64,
     pub annotations: Vec<Annotation>,
+    /// Ids of deleted annotations; the `datetime` of annotations deleted
+    /// by clients that predate ids.
     #[serde(default)]
     pub deleted: Vec<String>,
+    /// Document version at which each entry in `deleted` was recorded.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub deleted_versions: BTreeMap<String, u64>,
+    /// When each entry in `deleted` was recorded, for pruning.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub deleted_at: BTreeMap<String, i64>,
+    pub updated_at: i64,
+    /// Set on paginated responses when more annotations follow; pass it back
+    /// as `cursor` to fetch the next page.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+    /// Set when `deleted` was cut short by `deleted_limit`; pass it back as
+    /// `deleted_cursor` for the rest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_deleted_cursor: Option<String>,
+}
+
+/// The version fields of `DocumentAnnotations`, for checking whether a
+/// client's copy is current.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AnnotationsStamp {
+    pub version: u64,
     pub updated_at: i64,
 }
 
+impl DocumentAnnotations {
+    /// Forget tombstones recorded before `cutoff` at or below `version`.
+    /// Returns how many were dropped.
+    pub fn prune_tombstones(&mut self, cutoff: i64, version: u64) -> usize {
+        let before = self.deleted.len();
+        let (deleted_versions, deleted_at) = (&mut self.deleted_versions, &mut self.deleted_at);
+        let updated_at = self.updated_at;
+        self.deleted.retain(|id| {
+            // Tombstones from before deletion times were tracked are at
+            // least as old as the last write
+            let at = deleted_at.get(id).copied().unwrap_or(updated_at);
+            let seen = deleted_versions.get(id).copied().unwrap_or(0) <= version;
+            if at < cutoff && seen {
+                deleted_versions.remove(id);
+                deleted_at.remove(id);
+                false
+            } else {
+                true
+            }
+        });
+        before - self.deleted.len()
+    }
+
+    /// Only the annotations and deletions recorded after `version`.
+    ///
+    /// Entries stored before versions were tracked count as version 0, so
+    /// they only appear in a full fetch.
+    pub fn changes_since(mut self, version: u64) -> Self {
+        self.annotations
+            .retain(|a| a.version.unwrap_or(0) > version);
+        let deleted_versions = &self.deleted_versions;
+        self.deleted
+            .retain(|id| deleted_versions.get(id).copied().unwrap_or(0) > version);
+        self
+    }
+
+    /// Up to `limit` deletions ordered by id, starting after `cursor`.
+    pub fn page_deleted(mut self, cursor: Option<&str>, limit: usize) -> Self {
+        self.deleted.sort();
+        if let Some(cursor) = cursor {
+            self.deleted.retain(|id| id.as_str() > cursor);
+        }
+        if self.deleted.len() > limit {
+            self.deleted.truncate(limit);
+            self.next_deleted_cursor = self.deleted.last().cloned();
+        }
+        let kept: HashSet<&String> = self.deleted.iter().collect();
+        self.deleted_versions.retain(|id, _| kept.contains(id));
+        self.deleted_at.retain(|id, _| kept.contains(id));
+        self
+    }
+
+    /// Up to `limit` annotations ordered by id, starting after `cursor`.
+    pub fn page(mut self, cursor: Option<&str>, limit: usize) -> Self {
+        self.annotations.sort_by(|a, b| a.id.cmp(&b.id));
+        if let Some(cursor) = cursor {
+            self.annotations
+                .retain(|a| a.id.as_deref().unwrap_or_default() > cursor);
+        }
+        if self.annotations.len() > limit {
+            self.annotations.truncate(limit);
+            self.next_cursor = self.annotations.last().and_then(|a| a.id.clone());
+        }
+        self
+    }
+}
+
 #[derive(Debug, Deserialize)]
-pub struct UpdateAnnotationsRequest {
+pub struct SearchQuery {
+    pub q: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnnotationSearchResponse {
+    /// Matching annotations in reading order.
     pub annotations: Vec<Annotation>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LibrarySearchQuery {
+    pub q: String,
+    pub limit: Option<usize>,
+}
+
+/// An annotation matching a search across all documents.
+#[derive(Debug, Serialize)]
+pub struct AnnotationSearchHit {
+    pub document: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Excerpt of the highlight or note around the first match.
+    pub snippet: String,
+    pub annotation: Annotation,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LibrarySearchResponse {
+    pub results: Vec<AnnotationSearchHit>,
+    /// Results beyond `limit` were left out.
+    pub truncated: bool,
+}
+
+/// One book's written notes, oldest first.
+#[derive(Debug, Serialize)]
+pub struct NotebookBook {
+    pub document: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub notes: Vec<Annotation>,
+}
+
+/// How often a highlight has come up in random review.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HighlightReview {
+    pub count: u32,
+    pub last_reviewed: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RandomHighlightsQuery {
+    pub count: Option<usize>,
+    /// Prefer highlights reviewed least, and longest ago.
     #[serde(default)]
-    pub deleted: Vec<String>,
-    #[serde(default)]
-    pub base_version: Option<u64>,
+    pub weighted: bool,
 }
 
 #[derive(Debug, Serialize)]
-pub struct UpdateAnnotationsResponse {
-    pub version: u64,
-    pub timestamp: i64,
+pub struct ReviewHighlight {
+    pub document: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub annotation: Annotation,
+    /// Times this highlight was served before, not counting this one.
+    pub review_count: u32,
 }
 
-// === Errors ===
+#[derive(Debug, Serialize)]
+pub struct RandomHighlightsResponse {
+    pub highlights: Vec<ReviewHighlight>,
+}
 
+/// Every annotation with a note, by book; the most recently annotated
+/// book comes first.
 #[derive(Debug, Serialize)]
-pub struct ErrorResponse {
-    pub code: u32,
-    pub message: String,
+pub struct NotebookResponse {
+    pub books: Vec<NotebookBook>,
 }
 
-impl ErrorResponse {
-    pub fn new(code: u32, message: impl Into<String>) -> Self {
-        Self {
-            code,
-            message: message.into(),
-        }
-    }
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnotationExportFormat {
+    #[default]
+    Markdown,
+    /// Markdown with YAML frontmatter and block IDs.
+    Obsidian,
+    /// Joplin export archive.
+    Jex,
+    /// The Calibre viewer's annotation JSON.
+    Calibre,
+    /// Tab-separated flashcards for Anki's text import.
+    Anki,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnotationImportFormat {
+    Calibre,
+    /// A KOReader `metadata.<ext>.lua` sidecar.
+    Koreader,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClearAnnotationsQuery {
+    /// Keep deletions, and record the cleared annotations as deleted.
+    #[serde(default)]
+    pub keep_tombstones: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KindleImportRequest {
+    /// Contents of `My Clippings.txt`.
+    pub clippings: String,
+    /// Document for each book, by title or by its `Title (Author)` line.
+    /// Books left out are matched by the title in their metadata.
+    #[serde(default)]
+    pub mapping: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KindleImportResponse {
+    /// Annotations imported per document.
+    pub imported: BTreeMap<String, usize>,
+    /// Books that matched no document, as `Title (Author)`.
+    pub unmatched: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnnotationImportQuery {
+    pub format: AnnotationImportFormat,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnnotationExportQuery {
+    #[serde(default)]
+    pub format: AnnotationExportFormat,
+    /// Export only annotations with this tag.
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnkiExportQuery {
+    /// Export only annotations with this tag.
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AnnotationsQuery {
+    /// Return only changes made after this document version.
+    pub since_version: Option<u64>,
+    /// Page size; the full set is returned, in reading order, when neither
+    /// this nor `cursor` is given. Pages are in id order.
+    pub limit: Option<usize>,
+    /// `next_cursor` from the previous page.
+    pub cursor: Option<String>,
+    /// Identifies the fetching device, so tombstones it has seen can be
+    /// pruned.
+    pub device_id: Option<String>,
+    /// Return only annotations with this tag.
+    pub tag: Option<String>,
+    /// Most deletions to return; the rest follow from `deleted_cursor`.
+    pub deleted_limit: Option<usize>,
+    /// `next_deleted_cursor` from the previous response.
+    pub deleted_cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateAnnotationsRequest {
+    pub annotations: Vec<Annotation>,
+    #[serde(default)]
+    pub deleted: Vec<String>,
+    #[serde(default)]
+    pub base_version: Option<u64>,
+    /// Overrides the server's `KOSYNC_MERGE_STRATEGY` for this upload.
+    #[serde(default)]
+    pub strategy: Option<MergeStrategyKind>,
+    /// Collapse annotations with the same position and text into the
+    /// earliest one.
+    #[serde(default)]
+    pub dedup: bool,
+    /// The uploading device, credited with the annotations it adds or
+    /// changes.
+    #[serde(default)]
+    pub device_id: Option<String>,
+}
+
+/// A document's annotations published at `/shared/{token}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicShare {
+    pub token: String,
+    pub username: String,
+    pub document: String,
+    pub created_at: i64,
+}
+
+/// Response to publishing a document's annotations.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublicShareResponse {
+    pub token: String,
+    pub document: String,
+    /// Path of the page, relative to the server root.
+    pub url: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct FeedQuery {
+    /// Replace the token, so the old feed URL stops working.
+    #[serde(default)]
+    pub rotate: bool,
+}
+
+/// Where a user's highlights feed can be read without authentication.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FeedResponse {
+    pub token: String,
+    /// Path of the Atom feed, relative to the server root.
+    pub url: String,
+}
+
+/// A binary blob referenced from annotations, stored once per content hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: String,
+    /// Hex SHA-256 of the content.
+    pub sha256: String,
+    pub content_type: String,
+    pub size: usize,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AttachmentListResponse {
+    pub attachments: Vec<Attachment>,
+}
+
+/// A kept version of a document's annotations.
+#[derive(Debug, Serialize)]
+pub struct AnnotationVersion {
+    pub version: u64,
+    pub updated_at: i64,
+    /// Number of annotations in this version.
+    pub annotations: usize,
+    /// Number of deletions recorded up to this version.
+    pub deleted: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnnotationVersionsResponse {
+    pub versions: Vec<AnnotationVersion>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnnotationDiffQuery {
+    pub from: u64,
+    pub to: u64,
+}
+
+/// An annotation present in both versions of a diff, with the fields that
+/// changed between them.
+#[derive(Debug, Serialize)]
+pub struct AnnotationChange {
+    pub id: String,
+    pub fields: Vec<&'static str>,
+    pub before: Annotation,
+    pub after: Annotation,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnnotationDiffResponse {
+    pub from: u64,
+    pub to: u64,
+    pub added: Vec<Annotation>,
+    pub removed: Vec<Annotation>,
+    pub modified: Vec<AnnotationChange>,
+}
+
+/// What happened to an uploaded annotation that was not applied as sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictOutcome {
+    /// It had been deleted on the server, so the upload was dropped.
+    Deleted,
+    /// The stored values of `fields` were kept over the uploaded ones.
+    Kept,
+    /// It was stored as a separate annotation, under `stored_as`.
+    Duplicated,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnnotationConflict {
+    /// Id of the uploaded annotation.
+    pub id: String,
+    pub outcome: ConflictOutcome,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored_as: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpdateAnnotationsResponse {
+    pub version: u64,
+    pub timestamp: i64,
+    /// Ids of the uploaded annotations, in upload order.
+    pub ids: Vec<String>,
+    /// Uploaded annotations that were not applied as sent.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<AnnotationConflict>,
+    /// With `dedup`, ids removed as duplicates and the id each was merged
+    /// into.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub merged: BTreeMap<String, String>,
+}
+
+/// The result an upload would have, from the preview endpoint.
+#[derive(Debug, Serialize)]
+pub struct AnnotationPreviewResponse {
+    /// Version the upload would create.
+    pub version: u64,
+    pub annotations: Vec<Annotation>,
+    pub deleted: Vec<String>,
+    pub ids: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<AnnotationConflict>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub merged: BTreeMap<String, String>,
+}
+
+// === Shared documents ===
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareMember {
+    pub username: String,
+    /// The member's own (alias-resolved) hash for the shared document.
+    pub document: String,
+    /// Invited members only share annotations once they join.
+    pub joined: bool,
+}
+
+/// Users whose annotations for one document are kept in a single merged
+/// record. Progress stays per-user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareGroup {
+    pub id: String,
+    pub owner: String,
+    pub members: Vec<ShareMember>,
+    pub created_at: i64,
+}
+
+impl ShareGroup {
+    pub fn member(&self, username: &str) -> Option<&ShareMember> {
+        self.members.iter().find(|m| m.username == username)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateShareRequest {
+    pub document: String,
+    /// Users to invite; each must join before annotations are shared.
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShareListResponse {
+    pub shares: Vec<ShareGroup>,
+}
+
+// === Reading statistics (KOReader statistics plugin) ===
+
+/// A row of the statistics plugin's `book` table, identified by MD5.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatBook {
+    pub md5: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authors: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub series: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pages: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_open: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub highlights: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<i64>,
+    /// Seconds read, computed by the server from page stats.
+    #[serde(default)]
+    pub total_read_time: i64,
+    /// Distinct pages read, computed by the server from page stats.
+    #[serde(default)]
+    pub total_read_pages: i64,
+}
+
+impl StatBook {
+    /// Combine a stored book with an upload: fields present in the upload
+    /// win, except `last_open` which keeps the most recent value.
+    pub fn merged_with(self, other: &StatBook) -> StatBook {
+        StatBook {
+            md5: self.md5,
+            title: other.title.clone().or(self.title),
+            authors: other.authors.clone().or(self.authors),
+            series: other.series.clone().or(self.series),
+            language: other.language.clone().or(self.language),
+            pages: other.pages.or(self.pages),
+            last_open: self.last_open.max(other.last_open),
+            highlights: other.highlights.or(self.highlights),
+            notes: other.notes.or(self.notes),
+            total_read_time: 0,
+            total_read_pages: 0,
+        }
+    }
+}
+
+/// A row of the statistics plugin's `page_stat_data` table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageStat {
+    pub md5: String,
+    pub page: i64,
+    pub start_time: i64,
+    pub duration: i64,
+    pub total_pages: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatisticsUpload {
+    #[serde(default)]
+    pub books: Vec<StatBook>,
+    #[serde(default)]
+    pub page_stats: Vec<PageStat>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct StatisticsMergeResult {
+    pub books_added: u64,
+    pub page_stats_added: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatisticsQuery {
+    pub since: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Statistics {
+    pub books: Vec<StatBook>,
+    pub page_stats: Vec<PageStat>,
+}
+
+// === Reading sessions ===
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadingSession {
+    pub document: String,
+    /// Unix timestamp the session started.
+    pub start: i64,
+    /// Unix timestamp the session ended.
+    pub end: i64,
+    pub pages_read: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionsQuery {
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub document: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionsResponse {
+    pub sessions: Vec<ReadingSession>,
+}
+
+// === Devices ===
+
+/// A device that has synced progress, identified by its `device_id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub device_id: String,
+    /// Most recent human-readable device name.
+    pub device: String,
+    pub last_seen: i64,
+    pub last_document: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceListResponse {
+    pub devices: Vec<Device>,
+}
+
+// === Finished books ===
+
+/// A document that crossed the finished threshold.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FinishedBook {
+    pub document: String,
+    /// When the threshold was last crossed.
+    pub finished_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<DocumentMetadata>,
+    /// The user's star rating, if they left one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rating: Option<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FinishedQuery {
+    pub year: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadingLogFormat {
+    #[default]
+    Goodreads,
+    Storygraph,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReadingLogQuery {
+    #[serde(default)]
+    pub format: ReadingLogFormat,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FinishedYear {
+    pub year: i64,
+    pub count: usize,
+    pub books: Vec<FinishedBook>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FinishedResponse {
+    /// Most recent year first; books in the order they were finished.
+    pub years: Vec<FinishedYear>,
+}
+
+// === Goals and streaks ===
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ReadingGoal {
+    pub minutes: Option<u32>,
+    pub pages: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DayActivity {
+    /// Local calendar date, `YYYY-MM-DD`.
+    pub date: String,
+    pub minutes: u32,
+    pub pages: u32,
+    pub goal_met: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StreakResponse {
+    pub goal: ReadingGoal,
+    /// Consecutive days meeting the goal, ending today (or yesterday if
+    /// today's goal is not met yet).
+    pub current: u32,
+    pub longest: u32,
+    pub today: DayActivity,
+}
+
+// === Change notifications ===
+
+/// A change pushed to a user's connected clients.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SyncEvent {
+    Progress {
+        document: String,
+        progress: String,
+        percentage: f64,
+        device: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        device_id: Option<String>,
+        timestamp: i64,
+    },
+    ProgressDeleted {
+        document: String,
+    },
+    Annotations {
+        document: String,
+        version: u64,
+        timestamp: i64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        patch: Option<AnnotationPatch>,
+    },
+    /// Progress crossed the finished threshold.
+    Finished {
+        document: String,
+        percentage: f64,
+        timestamp: i64,
+    },
+}
+
+/// What one annotation write changed. A client holding `base_version` can
+/// apply it and be at the event's version without fetching.
+#[derive(Debug, Clone, Serialize)]
+pub struct AnnotationPatch {
+    pub base_version: u64,
+    pub annotations: Vec<Annotation>,
+    pub deleted: Vec<String>,
+}
+
+impl SyncEvent {
+    pub fn progress(update: &UpdateProgressRequest, document: &str, timestamp: i64) -> Self {
+        Self::Progress {
+            document: document.to_string(),
+            progress: update.progress.clone(),
+            percentage: update.percentage,
+            device: update.device.clone(),
+            device_id: update.device_id.clone(),
+            timestamp,
+        }
+    }
+
+    /// The `type` tag, used to match webhook subscriptions.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Progress { .. } => "progress",
+            Self::ProgressDeleted { .. } => "progress_deleted",
+            Self::Annotations { .. } => "annotations",
+            Self::Finished { .. } => "finished",
+        }
+    }
+}
+
+// === Integrations ===
+
+/// Credentials for pushing progress to Hardcover.app.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HardcoverIntegration {
+    /// Hardcover API token, sent as a bearer token.
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadwiseIntegration {
+    /// Readwise access token, from readwise.io/access_token.
+    pub token: String,
+}
+
+/// A document whose highlights failed to reach Readwise.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadwiseRetry {
+    pub attempts: u32,
+    pub retry_at: i64,
+}
+
+/// Login for a calibre-web instance, used with HTTP basic auth.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibreWebIntegration {
+    /// Base URL, e.g. `https://books.example.com`.
+    pub url: String,
+    pub username: String,
+    pub password: String,
+}
+
+/// The Calibre book a document hash corresponds to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibreBook {
+    pub book_id: u64,
+    /// Format the calibre-web reader opens, e.g. `epub`.
+    pub format: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CalibreBookMapping {
+    pub document: String,
+    #[serde(flatten)]
+    pub book: CalibreBook,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CalibreBookListResponse {
+    pub books: Vec<CalibreBookMapping>,
+}
+
+// === Webhooks ===
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    /// HMAC-SHA256 key for the `X-Kosync-Signature` header.
+    pub secret: String,
+    /// Event types to deliver; empty means all.
+    pub events: Vec<String>,
+    pub created_at: i64,
+}
+
+impl Webhook {
+    pub fn wants(&self, event: &SyncEvent) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == event.name())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+/// A webhook as listed back to its owner, without the secret.
+#[derive(Debug, Serialize)]
+pub struct WebhookInfo {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<String>,
+    pub created_at: i64,
+}
+
+impl From<Webhook> for WebhookInfo {
+    fn from(webhook: Webhook) -> Self {
+        Self {
+            id: webhook.id,
+            url: webhook.url,
+            events: webhook.events,
+            created_at: webhook.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookListResponse {
+    pub webhooks: Vec<WebhookInfo>,
+}
+
+/// Body POSTed to webhook URLs.
+#[derive(Debug, Serialize)]
+pub struct WebhookPayload<'a> {
+    pub username: &'a str,
+    pub event: &'a SyncEvent,
+}
+
+// === Usage metrics ===
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointUsage {
+    pub endpoint: String,
+    pub requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageResponse {
+    pub window_secs: u64,
+    pub endpoints: Vec<EndpointUsage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserUsage {
+    pub username: String,
+    pub requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub endpoints: Vec<EndpointUsage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminUsageResponse {
+    pub window_secs: u64,
+    pub users: Vec<UserUsage>,
+}
+
+// === Backups ===
+
+#[derive(Debug, Serialize)]
+pub struct BackupResponse {
+    /// File name of the backup within the backup directory.
+    pub file: String,
+    pub size: u64,
+}
+
+// === Disk usage ===
+
+/// The database file's size against its budget.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskUsage {
+    /// Bytes; 0 for an in-memory database.
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget: Option<u64>,
+    /// `size` as a percentage of `budget`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<f64>,
+    /// Writes are refused because the file reached its budget.
+    pub read_only: bool,
+}
+
+// === Errors ===
+
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    pub code: u32,
+    pub message: String,
+}
+
+impl ErrorResponse {
+    pub fn new(code: u32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+// === Change journal ===
+
+/// One change recorded in the journal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Increases by one with every change, and is never reused.
+    pub sequence: u64,
+    /// The user whose request made the change, or whose data a maintenance
+    /// task changed.
+    pub username: String,
+    /// The table changed.
+    pub table: String,
+    /// The changed entry's key, with a `(user, document)` key written
+    /// `user:document`.
+    pub key: String,
+    /// The record's version after the change, for records that have one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    pub timestamp: i64,
+    /// The entry was removed rather than written.
+    #[serde(default)]
+    pub deleted: bool,
+    /// `key` is a prefix, and every entry starting with it was removed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub prefix: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JournalQuery {
+    /// Sequence number of the last change the follower has.
+    #[serde(default)]
+    pub after: u64,
+    pub limit: Option<usize>,
+}
+
+/// A journal entry as a leader sends it to its followers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalChange {
+    #[serde(flatten)]
+    pub entry: JournalEntry,
+    /// What the key holds now, base64-encoded. Missing when the entry
+    /// removed it, or a later change did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalResponse {
+    /// The leader's latest sequence number.
+    pub sequence: u64,
+    pub changes: Vec<JournalChange>,
 }
@@ -0,0 +1,101 @@
+//! Requests to URLs users supply, such as webhooks.
+//!
+//! Unless `Config::private_urls` is set, these may only reach public
+//! addresses, so that an account can't use the server to probe services
+//! on its own network. A host is checked when the URL is saved and again
+//! every time it is resolved, since its DNS may change in between.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use reqwest::Url;
+
+/// Why `url` can't be used, if it can't: it must be http(s) and, unless
+/// `private` is set, must not name a non-public address.
+pub fn check_url(url: &str, private: bool) -> Result<(), &'static str> {
+    let url = Url::parse(url).map_err(|_| "not a valid url")?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("url must be http(s)");
+    }
+    let host = url.host_str().ok_or("url has no host")?;
+    // IPv6 hosts keep their brackets
+    let public = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => is_public(ip),
+        Err(_) => {
+            let domain = host.trim_end_matches('.').to_ascii_lowercase();
+            domain != "localhost" && !domain.ends_with(".localhost")
+        }
+    };
+    if public || private {
+        Ok(())
+    } else {
+        Err("url must not point at a private address")
+    }
+}
+
+/// An HTTP client for user-supplied URLs. It doesn't follow redirects,
+/// which could lead anywhere, and unless `private` is set refuses to
+/// connect to hosts resolving to non-public addresses.
+pub fn client(timeout: Duration, private: bool) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(Policy::none());
+    if !private {
+        builder = builder.dns_resolver(Arc::new(PublicResolver));
+    }
+    builder.build().expect("failed to build HTTP client")
+}
+
+/// Resolves names to their public addresses only.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || a == 0
+        // Carrier-grade NAT, 100.64.0.0/10
+        || (a == 100 && (64..128).contains(&b)))
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        // Unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // Link-local, fe80::/10
+        || (first & 0xffc0) == 0xfe80)
+}
@@ -0,0 +1,171 @@
+//! Ordering annotations by where they are in the book.
+//!
+//! Reflowable documents locate annotations by xpointer, such as
+//! `/body/DocFragment[12]/body/div/p[5]/text().37`; paged ones by page
+//! number. Xpointers are compared step by step, with sibling indices and the
+//! trailing character offset compared as numbers, so `p[10]` comes after
+//! `p[9]`.
+//!
+//! Clients also disagree on how to write a position down: `5`, `5.0` and
+//! `"5"` are the same page. `canonical_key` reduces an annotation's position
+//! to one string so such copies are recognized as the same annotation.
+
+use std::cmp::Ordering;
+
+use serde_json::Value;
+
+use crate::models::Annotation;
+
+/// Reading order: by position where the two positions can be compared,
+/// then by page number, then by creation time. Ties are broken by id so the
+/// order is the same on every request.
+pub fn reading_order(a: &Annotation, b: &Annotation) -> Ordering {
+    let position = match (start(a), start(b)) {
+        (Position::Page(x), Position::Page(y)) => x.total_cmp(&y),
+        (Position::Xpointer(x), Position::Xpointer(y)) => compare_xpointers(x, y),
+        _ => Ordering::Equal,
+    };
+    position
+        .then_with(|| {
+            a.pageno
+                .unwrap_or(i32::MAX)
+                .cmp(&b.pageno.unwrap_or(i32::MAX))
+        })
+        .then_with(|| a.datetime.cmp(&b.datetime))
+        .then_with(|| a.id.cmp(&b.id))
+}
+
+/// A string naming an annotation's position, equal for any two annotations
+/// at the same place however their positions were written.
+///
+/// Numbers, and strings holding one, are written in their shortest form;
+/// other strings are trimmed; objects (the page coordinates of PDF
+/// highlights) have their keys sorted.
+pub fn canonical_key(a: &Annotation) -> String {
+    let mut key = String::new();
+    for (i, value) in [Some(&a.page), a.pos0.as_ref(), a.pos1.as_ref()]
+        .into_iter()
+        .enumerate()
+    {
+        if i > 0 {
+            key.push('|');
+        }
+        push_canonical(&mut key, value.unwrap_or(&Value::Null));
+    }
+    key
+}
+
+fn push_canonical(out: &mut String, value: &Value) {
+    match value {
+        Value::Null => out.push('-'),
+        Value::Bool(b) => out.push_str(if *b { "t" } else { "f" }),
+        Value::Number(n) => push_number(out, n.as_f64()),
+        Value::String(s) => {
+            let s = s.trim();
+            match s.parse::<f64>() {
+                Ok(n) if n.is_finite() => push_number(out, Some(n)),
+                // Quoted, so no string can pass for a number or a separator
+                _ => out.push_str(&serde_json::to_string(s).unwrap_or_default()),
+            }
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                push_canonical(out, item);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            out.push('{');
+            for (i, (name, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::to_string(name).unwrap_or_default());
+                out.push(':');
+                push_canonical(out, item);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn push_number(out: &mut String, n: Option<f64>) {
+    match n {
+        // `-0` and `0` are the same place
+        Some(0.0) => out.push('0'),
+        Some(n) => out.push_str(&n.to_string()),
+        None => out.push_str("NaN"),
+    }
+}
+
+enum Position<'a> {
+    Page(f64),
+    Xpointer(&'a str),
+    Unknown,
+}
+
+/// Where an annotation starts: its highlight start if it has one, else its
+/// page.
+fn start(a: &Annotation) -> Position<'_> {
+    let pos0 = a.pos0.as_ref().and_then(Value::as_str);
+    match (pos0, &a.page) {
+        (Some(pos0), _) if pos0.starts_with('/') => Position::Xpointer(pos0),
+        (_, Value::String(page)) if page.starts_with('/') => Position::Xpointer(page),
+        (_, Value::Number(page)) => page.as_f64().map_or(Position::Unknown, Position::Page),
+        _ => Position::Unknown,
+    }
+}
+
+/// Compare two xpointers in document order. An element comes before its
+/// descendants.
+pub fn compare_xpointers(a: &str, b: &str) -> Ordering {
+    let (a, b) = (steps(a), steps(b));
+    for (x, y) in a.iter().zip(&b) {
+        let order = x
+            .index
+            .cmp(&y.index)
+            .then_with(|| x.name.cmp(y.name))
+            .then_with(|| x.offset.cmp(&y.offset));
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+struct Step<'a> {
+    name: &'a str,
+    /// 1-based position among siblings; 1 when left out.
+    index: u32,
+    /// Character offset, on the final `text()` step.
+    offset: u32,
+}
+
+fn steps(xpointer: &str) -> Vec<Step<'_>> {
+    xpointer
+        .split('/')
+        .filter(|step| !step.is_empty())
+        .map(|step| {
+            // `text().37`
+            let (step, offset) = match step.rsplit_once('.') {
+                Some((step, offset)) if step.ends_with(')') => (step, offset.parse().unwrap_or(0)),
+                _ => (step, 0),
+            };
+            let (name, index) = match step.split_once('[') {
+                Some((name, rest)) => (name, rest.trim_end_matches(']').parse().unwrap_or(1)),
+                None => (step, 1),
+            };
+            Step {
+                name,
+                index,
+                offset,
+            }
+        })
+        .collect()
+}
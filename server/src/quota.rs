@@ -0,0 +1,101 @@
+//! Keeping the database file within `KOSYNC_DISK_BUDGET_MB`.
+//!
+//! The file never shrinks while the server runs (see `backup::compact`),
+//! so once it crosses a threshold it stays there until compacted. Each
+//! crossing is logged once; with `KOSYNC_DISK_READ_ONLY`, reaching the
+//! budget also makes the server refuse writes, before a full disk starts
+//! failing its commits.
+
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::db::Database;
+use crate::error::Result;
+use crate::models::DiskUsage;
+use crate::AppState;
+
+/// How close the file is to its budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Normal,
+    /// Past `disk_warn_percent` of the budget.
+    Warning,
+    /// At or over the budget.
+    Full,
+}
+
+/// Measure the database file, and refuse or allow writes to match when
+/// `config.disk_read_only` is set.
+pub fn check(db: &Database, config: &Config) -> Result<(DiskUsage, Level)> {
+    let size = db.file_size()?;
+    let level = match config.disk_budget {
+        Some(budget) if size >= budget => Level::Full,
+        Some(budget) if size * 100 >= budget * config.disk_warn_percent => Level::Warning,
+        _ => Level::Normal,
+    };
+    if config.disk_read_only {
+        db.set_full(level == Level::Full);
+    }
+    Ok((usage(db, config, size), level))
+}
+
+/// The file's size against its budget as last checked.
+pub fn usage(db: &Database, config: &Config, size: u64) -> DiskUsage {
+    DiskUsage {
+        size,
+        budget: config.disk_budget,
+        percent: config
+            .disk_budget
+            .map(|budget| size as f64 * 100.0 / budget.max(1) as f64),
+        read_only: db.is_full(),
+    }
+}
+
+/// Check the file every `interval`, logging whenever it crosses a
+/// threshold.
+pub fn spawn_monitor(state: AppState, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        let mut previous = Level::Normal;
+        loop {
+            ticker.tick().await;
+            let (usage, level) = match state.with_db(|db| check(db, &state.config)) {
+                Ok(checked) => checked,
+                Err(e) => {
+                    tracing::error!("Failed to measure the database file: {}", e);
+                    continue;
+                }
+            };
+            if level == previous {
+                continue;
+            }
+            let budget = usage.budget.unwrap_or_default();
+            match level {
+                Level::Full if usage.read_only => tracing::error!(
+                    "The database file is {} bytes, at its budget of {}; refusing writes \
+                     until it is compacted or the budget raised",
+                    usage.size,
+                    budget
+                ),
+                Level::Full => tracing::error!(
+                    "The database file is {} bytes, over its budget of {}",
+                    usage.size,
+                    budget
+                ),
+                Level::Warning => tracing::warn!(
+                    "The database file is {} bytes, {:.0}% of its budget of {}",
+                    usage.size,
+                    usage.percent.unwrap_or_default(),
+                    budget
+                ),
+                Level::Normal => tracing::info!(
+                    "The database file is {} bytes, back under {}% of its budget of {}",
+                    usage.size,
+                    state.config.disk_warn_percent,
+                    budget
+                ),
+            }
+            previous = level;
+        }
+    });
+}
@@ -0,0 +1,188 @@
+use serde_json::json;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::models::{Annotation, ReadwiseIntegration, ReadwiseRetry, SyncEvent};
+use crate::AppState;
+
+/// Key the user's token is stored under in the integrations table.
+pub const NAME: &str = "readwise";
+
+/// Pushes per document before a failing one is dropped from the queue.
+const MAX_ATTEMPTS: u32 = 8;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Push new highlights to Readwise for users who configured a token.
+///
+/// Each annotation is sent once, tracked by id. Documents whose push fails
+/// are queued in the database and retried with exponential backoff, so
+/// failures survive restarts.
+pub fn spawn_sync(state: AppState) {
+    let mut events = state.events.subscribe();
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("failed to build Readwise HTTP client");
+
+    let retry_state = state.clone();
+    let retry_client = client.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(retry_state.config.readwise_retry_interval);
+        loop {
+            ticker.tick().await;
+            let due = match retry_state.with_db(|db| db.take_due_readwise_retries(crate::db::now()))
+            {
+                Ok(due) => due,
+                Err(e) => {
+                    tracing::error!("Failed to load Readwise retries: {}", e);
+                    continue;
+                }
+            };
+            for (username, document, retry) in due {
+                tokio::spawn(sync_document(
+                    retry_state.clone(),
+                    retry_client.clone(),
+                    username,
+                    document,
+                    retry.attempts,
+                ));
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Readwise sync skipped {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let SyncEvent::Annotations { document, .. } = &event.event else {
+                continue;
+            };
+            tokio::spawn(sync_document(
+                state.clone(),
+                client.clone(),
+                event.username.clone(),
+                document.clone(),
+                0,
+            ));
+        }
+    });
+}
+
+/// Push a document's unsent highlights, queueing a retry on failure.
+async fn sync_document(
+    state: AppState,
+    client: reqwest::Client,
+    username: String,
+    document: String,
+    attempts: u32,
+) {
+    let Err(e) = push_new(&state, &client, &username, &document).await else {
+        return;
+    };
+
+    let attempts = attempts + 1;
+    if attempts >= MAX_ATTEMPTS {
+        tracing::error!(
+            "Giving up on Readwise push of {} for {}: {}",
+            document,
+            username,
+            e
+        );
+        return;
+    }
+    tracing::warn!(
+        "Readwise push of {} for {} failed: {} (attempt {}/{})",
+        document,
+        username,
+        e,
+        attempts,
+        MAX_ATTEMPTS
+    );
+    let delay = state.config.readwise_retry_interval * 2u32.pow(attempts - 1);
+    let retry = ReadwiseRetry {
+        attempts,
+        retry_at: crate::db::now() + delay.as_secs() as i64,
+    };
+    if let Err(e) = state.with_db(|db| db.queue_readwise_retry(&username, &document, &retry)) {
+        tracing::error!("Failed to queue Readwise retry: {}", e);
+    }
+}
+
+async fn push_new(
+    state: &AppState,
+    client: &reqwest::Client,
+    username: &str,
+    document: &str,
+) -> Result<(), String> {
+    let integration: ReadwiseIntegration = match state
+        .with_db(|db| db.get_integration(username, NAME))
+        .map_err(|e| e.to_string())?
+    {
+        Some(integration) => integration,
+        None => return Ok(()),
+    };
+
+    let annotations = state
+        .with_db(|db| db.get_annotations(username, document))
+        .map_err(|e| e.to_string())?;
+    let pushed = state
+        .with_db(|db| db.readwise_pushed(username, document))
+        .map_err(|e| e.to_string())?;
+    let new: Vec<&Annotation> = annotations
+        .annotations
+        .iter()
+        .filter(|a| a.text.as_deref().is_some_and(|t| !t.trim().is_empty()))
+        .filter(|a| a.id.as_ref().is_some_and(|id| !pushed.contains(id)))
+        .collect();
+    if new.is_empty() {
+        return Ok(());
+    }
+
+    let metadata = state
+        .with_db(|db| db.get_metadata(username, document))
+        .map_err(|e| e.to_string())?
+        .unwrap_or_default();
+    let title = metadata.title.as_deref().unwrap_or(document);
+    let highlights: Vec<_> = new
+        .iter()
+        .map(|a| {
+            let mut highlight = json!({
+                "text": a.text,
+                "title": title,
+                "author": metadata.author,
+                "source_type": "kosync",
+                "category": "books",
+                "note": a.note,
+                "highlighted_at": a.datetime.replacen(' ', "T", 1),
+            });
+            if let Some(page) = a.pageno {
+                highlight["location"] = json!(page);
+                highlight["location_type"] = json!("page");
+            }
+            highlight
+        })
+        .collect();
+
+    let response = client
+        .post(&state.config.readwise_url)
+        .header("authorization", format!("Token {}", integration.token))
+        .json(&json!({ "highlights": highlights }))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}", response.status()));
+    }
+
+    let ids: Vec<String> = new.iter().filter_map(|a| a.id.clone()).collect();
+    state
+        .with_db(|db| db.add_readwise_pushed(username, document, &ids))
+        .map_err(|e| e.to_string())
+}
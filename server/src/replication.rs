@@ -0,0 +1,232 @@
+//! Continuous replication of the database to another directory, typically
+//! a mount of another disk or machine.
+//!
+//! Each run takes a backup (see `Database::backup`) and ships it in
+//! fixed-size chunks named by their SHA-256. A backup is written fresh
+//! from the tables in order, so an update changes only the few chunks
+//! holding the pages it touched; chunks already in the replica are not
+//! written again. A snapshot is a manifest listing its chunks:
+//!
+//! ```text
+//! <dir>/chunks/<sha256>
+//! <dir>/snapshots/kosync-20240131T235959Z.json
+//! ```
+//!
+//! The manifest is written last, so a snapshot is only listed once all of
+//! its chunks are in place.
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::backup;
+use crate::db::{self, Database};
+use crate::error::{AppError, Result};
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    created_at: i64,
+    size: u64,
+    chunk_size: usize,
+    /// SHA-256 of each chunk, in order.
+    chunks: Vec<String>,
+}
+
+/// A snapshot written by `Replicator::run`.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub name: String,
+    pub size: u64,
+    pub chunks: usize,
+    /// Chunks the replica didn't already hold.
+    pub uploaded: usize,
+    /// Old snapshots deleted to stay within the limit.
+    pub pruned: usize,
+}
+
+/// Ships snapshots of a database to `dir`, remembering which chunks are
+/// already there.
+pub struct Replicator {
+    dir: PathBuf,
+    /// Snapshots kept; 0 keeps all.
+    keep: usize,
+    known: Option<HashSet<String>>,
+    /// The database generation of the last snapshot.
+    replicated: Option<u64>,
+}
+
+impl Replicator {
+    pub fn new(dir: PathBuf, keep: usize) -> Self {
+        Self {
+            dir,
+            keep,
+            known: None,
+            replicated: None,
+        }
+    }
+
+    /// Write a snapshot of `db`, unless nothing was committed since the
+    /// last one.
+    pub fn run(&mut self, db: &Database) -> Result<Option<Snapshot>> {
+        let generation = db.generation();
+        if self.replicated == Some(generation) {
+            return Ok(None);
+        }
+        let chunks_dir = self.dir.join("chunks");
+        let snapshots_dir = self.dir.join("snapshots");
+        std::fs::create_dir_all(&chunks_dir)?;
+        std::fs::create_dir_all(&snapshots_dir)?;
+        let known = match &mut self.known {
+            Some(known) => known,
+            None => self.known.insert(list_names(&chunks_dir)?),
+        };
+
+        let created_at = db::now();
+        let copy = std::env::temp_dir().join(format!("kosync-replica-{}.db", uuid::Uuid::new_v4()));
+        db.backup(&copy)?;
+        let data = std::fs::read(&copy);
+        std::fs::remove_file(&copy)?;
+        let data = data?;
+
+        let mut manifest = Manifest {
+            created_at,
+            size: data.len() as u64,
+            chunk_size: CHUNK_SIZE,
+            chunks: Vec::new(),
+        };
+        let mut uploaded = 0;
+        for chunk in data.chunks(CHUNK_SIZE) {
+            let hash = format!("{:x}", Sha256::digest(chunk));
+            if !known.contains(&hash) {
+                write_atomically(&chunks_dir.join(&hash), chunk)?;
+                known.insert(hash.clone());
+                uploaded += 1;
+            }
+            manifest.chunks.push(hash);
+        }
+        let name = snapshot_name(created_at);
+        write_atomically(&snapshots_dir.join(&name), &serde_json::to_vec(&manifest)?)?;
+        self.replicated = Some(generation);
+
+        let pruned = match self.keep {
+            0 => 0,
+            keep => self.prune(keep)?,
+        };
+        Ok(Some(Snapshot {
+            name,
+            size: manifest.size,
+            chunks: manifest.chunks.len(),
+            uploaded,
+            pruned,
+        }))
+    }
+
+    /// Delete all but the newest `keep` snapshots, then the chunks no
+    /// remaining snapshot uses.
+    fn prune(&mut self, keep: usize) -> Result<usize> {
+        let snapshots_dir = self.dir.join("snapshots");
+        let snapshots = list_snapshots(&snapshots_dir)?;
+        let excess = snapshots.len().saturating_sub(keep);
+        if excess == 0 {
+            return Ok(0);
+        }
+        for name in &snapshots[..excess] {
+            std::fs::remove_file(snapshots_dir.join(name))?;
+        }
+        let mut used = HashSet::new();
+        for name in &snapshots[excess..] {
+            used.extend(read_manifest(&snapshots_dir.join(name))?.chunks);
+        }
+        let chunks_dir = self.dir.join("chunks");
+        let known = self.known.get_or_insert_with(HashSet::new);
+        for hash in list_names(&chunks_dir)? {
+            if !used.contains(&hash) {
+                std::fs::remove_file(chunks_dir.join(&hash))?;
+                known.remove(&hash);
+            }
+        }
+        Ok(excess)
+    }
+}
+
+/// Rebuild the newest snapshot in the replica at `dir` as a database file
+/// at `path`, returning the snapshot's name and size. Every chunk is
+/// checked against its hash.
+pub fn restore(dir: &Path, path: &Path) -> Result<(String, u64)> {
+    if path.exists() {
+        return Err(AppError::InvalidRequest(format!(
+            "{} already exists",
+            path.display()
+        )));
+    }
+    let snapshots_dir = dir.join("snapshots");
+    let name = list_snapshots(&snapshots_dir)?
+        .pop()
+        .ok_or_else(|| AppError::InvalidRequest(format!("no snapshots in {}", dir.display())))?;
+    let manifest = read_manifest(&snapshots_dir.join(&name))?;
+
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let mut file = std::fs::File::create(&partial)?;
+    for hash in &manifest.chunks {
+        let chunk = std::fs::read(dir.join("chunks").join(hash))?;
+        if format!("{:x}", Sha256::digest(&chunk)) != *hash {
+            return Err(AppError::InvalidRequest(format!(
+                "chunk {} is corrupt",
+                hash
+            )));
+        }
+        file.write_all(&chunk)?;
+    }
+    file.sync_all()?;
+    std::fs::rename(&partial, path)?;
+    Ok((name, manifest.size))
+}
+
+/// `kosync-20240131T235959Z.json`, sorting in the order snapshots were taken.
+fn snapshot_name(timestamp: i64) -> String {
+    backup::file_name(timestamp).replace(".db", ".json")
+}
+
+/// Snapshot manifests in `dir`, oldest first.
+fn list_snapshots(dir: &Path) -> Result<Vec<String>> {
+    let mut names: Vec<String> = list_names(dir)?
+        .into_iter()
+        .filter(|name| name.starts_with("kosync-") && name.ends_with("Z.json"))
+        .collect();
+    names.sort_unstable();
+    Ok(names)
+}
+
+fn list_names(dir: &Path) -> Result<HashSet<String>> {
+    let mut names = HashSet::new();
+    for entry in std::fs::read_dir(dir)? {
+        if let Ok(name) = entry?.file_name().into_string() {
+            if !name.ends_with(".partial") {
+                names.insert(name);
+            }
+        }
+    }
+    Ok(names)
+}
+
+fn read_manifest(path: &Path) -> Result<Manifest> {
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+/// Write under a `.partial` name and rename into place, so a file is either
+/// complete or absent.
+fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let mut file = std::fs::File::create(&partial)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
@@ -0,0 +1,287 @@
+//! A minimal client for S3-compatible object storage (AWS S3, MinIO,
+//! Cloudflare R2, Backblaze B2, ...), enough to upload, list and delete
+//! backups. Buckets are addressed path-style, `<endpoint>/<bucket>/<key>`,
+//! which every S3-compatible service accepts.
+
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::backup;
+use crate::config::Config;
+use crate::db;
+use crate::error::{AppError, Result};
+
+/// Generous, as backups are uploaded in one request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// What a request is signed with.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub region: String,
+}
+
+impl Credentials {
+    /// The `Authorization` header for a request, signed with AWS Signature
+    /// Version 4 over its `host`, `x-amz-content-sha256` and `x-amz-date`
+    /// headers. `path` is already URI-encoded; `amz_date` is the
+    /// `x-amz-date` value, e.g. `20130524T000000Z`.
+    pub fn authorization(
+        &self,
+        method: &str,
+        host: &str,
+        path: &str,
+        query: &[(&str, &str)],
+        payload_hash: &str,
+        amz_date: &str,
+    ) -> String {
+        let mut query: Vec<String> = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", uri_encode(name), uri_encode(value)))
+            .collect();
+        query.sort_unstable();
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            path,
+            query.join("&"),
+            host,
+            payload_hash,
+            amz_date,
+            signed_headers,
+            payload_hash
+        );
+
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = [date, self.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", self.secret_access_key).into_bytes(),
+                |key, part| hmac(&key, part.as_bytes()),
+            );
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id,
+            scope,
+            signed_headers,
+            hex(&hmac(&key, string_to_sign.as_bytes()))
+        )
+    }
+}
+
+/// A bucket, and the prefix keys are stored under within it.
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    client: reqwest::Client,
+    endpoint: reqwest::Url,
+    bucket: String,
+    prefix: String,
+    credentials: Credentials,
+}
+
+impl Bucket {
+    /// The bucket configured for backups, if one is.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let endpoint = config.s3_endpoint.as_deref()?;
+        let endpoint = match reqwest::Url::parse(endpoint) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                tracing::warn!("Ignoring KOSYNC_S3_ENDPOINT {}: {}", endpoint, e);
+                return None;
+            }
+        };
+        let (Some(bucket), Some(access_key_id), Some(secret_access_key)) = (
+            config.s3_bucket.clone(),
+            config.s3_access_key_id.clone(),
+            config.s3_secret_access_key.clone(),
+        ) else {
+            tracing::warn!(
+                "KOSYNC_S3_ENDPOINT needs KOSYNC_S3_BUCKET, KOSYNC_S3_ACCESS_KEY_ID and \
+                 KOSYNC_S3_SECRET_ACCESS_KEY"
+            );
+            return None;
+        };
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("HTTP client");
+        Some(Self {
+            client,
+            endpoint,
+            bucket,
+            prefix: config.s3_prefix.clone(),
+            credentials: Credentials {
+                access_key_id,
+                secret_access_key,
+                region: config.s3_region.clone(),
+            },
+        })
+    }
+
+    /// Where `name` is stored, for logs.
+    pub fn location(&self, name: &str) -> String {
+        format!("s3://{}/{}{}", self.bucket, self.prefix, name)
+    }
+
+    pub async fn put(&self, name: &str, body: Vec<u8>) -> Result<()> {
+        let key = format!("{}{}", self.prefix, name);
+        self.send(reqwest::Method::PUT, Some(&key), &[], body)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<()> {
+        let key = format!("{}{}", self.prefix, name);
+        self.send(reqwest::Method::DELETE, Some(&key), &[], Vec::new())
+            .await?;
+        Ok(())
+    }
+
+    /// Names of the objects under the prefix, without it, in key order.
+    pub async fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", self.prefix.as_str())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token));
+            }
+            let body = self
+                .send(reqwest::Method::GET, None, &query, Vec::new())
+                .await?;
+            for key in xml_values(&body, "Key") {
+                if let Some(name) = key.strip_prefix(self.prefix.as_str()) {
+                    names.push(name.to_string());
+                }
+            }
+            token = match xml_values(&body, "IsTruncated").first().map(String::as_str) {
+                Some("true") => xml_values(&body, "NextContinuationToken").pop(),
+                _ => None,
+            };
+            if token.is_none() {
+                return Ok(names);
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        key: Option<&str>,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<String> {
+        let mut path = self.endpoint.path().trim_end_matches('/').to_string();
+        path.push('/');
+        path.push_str(&uri_encode(&self.bucket));
+        if let Some(key) = key {
+            path.push('/');
+            path.push_str(&key.split('/').map(uri_encode).collect::<Vec<_>>().join("/"));
+        }
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        // Encoded the way it is signed
+        let encoded_query: Vec<String> = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", uri_encode(name), uri_encode(value)))
+            .collect();
+        let encoded_query = encoded_query.join("&");
+        url.set_query(Some(encoded_query.as_str()).filter(|q| !q.is_empty()));
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let payload_hash = hex(&Sha256::digest(&body));
+        let amz_date = backup::basic_time(db::now());
+        let authorization = self.credentials.authorization(
+            method.as_str(),
+            &host,
+            url.path(),
+            query,
+            &payload_hash,
+            &amz_date,
+        );
+        let response = self
+            .client
+            .request(method, url)
+            .header("x-amz-content-sha256", &payload_hash)
+            .header("x-amz-date", &amz_date)
+            .header("authorization", authorization)
+            .body(body)
+            .send()
+            .await
+            .map_err(s3_error)?;
+        let status = response.status();
+        let text = response.text().await.map_err(s3_error)?;
+        if !status.is_success() {
+            let message = xml_values(&text, "Message").pop().unwrap_or(text);
+            return Err(s3_error(format!("{} {}", status, message)));
+        }
+        Ok(text)
+    }
+}
+
+fn s3_error(message: impl std::fmt::Display) -> AppError {
+    AppError::Upstream(format!("S3: {}", message))
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters, as
+/// Signature Version 4 requires.
+fn uri_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// The text of every `<tag>` element. S3's listings are flat enough that
+/// this needs no XML parser.
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        values.push(
+            rest[..end]
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&"),
+        );
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
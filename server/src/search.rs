@@ -0,0 +1,87 @@
+//! Tokenizing for the annotation search index.
+//!
+//! Highlight text and notes are split into lowercase alphanumeric terms,
+//! which are stored as `user:term:document:annotation id` keys so a lookup
+//! is a range scan over the terms starting with a query word.
+
+use std::collections::BTreeSet;
+
+use crate::models::Annotation;
+
+/// Terms shorter than this aren't indexed.
+const MIN_TERM_LEN: usize = 2;
+/// Longer terms are truncated, so prefix queries still find them.
+const MAX_TERM_LEN: usize = 32;
+
+/// The distinct terms of `text`.
+pub fn terms(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_TERM_LEN)
+        .map(|word| word.to_lowercase().chars().take(MAX_TERM_LEN).collect())
+        .collect()
+}
+
+/// The terms an annotation is indexed under: its highlighted text and note.
+pub fn annotation_terms(annotation: &Annotation) -> BTreeSet<String> {
+    let mut all = BTreeSet::new();
+    for field in [&annotation.text, &annotation.note].into_iter().flatten() {
+        all.extend(terms(field));
+    }
+    all
+}
+
+/// Characters of context kept on each side of the first match.
+const SNIPPET_CONTEXT: usize = 60;
+
+/// A short excerpt of `text` around the first word matching a query term,
+/// or `None` if no word matches.
+pub fn snippet(text: &str, query: &[String]) -> Option<String> {
+    let mut offset = 0;
+    let mut found = None;
+    for word in text.split(|c: char| !c.is_alphanumeric()) {
+        let lower = word.to_lowercase();
+        if !word.is_empty() && query.iter().any(|q| lower.starts_with(q.as_str())) {
+            found = Some(offset);
+            break;
+        }
+        // The separator is one character, but not necessarily one byte
+        offset += word.len()
+            + text[offset + word.len()..]
+                .chars()
+                .next()
+                .map_or(0, char::len_utf8);
+    }
+    let start = found?;
+
+    let before: Vec<(usize, char)> = text[..start].char_indices().collect();
+    let from = before
+        .len()
+        .checked_sub(SNIPPET_CONTEXT)
+        .map_or(0, |i| before[i].0);
+    let to = text[start..]
+        .char_indices()
+        .nth(SNIPPET_CONTEXT * 2)
+        .map_or(text.len(), |(i, _)| start + i);
+
+    let mut snippet = String::new();
+    if from > 0 {
+        snippet.push('…');
+    }
+    snippet.push_str(text[from..to].trim());
+    if to < text.len() {
+        snippet.push('…');
+    }
+    Some(snippet)
+}
+
+/// Query words, lowercased. Each matches any indexed term it is a prefix
+/// of, and an annotation must match every word.
+pub fn query_terms(query: &str) -> Vec<String> {
+    query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase().chars().take(MAX_TERM_LEN).collect())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect()
+}
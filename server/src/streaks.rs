@@ -0,0 +1,169 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::models::{DayActivity, PageStat, ReadingGoal, ReadingSession, StreakResponse};
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+/// Reading done on one local day, as reported by each source.
+///
+/// Sessions and the statistics page log usually describe the same reading,
+/// so the two are not added together; the larger of each is used.
+#[derive(Debug, Default)]
+struct Day {
+    session_secs: i64,
+    session_pages: u32,
+    stat_secs: i64,
+    stat_pages: HashSet<(String, i64)>,
+    synced: bool,
+}
+
+impl Day {
+    fn minutes(&self) -> u32 {
+        (self.session_secs.max(self.stat_secs) / 60) as u32
+    }
+
+    fn pages(&self) -> u32 {
+        self.session_pages.max(self.stat_pages.len() as u32)
+    }
+
+    fn meets(&self, goal: ReadingGoal) -> bool {
+        if goal.minutes.is_none() && goal.pages.is_none() {
+            // Without a goal any reading activity keeps the streak going
+            return self.synced || self.session_secs > 0 || !self.stat_pages.is_empty();
+        }
+        goal.minutes.is_none_or(|m| self.minutes() >= m)
+            && goal.pages.is_none_or(|p| self.pages() >= p)
+    }
+}
+
+/// Daily activity gathered from a user's synced data.
+pub struct Activity {
+    offset_secs: i64,
+    days: BTreeMap<i64, Day>,
+}
+
+impl Activity {
+    pub fn new(utc_offset_minutes: i32) -> Self {
+        Self {
+            offset_secs: i64::from(utc_offset_minutes) * 60,
+            days: BTreeMap::new(),
+        }
+    }
+
+    fn day(&mut self, timestamp: i64) -> &mut Day {
+        let day = (timestamp + self.offset_secs).div_euclid(DAY_SECS);
+        self.days.entry(day).or_default()
+    }
+
+    pub fn add_session(&mut self, session: &ReadingSession) {
+        let day = self.day(session.start);
+        day.session_secs += session.end - session.start;
+        day.session_pages += session.pages_read;
+    }
+
+    pub fn add_page_stat(&mut self, stat: &PageStat) {
+        let day = self.day(stat.start_time);
+        day.stat_secs += stat.duration;
+        day.stat_pages.insert((stat.md5.clone(), stat.page));
+    }
+
+    /// A progress sync counts as activity, but carries no minutes or pages.
+    pub fn add_progress(&mut self, timestamp: i64) {
+        self.day(timestamp).synced = true;
+    }
+
+    pub fn streak(&self, goal: ReadingGoal, now: i64) -> StreakResponse {
+        let today = (now + self.offset_secs).div_euclid(DAY_SECS);
+        let met = |day: i64| self.days.get(&day).is_some_and(|d| d.meets(goal));
+
+        let mut longest = 0;
+        let mut run = 0;
+        let mut previous = None;
+        for (&day, activity) in &self.days {
+            if day > today {
+                break;
+            }
+            if !activity.meets(goal) {
+                run = 0;
+            } else {
+                run = if previous == Some(day - 1) {
+                    run + 1
+                } else {
+                    1
+                };
+                previous = Some(day);
+            }
+            longest = longest.max(run);
+        }
+
+        let mut current = 0;
+        let mut day = if met(today) { today } else { today - 1 };
+        while met(day) {
+            current += 1;
+            day -= 1;
+        }
+
+        let activity = self.days.get(&today);
+        StreakResponse {
+            goal,
+            current,
+            longest,
+            today: DayActivity {
+                date: format_date(today),
+                minutes: activity.map_or(0, Day::minutes),
+                pages: activity.map_or(0, Day::pages),
+                goal_met: met(today),
+            },
+        }
+    }
+}
+
+/// Calendar year of a timestamp in the given UTC offset.
+pub(crate) fn local_year(timestamp: i64, utc_offset_minutes: i32) -> i64 {
+    let days = (timestamp + i64::from(utc_offset_minutes) * 60).div_euclid(DAY_SECS);
+    civil_from_days(days).0
+}
+
+/// Calendar date of a timestamp in the given UTC offset, `YYYY-MM-DD`.
+pub(crate) fn local_date(timestamp: i64, utc_offset_minutes: i32) -> String {
+    format_date((timestamp + i64::from(utc_offset_minutes) * 60).div_euclid(DAY_SECS))
+}
+
+/// `YYYY-MM-DD` for a count of days since 1970-01-01.
+pub(crate) fn format_date(days: i64) -> String {
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Days since 1970-01-01 of a date starting `YYYY-MM-DD`, such as a
+/// KOReader annotation `datetime`.
+pub(crate) fn parse_date(date: &str) -> Option<i64> {
+    let mut parts = date.get(..10)?.split('-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Howard Hinnant's days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146_097 + doe - 719_468)
+}
+
+/// Civil `(year, month, day)` for a count of days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    // Howard Hinnant's days_from_civil, inverted
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
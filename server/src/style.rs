@@ -0,0 +1,74 @@
+//! Canonical highlight styles.
+//!
+//! Devices don't agree on how to write a highlight's style: one sends
+//! `"Yellow"`, another `"#FFFF33"`, an older client `"highlight"` for what
+//! KOReader calls `"lighten"`. Uploads are rewritten to one spelling before
+//! they are merged, so such differences aren't taken for edits.
+
+use crate::models::Annotation;
+
+/// The drawers KOReader knows, plus `ink` for freehand drawings.
+pub const DRAWERS: [&str; 5] = ["lighten", "underscore", "strikeout", "invert", "ink"];
+
+/// KOReader's highlight colors, by name and the value it draws them with.
+const COLORS: [(&str, &str); 9] = [
+    ("red", "#ff3300"),
+    ("orange", "#ff8800"),
+    ("yellow", "#ffff33"),
+    ("green", "#00aa66"),
+    ("olive", "#88ff77"),
+    ("cyan", "#00ffee"),
+    ("blue", "#0066ff"),
+    ("purple", "#ee00ff"),
+    ("gray", "#808080"),
+];
+
+/// Rewrite an annotation's drawer and color to their canonical forms.
+/// Values that can't be recognized are left as they are, for validation
+/// to reject.
+pub fn normalize(annotation: &mut Annotation) {
+    if let Some(drawer) = annotation.drawer.as_deref().and_then(drawer) {
+        annotation.drawer = Some(drawer.to_string());
+    }
+    if let Some(color) = annotation.color.as_deref().and_then(color) {
+        annotation.color = Some(color);
+    }
+}
+
+/// The canonical name of a drawer, accepting the names other clients use.
+pub fn drawer(value: &str) -> Option<&'static str> {
+    let value = value.trim().to_ascii_lowercase();
+    let value = match value.as_str() {
+        "highlight" => "lighten",
+        "underline" => "underscore",
+        "strikethrough" => "strikeout",
+        other => other,
+    };
+    DRAWERS.iter().copied().find(|d| *d == value)
+}
+
+/// A color as KOReader's name for it if it has one, otherwise as lowercase
+/// `#rrggbb`.
+pub fn color(value: &str) -> Option<String> {
+    let value = value.trim().to_ascii_lowercase();
+    let Some(hex) = value.strip_prefix('#') else {
+        let name = if value == "grey" { "gray" } else { &value };
+        return COLORS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(n, _)| n.to_string());
+    };
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let hex = match hex.len() {
+        3 => hex.chars().flat_map(|c| [c, c]).collect(),
+        6 => hex.to_string(),
+        _ => return None,
+    };
+    let hex = format!("#{}", hex);
+    Some(match COLORS.iter().find(|(_, v)| *v == hex) {
+        Some((name, _)) => name.to_string(),
+        None => hex,
+    })
+}
@@ -0,0 +1,333 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::backup;
+use crate::calibre_web;
+use crate::config::{Durability, DEMO_USER};
+use crate::follower::Leader;
+use crate::hardcover;
+use crate::quota;
+use crate::readwise;
+use crate::replication::Replicator;
+use crate::s3;
+use crate::webhooks;
+use crate::AppState;
+
+/// Start every background task enabled by the configuration. A follower
+/// runs none of those changing users' data: it gets those changes from
+/// its leader.
+pub fn spawn_all(state: &AppState) {
+    match Leader::from_config(&state.config) {
+        Ok(Some(leader)) => spawn_following(state.clone(), leader, state.config.follow_interval),
+        Ok(None) => spawn_leading(state),
+        Err(e) => tracing::error!("Can't follow the leader: {}", e),
+    }
+    if !state.config.journal_retention.is_zero() {
+        spawn_journal_pruning(
+            state.clone(),
+            state.config.journal_retention,
+            state.config.retention_interval,
+        );
+    }
+    if state.config.disk_budget.is_some() {
+        quota::spawn_monitor(state.clone(), state.config.disk_check_interval);
+    }
+    if let Some(interval) = state.config.backup_interval {
+        let bucket = s3::Bucket::from_config(&state.config);
+        match (state.config.backup_dir.clone(), bucket) {
+            (None, None) => tracing::warn!(
+                "KOSYNC_BACKUP_INTERVAL_SECS is set without KOSYNC_BACKUP_DIR or KOSYNC_S3_ENDPOINT"
+            ),
+            (dir, bucket) => spawn_backups(state.clone(), dir, bucket, interval),
+        }
+    }
+    if let Some(dir) = state.config.replica_dir.clone() {
+        spawn_replication(state.clone(), dir, state.config.replica_interval);
+    }
+    if state.config.durability == Durability::Eventual {
+        spawn_flush(state.clone(), state.config.flush_interval);
+    }
+}
+
+/// Start the tasks that change users' data.
+fn spawn_leading(state: &AppState) {
+    webhooks::spawn_dispatcher(state.clone());
+    hardcover::spawn_sync(state.clone());
+    calibre_web::spawn_sync(state.clone());
+    readwise::spawn_sync(state.clone());
+    if state.config.demo_mode {
+        match state.with_db(|db| db.user_exists(DEMO_USER)) {
+            // Demo mode never stores the account, so this one is somebody's
+            Ok(true) => tracing::error!(
+                "Not resetting '{}': a registered account has that name",
+                DEMO_USER
+            ),
+            Ok(false) => spawn_demo_reset(state.clone(), state.config.demo_reset_interval),
+            Err(e) => tracing::error!("Failed to look up the '{}' account: {}", DEMO_USER, e),
+        }
+    }
+    if let Some(retention) = state.config.progress_retention {
+        spawn_progress_retention(state.clone(), retention, state.config.retention_interval);
+    }
+    if let Some(retention) = state.config.tombstone_retention {
+        spawn_tombstone_pruning(state.clone(), retention, state.config.retention_interval);
+    }
+    if !state.config.trash_retention.is_zero() {
+        spawn_trash_emptying(
+            state.clone(),
+            state.config.trash_retention,
+            state.config.retention_interval,
+        );
+    }
+}
+
+/// Pull the leader's changes every `interval`.
+fn spawn_following(state: AppState, leader: Leader, interval: Duration) {
+    tracing::info!(
+        "Following {} every {}s",
+        state.config.leader_url.as_deref().unwrap_or_default(),
+        interval.as_secs()
+    );
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match leader.pull(&state).await {
+                Ok(0) => {}
+                Ok(applied) => tracing::debug!("Applied {} changes from the leader", applied),
+                Err(e) => tracing::error!("Following the leader failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Make commits durable every `interval`, when there were any since the
+/// last flush.
+fn spawn_flush(state: AppState, interval: Duration) {
+    tracing::info!("Flushing commits to disk every {}ms", interval.as_millis());
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut flushed = None;
+        loop {
+            ticker.tick().await;
+            let generation = state.with_db(|db| db.generation());
+            if flushed == Some(generation) {
+                continue;
+            }
+            match state.with_db(|db| db.flush()) {
+                Ok(()) => flushed = Some(generation),
+                Err(e) => tracing::error!("Failed to flush commits to disk: {}", e),
+            }
+        }
+    });
+}
+
+/// Replicate the database to `dir` whenever something changed, checking
+/// every `interval`.
+fn spawn_replication(state: AppState, dir: PathBuf, interval: Duration) {
+    tracing::info!(
+        "Replicating to {} every {}s",
+        dir.display(),
+        interval.as_secs()
+    );
+    let mut replicator = Replicator::new(dir, state.config.replica_keep);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let started = std::time::Instant::now();
+            match state.with_db(|db| replicator.run(db)) {
+                Ok(Some(snapshot)) => tracing::info!(
+                    "Replicated snapshot {} ({} bytes, {} of {} chunks new, in {}ms), \
+                     deleted {} old snapshots",
+                    snapshot.name,
+                    snapshot.size,
+                    snapshot.uploaded,
+                    snapshot.chunks,
+                    started.elapsed().as_millis(),
+                    snapshot.pruned
+                ),
+                Ok(None) => {}
+                Err(e) => tracing::error!("Replication failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Periodically back up the database to `dir` and/or `bucket`, keeping the
+/// newest `config.backup_keep` backups in each. Runs are aligned to the
+/// interval, so a daily backup is taken at midnight UTC whenever the server
+/// was started.
+fn spawn_backups(
+    state: AppState,
+    dir: Option<PathBuf>,
+    bucket: Option<s3::Bucket>,
+    interval: Duration,
+) {
+    let period = interval.as_secs().max(1);
+    let wait = period - crate::db::now().rem_euclid(period as i64) as u64;
+    let targets: Vec<String> = dir
+        .iter()
+        .map(|dir| dir.display().to_string())
+        .chain(bucket.iter().map(|bucket| bucket.location("")))
+        .collect();
+    tracing::info!(
+        "Backing up to {} every {}s, next in {}s",
+        targets.join(" and "),
+        period,
+        wait
+    );
+    // Without a backup directory, a backup is only kept here until uploaded
+    let staging = dir.clone().unwrap_or_else(std::env::temp_dir);
+    tokio::spawn(async move {
+        let start = tokio::time::Instant::now() + Duration::from_secs(wait);
+        let mut ticker = tokio::time::interval_at(start, Duration::from_secs(period));
+        loop {
+            ticker.tick().await;
+            let started = std::time::Instant::now();
+            let (path, size) = match state.with_db(|db| backup::create_in(db, &staging)) {
+                Ok(written) => written,
+                Err(e) => {
+                    tracing::error!("Scheduled backup failed: {}", e);
+                    continue;
+                }
+            };
+            if let Some(dir) = &dir {
+                let pruned = match state.config.backup_keep {
+                    0 => Ok(0),
+                    keep => backup::prune(dir, keep),
+                };
+                match pruned {
+                    Ok(pruned) => tracing::info!(
+                        "Wrote backup {} ({} bytes in {}ms), deleted {} old backups",
+                        path.display(),
+                        size,
+                        started.elapsed().as_millis(),
+                        pruned
+                    ),
+                    Err(e) => tracing::error!(
+                        "Wrote backup {} but failed to delete old backups: {}",
+                        path.display(),
+                        e
+                    ),
+                }
+            }
+            if let Some(bucket) = &bucket {
+                let started = std::time::Instant::now();
+                let uploaded = backup::upload(bucket, &path, state.config.backup_keep).await;
+                let location =
+                    bucket.location(&path.file_name().unwrap_or_default().to_string_lossy());
+                match uploaded {
+                    Ok(pruned) => tracing::info!(
+                        "Uploaded backup {} ({} bytes in {}ms), deleted {} old backups",
+                        location,
+                        size,
+                        started.elapsed().as_millis(),
+                        pruned
+                    ),
+                    Err(e) => tracing::error!("Failed to upload backup {}: {}", location, e),
+                }
+                if dir.is_none() {
+                    if let Err(e) = std::fs::remove_file(&path) {
+                        tracing::warn!("Failed to delete {}: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Periodically forget annotation deletions every syncing device has seen.
+fn spawn_tombstone_pruning(state: AppState, retention: Duration, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let cutoff = crate::db::now() - retention.as_secs() as i64;
+            match state.with_db(|db| db.prune_tombstones(cutoff)) {
+                Ok(0) => {}
+                Ok(pruned) => tracing::info!("Pruned {} annotation tombstones", pruned),
+                Err(e) => tracing::error!("Tombstone pruning failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Periodically drop change journal entries older than `retention`.
+fn spawn_journal_pruning(state: AppState, retention: Duration, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let cutoff = crate::db::now() - retention.as_secs() as i64;
+            match state.with_db(|db| db.prune_journal(cutoff)) {
+                Ok(0) => {}
+                Ok(pruned) => tracing::info!("Pruned {} change journal entries", pruned),
+                Err(e) => tracing::error!("Change journal pruning failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Periodically delete trash items older than `retention` for good.
+fn spawn_trash_emptying(state: AppState, retention: Duration, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let cutoff = crate::db::now() - retention.as_secs() as i64;
+            match state.with_db(|db| db.empty_trash(cutoff)) {
+                Ok(0) => {}
+                Ok(emptied) => tracing::info!("Emptied {} items from the trash", emptied),
+                Err(e) => tracing::error!("Emptying the trash failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Periodically purge stale progress and progress left behind by deleted
+/// users.
+fn spawn_progress_retention(state: AppState, retention: Duration, interval: Duration) {
+    tracing::info!(
+        "Purging progress untouched for {} days every {}s",
+        retention.as_secs() / (24 * 60 * 60),
+        interval.as_secs()
+    );
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let cutoff = crate::db::now() - retention.as_secs() as i64;
+            let keep: &[&str] = if state.config.demo_mode {
+                &[DEMO_USER]
+            } else {
+                &[]
+            };
+            match state.with_db(|db| db.purge_progress(Some(cutoff), keep)) {
+                Ok(0) => {}
+                Ok(purged) => tracing::info!("Purged progress for {} documents", purged),
+                Err(e) => tracing::error!("Progress retention failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Periodically wipe everything synced to the shared demo account.
+fn spawn_demo_reset(state: AppState, interval: Duration) {
+    tracing::info!(
+        "Demo mode enabled; '{}' data resets every {}s",
+        DEMO_USER,
+        interval.as_secs()
+    );
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match state.with_db(|db| db.delete_user_data(DEMO_USER)) {
+                Ok(()) => tracing::debug!("Demo account data reset"),
+                Err(e) => tracing::error!("Failed to reset demo account: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,127 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::events::UserEvent;
+use crate::models::{Webhook, WebhookPayload};
+use crate::{outbound, AppState};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest wait between two delivery attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Listen for sync events and POST them to the owning user's webhooks.
+///
+/// Each delivery runs in its own task so a slow or failing endpoint only
+/// delays its own retries.
+pub fn spawn_dispatcher(state: AppState) {
+    let mut events = state.events.subscribe();
+    let private_urls = state.config.private_urls;
+    let client = outbound::client(REQUEST_TIMEOUT, private_urls);
+
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Webhook dispatcher skipped {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let webhooks = match state.with_db(|db| db.list_webhooks(&event.username)) {
+                Ok(webhooks) => webhooks,
+                Err(e) => {
+                    tracing::error!("Failed to load webhooks for {}: {}", event.username, e);
+                    continue;
+                }
+            };
+
+            for webhook in webhooks.into_iter().filter(|w| w.wants(&event.event)) {
+                // Saved before private addresses were refused
+                if let Err(e) = outbound::check_url(&webhook.url, private_urls) {
+                    tracing::warn!("Skipping webhook {}: {}", webhook.id, e);
+                    continue;
+                }
+                tokio::spawn(deliver(
+                    client.clone(),
+                    webhook,
+                    event.clone(),
+                    state.config.webhook_max_attempts,
+                    state.config.webhook_retry_delay,
+                ));
+            }
+        }
+    });
+}
+
+/// POST one event, retrying with exponential backoff on failure.
+async fn deliver(
+    client: reqwest::Client,
+    webhook: Webhook,
+    event: UserEvent,
+    max_attempts: u32,
+    retry_delay: Duration,
+) {
+    let payload = WebhookPayload {
+        username: &event.username,
+        event: &event.event,
+    };
+    let body = match serde_json::to_vec(&payload) {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("Failed to serialize webhook payload: {}", e);
+            return;
+        }
+    };
+    let signature = sign(&webhook.secret, &body);
+
+    let mut delay = retry_delay;
+    for attempt in 1..=max_attempts.max(1) {
+        let result = client
+            .post(&webhook.url)
+            .header("content-type", "application/json")
+            .header("x-kosync-event", event.event.name())
+            .header("x-kosync-signature", &signature)
+            .body(body.clone())
+            .send()
+            .await;
+
+        match result {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => tracing::warn!(
+                "Webhook {} returned {} (attempt {}/{})",
+                webhook.id,
+                response.status(),
+                attempt,
+                max_attempts
+            ),
+            Err(e) => tracing::warn!(
+                "Webhook {} failed: {} (attempt {}/{})",
+                webhook.id,
+                e,
+                attempt,
+                max_attempts
+            ),
+        }
+
+        if attempt < max_attempts {
+            tokio::time::sleep(delay).await;
+            delay = delay.saturating_mul(2).min(MAX_RETRY_DELAY);
+        }
+    }
+    tracing::error!("Giving up on webhook {} for {}", webhook.id, event.username);
+}
+
+/// `sha256=<hex HMAC of the body>`, in the style of GitHub webhooks.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
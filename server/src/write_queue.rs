@@ -0,0 +1,157 @@
+//! Group commit for small, frequent writes such as progress updates.
+//!
+//! Every redb commit ends in an fsync, which dominates the cost of a
+//! single progress update. Writes submitted while a batch is being
+//! committed wait in a queue; the first caller to find the queue idle
+//! applies everything waiting in one transaction, commits once, and hands
+//! each caller its own result.
+
+use std::sync::{mpsc, Condvar, Mutex, MutexGuard};
+
+use redb::WriteTransaction;
+
+use crate::db::Database;
+use crate::error::{AppError, Result};
+
+type PendingWrite = Box<dyn FnOnce(&Database, &WriteTransaction) -> Applied + Send>;
+/// Given why the batch failed, if it did.
+type Finish = Box<dyn FnOnce(Option<&str>) + Send>;
+
+/// A write applied to the batch's transaction.
+struct Applied {
+    /// Why the batch can't be committed, when this write failed in a way
+    /// that may have left it half done.
+    fatal: Option<String>,
+    /// Hands the caller its result.
+    finish: Finish,
+}
+
+#[derive(Default)]
+pub(crate) struct WriteQueue {
+    state: Mutex<State>,
+    batch_done: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    pending: Vec<PendingWrite>,
+    /// Batches taken from `pending` so far.
+    taken: u64,
+    /// Batches committed or failed so far.
+    finished: u64,
+    /// A batch is being applied.
+    busy: bool,
+}
+
+impl WriteQueue {
+    /// Apply `op` in the next batch and return its result once that batch
+    /// is committed.
+    ///
+    /// `op` must do its checks before writing anything: one refused with a
+    /// client error is reported to its caller alone while the rest of the
+    /// batch still commits. Any other error fails the whole batch.
+    pub(crate) fn submit<T: Send + 'static>(
+        &self,
+        db: &Database,
+        op: impl FnOnce(&Database, &WriteTransaction) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let (sender, receiver) = mpsc::channel();
+        let job: PendingWrite = Box::new(move |db, write_txn| {
+            let result = op(db, write_txn);
+            let fatal = match &result {
+                Err(e) if !e.is_client_error() => Some(e.to_string()),
+                _ => None,
+            };
+            Applied {
+                fatal,
+                finish: Box::new(move |failure| {
+                    let result = match (result, failure) {
+                        (Ok(_), Some(failure)) => Err(AppError::WriteBatch(failure.to_string())),
+                        (result, _) => result,
+                    };
+                    let _ = sender.send(result);
+                }),
+            }
+        });
+
+        let mut state = self.lock();
+        state.pending.push(job);
+        // Everything pending is taken at once, so this write goes in
+        // whichever batch is taken next
+        let batch = state.taken;
+        while state.finished <= batch {
+            if state.busy {
+                state = self
+                    .batch_done
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner());
+                continue;
+            }
+            let jobs = std::mem::take(&mut state.pending);
+            state.taken += 1;
+            state.busy = true;
+            drop(state);
+
+            let finished = BatchFinished(self);
+            apply(db, jobs);
+            drop(finished);
+            state = self.lock();
+        }
+        drop(state);
+
+        // Only a batch that never got a transaction, or panicked, drops
+        // its writes unanswered
+        receiver
+            .recv()
+            .unwrap_or_else(|_| Err(AppError::WriteBatch("the batch was abandoned".into())))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Marks the batch being applied as finished, even if applying it panics,
+/// so the callers waiting on it don't wait forever.
+struct BatchFinished<'a>(&'a WriteQueue);
+
+impl Drop for BatchFinished<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.busy = false;
+        state.finished += 1;
+        self.0.batch_done.notify_all();
+    }
+}
+
+fn apply(db: &Database, jobs: Vec<PendingWrite>) {
+    let write_txn = match db.begin_write() {
+        Ok(write_txn) => write_txn,
+        Err(e) => {
+            tracing::error!("Failed to start a write batch: {}", e);
+            return;
+        }
+    };
+    let count = jobs.len();
+    let mut failure = None;
+    let mut applied = Vec::with_capacity(count);
+    for job in jobs {
+        let result = job(db, &write_txn);
+        if failure.is_none() {
+            failure = result.fatal.clone();
+        }
+        applied.push(result);
+    }
+    if failure.is_none() {
+        match write_txn.commit() {
+            Ok(()) if count > 1 => {
+                tracing::debug!("Committed {} writes in one transaction", count)
+            }
+            Ok(()) => {}
+            Err(e) => failure = Some(e.to_string()),
+        }
+    }
+    for result in applied {
+        (result.finish)(failure.as_deref());
+    }
+}
@@ -0,0 +1,63 @@
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::HeaderMap,
+    response::Response,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::error::Result;
+use crate::events::UserEvent;
+use crate::handlers::authorize;
+use crate::AppState;
+
+/// `GET /syncs/ws`: authenticate with the usual headers, then receive a
+/// JSON text message for every progress or annotation change on the account.
+pub async fn sync_socket(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<Response> {
+    let username = authorize(&state, &headers)?;
+    // Subscribe before the handshake completes so nothing published right
+    // after the client sees the upgrade is missed.
+    let events = state.events.subscribe();
+    Ok(ws.on_upgrade(move |socket| push_events(socket, events, username)))
+}
+
+async fn push_events(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<UserEvent>,
+    username: String,
+) {
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if event.username == username => {
+                    let text = match serde_json::to_string(&event.event) {
+                        Ok(text) => text,
+                        Err(e) => {
+                            tracing::error!("Failed to serialize sync event: {}", e);
+                            continue;
+                        }
+                    };
+                    if socket.send(Message::Text(text.into())).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("WebSocket for {} skipped {} events", username, skipped);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                // Clients have nothing to say on this channel; just notice hangups.
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}